@@ -0,0 +1,71 @@
+//! Throughput of the pipeline that reads and transforms files, by number of
+//! workers and distribution of input sizes. Run with
+//! `cargo bench --bench pipeline`.
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use jbackup::bench::MultithreadPipeline;
+
+/// Times of each run are noisy, so the fastest of these many runs is kept.
+const RUNS: usize = 3;
+
+fn run_pipeline(num_workers: usize, input_sizes: &[usize]) -> Duration {
+    let start = Instant::now();
+    let mut pipeline = MultithreadPipeline::<Vec<u8>, usize, usize>::new(
+        0,
+        Box::new(|total, output| {
+            *total += output;
+            Ok(())
+        }),
+    );
+    pipeline.spawn_workers(num_workers, (), |_, input: Vec<u8>| {
+        // some work proportional to the input size
+        black_box(
+            input
+                .iter()
+                .fold(0usize, |acc, x| acc.wrapping_mul(31) ^ *x as usize),
+        );
+        input.len()
+    });
+
+    for size in input_sizes {
+        pipeline.write(vec![1; *size]);
+        pipeline.poll();
+    }
+    let total = pipeline.finalize().unwrap();
+    assert_eq!(total, input_sizes.iter().sum::<usize>());
+    start.elapsed().max(Duration::from_nanos(1))
+}
+
+fn main() {
+    let distributions: [(&str, Vec<usize>); 3] = [
+        ("small files", vec![4 * 1024; 20_000]),
+        ("large files", vec![4 * 1024 * 1024; 50]),
+        (
+            "mixed sizes",
+            (0..5_000)
+                .map(|i| if i % 100 == 0 { 4 * 1024 * 1024 } else { 1024 })
+                .collect(),
+        ),
+    ];
+
+    for (name, input_sizes) in &distributions {
+        let total_bytes: usize = input_sizes.iter().sum();
+        for num_workers in [1, 2, 4, 8, 16] {
+            let elapsed = (0..RUNS)
+                .map(|_| run_pipeline(num_workers, input_sizes))
+                .min()
+                .unwrap();
+            println!(
+                "{:>12}, {:>2} worker(s): {:>8.2} MiB/s, {:>10.0} inputs/s",
+                name,
+                num_workers,
+                total_bytes as f64 / 1024.0 / 1024.0 / elapsed.as_secs_f64(),
+                input_sizes.len() as f64 / elapsed.as_secs_f64()
+            );
+        }
+    }
+}
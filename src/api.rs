@@ -0,0 +1,259 @@
+//! Functions for using jbackup from another program, instead of through the
+//! command line.
+//!
+//! Each function is given the directory of the repository to use (`repo`),
+//! so a process can use several repositories, and the working directory is
+//! left as it is.
+
+use std::path::{Path, PathBuf};
+
+use crate::{
+    file_structure::{self, ConfigFile, SnapshotMetaFile},
+    repository::Repository,
+    subcommand::{init, log, restore, snapshot},
+    util::io_util::simplify_result,
+};
+
+pub use crate::{
+    file_structure::{DiffDirection, OnLargeFile, SnapshotIdFormat},
+    util::archive_utils::{CompressionLevel, TarFormat},
+};
+
+/// A snapshot in a repository.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Snapshot {
+    pub id: String,
+    /// unix timestamp (in seconds) of when the snapshot was created
+    pub date: i64,
+    pub message: Option<String>,
+    /// the snapshots this snapshot was created on top of
+    pub parents: Vec<String>,
+    /// the snapshots created on top of this snapshot
+    pub children: Vec<String>,
+}
+
+impl From<SnapshotMetaFile> for Snapshot {
+    fn from(meta: SnapshotMetaFile) -> Self {
+        Snapshot {
+            id: meta.id,
+            date: meta.date,
+            message: meta.message,
+            parents: meta.parents,
+            children: meta.children,
+        }
+    }
+}
+
+/// The config of a new repository. See `jbackup help` for what each option
+/// does.
+#[derive(Clone, Debug)]
+pub struct InitOptions {
+    pub transformers: Vec<String>,
+    pub max_file_size: Option<u64>,
+    pub on_large_file: OnLargeFile,
+    /// absolute path
+    pub snapshots_dir: Option<PathBuf>,
+    pub tar_format: TarFormat,
+    /// how much the delta lists between snapshots are compressed
+    pub compression: CompressionLevel,
+    /// absolute path
+    pub tmp_dir: Option<PathBuf>,
+    /// split files at least this large (in bytes) into chunks
+    pub chunking: Option<u64>,
+    /// map files at least this large (in bytes) into memory when
+    /// snapshotting, instead of reading them
+    pub mmap: Option<u64>,
+    pub diff_direction: DiffDirection,
+    /// store the first snapshot as a diff from an empty snapshot too, so
+    /// every snapshot is a diff. Only for forward diffs.
+    pub diff_from_empty: bool,
+    /// only store snapshots, without a working directory
+    pub bare: bool,
+    /// keep the metadata of every snapshot in one index file
+    pub compact_meta: bool,
+    /// skip directories tagged with `CACHEDIR.TAG`
+    pub exclude_caches: bool,
+    /// skip directories containing a file with any of these names
+    pub exclude_if_present: Vec<String>,
+    /// skip directories on other filesystems than the working directory
+    pub one_file_system: bool,
+    /// flush the files of new snapshots to the disk
+    pub fsync: bool,
+    /// keep the parent of a snapshot full when replacing it with a diff
+    /// would make a chain of diffs longer than this
+    pub max_diff_chain: Option<usize>,
+    /// gzip the head, branches, tags and snapshot meta files
+    pub gzip_meta: bool,
+    /// refuse to remove or replace snapshots once they're written
+    pub immutable: bool,
+    /// how the ids of new snapshots are made
+    pub snapshot_id_format: SnapshotIdFormat,
+    /// treat names that differ only by case as the same file
+    pub ignore_case: bool,
+    /// buffer archives and delta lists in this many bytes, instead of the
+    /// default
+    pub io_buffer_size: Option<u64>,
+}
+
+impl Default for InitOptions {
+    fn default() -> Self {
+        InitOptions {
+            transformers: Vec::new(),
+            max_file_size: None,
+            on_large_file: OnLargeFile::Skip,
+            snapshots_dir: None,
+            tar_format: TarFormat::Gnu,
+            compression: CompressionLevel::Default,
+            tmp_dir: None,
+            chunking: None,
+            mmap: None,
+            diff_direction: DiffDirection::Reverse,
+            diff_from_empty: false,
+            bare: false,
+            compact_meta: false,
+            exclude_caches: false,
+            exclude_if_present: Vec::new(),
+            one_file_system: false,
+            fsync: false,
+            max_diff_chain: None,
+            gzip_meta: false,
+            immutable: false,
+            snapshot_id_format: SnapshotIdFormat::TimestampHash,
+            ignore_case: false,
+            io_buffer_size: None,
+        }
+    }
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct SnapshotOptions {
+    pub message: Option<String>,
+    pub tag: Option<String>,
+    /// unix timestamp (in seconds) to date the snapshot with, instead of the
+    /// current time, so its id is known (ex. in tests)
+    pub date: Option<i64>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum SnapshotOutcome {
+    Created(Snapshot),
+    /// Nothing changed since the snapshot the head points to, so no snapshot
+    /// was created. Contains the head's snapshot.
+    NoChanges(Snapshot),
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct RestoreOptions {
+    /// only restore the files matching the glob, leaving other files as-is
+    pub path_glob: Option<String>,
+    /// restore files as they're stored, without reversing the transformers
+    pub no_transform: bool,
+    /// move files aside instead of overwriting or removing them
+    pub backup: bool,
+    /// skip files that can't be restored, and fail after restoring the rest
+    pub keep_going: bool,
+    /// write runs of zero bytes as holes, for sparse files
+    pub sparse: bool,
+}
+
+/// Initializes a repository in `repo`.
+pub fn init(repo: &Path, options: InitOptions) -> Result<(), String> {
+    let root = get_root(repo)?;
+
+    let path_to_string = |path: PathBuf| match path.into_os_string().into_string() {
+        Ok(x) => Ok(x),
+        Err(path) => Err(format!("Path is not UTF-8: {:?}", path)),
+    };
+
+    init::init(
+        &root,
+        ConfigFile {
+            transformers: options.transformers,
+            max_file_size: options.max_file_size,
+            on_large_file: options.on_large_file,
+            snapshots_dir: options.snapshots_dir.map(path_to_string).transpose()?,
+            tar_format: options.tar_format,
+            compression: options.compression,
+            tmp_dir: options.tmp_dir.map(path_to_string).transpose()?,
+            chunking: options.chunking,
+            mmap: options.mmap,
+            diff_direction: options.diff_direction,
+            diff_from_empty: options.diff_from_empty,
+            bare: options.bare,
+            compact_meta: options.compact_meta,
+            exclude_caches: options.exclude_caches,
+            exclude_if_present: options.exclude_if_present,
+            one_file_system: options.one_file_system,
+            fsync: options.fsync,
+            max_diff_chain: options.max_diff_chain,
+            gzip_meta: options.gzip_meta,
+            immutable: options.immutable,
+            snapshot_id_format: options.snapshot_id_format,
+            snapshot_id_seq: 0,
+            ignore_case: options.ignore_case,
+            io_buffer_size: options.io_buffer_size,
+        },
+    )?;
+    Ok(())
+}
+
+/// Creates a snapshot of `repo` with the repository's config, and moves the
+/// head to it, unless nothing changed since the head's snapshot.
+pub fn snapshot(repo: &Path, options: SnapshotOptions) -> Result<SnapshotOutcome, String> {
+    let repo = open_repository(repo)?;
+
+    let snapshot_options = snapshot::SnapshotOptions::dated(&repo, options.date);
+    let outcome = snapshot::snapshot(&repo, &snapshot_options, options.message, options.tag)?;
+
+    Ok(match outcome {
+        snapshot::SnapshotOutcome::Created(created) => {
+            SnapshotOutcome::Created(Snapshot::from(created.snapshot))
+        }
+        snapshot::SnapshotOutcome::NoChanges(head) => {
+            SnapshotOutcome::NoChanges(Snapshot::from(head))
+        }
+    })
+}
+
+/// Restores `repo` to the snapshot `reference` points to (ex. a snapshot id,
+/// branch, tag or `HEAD~1`).
+pub fn restore(repo: &Path, reference: &str, options: RestoreOptions) -> Result<(), String> {
+    let repo = open_repository(repo)?;
+
+    restore::restore(
+        &repo,
+        reference,
+        options.path_glob.as_deref(),
+        options.no_transform,
+        options.backup,
+        options.keep_going,
+        options.sparse,
+    )
+}
+
+/// Lists the snapshots in `repo`, oldest first.
+pub fn log(repo: &Path) -> Result<Vec<Snapshot>, String> {
+    let repo = open_repository(repo)?;
+
+    Ok(log::read_log(&repo)?
+        .into_iter()
+        .map(Snapshot::from)
+        .collect())
+}
+
+/// The path of the repository in `repo`, as the root of a `Repository`.
+fn get_root(repo: &Path) -> Result<String, String> {
+    match simplify_result(repo.canonicalize())?
+        .into_os_string()
+        .into_string()
+    {
+        Ok(x) => Ok(x),
+        Err(path) => Err(format!("Path is not UTF-8: {:?}", path)),
+    }
+}
+
+fn open_repository(repo: &Path) -> Result<Repository, String> {
+    let repo = Repository::open(&get_root(repo)?)?;
+    file_structure::ensure_jbackup_snapshots_dir_exists(&repo)?;
+    Ok(repo)
+}
@@ -3,6 +3,7 @@ use std::collections::{HashMap, HashSet, VecDeque};
 pub struct Parser {
     flags: HashSet<String>,
     options: HashSet<String>,
+    multi_options: HashSet<String>,
 }
 
 impl Parser {
@@ -10,6 +11,7 @@ impl Parser {
         Parser {
             flags: HashSet::new(),
             options: HashSet::new(),
+            multi_options: HashSet::new(),
         }
     }
 
@@ -23,10 +25,18 @@ impl Parser {
         self
     }
 
+    /// An option that can be given more than once. Its values are collected
+    /// in order into `Arguments::multi_options`.
+    pub fn multi_option(&mut self, name: &str) -> &mut Parser {
+        self.multi_options.insert(String::from(name));
+        self
+    }
+
     pub fn parse(&self, args_iter: impl Iterator<Item = String>) -> Arguments {
         let mut args = Arguments {
             flags: HashSet::new(),
             options: HashMap::new(),
+            multi_options: HashMap::new(),
             normal: VecDeque::new(),
         };
 
@@ -35,12 +45,16 @@ impl Parser {
         for s in args_iter {
             match option_name.take() {
                 Some(k) => {
-                    args.options.insert(k, s);
+                    if self.multi_options.contains(&k) {
+                        args.multi_options.entry(k).or_default().push(s);
+                    } else {
+                        args.options.insert(k, s);
+                    }
                 }
                 None => {
                     if self.flags.contains(&s) {
                         args.flags.insert(s);
-                    } else if self.options.contains(&s) {
+                    } else if self.options.contains(&s) || self.multi_options.contains(&s) {
                         option_name.replace(s);
                     } else {
                         args.normal.push_back(s);
@@ -56,6 +70,7 @@ impl Parser {
 pub struct Arguments {
     pub flags: HashSet<String>,
     pub options: HashMap<String, String>,
+    pub multi_options: HashMap<String, Vec<String>>,
     pub normal: VecDeque<String>,
 }
 
@@ -74,4 +89,18 @@ mod test {
             Some(&String::from("b"))
         );
     }
+
+    #[test]
+    pub fn collects_multi_options() {
+        let args = Parser::new().multi_option("a").option("b").parse(
+            vec!["a", "x", "b", "y", "a", "z", "b", "w"]
+                .into_iter()
+                .map(String::from),
+        );
+        assert_eq!(
+            args.multi_options.get("a"),
+            Some(&vec![String::from("x"), String::from("z")])
+        );
+        assert_eq!(args.options.get("b"), Some(&String::from("w")));
+    }
 }
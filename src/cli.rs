@@ -0,0 +1,700 @@
+//! The `jbackup` command line tool.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    fs,
+    time::SystemTime,
+};
+
+use crate::{
+    CONFIG_PATH, arguments,
+    file_structure::{self, OperationsLogEntry, OperationsLogFile},
+    repository::Repository,
+    subcommand::{self, snapshot::to_timestamp},
+    util::{
+        color_util::{self, ColorChoice},
+        io_util::simplify_result,
+        progress_util::{self, ProgressFormat},
+    },
+};
+
+const HELP_TEXT: &str = "
+Subcommands
+---
+
+init
+  Initializes a repository for jbackup in the current working directory.
+
+  Options:
+    --transformer <name>
+      Transform files before storing them (ex. minecraft). 'exec:<program>'
+      transforms them with an external program instead (see the README for
+      the protocol), and 'exec-lossy:<program>' with one that doesn't give
+      back exactly what it was given.
+    --max-file-size <size>
+      Files larger than this size (ex. 500M, 2G) are handled by --on-large
+      when creating snapshots.
+    --on-large <skip|abort>
+      Skip large files with a warning, or abort the snapshot. Defaults to skip.
+    --tar-format <gnu|pax>
+      The header format of the tars that snapshots are stored in. Both store
+      long paths losslessly, but more tools can read long paths in pax.
+      Defaults to gnu.
+    --compression <store|fast|default|best>
+      How much the diffs between snapshots are compressed. store doesn't
+      compress them, which saves time when the files don't compress well (ex.
+      already compressed files). Defaults to default.
+    --snapshots-dir <absolute path>
+      Store snapshots in another directory (ex. on an external drive) instead
+      of .jbackup/snapshots.
+    --tmp-dir <absolute path>
+      Write scratch files for snapshotting and restoring to another directory
+      instead of .jbackup. Overridden by the JBACKUP_TMPDIR environment
+      variable.
+    --chunking <size>
+      Split files at least this large (ex. 64M) into content-defined chunks,
+      which are stored once each, so a small change in a large file only
+      stores the changed chunks. Useful for VM images and databases.
+    --mmap <size>
+      Map files at least this large (ex. 256M) into memory when snapshotting,
+      instead of reading them, if no transformer changes them. Uses less
+      memory. Each file is cloned as a reflink into the temp directory first,
+      so it can be written to while it's mapped. Without reflinks (ex. on
+      ext4), files are read.
+    --io-buffer-size <size>
+      Buffer snapshot tars and diffs in this many bytes (ex. 1M) when reading
+      and writing them, so many small files take fewer system calls. Defaults
+      to 256K.
+    --diff-direction <reverse|forward>
+      reverse stores the newest snapshot in full and older snapshots as diffs,
+      so recent snapshots restore fastest. forward stores the first snapshot
+      in full and newer snapshots as diffs, so old snapshots restore fastest.
+      Defaults to reverse.
+    --max-diff-chain <n>
+      Keep a snapshot's full payload instead of replacing it with a diff
+      when that would make restoring any snapshot apply more than n diffs.
+      Uses more space, but bounds how long restoring takes. Unlimited by
+      default.
+    --from-empty
+      With --diff-direction forward, store the first snapshot as a diff from
+      an empty snapshot too, so only the newest snapshot is stored in full.
+    --snapshot-id-format <timestamp-hash|ulid|seq>
+      How the ids of new snapshots are made. timestamp-hash is the creation
+      time and the md5 of the snapshot's files. ulid is a ULID, which sorts by
+      creation time to the millisecond. seq numbers snapshots 0000000001,
+      0000000002, ... Defaults to timestamp-hash.
+    --bare
+      Create a repository that only stores snapshots, without a working
+      directory. snapshot, restore, clean and stash refuse to run in it.
+    --compact-meta
+      Keep the metadata of every snapshot in one index file, as well as in
+      each snapshot's meta file, so log and references like HEAD~100 read one
+      file instead of one per snapshot. Useful with thousands of snapshots.
+    --exclude-caches
+      Leave directories tagged as caches with a CACHEDIR.TAG file out of
+      snapshots, and don't remove them when restoring.
+    --one-file-system, --xdev
+      Leave directories on other filesystems than the working directory (ex.
+      mounted drives) out of snapshots, and don't remove them when restoring.
+    --fsync
+      Flush the payload, diffs and metadata of each snapshot to the disk
+      before reporting it as created, so a power loss can't lose it. Slower.
+    --exclude-if-present <name>
+      Leave directories containing a file with the name (ex. .nobackup) out
+      of snapshots, and don't remove them when restoring. Can be given more
+      than once.
+    --gzip-meta
+      Gzip the head, branches, tags and snapshot meta files, which get large
+      in repositories with many branches, or snapshots with many parents and
+      children. Plaintext and gzipped files are both read either way.
+    --immutable
+      Refuse to remove or replace snapshots once they're written: squash,
+      clean-snapshots and snapshot --amend fail unless --force-immutable is
+      given. New snapshots can still be created.
+    --ignore-case
+      Treat names that differ only by case (ex. README and readme) as the same
+      file, like case-insensitive filesystems (macOS, Windows) do. Only the
+      first of such names is snapshotted, with a warning.
+    --import <archive.tar.gz>
+      Create the first snapshot from the files in the archive, instead of the
+      working directory, running the transformers on them. The archive is
+      checked before the repository is created.
+    -m <message>
+      The message of the snapshot created with --import.
+
+snapshot
+  Creates a snapshot of the current files in the repository. No snapshot is
+  created if nothing changed since the last snapshot.
+
+  Options:
+    -m <message>
+      Supply a message to annotate the snapshot. Without it, the message is
+      written in $EDITOR when it's set and jbackup is run from a terminal,
+      with the changed files listed in '#' comments. An empty message aborts
+      the snapshot.
+    -v, --verbose
+      Print each file as it's inserted into the snapshot.
+    --print-id
+      Print only the new snapshot's id to stdout, and everything else to
+      stderr, for scripts. If nothing changed, the id of the head's snapshot
+      is printed instead.
+    --skip-non-utf8
+      Leave out files with paths that aren't UTF-8, with a warning.
+    --follow-symlinks
+      Store the contents of the files and directories that symlinks point to,
+      instead of skipping symlinks.
+    --exclude-caches
+      Leave out directories tagged as caches with a CACHEDIR.TAG file, even if
+      the repository wasn't initialized with --exclude-caches.
+    --one-file-system, --xdev
+      Leave out directories on other filesystems than the working directory,
+      even if the repository wasn't initialized with --one-file-system.
+    --exclude-if-present <name>
+      Leave out directories containing a file with the name (ex. .nobackup),
+      as well as the ones the repository was initialized with. Can be given
+      more than once.
+    --stdin-file-list
+      Snapshot the files listed on stdin, one path per line, instead of every
+      file in the working directory. Paths must be in the working directory.
+    -0
+      With --stdin-file-list, paths are separated by NUL instead of newlines
+      (ex. from find -print0).
+    --no-transform
+      Store files without the configured transformers, for debugging
+      transformers. The snapshot is restored without transformers.
+    --fsync
+      Flush the snapshot's files to the disk, even if the repository wasn't
+      initialized with --fsync.
+    --allow-lossy
+      Snapshot even if a configured transformer is lossy, so its files can't
+      be restored exactly as they were, with a warning. Fails otherwise.
+    --tag <name>
+      Tag the snapshot with a name. Fails before creating the snapshot if the
+      tag already exists.
+    --parent <ref>
+      Record the snapshot as a merge, with the snapshot as another parent.
+      Can be given more than once. The head is always the first parent, and
+      the only one the snapshot is diffed against.
+    --amend
+      Replace the head's snapshot with the new snapshot, keeping its message
+      unless -m is given. Branches and tags on it move to the new snapshot.
+      Fails if other snapshots descend from the head's snapshot. Only
+      supported for reverse diffs.
+    --on-branch <name>
+      Add the snapshot to the branch, as a child of its tip and diffed
+      against it, instead of to the head's branch. The head doesn't move. A
+      missing branch is created at the head's snapshot.
+    --max-diff-chain <n>
+      Override the repository's maximum diff chain length for this snapshot.
+    --max-file-size <size>
+      Override the repository's max file size for this snapshot.
+    --on-large <skip|abort>
+      Override what happens to files larger than the max file size.
+    --threads-transform <count>
+      Number of threads that read and transform files. Defaults to half of
+      the available cores, rounded up.
+    --threads-compress <count>
+      Number of threads that compress the snapshot. Defaults to half of the
+      available cores, rounded down.
+    --jobs-per-file <count>
+      Number of threads each file can be transformed with, for transformers
+      that can split the work on one file (ex. minecraft_mca decodes a region
+      file's chunks in parallel). Defaults to 1.
+    --io-retries <n>
+      Number of times to retry reading a file after a transient error (ex. a
+      timeout on a network mount). Defaults to 2.
+    --stats
+      Also print how many bytes went into and came out of each transformer,
+      over the files it transforms.
+    --keep-going
+      Leave out files that can't be read or transformed, instead of failing
+      the snapshot. The errors are listed and the command still fails, after
+      the snapshot of the other files is created.
+
+log [<from>..<to>]
+  View all snapshots in the repository. The id of the snapshot the head points
+  to is highlighted, and the branches and tags at each snapshot are listed
+  next to it. With a range, only the snapshots on a path from <from> to
+  <to> (including both) are shown. <to> defaults to HEAD, and leaving out
+  <from> shows all snapshots before <to>.
+
+  Options:
+    --graph
+      Show one line per snapshot, with ASCII edges from each snapshot to its
+      children.
+    --reverse
+      Show the newest snapshots first. Can't be combined with --graph.
+    --limit <n>, -n <n>
+      Only show the newest <n> snapshots.
+    --branch <name>
+      Only show the snapshots the branch was built on: its tip, and the
+      snapshots reachable from it through their parents.
+
+diff <from> [to]
+  Lists the files that were added, modified, deleted or linked from one
+  snapshot to another. Without <to>, lists the changes from <from> to the
+  working directory, with the files transformed like a snapshot would store
+  them.
+
+  Options:
+    --output <file>
+      Also write the changes to <file> as a patch: a delta list that turns
+      <from> into <to> (or the working directory), in the format snapshots'
+      diffs are stored in.
+
+reflog
+  View the history of snapshots that the head has pointed to, newest first.
+  Every command that changes the repository or the working directory is also
+  recorded in .jbackup/operations.log, with its arguments, result, and the
+  snapshots it created and removed.
+
+recover-head
+  Rebuilds the head file if it's missing or corrupt, by guessing the head from
+  the branches: the branch at the snapshot the reflog shows the head last
+  moved to, otherwise main, otherwise the branch with the newest snapshot.
+  Only prints the guess unless -f is given.
+
+  Options:
+    -f
+      Write the guessed head to the head file.
+
+rename-branch <old> <new>
+  Renames a branch. If it's the current branch, the head moves to the new
+  name. Fails if <old> doesn't exist or <new> already exists.
+
+restore <ref>
+  Restores the working directory to a snapshot. Files that are not in the
+  snapshot are removed, unless the snapshot left them out on purpose (ex.
+  with --skip-non-utf8). Files are restored with the transformers the snapshot
+  was created with, with a warning if they differ from the config.
+
+  Options:
+    --path <glob>
+      Only restore the files matching the glob, leaving all other files
+      untouched. '*' matches within a directory, '**' matches across
+      directories.
+    --no-transform
+      Restore files as they're stored, without reversing the transformers the
+      snapshot was created with. Use it to recover files when a transformer
+      is broken.
+    --backup
+      Move files that would be overwritten with other contents or removed
+      aside to <name>.jbackup-orig instead, and list them at the end. Older
+      backups are kept.
+    --keep-going
+      Skip files that can't be restored, instead of stopping at the first
+      one. Skipped files are left as they are. The errors are listed and the
+      command still fails, after the other files are restored.
+    --sparse
+      Write runs of zero bytes as holes, so sparse files (ex. VM disk images)
+      take less space. Files are written densely on filesystems without
+      sparse files.
+    --abort
+      Remove the files left by an interrupted restore. Otherwise, restoring
+      the same snapshot again continues where the interrupted restore stopped.
+
+clean [ref]
+  Removes files that are not in a snapshot (HEAD by default), and the
+  directories left empty by removing them. Files the snapshot left out on
+  purpose are kept.
+
+  Options:
+    -n, --dry-run
+      List the files that would be removed, without removing them.
+    -f
+      Remove the files. Required unless --dry-run is specified.
+
+clean-snapshots
+  Removes the snapshots that aren't kept by a retention policy. Each run of
+  removed snapshots is squashed into the kept snapshot after it, so the kept
+  snapshots can still be restored. The snapshots the head, branches and tags
+  point to are always kept.
+
+  Options:
+    --keep-last <n>
+      Keep the newest <n> snapshots.
+    --keep-within <duration>
+      Keep the snapshots newer than <duration>, a number followed by s, m, h,
+      d or w (ex. 90d). Snapshots kept by either rule are kept.
+    -n, --dry-run
+      List the snapshots that would be removed, without removing them.
+    -f
+      Remove the snapshots. Required unless --dry-run is specified.
+
+gc
+  Removes the chunks (see init --chunking) that no snapshot or stash uses,
+  which are left behind by squash, clean-snapshots, snapshot --amend, and
+  snapshots that failed or had no changes. Reconstructs every snapshot, so
+  it's slow. Don't run it while a snapshot is being created.
+
+  Options:
+    -n, --dry-run
+      Count the chunks that would be removed, without removing them.
+
+rev-parse <ref>
+  Prints the id of the snapshot a reference points to.
+
+show-tar <ref>
+  Writes the tar.gz of a snapshot to stdout. Files in the tar are stored as
+  they are after being transformed, so they may differ from the original files.
+  The tar isn't directly usable as a backup of the files, unless it's read
+  with the same transformers configured.
+
+  Options:
+    --payload-only
+      Write the stored tar, without reversing the transformers. This is the
+      default, so the flag only makes it explicit.
+    --transform-out
+      Reverse the transformers the snapshot was created with, like restore,
+      so the tar has the original files.
+    --format <tar.gz|tar>
+      The format to write the tar in. 'tar' writes it without gzipping it, for
+      tools that compress it themselves. Defaults to 'tar.gz'.
+
+extract <ref> <path> <output path>
+  Writes one file of a snapshot to <output path>, overwriting it if it exists,
+  without touching the working directory. <path> is relative to the working
+  directory, and can't be absolute or contain '..'.
+
+  Options:
+    --no-transform
+      Write the file as it's stored, without reversing the transformers the
+      snapshot was created with.
+
+squash <from>..<to>
+  Collapses the snapshots from <from> to <to> into one snapshot, with the
+  contents and id of <to>, whose parent is <from>'s parent. The other
+  snapshots in the range are removed, so branches, tags and the head can't
+  point to them. <to> defaults to HEAD.
+
+  Options:
+    -m <message>
+      Replace the message of the squashed snapshot.
+    -n, --dry-run
+      List the snapshots that would be removed, without removing them.
+    -f
+      Squash the snapshots. Required unless --dry-run is specified.
+
+stash [push|pop|list]
+  Saves the working directory to a stash without changing it. Stashes are
+  kept apart from snapshots, so they don't appear in log or move the head.
+
+  Actions:
+    push
+      Save the working directory to a new stash. This is the default.
+    pop
+      Restore the working directory to the newest stash, then remove it.
+    list
+      List the stashes, newest first.
+
+  Options:
+    -m <message>
+      Supply a message to annotate the stash.
+
+size
+  Reports the disk usage of the stored snapshots, and lists the largest
+  snapshots and any orphaned files.
+
+  Options:
+    --top <n>
+      The number of snapshots to list. Defaults to 10.
+    --json
+      Print the report as JSON.
+
+chain-depth
+  Reports how many diffs must be applied to restore each snapshot (its
+  chain depth), and lists the snapshots with the deepest chains. Snapshots
+  with a full payload have a depth of 0.
+
+  Options:
+    --top <n>
+      The number of snapshots to list. Defaults to 10.
+    --sort <depth|date>
+      List the deepest or the newest snapshots first. Defaults to depth.
+    --json
+      Print the report as JSON.
+
+self-test
+  Checks that transformers restore the files they transform, by running
+  sample files of the formats they handle through them. Reports whether each
+  transformer passed.
+
+  Options:
+    --transformer <name>
+      Test the transformer instead of the configured transformers. Can be
+      given more than once, and outside of a repository.
+    --file <path>
+      Also test the transformers on the file (ex. a region file from a real
+      world). Can be given more than once.
+
+verify
+  Checks that the files of every snapshot exist and decompress, and that every
+  snapshot can be restored from a full payload.
+
+  Options:
+    --deep
+      Also reconstruct every snapshot by applying its delta list, and check
+      the full payloads against their snapshot ids. Slow, since it
+      decompresses every snapshot.
+
+help
+  Lists available commands.
+
+Global options
+---
+
+--color <auto|always|never>
+  Whether to color the output. auto (the default) colors the output when it's
+  printed to a terminal, unless the NO_COLOR environment variable is set.
+
+--progress-json
+  Report the progress of snapshot and restore on stderr as one JSON object per
+  line, for programs built on top of jbackup. A \"file\" event (with path,
+  done and total) is reported for each file added to or restored from a
+  snapshot, and a \"snapshot\" event (with id, done and total) for each
+  snapshot reconstructed while restoring. total is null when it isn't known
+  ahead of time. Warnings are still printed as text.
+
+--force-immutable
+  Remove or replace snapshots (squash, clean-snapshots and snapshot --amend)
+  even though the repository was initialized with --immutable.
+
+References
+---
+
+Commands that take a <ref> accept, in order of precedence: HEAD (the current
+snapshot), a branch name, a tag name, or a snapshot id. Add ~N to refer to
+the Nth ancestor (ex. HEAD~2), following only the first parent of each snapshot.
+";
+
+/// Runs the subcommand in the arguments (not including the path of the
+/// executable) on the repository in the current working directory.
+pub fn run(args_iter: impl Iterator<Item = String>) -> Result<(), String> {
+    let raw_args: Vec<String> = args_iter.collect();
+    let mut args = arguments::Parser::new()
+        .flag("--help")
+        .flag("--progress-json")
+        .flag("--force-immutable")
+        .option("--color")
+        .parse(raw_args.iter().cloned());
+
+    color_util::set_color_choice(match args.options.remove("--color") {
+        Some(x) => x.parse()?,
+        None => ColorChoice::Auto,
+    });
+
+    if args.flags.contains("--progress-json") {
+        progress_util::set_progress_format(ProgressFormat::Json);
+    }
+
+    let force_immutable = args.flags.contains("--force-immutable");
+
+    if args.flags.contains("--help") {
+        println!("{}", HELP_TEXT);
+        return Ok(());
+    }
+
+    let command = args.normal.pop_front().unwrap_or_default();
+
+    if !LOGGED_COMMANDS.contains(&command.as_str()) {
+        return run_subcommand(&command, args.normal, force_immutable);
+    }
+
+    // a repository that doesn't exist yet has no snapshots, but `init` may
+    // create one
+    let snapshot_ids_before = match fs::exists(CONFIG_PATH) {
+        Ok(true) => Repository::open(REPOSITORY_ROOT)
+            .and_then(|repo| file_structure::get_snapshot_ids(&repo))
+            .ok(),
+        Ok(false) => Some(HashSet::new()),
+        Err(_) => None,
+    };
+    let result = run_subcommand(&command, args.normal, force_immutable);
+    if let Err(err) = log_operation(&command, raw_args, &result, snapshot_ids_before) {
+        eprintln!("Warn: Failed to write to the operations log: {}", err);
+    }
+    result
+}
+
+/// Subcommands that can change the repository or the working directory, which
+/// are recorded in the operations log (see `OperationsLogFile`).
+const LOGGED_COMMANDS: &[&str] = &[
+    "init",
+    "snapshot",
+    "recover-head",
+    "rename-branch",
+    "restore",
+    "clean",
+    "clean-snapshots",
+    "squash",
+    "stash",
+    "gc",
+];
+
+/// Records a command in the operations log, with the snapshots that were
+/// created and removed since `snapshot_ids_before` was listed (`None` if they
+/// couldn't be listed). Commands run outside of a repository aren't recorded.
+fn log_operation(
+    command: &str,
+    args: Vec<String>,
+    result: &Result<(), String>,
+    snapshot_ids_before: Option<HashSet<String>>,
+) -> Result<(), String> {
+    if !simplify_result(fs::exists(CONFIG_PATH))? {
+        return Ok(());
+    }
+    let repo = Repository::open(REPOSITORY_ROOT)?;
+
+    let (mut created_snapshot_ids, mut removed_snapshot_ids) =
+        match (snapshot_ids_before, file_structure::get_snapshot_ids(&repo)) {
+            (Some(before), Ok(after)) => (
+                after.difference(&before).cloned().collect(),
+                before.difference(&after).cloned().collect(),
+            ),
+            _ => (Vec::new(), Vec::new()),
+        };
+    created_snapshot_ids.sort();
+    removed_snapshot_ids.sort();
+
+    OperationsLogFile::append(
+        &repo,
+        &OperationsLogEntry {
+            date: to_timestamp(SystemTime::now())?,
+            command: String::from(command),
+            args,
+            error: result.as_ref().err().cloned(),
+            created_snapshot_ids,
+            removed_snapshot_ids,
+        },
+    )
+}
+
+/// The root of the repository commands are run on, which is the current
+/// working directory.
+const REPOSITORY_ROOT: &str = ".";
+
+/// Opens the repository commands are run on. `force_immutable` is set by
+/// `--force-immutable` (see `Repository::force_immutable`).
+fn open_repository(force_immutable: bool) -> Result<Repository, String> {
+    let mut repo = Repository::open(REPOSITORY_ROOT)?;
+    repo.force_immutable = force_immutable;
+    Ok(repo)
+}
+
+fn run_subcommand(
+    command: &str,
+    args: VecDeque<String>,
+    force_immutable: bool,
+) -> Result<(), String> {
+    let open = || open_repository(force_immutable);
+    match command {
+        "" | "help" => {
+            println!("{}", HELP_TEXT);
+            Ok(())
+        }
+        "init" => match subcommand::init::main(REPOSITORY_ROOT, args) {
+            Err(error) => Err(format!("Failed to initalize repository: {error}")),
+            Ok(_) => Ok(()),
+        },
+        "snapshot" => match open().and_then(|mut repo| subcommand::snapshot::main(&mut repo, args))
+        {
+            Err(error) => Err(format!("Failed to snapshot repository: {error}")),
+            Ok(_) => Ok(()),
+        },
+        "log" => match open().and_then(|repo| subcommand::log::main(&repo, args)) {
+            Err(error) => Err(format!("Failed to get logs: {error}")),
+            Ok(_) => Ok(()),
+        },
+        "diff" => match open().and_then(|repo| subcommand::diff::main(&repo, args)) {
+            Err(error) => Err(format!("Failed to diff snapshots: {error}")),
+            Ok(_) => Ok(()),
+        },
+        "reflog" => match open().and_then(|repo| subcommand::reflog::main(&repo)) {
+            Err(error) => Err(format!("Failed to get reflog: {error}")),
+            Ok(_) => Ok(()),
+        },
+        "recover-head" => match subcommand::recover_head::main(REPOSITORY_ROOT, args) {
+            Err(error) => Err(format!("Failed to recover head: {error}")),
+            Ok(_) => Ok(()),
+        },
+        "rename-branch" => {
+            match open().and_then(|repo| subcommand::rename_branch::main(&repo, args)) {
+                Err(error) => Err(format!("Failed to rename branch: {error}")),
+                Ok(_) => Ok(()),
+            }
+        }
+        "restore" => match open().and_then(|repo| subcommand::restore::main(&repo, args)) {
+            Err(error) => Err(format!("Failed to restore: {error}")),
+            Ok(_) => Ok(()),
+        },
+        "clean" => match open().and_then(|repo| subcommand::clean::main(&repo, args)) {
+            Err(error) => Err(format!("Failed to clean: {error}")),
+            Ok(_) => Ok(()),
+        },
+        "chain-depth" => match open().and_then(|repo| subcommand::chain_depth::main(&repo, args)) {
+            Err(error) => Err(format!("Failed to report chain depths: {error}")),
+            Ok(_) => Ok(()),
+        },
+        "self-test" => match subcommand::self_test::main(REPOSITORY_ROOT, args) {
+            Err(error) => Err(format!("Self-test failed: {error}")),
+            Ok(_) => Ok(()),
+        },
+        "clean-snapshots" => {
+            match open().and_then(|repo| subcommand::clean_snapshots::main(&repo, args)) {
+                Err(error) => Err(format!("Failed to clean snapshots: {error}")),
+                Ok(_) => Ok(()),
+            }
+        }
+        "rev-parse" => match open().and_then(|repo| subcommand::rev_parse::main(&repo, args)) {
+            Err(error) => Err(format!("Failed to resolve reference: {error}")),
+            Ok(_) => Ok(()),
+        },
+        "extract" => match open().and_then(|repo| subcommand::extract::main(&repo, args)) {
+            Err(error) => Err(format!("Failed to extract file: {error}")),
+            Ok(_) => Ok(()),
+        },
+        "show-tar" => match open().and_then(|repo| subcommand::show_tar::main(&repo, args)) {
+            Err(error) => Err(format!("Failed to show tar: {error}")),
+            Ok(_) => Ok(()),
+        },
+        "squash" => match open().and_then(|repo| subcommand::squash::main(&repo, args)) {
+            Err(error) => Err(format!("Failed to squash snapshots: {error}")),
+            Ok(_) => Ok(()),
+        },
+        "stash" => match open().and_then(|repo| subcommand::stash::main(&repo, args)) {
+            Err(error) => Err(format!("Failed to stash: {error}")),
+            Ok(_) => Ok(()),
+        },
+        "gc" => match open().and_then(|repo| subcommand::gc::main(&repo, args)) {
+            Err(error) => Err(format!("Failed to remove unused chunks: {error}")),
+            Ok(_) => Ok(()),
+        },
+        "verify" => match open().and_then(|repo| subcommand::verify::main(&repo, args)) {
+            Err(error) => Err(format!("Failed to verify repository: {error}")),
+            Ok(_) => Ok(()),
+        },
+        "size" => match open().and_then(|repo| subcommand::size::main(&repo, args)) {
+            Err(error) => Err(format!("Failed to get repository size: {error}")),
+            Ok(_) => Ok(()),
+        },
+        // todo: remove __debug commands
+
+        // this command allows restoring of a snapshot.
+        // data will be stored in the "./.jbackup/_debug" directory.
+        "__debug_restore" => {
+            match open().and_then(|repo| subcommand::__debug_restore::main(&repo, args)) {
+                Err(err) => Err(format!("Failed to restore: {err}")),
+                Ok(_) => Ok(()),
+            }
+        }
+
+        "__debug_transform_out" => {
+            match open().and_then(|repo| subcommand::__debug_restore::main2(&repo, args)) {
+                Err(err) => Err(format!("Failed to transform out: {err}")),
+                Ok(_) => Ok(()),
+            }
+        }
+
+        _ => Err(format!("Error: unknown command '{}'", command)),
+    }
+}
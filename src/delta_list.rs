@@ -1,19 +1,28 @@
 use std::{
+    cmp::Ordering,
+    fmt::Display,
     fs::File,
-    io::{BufReader, ErrorKind, Read, Write},
+    io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write},
 };
 
 use flate2::{bufread::GzDecoder, write::GzEncoder};
 
-use crate::util::{
-    archive_utils::{TarReader, TarWriter},
-    io_util::simplify_result,
+use crate::{
+    file_structure::ConfigFile,
+    util::{
+        archive_utils::{TarStream, append_tar_entry, append_tar_link, get_link_target},
+        io_util::simplify_result,
+    },
 };
 
-pub fn generate_delta_list(
-    mut start_tar: TarReader,
-    mut end_tar: TarReader,
-    mut delta_list: JBackupFileDeltaListWriter,
+/// The version of delta lists that are written.
+const DELTA_LIST_VERSION: u32 = 3;
+
+pub fn generate_delta_list<S: Read, E: Read, W: Write>(
+    mut start_tar: tar::Archive<S>,
+    mut end_tar: tar::Archive<E>,
+    mut delta_list: JBackupFileDeltaListWriter<W>,
+    order: PathOrder,
 ) -> Result<(), String> {
     let mut start_entries = simplify_result(start_tar.entries())?;
     let mut end_entries = simplify_result(end_tar.entries())?;
@@ -28,35 +37,73 @@ pub fn generate_delta_list(
                 let end_path = get_entry_path(&end_entry_uw)?;
 
                 if start_path == end_path {
+                    let start_link = get_link_target(&start_entry_uw)?;
+                    let end_link = get_link_target(&end_entry_uw)?;
+
+                    // hard links have no contents to diff, so a link that
+                    // changed is replaced
+                    if start_link.is_some() || end_link.is_some() {
+                        if start_link != end_link {
+                            delta_list.add(JBackupDelta {
+                                path: start_path,
+                                content: JBackupDeltaContent::Deleted,
+                            })?;
+                            delta_list.add(get_added_delta(end_path, &mut end_entry_uw)?)?;
+                        }
+
+                        start_entry = start_entries.next();
+                        end_entry = end_entries.next();
+                        continue;
+                    }
+
                     let start_buf = get_entry_data(&mut start_entry_uw)?;
                     let end_buf = get_entry_data(&mut end_entry_uw)?;
 
-                    if let Some(res) = xdelta3::encode(&end_buf, &start_buf) {
-                        delta_list.add(JBackupDelta {
-                            path: start_path,
-                            content: JBackupDeltaContent::Modified { xdelta: res },
-                        })?;
-                    } else {
-                        // eprintln!("Warn: no xdelta output for {}", &start_path);
+                    if start_buf == end_buf {
+                        // unchanged files don't need a delta
+                        start_entry = start_entries.next();
+                        end_entry = end_entries.next();
+                        continue;
+                    }
+
+                    match xdelta3::encode(&end_buf, &start_buf) {
+                        Some(res)
+                            if res.len() < end_buf.len()
+                                && is_decodable(&res, &start_buf, &end_buf) =>
+                        {
+                            delta_list.add(JBackupDelta {
+                                path: start_path,
+                                content: JBackupDeltaContent::Modified { xdelta: res },
+                            })?;
+                        }
+                        _ => {
+                            // xdelta3 fails to encode very small files (its output buffer
+                            // is too small), and files that changed entirely (ex.
+                            // compressed files) have xdeltas larger than the file, so
+                            // replace the file instead
+                            delta_list.add(JBackupDelta {
+                                path: start_path.clone(),
+                                content: JBackupDeltaContent::Deleted,
+                            })?;
+                            delta_list.add(JBackupDelta {
+                                path: start_path,
+                                content: JBackupDeltaContent::Added { content: end_buf },
+                            })?;
+                        }
                     }
 
                     start_entry = start_entries.next();
                     end_entry = end_entries.next();
-                } else if start_path < end_path {
+                } else if order.cmp_paths(&start_path, &end_path) == Ordering::Less {
                     delta_list.add(JBackupDelta {
-                        path: start_path.to_string(),
+                        path: start_path,
                         content: JBackupDeltaContent::Deleted,
                     })?;
 
                     start_entry = start_entries.next();
                     end_entry = Some(Ok(end_entry_uw));
                 } else {
-                    let buf = get_entry_data(&mut end_entry_uw)?;
-
-                    delta_list.add(JBackupDelta {
-                        path: end_path,
-                        content: JBackupDeltaContent::Added { content: buf },
-                    })?;
+                    delta_list.add(get_added_delta(end_path, &mut end_entry_uw)?)?;
 
                     start_entry = Some(Ok(start_entry_uw));
                     end_entry = end_entries.next();
@@ -72,21 +119,27 @@ pub fn generate_delta_list(
             }
 
             (None, Some(Ok(mut end_entry_uw))) => {
-                let buf = get_entry_data(&mut end_entry_uw)?;
-
-                delta_list.add(JBackupDelta {
-                    path: get_entry_path(&end_entry_uw)?,
-                    content: JBackupDeltaContent::Added { content: buf },
-                })?;
+                let end_path = get_entry_path(&end_entry_uw)?;
+                delta_list.add(get_added_delta(end_path, &mut end_entry_uw)?)?;
 
                 end_entry = end_entries.next();
             }
             (None, None) => {
                 break;
             }
-            _ => {
-                return Err(String::from(
-                    "Unknown error occurred while reading input archives",
+            // stop at the first entry that can't be read, since the entries
+            // after it can't be found, which would list the files as deleted
+            // or added
+            (Some(Err(err)), _) => {
+                return Err(format!(
+                    "Failed to read the start tar (it may be truncated or corrupt): {}",
+                    err
+                ));
+            }
+            (_, Some(Err(err))) => {
+                return Err(format!(
+                    "Failed to read the end tar (it may be truncated or corrupt): {}",
+                    err
                 ));
             }
         }
@@ -97,15 +150,50 @@ pub fn generate_delta_list(
     Ok(())
 }
 
-pub fn restore_from_delta_list(
-    mut start_tar: TarReader,
-    mut end_tar: TarWriter,
-    mut delta_list: JBackupFileDeltaListReader,
+/// Applies a delta list to `start_tar`, writing the tar it was generated
+/// from to `end_tar`. Fails listing every delta that doesn't apply (see
+/// `try_restore_from_delta_list`).
+pub fn restore_from_delta_list<S: Read, O: TarStream, D: BufRead>(
+    start_tar: tar::Archive<S>,
+    end_tar: tar::Builder<O>,
+    delta_list: JBackupFileDeltaListReader<D>,
+    order: PathOrder,
 ) -> Result<(), String> {
+    let conflicts = try_restore_from_delta_list(start_tar, end_tar, delta_list, order)?;
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+
+    let mut message = format!(
+        "Patching conflict: {} delta(s) don't apply to the snapshot tar:",
+        conflicts.len()
+    );
+    for conflict in &conflicts {
+        message.push_str(&format!("\n  {}", conflict));
+    }
+    Err(message)
+}
+
+/// Like `restore_from_delta_list`, but deltas that don't apply to
+/// `start_tar` are skipped instead, and returned once the rest of the delta
+/// list is applied. The files of skipped deltas are left as they are in
+/// `start_tar`, so `end_tar` is only the tar the delta list was generated
+/// from if there are no conflicts.
+///
+/// Conflicts happen when a delta list is applied to a tar other than the one
+/// it was generated from, so listing them all makes it clearer what's wrong
+/// with the tar than just the first.
+pub fn try_restore_from_delta_list<S: Read, O: TarStream, D: BufRead>(
+    mut start_tar: tar::Archive<S>,
+    mut end_tar: tar::Builder<O>,
+    mut delta_list: JBackupFileDeltaListReader<D>,
+    order: PathOrder,
+) -> Result<Vec<PatchConflict>, String> {
     let mut start_entries = simplify_result(start_tar.entries())?;
     let mut start_entry = start_entries.next();
 
     let mut delta_entry = delta_list.next()?;
+    let mut conflicts = Vec::new();
 
     loop {
         match (start_entry.take(), delta_entry.take()) {
@@ -118,44 +206,38 @@ pub fn restore_from_delta_list(
                         JBackupDeltaContent::Modified { xdelta } => {
                             let start_buf = get_entry_data(&mut start_entry_uw)?;
 
-                            if let Some(res) = xdelta3::decode(&xdelta, &start_buf) {
-                                add_tar_entry(&mut end_tar, &start_path, res)?;
-                            } else {
-                                add_tar_entry(&mut end_tar, &start_path, start_buf)?;
-                                // eprintln!("Warn: No xdelta output for {}", &start_path);
+                            match xdelta3::decode(&xdelta, &start_buf) {
+                                Some(res) => add_tar_entry(&mut end_tar, &start_path, res)?,
+                                None => {
+                                    add_tar_entry(&mut end_tar, &start_path, start_buf)?;
+                                    conflicts.push(PatchConflict {
+                                        path: start_path,
+                                        kind: PatchConflictKind::XdeltaFailed,
+                                    });
+                                }
                             }
                         }
                         JBackupDeltaContent::Deleted => {
                             // do nothing
                         }
-                        JBackupDeltaContent::Added { content: _ } => {
-                            return Err(format!(
-                                "Patching conflict: Delta contains an Add operation on '{}' that already exists.",
-                                start_path
-                            ));
+                        JBackupDeltaContent::Added { .. } | JBackupDeltaContent::Linked { .. } => {
+                            copy_tar_entry(&mut end_tar, &start_path, start_entry_uw)?;
+                            conflicts.push(PatchConflict {
+                                path: start_path,
+                                kind: PatchConflictKind::AlreadyExists,
+                            });
                         }
                     };
 
                     start_entry = start_entries.next();
                     delta_entry = delta_list.next()?;
-                } else if start_path < delta_path {
-                    simplify_result(end_tar.append_data(
-                        &mut start_entry_uw.header().clone(),
-                        start_path,
-                        start_entry_uw,
-                    ))?;
+                } else if order.cmp_paths(&start_path, &delta_path) == Ordering::Less {
+                    copy_tar_entry(&mut end_tar, &start_path, start_entry_uw)?;
 
                     start_entry = start_entries.next();
                     delta_entry = Some(delta_entry_uw);
                 } else {
-                    let JBackupDeltaContent::Added { content } = delta_entry_uw.content else {
-                        return Err(format!(
-                            "Patching conflict: Cannot operate on '{}' since that file doesn't exist.",
-                            delta_entry_uw.path
-                        ));
-                    };
-
-                    add_tar_entry(&mut end_tar, &delta_entry_uw.path, content)?;
+                    add_tar_entry_from_delta(&mut end_tar, delta_entry_uw, &mut conflicts)?;
 
                     start_entry = Some(Ok(start_entry_uw));
                     delta_entry = delta_list.next()?;
@@ -164,27 +246,13 @@ pub fn restore_from_delta_list(
 
             (Some(Ok(start_entry_uw)), None) => {
                 let start_path = get_entry_path(&start_entry_uw)?;
-
-                simplify_result(end_tar.append_data(
-                    &mut start_entry_uw.header().clone(),
-                    start_path,
-                    start_entry_uw,
-                ))?;
+                copy_tar_entry(&mut end_tar, &start_path, start_entry_uw)?;
 
                 start_entry = start_entries.next();
             }
 
             (None, Some(delta_entry_uw)) => {
-                let end_path = delta_entry_uw.path;
-
-                let JBackupDeltaContent::Added { content } = delta_entry_uw.content else {
-                    return Err(format!(
-                        "Patching conflict: Cannot operate on '{}' since that file doesn't exist.",
-                        end_path
-                    ));
-                };
-
-                add_tar_entry(&mut end_tar, &end_path, content)?;
+                add_tar_entry_from_delta(&mut end_tar, delta_entry_uw, &mut conflicts)?;
 
                 delta_entry = delta_list.next()?;
             }
@@ -192,44 +260,280 @@ pub fn restore_from_delta_list(
             (None, None) => {
                 break;
             }
-            _ => {}
+            // the rest of the tar can't be read, so its files would be
+            // missing from `end_tar`
+            (Some(Err(err)), _) => {
+                return Err(format!(
+                    "Failed to read the snapshot tar (it may be truncated or corrupt): {}",
+                    err
+                ));
+            }
         }
     }
 
-    simplify_result(end_tar.into_inner())?;
+    simplify_result(simplify_result(end_tar.into_inner())?.finish_stream())?;
 
-    Ok(())
+    Ok(conflicts)
+}
+
+/// A delta that doesn't apply to the tar a delta list is applied to.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PatchConflict {
+    pub path: Vec<u8>,
+    pub kind: PatchConflictKind,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PatchConflictKind {
+    /// the delta adds the file (or a hard link), but the tar already has it
+    AlreadyExists,
+    /// the delta modifies or deletes the file, but the tar doesn't have it
+    Missing,
+    /// the delta's xdelta can't be applied to the file's contents in the tar
+    XdeltaFailed,
+}
+
+impl Display for PatchConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let path = String::from_utf8_lossy(&self.path);
+        match self.kind {
+            PatchConflictKind::AlreadyExists => {
+                write!(f, "'{}' is added, but it already exists", path)
+            }
+            PatchConflictKind::Missing => {
+                write!(f, "'{}' is changed, but it doesn't exist", path)
+            }
+            PatchConflictKind::XdeltaFailed => {
+                write!(f, "'{}' has an xdelta that doesn't apply to it", path)
+            }
+        }
+    }
+}
+
+/// Checks if `xdelta3::decode` will be able to apply the xdelta, since it can
+/// only output up to twice the combined size of the xdelta and the source.
+fn is_decodable(xdelta: &[u8], source: &[u8], target: &[u8]) -> bool {
+    target.len() <= (xdelta.len() + source.len()) * 2
+}
+
+/// Compares paths in the order that `walk_file_tree` visits them, which is the
+/// order of the entries in snapshot tars (unless the repository ignores case,
+/// see `PathOrder`): name by name, with each name compared as bytes.
+///
+/// Comparing whole paths as bytes gives a different order when a file and a
+/// directory's name share a prefix (ex. `a.txt` < `a/b.txt`, since `.` < `/`).
+pub fn cmp_paths(a: &[u8], b: &[u8]) -> Ordering {
+    a.split(|x| *x == b'/').cmp(b.split(|x| *x == b'/'))
+}
+
+/// The order of the paths in a repository's snapshot tars, which
+/// `walk_file_tree` visits them in, and diffing tars relies on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PathOrder {
+    /// names compared as bytes (see `cmp_paths`)
+    #[default]
+    Bytes,
+    /// names compared ignoring case, then as bytes, for repositories where
+    /// names that differ only by case are the same file (the config's
+    /// `ignore_case`). Such names end up next to each other.
+    IgnoreCase,
 }
 
-fn get_entry_path(entry: &tar::Entry<'_, GzDecoder<BufReader<File>>>) -> Result<String, String> {
-    if let Some(s) = simplify_result(entry.path())?.to_str() {
-        Ok(String::from(s))
-    } else {
-        Err(String::from("Tar entry contains non-UTF-8 characters."))
+impl PathOrder {
+    pub fn from_config(config: &ConfigFile) -> PathOrder {
+        if config.ignore_case {
+            PathOrder::IgnoreCase
+        } else {
+            PathOrder::Bytes
+        }
+    }
+
+    /// Compares the names of two entries in the same directory.
+    pub fn cmp_names(self, a: &[u8], b: &[u8]) -> Ordering {
+        match self {
+            PathOrder::Bytes => a.cmp(b),
+            PathOrder::IgnoreCase => fold_case(a).cmp(&fold_case(b)).then_with(|| a.cmp(b)),
+        }
+    }
+
+    /// Compares paths name by name, like `cmp_paths`.
+    pub fn cmp_paths(self, a: &[u8], b: &[u8]) -> Ordering {
+        if self == PathOrder::Bytes {
+            return cmp_paths(a, b);
+        }
+
+        let mut a_names = a.split(|x| *x == b'/');
+        let mut b_names = b.split(|x| *x == b'/');
+        loop {
+            match (a_names.next(), b_names.next()) {
+                (Some(a_name), Some(b_name)) => match self.cmp_names(a_name, b_name) {
+                    Ordering::Equal => {}
+                    ordering => return ordering,
+                },
+                (None, None) => return Ordering::Equal,
+                (None, Some(_)) => return Ordering::Less,
+                (Some(_), None) => return Ordering::Greater,
+            }
+        }
+    }
+
+    /// Whether the paths are of the same file, which they are if they only
+    /// differ by case when ignoring case.
+    pub fn is_same_path(self, a: &[u8], b: &[u8]) -> bool {
+        match self {
+            PathOrder::Bytes => a == b,
+            PathOrder::IgnoreCase => fold_case(a) == fold_case(b),
+        }
+    }
+}
+
+/// Lower cases a name, for comparing names ignoring case. Names that aren't
+/// UTF-8 only have their ASCII letters lower cased.
+pub fn fold_case(name: &[u8]) -> Vec<u8> {
+    match std::str::from_utf8(name) {
+        Ok(name) => name.to_lowercase().into_bytes(),
+        Err(_) => name.to_ascii_lowercase(),
     }
 }
 
-fn get_entry_data(
-    entry: &mut tar::Entry<'_, GzDecoder<BufReader<File>>>,
-) -> Result<Vec<u8>, String> {
+/// Paths are compared as bytes, since they may not be UTF-8.
+fn get_entry_path<R: Read>(entry: &tar::Entry<'_, R>) -> Result<Vec<u8>, String> {
+    Ok(entry.path_bytes().into_owned())
+}
+
+fn get_entry_data<R: Read>(entry: &mut tar::Entry<'_, R>) -> Result<Vec<u8>, String> {
     let mut buf = Vec::new();
     simplify_result(entry.read_to_end(&mut buf))?;
     Ok(buf)
 }
 
-fn add_tar_entry(
-    archive: &mut tar::Builder<GzEncoder<File>>,
-    path: &str,
+fn add_tar_entry<W: Write>(
+    archive: &mut tar::Builder<W>,
+    path: &[u8],
     content: Vec<u8>,
 ) -> Result<(), String> {
     let mut header = tar::Header::new_gnu();
     header.set_size(content.len().try_into().unwrap());
-    simplify_result(archive.append_data(&mut header, path, content.as_slice()))?;
+    simplify_result(append_tar_entry(
+        archive,
+        &mut header,
+        path,
+        content.as_slice(),
+    ))?;
     Ok(())
 }
 
+/// Creates the delta that adds an entry of the end tar.
+fn get_added_delta<R: Read>(
+    path: Vec<u8>,
+    entry: &mut tar::Entry<'_, R>,
+) -> Result<JBackupDelta, String> {
+    let content = match get_link_target(entry)? {
+        Some(target) => JBackupDeltaContent::Linked { target },
+        None => JBackupDeltaContent::Added {
+            content: get_entry_data(entry)?,
+        },
+    };
+    Ok(JBackupDelta { path, content })
+}
+
+/// Adds the entry a delta adds. Other deltas change a file that isn't in the
+/// start tar, so they're added to `conflicts` instead.
+fn add_tar_entry_from_delta<W: Write>(
+    archive: &mut tar::Builder<W>,
+    delta: JBackupDelta,
+    conflicts: &mut Vec<PatchConflict>,
+) -> Result<(), String> {
+    match delta.content {
+        JBackupDeltaContent::Added { content } => add_tar_entry(archive, &delta.path, content),
+        JBackupDeltaContent::Linked { target } => simplify_result(append_tar_link(
+            archive,
+            &mut tar::Header::new_gnu(),
+            &delta.path,
+            &target,
+        )),
+        _ => {
+            conflicts.push(PatchConflict {
+                path: delta.path,
+                kind: PatchConflictKind::Missing,
+            });
+            Ok(())
+        }
+    }
+}
+
+/// How a delta list changes a file (see `list_changes`).
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum FileChange {
+    Added,
+    Modified,
+    Deleted,
+    /// the file became a hard link to `target`, the path of another file
+    Linked {
+        target: Vec<u8>,
+    },
+}
+
+/// Describes a change to the file at `path`, as `diff` lists it (ex.
+/// "Modified: a.txt").
+pub fn format_change(path: &[u8], change: &FileChange) -> String {
+    let path = String::from_utf8_lossy(path);
+    match change {
+        FileChange::Added => format!("Added:    {}", path),
+        FileChange::Modified => format!("Modified: {}", path),
+        FileChange::Deleted => format!("Deleted:  {}", path),
+        FileChange::Linked { target } => {
+            format!("Linked:   {} -> {}", path, String::from_utf8_lossy(target))
+        }
+    }
+}
+
+/// Lists the paths of the files a delta list changes, in order, and how
+/// they're changed. A file that's replaced (deleted, then added again) is
+/// listed once.
+pub fn list_changes<R: BufRead>(
+    mut delta_list: JBackupFileDeltaListReader<R>,
+) -> Result<Vec<(Vec<u8>, FileChange)>, String> {
+    let mut changes: Vec<(Vec<u8>, FileChange)> = Vec::new();
+
+    while let Some(delta) = delta_list.next()? {
+        let replaced = matches!(
+            changes.last(),
+            Some((path, FileChange::Deleted)) if *path == delta.path
+        );
+        let change = match delta.content {
+            JBackupDeltaContent::Deleted => FileChange::Deleted,
+            JBackupDeltaContent::Modified { .. } => FileChange::Modified,
+            JBackupDeltaContent::Added { .. } if replaced => FileChange::Modified,
+            JBackupDeltaContent::Added { .. } => FileChange::Added,
+            JBackupDeltaContent::Linked { target } => FileChange::Linked { target },
+        };
+        if replaced && change != FileChange::Deleted {
+            changes.pop();
+        }
+        changes.push((delta.path, change));
+    }
+
+    Ok(changes)
+}
+
+/// Copies an unchanged entry of the start tar. The header is copied, but
+/// paths are passed separately, since long paths aren't stored in the header.
+fn copy_tar_entry<W: Write, R: Read>(
+    archive: &mut tar::Builder<W>,
+    path: &[u8],
+    entry: tar::Entry<'_, R>,
+) -> Result<(), String> {
+    let mut header = entry.header().clone();
+    match get_link_target(&entry)? {
+        Some(target) => simplify_result(append_tar_link(archive, &mut header, path, &target)),
+        None => simplify_result(append_tar_entry(archive, &mut header, path, entry)),
+    }
+}
+
 struct JBackupDelta {
-    path: String,
+    /// the path of the file, which may not be UTF-8
+    path: Vec<u8>,
     content: JBackupDeltaContent,
 }
 
@@ -240,38 +544,48 @@ enum JBackupDeltaContent {
     Modified { xdelta: Vec<u8> },
     /// Serialized id: 3
     Added { content: Vec<u8> },
+    /// A hard link to `target`, the path of another file.
+    /// Serialized id: 4
+    Linked { target: Vec<u8> },
 }
 
-/// A delta list. Files should always be added in UTF-8-byte-ascending order.
+/// A delta list. Files should always be added in the order of the paths in
+/// the snapshot tars (see `PathOrder`).
 ///
 /// The format is as follows:
 ///
 /// - Magic bytes: 'DL'
-/// - Version number: 1u32
-/// - (string length: u64, char[], Delta)[]
+/// - Version number: 3u32
+/// - (path length: u64, path: byte[], Delta)[]
 ///   - Delta is one of the following:
 ///     - [Deleted]
 ///     - [Modified, xdelta length: u64, xdelta: byte[]]
 ///     - [Add, content length: u64, content: byte[]]
+///     - [Link, target length: u64, target: byte[]]
 ///
 /// All numbers are encoded in big-endian.
-pub struct JBackupFileDeltaListWriter {
-    writer: GzEncoder<File>,
+///
+/// Paths are the raw bytes of the path, which may not be UTF-8. In version 1,
+/// paths were always UTF-8. Version 3 added Link deltas, for hard links.
+/// Otherwise, the versions are the same, so older delta lists can still be
+/// read.
+pub struct JBackupFileDeltaListWriter<W: Write = BufWriter<File>> {
+    writer: GzEncoder<W>,
 }
 
-impl JBackupFileDeltaListWriter {
-    pub fn new(mut writer: GzEncoder<File>) -> Result<Self, String> {
+impl<W: Write> JBackupFileDeltaListWriter<W> {
+    pub fn new(mut writer: GzEncoder<W>) -> Result<Self, String> {
         simplify_result(writer.write_all("DL".as_bytes()))?;
-        simplify_result(writer.write_all(&1u32.to_be_bytes()))?;
+        simplify_result(writer.write_all(&DELTA_LIST_VERSION.to_be_bytes()))?;
         Ok(JBackupFileDeltaListWriter { writer })
     }
 
     /// Add a file operation to the delta list
     fn add(&mut self, delta: JBackupDelta) -> Result<(), String> {
-        self.add_string(&delta.path)?;
+        self.add_bytes(&delta.path)?;
 
         match delta.content {
-            JBackupDeltaContent::Deleted {} => {
+            JBackupDeltaContent::Deleted => {
                 simplify_result(self.writer.write_all(&[1]))?;
             }
             JBackupDeltaContent::Modified { xdelta } => {
@@ -282,6 +596,10 @@ impl JBackupFileDeltaListWriter {
                 simplify_result(self.writer.write_all(&[3]))?;
                 self.add_bytes(&content)?;
             }
+            JBackupDeltaContent::Linked { target } => {
+                simplify_result(self.writer.write_all(&[4]))?;
+                self.add_bytes(&target)?;
+            }
         };
 
         Ok(())
@@ -289,13 +607,12 @@ impl JBackupFileDeltaListWriter {
 
     pub fn try_finish(&mut self) -> Result<(), String> {
         simplify_result(self.writer.try_finish())?;
+        // the file is usually buffered, and errors are lost if it's only
+        // flushed when dropped
+        simplify_result(self.writer.get_mut().flush())?;
         Ok(())
     }
 
-    fn add_string(&mut self, s: &str) -> Result<(), String> {
-        self.add_bytes(s.as_bytes())
-    }
-
     fn add_bytes(&mut self, bytes: &[u8]) -> Result<(), String> {
         simplify_result(
             self.writer
@@ -306,12 +623,12 @@ impl JBackupFileDeltaListWriter {
     }
 }
 
-pub struct JBackupFileDeltaListReader {
-    reader: GzDecoder<BufReader<File>>,
+pub struct JBackupFileDeltaListReader<R: BufRead = BufReader<File>> {
+    reader: GzDecoder<R>,
 }
 
-impl JBackupFileDeltaListReader {
-    pub fn new(mut reader: GzDecoder<BufReader<File>>) -> Result<Self, String> {
+impl<R: BufRead> JBackupFileDeltaListReader<R> {
+    pub fn new(mut reader: GzDecoder<R>) -> Result<Self, String> {
         let mut header = [0u8; 2 + 4];
         if let Some(e) = reader.read_exact(&mut header).err() {
             if e.kind() == ErrorKind::UnexpectedEof {
@@ -319,22 +636,30 @@ impl JBackupFileDeltaListReader {
             } else {
                 return Err(format!(
                     "Unexpected IO Error when reading delta list: {}",
-                    e.to_string()
+                    e
                 ));
             }
         }
 
-        if header == [b'D', b'L', 0, 0, 0, 1] {
-            Ok(JBackupFileDeltaListReader { reader })
-        } else {
-            Err(String::from(
+        if header[0..2] != [b'D', b'L'] {
+            return Err(String::from(
                 "Header magic number doesn't match. Input file is not a delta list.",
-            ))
+            ));
         }
+
+        let version = u32::from_be_bytes(header[2..6].try_into().unwrap());
+        if version == 0 || version > DELTA_LIST_VERSION {
+            return Err(format!(
+                "Unsupported delta list version {} (the latest supported version is {})",
+                version, DELTA_LIST_VERSION
+            ));
+        }
+
+        Ok(JBackupFileDeltaListReader { reader })
     }
 
     fn next(&mut self) -> Result<Option<JBackupDelta>, String> {
-        let Ok(path) = self.read_string() else {
+        let Ok(path) = self.read_bytes() else {
             return Ok(None);
         };
 
@@ -348,16 +673,15 @@ impl JBackupFileDeltaListReader {
             3 => JBackupDeltaContent::Added {
                 content: self.read_bytes()?,
             },
+            4 => JBackupDeltaContent::Linked {
+                target: self.read_bytes()?,
+            },
             _ => return Err(format!("Unexpected operation with number '{}'", op_type)),
         };
 
         Ok(Some(JBackupDelta { path, content }))
     }
 
-    fn read_string(&mut self) -> Result<String, String> {
-        simplify_result(String::from_utf8(self.read_bytes()?))
-    }
-
     fn read_bytes(&mut self) -> Result<Vec<u8>, String> {
         let mut bytes_len_buff = [0u8; 8];
         simplify_result(self.reader.read_exact(&mut bytes_len_buff))?;
@@ -379,3 +703,600 @@ impl JBackupFileDeltaListReader {
         Ok(bytes[0])
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use flate2::{Compression, bufread::GzDecoder, write::GzEncoder};
+
+    use crate::util::archive_utils::{TarFormat, append_tar_link, get_link_target};
+
+    use std::cmp::Ordering;
+
+    use super::{
+        FileChange, JBackupDelta, JBackupDeltaContent, JBackupFileDeltaListReader,
+        JBackupFileDeltaListWriter, PatchConflict, PatchConflictKind, PathOrder, append_tar_entry,
+        cmp_paths, generate_delta_list, list_changes, restore_from_delta_list,
+        try_restore_from_delta_list,
+    };
+
+    fn create_tar<P: AsRef<[u8]>>(files: &[(P, &[u8])]) -> Vec<u8> {
+        create_tar_with_format(files, TarFormat::Gnu)
+    }
+
+    fn create_tar_with_format<P: AsRef<[u8]>>(files: &[(P, &[u8])], format: TarFormat) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, content) in files {
+            let mut header = format.new_header();
+            header.set_size(content.len().try_into().unwrap());
+            append_tar_entry(&mut builder, &mut header, path.as_ref(), *content).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    /// The files that `read_tar` is expected to return
+    fn to_expected_files<P: AsRef<[u8]>>(files: &[(P, &[u8])]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        files
+            .iter()
+            .map(|(path, content)| (path.as_ref().to_vec(), content.to_vec()))
+            .collect()
+    }
+
+    fn read_tar(tar_gz: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut archive = tar::Archive::new(GzDecoder::new(tar_gz));
+        let mut files = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = super::get_entry_path(&entry).unwrap();
+            files.push((path, super::get_entry_data(&mut entry).unwrap()));
+        }
+        files
+    }
+
+    /// Generates a delta list that turns `start` into `end`
+    fn create_delta_list(start: &[u8], end: &[u8]) -> Vec<u8> {
+        create_delta_list_in_order(start, end, PathOrder::Bytes)
+    }
+
+    fn create_delta_list_in_order(start: &[u8], end: &[u8], order: PathOrder) -> Vec<u8> {
+        let mut delta_list = Vec::new();
+        generate_delta_list(
+            tar::Archive::new(start),
+            tar::Archive::new(end),
+            JBackupFileDeltaListWriter::new(GzEncoder::new(&mut delta_list, Compression::fast()))
+                .unwrap(),
+            order,
+        )
+        .unwrap();
+        delta_list
+    }
+
+    fn read_delta_list(delta_list: &[u8]) -> Vec<JBackupDelta> {
+        let mut reader = JBackupFileDeltaListReader::new(GzDecoder::new(delta_list)).unwrap();
+        let mut deltas = Vec::new();
+        while let Some(delta) = reader.next().unwrap() {
+            deltas.push(delta);
+        }
+        deltas
+    }
+
+    fn apply_delta_list(start: &[u8], delta_list: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        apply_delta_list_in_order(start, delta_list, PathOrder::Bytes)
+    }
+
+    fn apply_delta_list_in_order(
+        start: &[u8],
+        delta_list: &[u8],
+        order: PathOrder,
+    ) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut end = Vec::new();
+        restore_from_delta_list(
+            tar::Archive::new(start),
+            tar::Builder::new(GzEncoder::new(&mut end, Compression::fast())),
+            JBackupFileDeltaListReader::new(GzDecoder::new(delta_list)).unwrap(),
+            order,
+        )
+        .unwrap();
+        read_tar(&end)
+    }
+
+    #[test]
+    pub fn identical_tars_have_empty_delta_list() {
+        let big_file = vec![7u8; 10_000];
+        let files: &[(&str, &[u8])] = &[("a.txt", b"a"), ("b/c", &big_file), ("empty", b"")];
+
+        let delta_list = create_delta_list(&create_tar(files), &create_tar(files));
+
+        assert_eq!(read_delta_list(&delta_list).len(), 0);
+    }
+
+    #[test]
+    pub fn delta_list_round_trip() {
+        let start = create_tar(&[
+            ("a.txt", b"v2\n"),
+            ("added", b"new file"),
+            ("same", b"unchanged"),
+        ]);
+        let end_files: &[(&str, &[u8])] = &[
+            ("a.txt", b"v1\n"),
+            ("deleted", b"old file"),
+            ("same", b"unchanged"),
+        ];
+
+        let delta_list = create_delta_list(&start, &create_tar(end_files));
+
+        assert_eq!(
+            apply_delta_list(&start, &delta_list),
+            to_expected_files(end_files)
+        );
+    }
+
+    #[test]
+    pub fn collects_conflicts_with_the_wrong_start_tar() {
+        let big_file: Vec<u8> = (0..10_000u32).map(|x| (x * 7 % 251) as u8).collect();
+        let mut big_file_2 = big_file.clone();
+        big_file_2[5_000] = 0;
+        let start = create_tar(&[("a", &big_file), ("b", b"old"), ("c", b"same")]);
+        let end = create_tar(&[("a", &big_file_2), ("c", b"same"), ("d", b"new")]);
+        let delta_list = create_delta_list(&start, &end);
+
+        // a tar the delta list wasn't generated from
+        let wrong_start = create_tar(&[("c", b"same"), ("d", b"exists")]);
+        let mut restored = Vec::new();
+        let conflicts = try_restore_from_delta_list(
+            tar::Archive::new(wrong_start.as_slice()),
+            tar::Builder::new(GzEncoder::new(&mut restored, Compression::fast())),
+            JBackupFileDeltaListReader::new(GzDecoder::new(delta_list.as_slice())).unwrap(),
+            PathOrder::Bytes,
+        )
+        .unwrap();
+
+        let conflict = |path: &str, kind| PatchConflict {
+            path: path.as_bytes().to_vec(),
+            kind,
+        };
+        assert_eq!(
+            conflicts,
+            [
+                conflict("a", PatchConflictKind::Missing),
+                conflict("b", PatchConflictKind::Missing),
+                conflict("d", PatchConflictKind::AlreadyExists),
+            ]
+        );
+        // files with conflicts are left as they are
+        assert_eq!(
+            read_tar(&restored),
+            to_expected_files::<&str>(&[("c", b"same"), ("d", b"exists")])
+        );
+
+        let err = restore_from_delta_list(
+            tar::Archive::new(wrong_start.as_slice()),
+            tar::Builder::new(GzEncoder::new(Vec::new(), Compression::fast())),
+            JBackupFileDeltaListReader::new(GzDecoder::new(delta_list.as_slice())).unwrap(),
+            PathOrder::Bytes,
+        )
+        .unwrap_err();
+        assert!(err.contains("3 delta(s) don't apply"));
+        assert!(err.contains("'d' is added, but it already exists"));
+    }
+
+    #[test]
+    pub fn replaces_files_with_xdeltas_larger_than_the_file() {
+        // incompressible data, which xdelta can't describe in fewer bytes
+        let mut state = 0x2545f491u32;
+        let mut random_bytes = |len: usize| -> Vec<u8> {
+            (0..len)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    state as u8
+                })
+                .collect()
+        };
+        let start = create_tar::<&str>(&[("a", &random_bytes(10_000))]);
+        let end_files: &[(&str, &[u8])] = &[("a", &random_bytes(10_000))];
+        let delta_list = create_delta_list(&start, &create_tar(end_files));
+
+        let deltas = read_delta_list(&delta_list);
+        assert_eq!(deltas.len(), 2);
+        assert!(matches!(deltas[0].content, JBackupDeltaContent::Deleted));
+        assert!(matches!(
+            deltas[1].content,
+            JBackupDeltaContent::Added { .. }
+        ));
+        assert_eq!(
+            apply_delta_list(&start, &delta_list),
+            to_expected_files(end_files)
+        );
+    }
+
+    #[test]
+    pub fn lists_changes() {
+        let big_file = vec![7u8; 10_000];
+        let mut big_file_changed = big_file.clone();
+        big_file_changed[5_000] = 8;
+        let start = create_tar(&[
+            ("a.txt", b"v1\n" as &[u8]),
+            ("big", &big_file),
+            ("deleted", b"old file"),
+            ("same", b"unchanged"),
+        ]);
+        let end = create_tar(&[
+            ("a.txt", b"v2\n" as &[u8]),
+            ("added", b"new file"),
+            ("big", &big_file_changed),
+            ("same", b"unchanged"),
+        ]);
+
+        let delta_list = create_delta_list(&start, &end);
+        let changes = list_changes(
+            JBackupFileDeltaListReader::new(GzDecoder::new(delta_list.as_slice())).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            changes,
+            [
+                (b"a.txt".to_vec(), FileChange::Modified),
+                (b"added".to_vec(), FileChange::Added),
+                (b"big".to_vec(), FileChange::Modified),
+                (b"deleted".to_vec(), FileChange::Deleted),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn delta_list_round_trip_with_empty_files() {
+        let big_file = vec![7u8; 10_000];
+        let start_files: &[(&str, &[u8])] = &[
+            ("added_empty", b""),
+            ("becomes_empty", b"contents"),
+            ("big_becomes_empty", &big_file),
+            ("empty_becomes_big", b""),
+            ("empty_becomes_nonempty", b""),
+            ("unchanged_empty", b""),
+        ];
+        let end_files: &[(&str, &[u8])] = &[
+            ("becomes_empty", b""),
+            ("big_becomes_empty", b""),
+            ("deleted_empty", b""),
+            ("empty_becomes_big", &big_file),
+            ("empty_becomes_nonempty", b"contents"),
+            ("unchanged_empty", b""),
+        ];
+        let start = create_tar(start_files);
+
+        // in both directions, since the delta list of a snapshot goes from
+        // the newer snapshot to the older one
+        let delta_list = create_delta_list(&start, &create_tar(end_files));
+        assert_eq!(
+            apply_delta_list(&start, &delta_list),
+            to_expected_files(end_files)
+        );
+
+        let end = create_tar(end_files);
+        let delta_list = create_delta_list(&end, &start);
+        assert_eq!(
+            apply_delta_list(&end, &delta_list),
+            to_expected_files(start_files)
+        );
+    }
+
+    #[test]
+    pub fn compares_paths_name_by_name() {
+        assert_eq!(cmp_paths(b"a/b.txt", b"a.txt"), Ordering::Less);
+        assert_eq!(cmp_paths(b"a-b/c", b"a/c"), Ordering::Greater);
+        assert_eq!(cmp_paths(b"a/b", b"a/b"), Ordering::Equal);
+        assert_eq!(cmp_paths(b"a/b", b"a/b/c"), Ordering::Less);
+        assert_eq!(cmp_paths("z".as_bytes(), "é".as_bytes()), Ordering::Less);
+    }
+
+    #[test]
+    pub fn compares_paths_ignoring_case() {
+        let order = PathOrder::IgnoreCase;
+        assert_eq!(order.cmp_paths(b"B", b"a"), Ordering::Greater);
+        assert_eq!(order.cmp_paths(b"a/C", b"a/b"), Ordering::Greater);
+        assert_eq!(order.cmp_paths(b"A/b", b"a.txt"), Ordering::Less);
+        assert_eq!(
+            order.cmp_paths("É".as_bytes(), "f".as_bytes()),
+            Ordering::Greater
+        );
+        // names that differ only by case are still ordered, next to each other
+        assert_eq!(order.cmp_paths(b"README", b"readme"), Ordering::Less);
+        assert_eq!(order.cmp_paths(b"README", b"readme.txt"), Ordering::Less);
+        assert_eq!(order.cmp_paths(b"readme", b"README.txt"), Ordering::Less);
+        assert_eq!(order.cmp_paths(b"a/b", b"a/b"), Ordering::Equal);
+
+        assert!(order.is_same_path(b"Dir/README", b"dir/readme"));
+        assert!(order.is_same_path("É".as_bytes(), "é".as_bytes()));
+        assert!(!order.is_same_path(b"a", b"b"));
+        assert!(!PathOrder::Bytes.is_same_path(b"A", b"a"));
+    }
+
+    #[test]
+    pub fn delta_list_round_trip_ignoring_case() {
+        let order = PathOrder::IgnoreCase;
+        let start_files: &[(&str, &[u8])] = &[
+            ("a", b"same"),
+            ("B/x", b"v1"),
+            ("c.txt", b"removed"),
+            ("README", b"renamed"),
+        ];
+        let end_files: &[(&str, &[u8])] = &[
+            ("a", b"same"),
+            ("B/x", b"v2"),
+            ("C/added", b"added"),
+            ("readme", b"renamed"),
+        ];
+        let start = create_tar(start_files);
+        let end = create_tar(end_files);
+
+        let delta_list = create_delta_list_in_order(&start, &end, order);
+        let mut changes: Vec<Vec<u8>> = read_delta_list(&delta_list)
+            .into_iter()
+            .map(|x| x.path)
+            .collect();
+        // small files are replaced, with a delete and an add
+        changes.dedup();
+        assert_eq!(
+            changes,
+            ["B/x", "C/added", "c.txt", "README", "readme"].map(|x| x.as_bytes().to_vec())
+        );
+        assert_eq!(
+            apply_delta_list_in_order(&start, &delta_list, order),
+            to_expected_files(end_files)
+        );
+
+        let delta_list = create_delta_list_in_order(&end, &start, order);
+        assert_eq!(
+            apply_delta_list_in_order(&end, &delta_list, order),
+            to_expected_files(start_files)
+        );
+    }
+
+    #[test]
+    pub fn delta_list_round_trip_with_directory_name_prefixes() {
+        // in the order walk_file_tree visits them, where "a/" comes before
+        // "a.txt" even though '/' > '.'
+        let start = create_tar(&[
+            ("a/changed", b"v2"),
+            ("a/unchanged", b"same"),
+            ("a-b/x", b"same"),
+            ("a.txt", b"added"),
+            ("é/x", b"v2"),
+        ]);
+        let end_files: &[(&str, &[u8])] = &[
+            ("a/changed", b"v1"),
+            ("a/deleted", b"old"),
+            ("a/unchanged", b"same"),
+            ("a-b/x", b"same"),
+            ("é/x", b"v1"),
+        ];
+
+        let delta_list = create_delta_list(&start, &create_tar(end_files));
+
+        let paths: Vec<Vec<u8>> = read_delta_list(&delta_list)
+            .into_iter()
+            .map(|x| x.path)
+            .collect();
+        assert!(!paths.contains(&b"a-b/x".to_vec()));
+        assert!(!paths.contains(&b"a/unchanged".to_vec()));
+
+        assert_eq!(
+            apply_delta_list(&start, &delta_list),
+            to_expected_files(end_files)
+        );
+    }
+
+    #[test]
+    pub fn delta_list_round_trip_with_files_that_became_directories() {
+        // "a" is a file in one snapshot, and a directory in the other
+        let file_files: &[(&str, &[u8])] = &[("a", b"file"), ("b/c", b"same")];
+        let dir_files: &[(&str, &[u8])] = &[("a/x", b"x"), ("a/y", b"y"), ("b/c", b"same")];
+
+        let delta_list = create_delta_list(&create_tar(file_files), &create_tar(dir_files));
+        assert_eq!(
+            apply_delta_list(&create_tar(file_files), &delta_list),
+            to_expected_files(dir_files)
+        );
+
+        let delta_list = create_delta_list(&create_tar(dir_files), &create_tar(file_files));
+        let paths: Vec<Vec<u8>> = read_delta_list(&delta_list)
+            .into_iter()
+            .map(|x| x.path)
+            .collect();
+        assert_eq!(paths, [b"a".to_vec(), b"a/x".to_vec(), b"a/y".to_vec()]);
+        assert_eq!(
+            apply_delta_list(&create_tar(dir_files), &delta_list),
+            to_expected_files(file_files)
+        );
+    }
+
+    #[test]
+    pub fn fails_on_corrupt_tars() {
+        let big_file = vec![7u8; 2000];
+        let files: &[(&str, &[u8])] = &[("a.txt", b"a"), ("b.bin", &big_file), ("c.txt", b"c")];
+        let tar = create_tar(files);
+        let end_tar = create_tar(&[("a.txt", b"b")]);
+
+        // the header of b.bin follows a.txt's header and padded contents
+        let mut bad_header = tar.clone();
+        bad_header[1024 + 10] ^= 0xFF;
+        let truncated = tar[..1024 + 512 + 1000].to_vec();
+
+        for corrupt in [bad_header, truncated] {
+            let generate = |start: &[u8], end: &[u8]| {
+                generate_delta_list(
+                    tar::Archive::new(start),
+                    tar::Archive::new(end),
+                    JBackupFileDeltaListWriter::new(GzEncoder::new(
+                        Vec::new(),
+                        Compression::fast(),
+                    ))
+                    .unwrap(),
+                    PathOrder::Bytes,
+                )
+            };
+            assert!(
+                generate(&corrupt, &end_tar)
+                    .unwrap_err()
+                    .starts_with("Failed to read the start tar")
+            );
+            assert!(
+                generate(&end_tar, &corrupt)
+                    .unwrap_err()
+                    .starts_with("Failed to read the end tar")
+            );
+
+            let delta_list = create_delta_list(&tar, &end_tar);
+            let result = restore_from_delta_list(
+                tar::Archive::new(corrupt.as_slice()),
+                tar::Builder::new(GzEncoder::new(Vec::new(), Compression::fast())),
+                JBackupFileDeltaListReader::new(GzDecoder::new(delta_list.as_slice())).unwrap(),
+                PathOrder::Bytes,
+            );
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    pub fn delta_list_round_trip_with_long_paths() {
+        let long_dir = "nested/".repeat(30);
+        let long_path = long_dir.clone() + "long.txt";
+        let unchanged_path = long_dir.clone() + "unchanged.txt";
+        assert!(long_path.len() > 200);
+
+        for format in [TarFormat::Gnu, TarFormat::Pax] {
+            let start = create_tar_with_format(
+                &[(&long_path, b"v2\n"), (&unchanged_path, b"unchanged")],
+                format,
+            );
+            let end_files: &[(&str, &[u8])] =
+                &[(&long_path, b"v1\n"), (&unchanged_path, b"unchanged")];
+
+            let delta_list = create_delta_list(&start, &create_tar_with_format(end_files, format));
+
+            assert_eq!(
+                apply_delta_list(&start, &delta_list),
+                to_expected_files(end_files)
+            );
+        }
+    }
+
+    #[test]
+    pub fn delta_list_round_trip_with_non_utf8_paths() {
+        let start = create_tar::<&[u8]>(&[(b"a\xff", b"v2\n"), (b"b\xfe/same", b"unchanged")]);
+        let end_files: &[(&[u8], &[u8])] = &[
+            (b"a\xff", b"v1\n"),
+            (b"b\xfe/same", b"unchanged"),
+            (b"c\xfd", b"deleted"),
+        ];
+
+        let delta_list = create_delta_list(&start, &create_tar(end_files));
+
+        assert_eq!(
+            apply_delta_list(&start, &delta_list),
+            to_expected_files(end_files)
+        );
+    }
+
+    /// Entries are (path, content, hard link target)
+    fn create_tar_with_links(files: &[(&str, &str, Option<&str>)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, content, link_target) in files {
+            let mut header = tar::Header::new_gnu();
+            match link_target {
+                Some(target) => append_tar_link(
+                    &mut builder,
+                    &mut header,
+                    path.as_bytes(),
+                    target.as_bytes(),
+                )
+                .unwrap(),
+                None => {
+                    header.set_size(content.len().try_into().unwrap());
+                    append_tar_entry(
+                        &mut builder,
+                        &mut header,
+                        path.as_bytes(),
+                        content.as_bytes(),
+                    )
+                    .unwrap()
+                }
+            }
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    pub fn delta_list_round_trip_with_hard_links() {
+        let start = create_tar_with_links(&[
+            ("a", "x", None),
+            ("b", "", Some("a")),
+            ("c", "y", None),
+            ("e", "", Some("a")),
+        ]);
+        let end_files = [
+            ("a", "x2", None),
+            ("b", "z", None),
+            ("c", "", Some("a")),
+            ("d", "", Some("a")),
+            ("e", "", Some("a")),
+        ];
+        let end = create_tar_with_links(&end_files);
+
+        let delta_list = create_delta_list(&start, &end);
+        let mut restored = Vec::new();
+        restore_from_delta_list(
+            tar::Archive::new(start.as_slice()),
+            tar::Builder::new(GzEncoder::new(&mut restored, Compression::fast())),
+            JBackupFileDeltaListReader::new(GzDecoder::new(delta_list.as_slice())).unwrap(),
+            PathOrder::Bytes,
+        )
+        .unwrap();
+
+        let mut archive = tar::Archive::new(GzDecoder::new(restored.as_slice()));
+        let mut restored_files = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let link_target = get_link_target(&entry)
+                .unwrap()
+                .map(|x| String::from_utf8(x).unwrap());
+            let path = String::from_utf8(super::get_entry_path(&entry).unwrap()).unwrap();
+            let content = String::from_utf8(super::get_entry_data(&mut entry).unwrap()).unwrap();
+            restored_files.push((path, content, link_target));
+        }
+
+        let expected: Vec<_> = end_files
+            .iter()
+            .map(|(path, content, link_target)| {
+                (
+                    path.to_string(),
+                    content.to_string(),
+                    link_target.map(String::from),
+                )
+            })
+            .collect();
+        assert_eq!(restored_files, expected);
+    }
+
+    #[test]
+    pub fn reads_version_1_delta_lists() {
+        let mut delta_list = Vec::new();
+        {
+            let mut writer = GzEncoder::new(&mut delta_list, Compression::fast());
+            writer.write_all(b"DL").unwrap();
+            writer.write_all(&1u32.to_be_bytes()).unwrap();
+            writer.write_all(&5u64.to_be_bytes()).unwrap();
+            writer.write_all(b"a.txt").unwrap();
+            writer.write_all(&[1]).unwrap();
+            writer.finish().unwrap();
+        }
+
+        let deltas = read_delta_list(&delta_list);
+        assert_eq!(deltas.len(), 1);
+        assert_eq!(deltas[0].path, b"a.txt");
+    }
+}
@@ -1,34 +1,150 @@
 use std::{
     collections::{HashMap, HashSet},
+    fmt::{self, Display},
     fs,
-    io::{self, ErrorKind},
+    io::{self, ErrorKind, Write},
     str::FromStr,
+    time::SystemTime,
 };
 
 use crate::{
-    BRANCHES_PATH, CONFIG_PATH, HEAD_PATH, JBACKUP_PATH, SNAPSHOTS_PATH, string_set,
-    tab_separated_key_value, util::io_util::simplify_result,
+    BRANCHES_PATH, HEAD_PATH, JBACKUP_PATH, OPERATIONS_LOG_PATH, REFLOG_PATH,
+    RESTORE_PROGRESS_PATH, STASH_LIST_PATH, STASHES_PATH, TAGS_PATH,
+    repository::Repository,
+    string_set, tab_separated_key_value,
+    util::{
+        archive_utils::{CompressionLevel, TarFormat},
+        io_util::{LockFile, append_to_file, simplify_result, sync_file},
+        json_util::json_string,
+        size_util::parse_size,
+    },
 };
 
+#[derive(Clone)]
 pub struct BranchesFile {
     pub branches: HashMap<String, String>,
 }
 
 impl BranchesFile {
-    pub fn read() -> Result<BranchesFile, String> {
-        let contents =
-            tab_separated_key_value::Config::single_value_only().read_file(BRANCHES_PATH)?;
+    pub fn read(repo: &Repository) -> Result<BranchesFile, String> {
+        let contents = tab_separated_key_value::Config::single_value_only()
+            .read_file(&repo.path(BRANCHES_PATH))?;
         Ok(BranchesFile {
             branches: contents.single_value,
         })
     }
 
-    pub fn write(self) -> Result<(), String> {
-        tab_separated_key_value::Contents {
-            multi_value: HashMap::new(),
-            single_value: self.branches,
+    pub fn write(self, repo: &Repository) -> Result<(), String> {
+        write_metadata_file(
+            repo,
+            &tab_separated_key_value::Contents {
+                multi_value: HashMap::new(),
+                single_value: self.branches,
+            },
+            BRANCHES_PATH,
+        )
+    }
+}
+
+/// Writes the contents of a head, branches or tags file, gzipped if the
+/// config's `gzip_meta` is set.
+fn write_metadata_file(
+    repo: &Repository,
+    contents: &tab_separated_key_value::Contents,
+    path: &str,
+) -> Result<(), String> {
+    let path = repo.path(path);
+    if repo.config.gzip_meta {
+        contents.write_gzip_file(&path)?;
+    } else {
+        contents.write_file(&path)?;
+    }
+    if repo.should_fsync() {
+        sync_file(&path)?;
+    }
+    Ok(())
+}
+
+/// Fails if a branch can't be named `name`, since it's written in the
+/// tab-separated branches file.
+pub fn check_branch_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.contains(['\t', '\n']) {
+        Err(format!("Invalid branch name '{}'", name))
+    } else {
+        Ok(())
+    }
+}
+
+/// Renames a branch, moving the head along with it if it's the current
+/// branch. The current branch can be renamed before it has any snapshots.
+///
+/// Each file written leaves the repository consistent, so a failed write
+/// never leaves the head on a branch that doesn't exist: the new branch is
+/// written first, then the head, and then the old branch is removed.
+pub fn rename_branch(repo: &Repository, old_name: &str, new_name: &str) -> Result<(), String> {
+    check_branch_name(new_name)?;
+
+    let mut branches_file = BranchesFile::read(repo)?;
+    let mut head_file = HeadFile::read(repo)?;
+    let is_curr_branch = head_file.curr_branch == old_name;
+    let snapshot_id = branches_file.branches.get(old_name).cloned();
+
+    if snapshot_id.is_none() && !is_curr_branch {
+        return Err(format!("The branch '{}' doesn't exist", old_name));
+    }
+    if branches_file.branches.contains_key(new_name) || head_file.curr_branch == new_name {
+        return Err(format!("The branch '{}' already exists", new_name));
+    }
+
+    if let Some(snapshot_id) = snapshot_id {
+        branches_file
+            .branches
+            .insert(String::from(new_name), snapshot_id);
+        branches_file.clone().write(repo)?;
+    }
+    if is_curr_branch {
+        head_file.curr_branch = String::from(new_name);
+        head_file.write(repo)?;
+    }
+    if branches_file.branches.remove(old_name).is_some() {
+        branches_file.write(repo)?;
+    }
+
+    Ok(())
+}
+
+/// Names for snapshots. Unlike branches, tags never move.
+///
+/// The tags file is optional, since repositories created before tags existed
+/// don't have one.
+pub struct TagsFile {
+    pub tags: HashMap<String, String>,
+}
+
+impl TagsFile {
+    pub fn read(repo: &Repository) -> Result<TagsFile, String> {
+        let path = repo.path(TAGS_PATH);
+        if !simplify_result(fs::exists(&path))? {
+            return Ok(TagsFile {
+                tags: HashMap::new(),
+            });
         }
-        .write_file(BRANCHES_PATH)
+
+        let contents = tab_separated_key_value::Config::single_value_only().read_file(&path)?;
+        Ok(TagsFile {
+            tags: contents.single_value,
+        })
+    }
+
+    pub fn write(self, repo: &Repository) -> Result<(), String> {
+        write_metadata_file(
+            repo,
+            &tab_separated_key_value::Contents {
+                multi_value: HashMap::new(),
+                single_value: self.tags,
+            },
+            TAGS_PATH,
+        )
     }
 }
 
@@ -38,8 +154,21 @@ pub struct HeadFile {
 }
 
 impl HeadFile {
-    pub fn read() -> Result<HeadFile, String> {
-        let map = tab_separated_key_value::Config::single_value_only().read_file(HEAD_PATH)?;
+    pub fn read(repo: &Repository) -> Result<HeadFile, String> {
+        match HeadFile::read_without_hint(repo) {
+            Ok(x) => Ok(x),
+            Err(err) => Err(format!(
+                "{} (if the head file is corrupt, 'jbackup recover-head' can rebuild it)",
+                err
+            )),
+        }
+    }
+
+    /// Reads the head file like `read`, without suggesting how to recover it
+    /// when it can't be read.
+    pub fn read_without_hint(repo: &Repository) -> Result<HeadFile, String> {
+        let map = tab_separated_key_value::Config::single_value_only()
+            .read_file(&repo.path(HEAD_PATH))?;
         let curr_snapshot_id = map.single_value.get("snapshotid");
         let curr_branch = map.single_value.get("branch");
         if curr_branch.is_none() {
@@ -49,30 +178,332 @@ impl HeadFile {
         }
 
         Ok(HeadFile {
-            curr_snapshot_id: curr_snapshot_id.map(|s| s.clone()),
+            curr_snapshot_id: curr_snapshot_id.cloned(),
             curr_branch: curr_branch
                 .expect("branch should have been validated to have a value")
                 .clone(),
         })
     }
 
-    pub fn write(self) -> Result<(), String> {
+    pub fn write(self, repo: &Repository) -> Result<(), String> {
+        write_metadata_file(
+            repo,
+            &tab_separated_key_value::Contents {
+                multi_value: HashMap::new(),
+                single_value: {
+                    let mut m = HashMap::new();
+                    self.curr_snapshot_id
+                        .map(|s| m.insert(String::from("snapshotid"), s));
+                    m.insert(String::from("branch"), self.curr_branch);
+                    m
+                },
+            },
+            HEAD_PATH,
+        )
+    }
+}
+
+/// An append-only record of every time the head moved to another snapshot.
+///
+/// Each line is a movement, with the fields separated by tabs:
+/// `{date}\t{old snapshot id}\t{new snapshot id}\t{operation}`. A missing
+/// snapshot id is written as `NULL`.
+pub struct ReflogFile;
+
+pub struct ReflogEntry {
+    pub date: i64,
+    pub old_snapshot_id: Option<String>,
+    pub new_snapshot_id: Option<String>,
+    /// the command that moved the head (ex. snapshot)
+    pub operation: String,
+}
+
+impl ReflogFile {
+    /// Reads all entries in the reflog, oldest first.
+    pub fn read(repo: &Repository) -> Result<Vec<ReflogEntry>, String> {
+        let contents = match fs::read_to_string(repo.path(REFLOG_PATH)) {
+            Ok(x) => x,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return simplify_result(Err(err)),
+        };
+
+        contents
+            .split('\n')
+            .filter(|line| !line.is_empty())
+            .map(ReflogEntry::parse)
+            .collect()
+    }
+
+    pub fn append(repo: &Repository, entry: ReflogEntry) -> Result<(), String> {
+        append_to_file(repo.path(REFLOG_PATH), entry.serialize().as_bytes())
+    }
+}
+
+impl ReflogEntry {
+    fn parse(line: &str) -> Result<ReflogEntry, String> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [date, old_snapshot_id, new_snapshot_id, operation] = fields[..] else {
+            return Err(format!("Corrupted reflog entry: '{}'", line));
+        };
+
+        fn parse_id(id: &str) -> Option<String> {
+            if id == "NULL" {
+                None
+            } else {
+                Some(String::from(id))
+            }
+        }
+
+        Ok(ReflogEntry {
+            date: simplify_result(date.parse::<i64>())?,
+            old_snapshot_id: parse_id(old_snapshot_id),
+            new_snapshot_id: parse_id(new_snapshot_id),
+            operation: String::from(operation),
+        })
+    }
+
+    fn serialize(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\n",
+            self.date,
+            self.old_snapshot_id.as_deref().unwrap_or("NULL"),
+            self.new_snapshot_id.as_deref().unwrap_or("NULL"),
+            self.operation
+        )
+    }
+}
+
+/// An append-only audit trail of the commands that change the repository or
+/// the working directory, whether or not they succeed. Unlike the reflog,
+/// which only records the head moving, it records every such command, and
+/// the snapshots it created and removed.
+///
+/// Each line is an entry, as a JSON object (see `OperationsLogEntry::serialize`).
+pub struct OperationsLogFile;
+
+pub struct OperationsLogEntry {
+    pub date: i64,
+    /// the subcommand (ex. snapshot)
+    pub command: String,
+    /// every argument the executable was run with
+    pub args: Vec<String>,
+    /// the error the command failed with, if it failed
+    pub error: Option<String>,
+    /// ids of the snapshots that exist after the command, but didn't before
+    pub created_snapshot_ids: Vec<String>,
+    /// ids of the snapshots that existed before the command, but don't after
+    pub removed_snapshot_ids: Vec<String>,
+}
+
+impl OperationsLogFile {
+    pub fn append(repo: &Repository, entry: &OperationsLogEntry) -> Result<(), String> {
+        append_to_file(repo.path(OPERATIONS_LOG_PATH), entry.serialize().as_bytes())
+    }
+}
+
+impl OperationsLogEntry {
+    fn serialize(&self) -> String {
+        let json_strings = |strings: &[String]| {
+            strings
+                .iter()
+                .map(|x| json_string(x))
+                .collect::<Vec<String>>()
+                .join(",")
+        };
+        format!(
+            "{{\"date\":{},\"command\":{},\"args\":[{}],\"result\":{},\"error\":{},\"created\":[{}],\"removed\":[{}]}}\n",
+            self.date,
+            json_string(&self.command),
+            json_strings(&self.args),
+            json_string(if self.error.is_some() { "failed" } else { "ok" }),
+            match &self.error {
+                Some(error) => json_string(error),
+                None => String::from("null"),
+            },
+            json_strings(&self.created_snapshot_ids),
+            json_strings(&self.removed_snapshot_ids)
+        )
+    }
+}
+
+/// The ids of the snapshots in the repository, from the names of their meta
+/// files, without reading them. The snapshots directory is only created by
+/// the first command that needs it, so it may not exist yet.
+pub fn get_snapshot_ids(repo: &Repository) -> Result<HashSet<String>, String> {
+    let mut snapshot_ids = HashSet::new();
+    let dir = match fs::read_dir(repo.snapshots_path()) {
+        Ok(x) => x,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(snapshot_ids),
+        Err(err) => return simplify_result(Err(err)),
+    };
+    for entry in dir {
+        let file_name = simplify_result(entry)?.file_name();
+        if let Some(id) = file_name.to_str().and_then(|x| x.strip_suffix(".meta")) {
+            snapshot_ids.insert(String::from(id));
+        }
+    }
+    Ok(snapshot_ids)
+}
+
+/// Progress of restoring a snapshot through a chain of diffs, so that an
+/// interrupted restore can continue from the last snapshot it reconstructed.
+/// Only exists while a restore is in progress.
+pub struct RestoreProgressFile {
+    /// the snapshot being restored
+    pub target_snapshot_id: String,
+    /// the last snapshot in the chain that was fully reconstructed
+    pub completed_snapshot_id: String,
+    /// the process that reconstructed it, whose pid is in the names of the
+    /// reconstructed tars. `None` for restores by older versions.
+    pub pid: Option<u32>,
+}
+
+impl RestoreProgressFile {
+    pub fn read(repo: &Repository) -> Result<Option<RestoreProgressFile>, String> {
+        let path = repo.path(RESTORE_PROGRESS_PATH);
+        if !simplify_result(fs::exists(&path))? {
+            return Ok(None);
+        }
+
+        let map = tab_separated_key_value::Config::single_value_only().read_file(&path)?;
+        let (Some(target), Some(completed)) = (
+            map.single_value.get("target"),
+            map.single_value.get("completed"),
+        ) else {
+            return Err(String::from(
+                "The restore progress file is missing required values (target, completed)",
+            ));
+        };
+
+        Ok(Some(RestoreProgressFile {
+            target_snapshot_id: target.clone(),
+            completed_snapshot_id: completed.clone(),
+            pid: match map.single_value.get("pid") {
+                Some(x) => Some(simplify_result(x.parse())?),
+                None => None,
+            },
+        }))
+    }
+
+    pub fn write(&self, repo: &Repository) -> Result<(), String> {
+        let mut single_value = HashMap::from([
+            (String::from("target"), self.target_snapshot_id.clone()),
+            (
+                String::from("completed"),
+                self.completed_snapshot_id.clone(),
+            ),
+        ]);
+        if let Some(pid) = self.pid {
+            single_value.insert(String::from("pid"), pid.to_string());
+        }
         tab_separated_key_value::Contents {
             multi_value: HashMap::new(),
-            single_value: {
-                let mut m = HashMap::new();
-                self.curr_snapshot_id
-                    .map(|s| m.insert(String::from("snapshotid"), s));
-                m.insert(String::from("branch"), self.curr_branch);
-                m
-            },
+            single_value,
         }
-        .write_file(HEAD_PATH)
+        .write_file(&repo.path(RESTORE_PROGRESS_PATH))
     }
+
+    pub fn remove(repo: &Repository) -> Result<(), String> {
+        match fs::remove_file(repo.path(RESTORE_PROGRESS_PATH)) {
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            result => simplify_result(result),
+        }
+    }
+}
+
+/// Stashes are full snapshots kept outside of the snapshot history, stored as
+/// `{STASHES_PATH}/{id}.tar.gz`.
+///
+/// Each line of the stash list is a stash, oldest first, with the fields
+/// separated by tabs: `{id}\t{date}\t{message}`.
+pub struct StashListFile {
+    pub stashes: Vec<StashEntry>,
 }
 
+#[derive(Debug, PartialEq)]
+pub struct StashEntry {
+    pub id: String,
+    pub date: i64,
+    pub message: Option<String>,
+}
+
+impl StashListFile {
+    pub fn read(repo: &Repository) -> Result<StashListFile, String> {
+        let contents = match fs::read_to_string(repo.path(STASH_LIST_PATH)) {
+            Ok(x) => x,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Ok(StashListFile {
+                    stashes: Vec::new(),
+                });
+            }
+            Err(err) => return simplify_result(Err(err)),
+        };
+
+        Ok(StashListFile {
+            stashes: contents
+                .split('\n')
+                .filter(|line| !line.is_empty())
+                .map(StashEntry::parse)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    pub fn write(&self, repo: &Repository) -> Result<(), String> {
+        simplify_result(fs::create_dir_all(repo.path(STASHES_PATH)))?;
+        let contents: String = self.stashes.iter().map(|x| x.serialize()).collect();
+        simplify_result(fs::write(repo.path(STASH_LIST_PATH), contents))
+    }
+}
+
+impl StashEntry {
+    pub fn get_payload_path(&self, repo: &Repository) -> String {
+        format!("{}/{}.tar.gz", repo.path(STASHES_PATH), self.id)
+    }
+
+    fn parse(line: &str) -> Result<StashEntry, String> {
+        let fields: Vec<&str> = line.splitn(3, '\t').collect();
+        let [id, date, message] = fields[..] else {
+            return Err(format!("Corrupted stash entry: '{}'", line));
+        };
+
+        Ok(StashEntry {
+            id: String::from(id),
+            date: simplify_result(date.parse::<i64>())?,
+            message: if message.is_empty() {
+                None
+            } else {
+                Some(String::from(message))
+            },
+        })
+    }
+
+    fn serialize(&self) -> String {
+        // the message is the last field, so only newlines need replacing
+        let message = self.message.as_deref().unwrap_or("").replace('\n', " ");
+        format!("{}\t{}\t{}\n", self.id, self.date, message)
+    }
+}
+
+/// The version of snapshot meta files that are written. Meta files without a
+/// version are version 1.
+///
+/// Meta files from newer versions of jbackup can still be read, as long as
+/// the keys this version knows about are unchanged, but they're read-only (see
+/// `SnapshotMetaFile::check_writable`), since rewriting them would drop what
+/// this version doesn't know about.
+pub const SNAPSHOT_META_VERSION: u32 = 1;
+
+/// The diff child of snapshots stored as a diff from the empty snapshot,
+/// which has no files (see the config's `diff_from_empty`). It isn't stored:
+/// `find_restore_path` ends at `SnapshotMetaFile::empty` instead, which is
+/// restored as a tar without files.
+pub const EMPTY_SNAPSHOT_ID: &str = "empty";
+
+#[derive(Clone)]
 pub struct SnapshotMetaFile {
     pub id: String,
+    /// the version of the meta file (see `SNAPSHOT_META_VERSION`)
+    pub version: u32,
     pub date: i64,
     pub message: Option<String>,
     /// if set, the full contents of the snapshot are stored in
@@ -88,17 +519,134 @@ pub struct SnapshotMetaFile {
     /// such that the snapshot (_snapshotId_) can be used to recover _dparent_
     /// by applying the delta file `{dparent}-diff-{snapshotId}` to _dparent_
     pub diff_parents: Vec<String>,
+    /// names of the transformers the snapshot's files were transformed with
+    /// (see `get_config_transformer_names`), which must be reversed with the
+    /// same transformers. `None` for snapshots created before transformers
+    /// were recorded.
+    pub transformers: Option<Vec<String>>,
+    /// how the working directory was walked to list the snapshot's files.
+    /// `None` for partial snapshots, which weren't walked from the working
+    /// directory (ex. imported from an archive, or listed with
+    /// `--stdin-file-list`) or left out files that couldn't be read
+    /// (`--keep-going`), and for snapshots created before walks were
+    /// recorded, so it isn't known which files they left out.
+    pub walk: Option<SnapshotWalk>,
+}
+
+/// The settings of the walk that listed a snapshot's files (see
+/// `walk_file_tree`), which decide which files in the working directory the
+/// snapshot would have stored. Restoring and cleaning only remove the files
+/// that aren't in the snapshot but would have been stored, so files that were
+/// left out on purpose are kept.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct SnapshotWalk {
+    /// files with paths that aren't UTF-8 were left out (`--skip-non-utf8`)
+    pub skip_non_utf8: bool,
+    /// files larger than this (in bytes) were left out (`max_file_size` with
+    /// `on_large skip`)
+    pub max_file_size: Option<u64>,
+    /// directories tagged as caches were left out (`exclude_caches`)
+    pub exclude_caches: bool,
+    /// directories containing a file with any of these names were left out
+    /// (`exclude_if_present`)
+    pub exclude_if_present: Vec<String>,
+    /// directories on other devices than the working directory were left out
+    /// (`one_file_system`)
+    pub one_file_system: bool,
+}
+
+impl SnapshotWalk {
+    /// Parses the settings written by `serialize`. Returns `None` if there's
+    /// a setting this version doesn't know, since it can't tell which files
+    /// the walk left out.
+    fn parse(settings: &[String]) -> Option<SnapshotWalk> {
+        let mut walk = SnapshotWalk::default();
+        for setting in settings.iter().filter(|x| !x.is_empty()) {
+            match setting.as_str() {
+                "skip_non_utf8" => walk.skip_non_utf8 = true,
+                "exclude_caches" => walk.exclude_caches = true,
+                "one_file_system" => walk.one_file_system = true,
+                _ => match setting.split_once('=') {
+                    Some(("max_file_size", size)) => {
+                        walk.max_file_size = Some(size.parse().ok()?);
+                    }
+                    Some(("exclude_if_present", marker)) => {
+                        walk.exclude_if_present.push(String::from(marker));
+                    }
+                    _ => return None,
+                },
+            }
+        }
+        Some(walk)
+    }
+
+    fn serialize(&self) -> Vec<String> {
+        let mut settings = Vec::new();
+        if self.skip_non_utf8 {
+            settings.push(String::from("skip_non_utf8"));
+        }
+        if let Some(max_file_size) = self.max_file_size {
+            settings.push(format!("max_file_size={}", max_file_size));
+        }
+        if self.exclude_caches {
+            settings.push(String::from("exclude_caches"));
+        }
+        for marker in &self.exclude_if_present {
+            settings.push(format!("exclude_if_present={}", marker));
+        }
+        if self.one_file_system {
+            settings.push(String::from("one_file_system"));
+        }
+        // an empty value records a walk without any settings
+        if settings.is_empty() {
+            settings.push(String::new());
+        }
+        settings
+    }
 }
 
 impl SnapshotMetaFile {
-    pub fn read(snapshot_id: &str) -> Result<SnapshotMetaFile, String> {
+    /// The empty snapshot that snapshots with the diff child
+    /// `EMPTY_SNAPSHOT_ID` are restored from.
+    pub fn empty() -> SnapshotMetaFile {
+        SnapshotMetaFile {
+            id: String::from(EMPTY_SNAPSHOT_ID),
+            version: SNAPSHOT_META_VERSION,
+            date: 0,
+            message: None,
+            full_type: SnapshotFullType::Empty,
+            children: Vec::new(),
+            parents: Vec::new(),
+            diff_children: Vec::new(),
+            diff_parents: Vec::new(),
+            transformers: None,
+            walk: None,
+        }
+    }
+
+    pub fn read(repo: &Repository, snapshot_id: &str) -> Result<SnapshotMetaFile, String> {
         let result = tab_separated_key_value::Config {
             multivalue_keys: SnapshotMetaFile::get_multivalue_keys(),
         }
-        .read_file(&(SnapshotMetaFile::get_meta_file_path(&snapshot_id)))?;
+        .read_file(&SnapshotMetaFile::get_meta_file_path(repo, snapshot_id))?;
+        SnapshotMetaFile::from_contents(snapshot_id, result)
+    }
+
+    /// Parses the contents of a meta file, as returned by `serialize`.
+    fn parse(snapshot_id: &str, data: &str) -> Result<SnapshotMetaFile, String> {
+        let result = tab_separated_key_value::Config {
+            multivalue_keys: SnapshotMetaFile::get_multivalue_keys(),
+        }
+        .read_string(data)?;
+        SnapshotMetaFile::from_contents(snapshot_id, result)
+    }
 
+    fn from_contents(
+        snapshot_id: &str,
+        result: tab_separated_key_value::Contents,
+    ) -> Result<SnapshotMetaFile, String> {
         let snapshot_date = match result.single_value.get("date") {
-            Some(s) => simplify_result(i64::from_str_radix(s, 10))?,
+            Some(s) => simplify_result(s.parse::<i64>())?,
             None => {
                 return Err(format!(
                     "Missing key 'date' in metadata of snapshot {}",
@@ -107,6 +655,19 @@ impl SnapshotMetaFile {
             }
         };
 
+        let version = match result.single_value.get("version") {
+            Some(s) => match s.parse::<u32>() {
+                Ok(x) if x > 0 => x,
+                _ => {
+                    return Err(format!(
+                        "Invalid version '{}' in metadata of snapshot {}",
+                        s, snapshot_id
+                    ));
+                }
+            },
+            None => 1,
+        };
+
         let full_type = match result.single_value.get("full") {
             Some(s) => s.parse::<SnapshotFullType>()?,
             None => SnapshotFullType::None,
@@ -118,6 +679,7 @@ impl SnapshotMetaFile {
 
         Ok(SnapshotMetaFile {
             id: String::from(snapshot_id),
+            version,
             date: snapshot_date,
             message: result.single_value.get("message").cloned(),
             full_type,
@@ -125,39 +687,109 @@ impl SnapshotMetaFile {
             parents: get_multivalue(&result, "parent"),
             diff_children: get_multivalue(&result, "dchild"),
             diff_parents: get_multivalue(&result, "dparent"),
+            // an empty value records that there are no transformers
+            transformers: result
+                .multi_value
+                .get("transformer")
+                .map(|names| names.iter().filter(|x| !x.is_empty()).cloned().collect()),
+            walk: result
+                .multi_value
+                .get("walk")
+                .and_then(|settings| SnapshotWalk::parse(settings)),
         })
     }
 
-    pub fn write(&self) -> Result<(), String> {
-        simplify_result(fs::write(
-            SnapshotMetaFile::get_meta_file_path(&self.id),
-            self.serialize()?,
-        ))
+    pub fn write(&self, repo: &Repository) -> Result<(), String> {
+        self.check_writable()?;
+        let contents = self.serialize()?;
+        let path = SnapshotMetaFile::get_meta_file_path(repo, &self.id);
+        if repo.config.gzip_meta {
+            tab_separated_key_value::write_gzip_data(&path, &contents)?;
+        } else {
+            simplify_result(fs::write(&path, &contents))?;
+        }
+        if repo.should_fsync() {
+            sync_file(&path)?;
+        }
+
+        // the index is updated after the meta file, so it's never newer than
+        // the meta files it was updated with (see `SnapshotIndexFile`)
+        if repo.config.compact_meta {
+            SnapshotIndexFile::append(repo, &self.id, &contents)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the meta file of the snapshot, and the snapshot from the index.
+    /// Fails in immutable repositories (see
+    /// `Repository::ensure_snapshots_removable`).
+    pub fn remove(&self, repo: &Repository) -> Result<(), String> {
+        self.check_writable()?;
+        repo.ensure_snapshots_removable()?;
+        simplify_result(fs::remove_file(SnapshotMetaFile::get_meta_file_path(
+            repo, &self.id,
+        )))?;
+
+        if repo.config.compact_meta {
+            SnapshotIndexFile::append(repo, &self.id, "")?;
+        }
+        Ok(())
     }
 
-    pub fn get_meta_file_path(id: &str) -> String {
-        String::from(SNAPSHOTS_PATH) + "/" + id + ".meta"
+    /// Whether the snapshot was created by a newer version of jbackup, so it
+    /// can be read, but not changed.
+    pub fn is_read_only(&self) -> bool {
+        self.version > SNAPSHOT_META_VERSION
+    }
+
+    /// Fails if the snapshot is read-only (see `is_read_only`). Commands that
+    /// change several snapshots check each one before changing any of them.
+    pub fn check_writable(&self) -> Result<(), String> {
+        if self.is_read_only() {
+            Err(format!(
+                "Snapshot {} was created by a newer version of jbackup (metadata version {}, while this version supports up to {}), so it can only be read. Upgrade jbackup to change it.",
+                self.id, self.version, SNAPSHOT_META_VERSION
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn get_meta_file_path(repo: &Repository, id: &str) -> String {
+        repo.prepend_snapshot_path(&SnapshotMetaFile::get_meta_file_name(id))
+    }
+
+    pub fn get_meta_file_name(id: &str) -> String {
+        String::from(id) + ".meta"
     }
 
     pub fn get_full_payload_filename(&self) -> Result<String, String> {
-        match &self.full_type {
-            SnapshotFullType::None => Err(String::from("A full snapshot payload is not included")),
-            _ => Ok(self.id.clone() + "-full." + &self.full_type.to_string()),
+        match self.full_type.file_extension() {
+            None => Err(String::from("A full snapshot payload is not included")),
+            Some(extension) => Ok(self.id.clone() + "-full." + extension),
         }
     }
 
     pub fn get_diff_path_from_child_snapshot(&self, snapshot_id: &str) -> String {
-        self.id.clone() + "-diff-" + &snapshot_id
+        self.id.clone() + "-diff-" + snapshot_id
     }
 
     fn get_multivalue_keys() -> HashSet<String> {
-        string_set!["child", "parent", "dchild", "dparent"]
+        string_set![
+            "child",
+            "parent",
+            "dchild",
+            "dparent",
+            "transformer",
+            "walk"
+        ]
     }
 
     fn serialize(&self) -> Result<String, String> {
         tab_separated_key_value::Contents {
             single_value: {
                 let mut m = HashMap::new();
+                m.insert(String::from("version"), self.version.to_string());
                 m.insert(String::from("date"), self.date.to_string());
 
                 self.message
@@ -176,6 +808,18 @@ impl SnapshotMetaFile {
                 m.insert(String::from("parent"), self.parents.clone());
                 m.insert(String::from("dchild"), self.diff_children.clone());
                 m.insert(String::from("dparent"), self.diff_parents.clone());
+                match &self.transformers {
+                    Some(names) if names.is_empty() => {
+                        m.insert(String::from("transformer"), vec![String::new()]);
+                    }
+                    Some(names) => {
+                        m.insert(String::from("transformer"), names.clone());
+                    }
+                    None => {}
+                }
+                if let Some(walk) = &self.walk {
+                    m.insert(String::from("walk"), walk.serialize());
+                }
                 m
             },
         }
@@ -183,20 +827,115 @@ impl SnapshotMetaFile {
     }
 }
 
-#[derive(PartialEq, Eq)]
+/// A cache of every snapshot's meta file in a single file, so commands that
+/// read the metadata of every snapshot (ex. `log`) read one file instead of
+/// one per snapshot. Only maintained when `compact_meta` is set in the config.
+/// The meta files remain the source of truth: the index is rebuilt from them
+/// whenever it's missing, unreadable, or older than a meta file.
+///
+/// Each line is a snapshot, with the fields separated by a tab:
+/// `{snapshot id}\t{meta file}`, where the meta file's backslashes and
+/// newlines are escaped. Lines are appended when meta files are written, so a
+/// later line for a snapshot replaces earlier ones, and an empty meta file
+/// records that the snapshot was removed.
+pub struct SnapshotIndexFile;
+
+impl SnapshotIndexFile {
+    pub fn get_path(repo: &Repository) -> String {
+        repo.prepend_snapshot_path("index")
+    }
+
+    /// Reads the metadata of the snapshots in the index, or `None` if the
+    /// index doesn't exist.
+    fn read(repo: &Repository) -> Result<Option<HashMap<String, SnapshotMetaFile>>, String> {
+        let contents = match fs::read_to_string(SnapshotIndexFile::get_path(repo)) {
+            Ok(x) => x,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return simplify_result(Err(err)),
+        };
+        SnapshotIndexFile::parse(&contents).map(Some)
+    }
+
+    fn parse(contents: &str) -> Result<HashMap<String, SnapshotMetaFile>, String> {
+        let mut snapshots = HashMap::new();
+        for line in contents.split('\n').filter(|line| !line.is_empty()) {
+            let Some((id, meta)) = line.split_once('\t') else {
+                return Err(format!("Corrupted snapshot index entry: '{}'", line));
+            };
+            if meta.is_empty() {
+                snapshots.remove(id);
+                continue;
+            }
+
+            let meta = tab_separated_key_value::unescape_string(meta)?;
+            snapshots.insert(String::from(id), SnapshotMetaFile::parse(id, &meta)?);
+        }
+        Ok(snapshots)
+    }
+
+    fn serialize_entry(id: &str, meta_contents: &str) -> String {
+        format!(
+            "{}\t{}\n",
+            id,
+            tab_separated_key_value::escape_string(meta_contents)
+        )
+    }
+
+    /// Records the new contents of a snapshot's meta file, or that the
+    /// snapshot was removed if `meta_contents` is empty.
+    fn append(repo: &Repository, id: &str, meta_contents: &str) -> Result<(), String> {
+        let mut file = simplify_result(
+            fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(SnapshotIndexFile::get_path(repo)),
+        )?;
+        simplify_result(
+            file.write_all(SnapshotIndexFile::serialize_entry(id, meta_contents).as_bytes()),
+        )
+    }
+
+    /// Replaces the index with one entry per snapshot.
+    fn write(repo: &Repository, snapshots: &[SnapshotMetaFile]) -> Result<(), String> {
+        let mut contents = String::new();
+        for snapshot in snapshots {
+            contents.push_str(&SnapshotIndexFile::serialize_entry(
+                &snapshot.id,
+                &snapshot.serialize()?,
+            ));
+        }
+        simplify_result(fs::write(SnapshotIndexFile::get_path(repo), contents))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub enum SnapshotFullType {
     None,
     Tar,
     TarGz,
+    /// the empty snapshot (see `SnapshotMetaFile::empty`), which has no
+    /// files, so it's restored without a payload. Never written.
+    Empty,
 }
 
-impl ToString for SnapshotFullType {
-    fn to_string(&self) -> String {
-        String::from(match self {
-            SnapshotFullType::None => "",
-            SnapshotFullType::Tar => "tar",
-            SnapshotFullType::TarGz => "tar.gz",
-        })
+impl SnapshotFullType {
+    /// The extension of the full payload's file, or `None` if there is no
+    /// full payload.
+    pub fn file_extension(&self) -> Option<&'static str> {
+        match self {
+            SnapshotFullType::None | SnapshotFullType::Empty => None,
+            SnapshotFullType::Tar => Some("tar"),
+            SnapshotFullType::TarGz => Some("tar.gz"),
+        }
+    }
+}
+
+/// Formats the type as it's written in snapshot meta files. `None` is
+/// formatted as an empty string, which is what a meta file without a `full`
+/// key is read as.
+impl Display for SnapshotFullType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.file_extension().unwrap_or(""))
     }
 }
 
@@ -213,14 +952,207 @@ impl FromStr for SnapshotFullType {
     }
 }
 
+/// Resolves a reference to a snapshot id. A reference is one of (in order of
+/// precedence):
+///
+/// - `HEAD`, the snapshot currently checked out
+/// - a branch name
+/// - a tag name
+/// - a snapshot id
+///
+/// Any of the above may be followed by `~N` to refer to the `N`th ancestor
+/// (`~` alone is the same as `~1`). When a snapshot has multiple parents, only
+/// the first parent is followed, like git's first-parent convention.
+pub fn resolve_ref(repo: &Repository, reference: &str) -> Result<String, String> {
+    // following parents reads a meta file per generation, so with an index,
+    // the parents are read from it instead
+    if reference.contains('~') && repo.config.compact_meta {
+        let snapshots: HashMap<String, SnapshotMetaFile> = get_all_snapshot_meta_files(repo)?
+            .into_iter()
+            .map(|x| (x.id.clone(), x))
+            .collect();
+        return resolve_ref_with(
+            reference,
+            &HeadFile::read(repo)?,
+            &BranchesFile::read(repo)?,
+            &TagsFile::read(repo)?,
+            |id| snapshots.contains_key(id),
+            |id| match snapshots.get(id) {
+                Some(snapshot) => Ok(snapshot.parents.first().cloned()),
+                None => Err(format!("Snapshot {} doesn't exist", id)),
+            },
+        );
+    }
+
+    resolve_ref_with(
+        reference,
+        &HeadFile::read(repo)?,
+        &BranchesFile::read(repo)?,
+        &TagsFile::read(repo)?,
+        |id| fs::exists(SnapshotMetaFile::get_meta_file_path(repo, id)).unwrap_or(false),
+        |id| Ok(SnapshotMetaFile::read(repo, id)?.parents.first().cloned()),
+    )
+}
+
+fn resolve_ref_with(
+    reference: &str,
+    head_file: &HeadFile,
+    branches_file: &BranchesFile,
+    tags_file: &TagsFile,
+    snapshot_exists: impl Fn(&str) -> bool,
+    first_parent: impl Fn(&str) -> Result<Option<String>, String>,
+) -> Result<String, String> {
+    let (base_reference, num_generations) = match reference.split_once('~') {
+        None => (reference, 0),
+        Some((base, "")) => (base, 1),
+        Some((base, n)) => match n.parse::<usize>() {
+            Ok(n) => (base, n),
+            Err(_) => {
+                return Err(format!(
+                    "Invalid reference '{}': expected a number after '~'",
+                    reference
+                ));
+            }
+        },
+    };
+
+    let mut snapshot_id = resolve_base_ref(
+        base_reference,
+        head_file,
+        branches_file,
+        tags_file,
+        snapshot_exists,
+    )?;
+
+    for generation in 0..num_generations {
+        snapshot_id = match first_parent(&snapshot_id)? {
+            Some(parent) => parent,
+            None => {
+                return Err(format!(
+                    "Cannot resolve '{}': {} is the first snapshot, which is only {} generation(s) before {}",
+                    reference, snapshot_id, generation, base_reference
+                ));
+            }
+        };
+    }
+
+    Ok(snapshot_id)
+}
+
+fn resolve_base_ref(
+    reference: &str,
+    head_file: &HeadFile,
+    branches_file: &BranchesFile,
+    tags_file: &TagsFile,
+    snapshot_exists: impl Fn(&str) -> bool,
+) -> Result<String, String> {
+    if reference == "HEAD" {
+        return match &head_file.curr_snapshot_id {
+            Some(id) => Ok(id.clone()),
+            None => Err(String::from("HEAD does not point to a snapshot yet")),
+        };
+    }
+
+    if let Some(id) = branches_file.branches.get(reference) {
+        return Ok(id.clone());
+    }
+
+    if let Some(id) = tags_file.tags.get(reference) {
+        return Ok(id.clone());
+    }
+
+    // snapshot ids are used in file names, so they can't contain '/'
+    if !reference.is_empty() && !reference.contains('/') && snapshot_exists(reference) {
+        return Ok(String::from(reference));
+    }
+
+    Err(format!(
+        "'{}' is not a branch, tag or snapshot id",
+        reference
+    ))
+}
+
+/// Resolves a range of snapshots, `<from>..<to>`, where both ends are
+/// references. `<to>` defaults to `HEAD`, and leaving out `<from>` (returned
+/// as `None`) starts the range at the first snapshot.
+pub fn resolve_range(repo: &Repository, range: &str) -> Result<(Option<String>, String), String> {
+    let Some((from_reference, to_reference)) = range.split_once("..") else {
+        return Err(format!(
+            "Invalid range: '{}' (expected <from>..<to>)",
+            range
+        ));
+    };
+
+    let from_id = match from_reference {
+        "" => None,
+        x => Some(resolve_ref(repo, x)?),
+    };
+    let to_id = resolve_ref(
+        repo,
+        if to_reference.is_empty() {
+            "HEAD"
+        } else {
+            to_reference
+        },
+    )?;
+    Ok((from_id, to_id))
+}
+
+/// Finds the snapshots on a path from `from_id` to `to_id` through their
+/// parents, including both ends. Without `from_id`, finds all ancestors of
+/// `to_id`.
+pub fn find_snapshots_between(
+    snapshots: &HashMap<String, SnapshotMetaFile>,
+    from_id: Option<&str>,
+    to_id: &str,
+) -> Result<HashSet<String>, String> {
+    let mut ancestors = HashSet::new();
+    let mut to_visit = vec![to_id];
+    while let Some(id) = to_visit.pop() {
+        if !ancestors.insert(String::from(id)) {
+            continue;
+        }
+        match snapshots.get(id) {
+            Some(snapshot) => to_visit.extend(snapshot.parents.iter().map(|x| x.as_str())),
+            None => return Err(format!("Snapshot {} doesn't exist", id)),
+        }
+    }
+
+    let Some(from_id) = from_id else {
+        return Ok(ancestors);
+    };
+    if !ancestors.contains(from_id) {
+        return Err(format!(
+            "Snapshot {} is not an ancestor of snapshot {}",
+            from_id, to_id
+        ));
+    }
+
+    // the ancestors of `to_id` that are also descendants of `from_id`
+    let mut between = HashSet::new();
+    let mut to_visit = vec![from_id];
+    while let Some(id) = to_visit.pop() {
+        if !ancestors.contains(id) || !between.insert(String::from(id)) {
+            continue;
+        }
+        to_visit.extend(snapshots[id].children.iter().map(|x| x.as_str()));
+    }
+    Ok(between)
+}
+
 /// Retrieves all snapshot metadata files in the current repository.
 /// This function parses all files and returns the files in arbitrary order.
-pub fn get_all_snapshot_meta_files() -> Result<Vec<SnapshotMetaFile>, String> {
-    ensure_jbackup_snapshots_dir_exists()?;
+///
+/// With `compact_meta`, the metadata is read from the index instead, unless
+/// it's out of date, in which case the index is rebuilt from the meta files.
+pub fn get_all_snapshot_meta_files(repo: &Repository) -> Result<Vec<SnapshotMetaFile>, String> {
+    ensure_jbackup_snapshots_dir_exists(repo)?;
+    let compact_meta = repo.config.compact_meta;
 
     let mut snapshot_ids = Vec::new();
+    let mut newest_meta_modified = None;
 
-    let dir = simplify_result(fs::read_dir(SNAPSHOTS_PATH))?;
+    let dir = simplify_result(fs::read_dir(repo.snapshots_path()))?;
 
     for item in dir {
         match item {
@@ -229,75 +1161,557 @@ pub fn get_all_snapshot_meta_files() -> Result<Vec<SnapshotMetaFile>, String> {
                 Err(_) => {}
                 Ok(file_name) => match file_name.strip_suffix(".meta") {
                     None => {}
-                    Some(x) => snapshot_ids.push(String::from(x)),
+                    Some(x) => {
+                        snapshot_ids.push(String::from(x));
+                        if compact_meta {
+                            let modified = simplify_result(
+                                entry.metadata().and_then(|metadata| metadata.modified()),
+                            )?;
+                            newest_meta_modified = newest_meta_modified.max(Some(modified));
+                        }
+                    }
                 },
             },
         }
     }
 
+    if compact_meta
+        && let Some(snapshots) = read_current_index(repo, &snapshot_ids, newest_meta_modified)
+    {
+        return Ok(snapshots);
+    }
+
     let mut snapshots = Vec::new();
 
     for item in snapshot_ids {
-        let meta = SnapshotMetaFile::read(&item)?;
+        let meta = SnapshotMetaFile::read(repo, &item)?;
         snapshots.push(meta);
     }
 
+    if compact_meta {
+        SnapshotIndexFile::write(repo, &snapshots)?;
+    }
+
     Ok(snapshots)
 }
 
+/// Reads the snapshots in the index, or `None` if the index is out of date:
+/// if it doesn't have exactly the snapshots with meta files, or a meta file
+/// was modified after it.
+fn read_current_index(
+    repo: &Repository,
+    snapshot_ids: &[String],
+    newest_meta_modified: Option<SystemTime>,
+) -> Option<Vec<SnapshotMetaFile>> {
+    let index_modified = fs::metadata(SnapshotIndexFile::get_path(repo))
+        .and_then(|metadata| metadata.modified())
+        .ok()?;
+    if newest_meta_modified.is_some_and(|x| x > index_modified) {
+        return None;
+    }
+
+    let mut snapshots = match SnapshotIndexFile::read(repo) {
+        Ok(x) => x?,
+        Err(err) => {
+            eprintln!(
+                "Warn: Rebuilding the snapshot index, since it couldn't be read: {}",
+                err
+            );
+            return None;
+        }
+    };
+    if snapshots.len() != snapshot_ids.len() {
+        return None;
+    }
+    snapshot_ids.iter().map(|id| snapshots.remove(id)).collect()
+}
+
 pub struct ConfigFile {
     pub transformers: Vec<String>,
+    /// files larger than this (in bytes) are handled by `on_large_file`
+    /// during snapshots
+    pub max_file_size: Option<u64>,
+    pub on_large_file: OnLargeFile,
+    /// absolute path to store snapshots in, instead of `DEFAULT_SNAPSHOTS_PATH`
+    pub snapshots_dir: Option<String>,
+    /// header format of the entries in snapshot tars
+    pub tar_format: TarFormat,
+    /// how much the delta lists between snapshots are compressed
+    pub compression: CompressionLevel,
+    /// absolute path to write scratch files to, instead of `JBACKUP_PATH`
+    pub tmp_dir: Option<String>,
+    /// if set, files at least this large (in bytes) are split into chunks
+    /// stored in the chunk store (see `ChunkingTransformer`)
+    pub chunking: Option<u64>,
+    /// if set, files at least this large (in bytes) that no transformer
+    /// changes are mapped into memory when snapshotting, instead of read
+    pub mmap: Option<u64>,
+    pub diff_direction: DiffDirection,
+    /// store the first snapshot as a diff from the empty snapshot too (see
+    /// `EMPTY_SNAPSHOT_ID`), so every snapshot is stored as a diff. Only for
+    /// forward diffs.
+    pub diff_from_empty: bool,
+    /// bare repositories only store snapshots, and have no working directory
+    /// to snapshot or restore to
+    pub bare: bool,
+    /// keep the metadata of every snapshot in one index file (see
+    /// `SnapshotIndexFile`), so it can be read at once
+    pub compact_meta: bool,
+    /// skip directories tagged as caches with a `CACHEDIR.TAG` file when
+    /// snapshotting, and leave them alone when restoring
+    pub exclude_caches: bool,
+    /// names of marker files: directories containing one are skipped when
+    /// snapshotting, and left alone when restoring
+    pub exclude_if_present: Vec<String>,
+    /// if set, a snapshot's parent keeps its full payload when replacing it
+    /// with a diff would make a chain of diffs longer than this (see
+    /// `chain_depth`), so restoring never applies more diffs
+    pub max_diff_chain: Option<usize>,
+    /// flush the payloads, diffs and metadata of new snapshots to the disk
+    /// before moving on, so a power loss can't lose a snapshot that was
+    /// reported as created (see `Repository::should_fsync`)
+    pub fsync: bool,
+    /// skip directories on other filesystems than the working directory (ex.
+    /// mounted drives) when snapshotting, and leave them alone when restoring
+    pub one_file_system: bool,
+    /// gzip the head, branches, tags and snapshot meta files when writing
+    /// them. Both gzipped and plaintext files are read either way.
+    pub gzip_meta: bool,
+    /// snapshots can't be removed or replaced once they're written, unless
+    /// `--force-immutable` is given (see
+    /// `Repository::ensure_snapshots_removable`)
+    pub immutable: bool,
+    /// how the ids of new snapshots are made
+    pub snapshot_id_format: SnapshotIdFormat,
+    /// the number in the id of the last snapshot created with the `seq` id
+    /// format (see `next_snapshot_seq`)
+    pub snapshot_id_seq: u64,
+    /// names that differ only by case are the same file, like on
+    /// case-insensitive filesystems, which changes the order paths are in in
+    /// snapshot tars (see `PathOrder`)
+    pub ignore_case: bool,
+    /// if set, how many bytes archives and delta lists are buffered in,
+    /// instead of `DEFAULT_IO_BUFFER_SIZE` (see `Repository::io_buffer_size`)
+    pub io_buffer_size: Option<u64>,
+}
+
+/// The config of a repository initialized without any options, which is
+/// also what a config without a key is read as.
+impl Default for ConfigFile {
+    fn default() -> Self {
+        ConfigFile {
+            transformers: Vec::new(),
+            max_file_size: None,
+            on_large_file: OnLargeFile::Skip,
+            snapshots_dir: None,
+            tar_format: TarFormat::Gnu,
+            compression: CompressionLevel::Default,
+            tmp_dir: None,
+            chunking: None,
+            mmap: None,
+            diff_direction: DiffDirection::Reverse,
+            diff_from_empty: false,
+            bare: false,
+            compact_meta: false,
+            exclude_caches: false,
+            exclude_if_present: Vec::new(),
+            max_diff_chain: None,
+            fsync: false,
+            one_file_system: false,
+            gzip_meta: false,
+            immutable: false,
+            snapshot_id_format: SnapshotIdFormat::TimestampHash,
+            snapshot_id_seq: 0,
+            ignore_case: false,
+            io_buffer_size: None,
+        }
+    }
 }
 
 impl ConfigFile {
-    pub fn read() -> Result<ConfigFile, String> {
+    /// Reads the config file at `path` (see `Repository::config_path`).
+    pub fn read(path: &str) -> Result<ConfigFile, String> {
         let contents = tab_separated_key_value::Config {
-            multivalue_keys: string_set!["transformer"],
+            multivalue_keys: string_set!["transformer", "exclude_if_present"],
         }
-        .read_file(CONFIG_PATH)?;
+        .read_file(path)?;
         Ok(ConfigFile {
             transformers: match contents.multi_value.get("transformer") {
                 Some(x) => x.clone(),
                 None => Vec::new(),
             },
+            max_file_size: match contents.single_value.get("max_file_size") {
+                Some(x) => Some(parse_size(x)?),
+                None => None,
+            },
+            on_large_file: match contents.single_value.get("on_large") {
+                Some(x) => x.parse()?,
+                None => OnLargeFile::Skip,
+            },
+            snapshots_dir: contents.single_value.get("snapshots_dir").cloned(),
+            tar_format: match contents.single_value.get("tar_format") {
+                Some(x) => x.parse()?,
+                None => TarFormat::Gnu,
+            },
+            compression: match contents.single_value.get("compression") {
+                Some(x) => x.parse()?,
+                None => CompressionLevel::Default,
+            },
+            tmp_dir: contents.single_value.get("tmp_dir").cloned(),
+            chunking: match contents.single_value.get("chunking") {
+                Some(x) => Some(parse_size(x)?),
+                None => None,
+            },
+            mmap: match contents.single_value.get("mmap") {
+                Some(x) => Some(parse_size(x)?),
+                None => None,
+            },
+            diff_direction: match contents.single_value.get("diff_direction") {
+                Some(x) => x.parse()?,
+                None => DiffDirection::Reverse,
+            },
+            bare: contents
+                .single_value
+                .get("bare")
+                .is_some_and(|x| x == "true"),
+            compact_meta: contents
+                .single_value
+                .get("compact_meta")
+                .is_some_and(|x| x == "true"),
+            exclude_caches: contents
+                .single_value
+                .get("exclude_caches")
+                .is_some_and(|x| x == "true"),
+            max_diff_chain: match contents.single_value.get("max_diff_chain") {
+                Some(x) => match x.parse() {
+                    Ok(x) => Some(x),
+                    Err(_) => {
+                        return Err(format!("Invalid max_diff_chain in the config: '{}'", x));
+                    }
+                },
+                None => None,
+            },
+            fsync: contents
+                .single_value
+                .get("fsync")
+                .is_some_and(|x| x == "true"),
+            one_file_system: contents
+                .single_value
+                .get("one_file_system")
+                .is_some_and(|x| x == "true"),
+            exclude_if_present: contents
+                .multi_value
+                .get("exclude_if_present")
+                .cloned()
+                .unwrap_or_default(),
+            gzip_meta: contents
+                .single_value
+                .get("gzip_meta")
+                .is_some_and(|x| x == "true"),
+            immutable: contents
+                .single_value
+                .get("immutable")
+                .is_some_and(|x| x == "true"),
+            snapshot_id_format: match contents.single_value.get("snapshot_id_format") {
+                Some(x) => x.parse()?,
+                None => SnapshotIdFormat::TimestampHash,
+            },
+            snapshot_id_seq: match contents.single_value.get("snapshot_id_seq") {
+                Some(x) => match x.parse() {
+                    Ok(x) => x,
+                    Err(_) => {
+                        return Err(format!("Invalid snapshot_id_seq in the config: '{}'", x));
+                    }
+                },
+                None => 0,
+            },
+            ignore_case: contents
+                .single_value
+                .get("ignore_case")
+                .is_some_and(|x| x == "true"),
+            diff_from_empty: contents
+                .single_value
+                .get("diff_from_empty")
+                .is_some_and(|x| x == "true"),
+            io_buffer_size: match contents.single_value.get("io_buffer_size") {
+                Some(x) => Some(parse_size(x)?),
+                None => None,
+            },
         })
     }
 
-    pub fn write(self) -> Result<(), String> {
+    /// Increments the config's `snapshot_id_seq`, returning the new number,
+    /// for the id of a snapshot in the `seq` format. The number is never
+    /// reused, even if the snapshot isn't created. The config is locked while
+    /// it's incremented, so snapshots made at the same time never get the
+    /// same number.
+    pub fn next_snapshot_seq(repo: &Repository) -> Result<u64, String> {
+        let _lock = LockFile::acquire(format!("{}.lock", repo.config_path()))?;
+        let mut config = ConfigFile::read(&repo.config_path())?;
+        config.snapshot_id_seq += 1;
+        let seq = config.snapshot_id_seq;
+        config.write(&repo.config_path())?;
+        Ok(seq)
+    }
+
+    /// Fails if the repository is bare, for subcommands that read or write
+    /// the working directory.
+    pub fn ensure_not_bare(&self) -> Result<(), String> {
+        if self.bare {
+            Err(String::from(
+                "This is a bare repository, so it has no working directory",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Writes the config file at `path` (see `Repository::config_path`).
+    pub fn write(&self, path: &str) -> Result<(), String> {
+        let tmp_config_path = format!("{}.tmp", path);
         tab_separated_key_value::Contents {
             multi_value: {
                 let mut m = HashMap::new();
-                m.insert(String::from("transformer"), self.transformers);
+                m.insert(String::from("transformer"), self.transformers.clone());
+                m.insert(
+                    String::from("exclude_if_present"),
+                    self.exclude_if_present.clone(),
+                );
                 m
             },
-            single_value: HashMap::new(),
+            single_value: {
+                let mut m = HashMap::new();
+                if let Some(max_file_size) = self.max_file_size {
+                    m.insert(String::from("max_file_size"), max_file_size.to_string());
+                }
+                // written even without `max_file_size`, so it's used if
+                // `snapshot --max-file-size` is given later
+                m.insert(String::from("on_large"), self.on_large_file.to_string());
+                if let Some(snapshots_dir) = &self.snapshots_dir {
+                    m.insert(String::from("snapshots_dir"), snapshots_dir.clone());
+                }
+                m.insert(String::from("tar_format"), self.tar_format.to_string());
+                m.insert(String::from("compression"), self.compression.to_string());
+                if let Some(tmp_dir) = &self.tmp_dir {
+                    m.insert(String::from("tmp_dir"), tmp_dir.clone());
+                }
+                if let Some(chunking) = self.chunking {
+                    m.insert(String::from("chunking"), chunking.to_string());
+                }
+                if let Some(mmap) = self.mmap {
+                    m.insert(String::from("mmap"), mmap.to_string());
+                }
+                m.insert(
+                    String::from("diff_direction"),
+                    self.diff_direction.to_string(),
+                );
+                if self.bare {
+                    m.insert(String::from("bare"), String::from("true"));
+                }
+                if self.compact_meta {
+                    m.insert(String::from("compact_meta"), String::from("true"));
+                }
+                if self.exclude_caches {
+                    m.insert(String::from("exclude_caches"), String::from("true"));
+                }
+                if let Some(max_diff_chain) = self.max_diff_chain {
+                    m.insert(String::from("max_diff_chain"), max_diff_chain.to_string());
+                }
+                if self.fsync {
+                    m.insert(String::from("fsync"), String::from("true"));
+                }
+                if self.one_file_system {
+                    m.insert(String::from("one_file_system"), String::from("true"));
+                }
+                if self.gzip_meta {
+                    m.insert(String::from("gzip_meta"), String::from("true"));
+                }
+                if self.immutable {
+                    m.insert(String::from("immutable"), String::from("true"));
+                }
+                if self.snapshot_id_format != SnapshotIdFormat::TimestampHash {
+                    m.insert(
+                        String::from("snapshot_id_format"),
+                        self.snapshot_id_format.to_string(),
+                    );
+                }
+                if self.ignore_case {
+                    m.insert(String::from("ignore_case"), String::from("true"));
+                }
+                if self.diff_from_empty {
+                    m.insert(String::from("diff_from_empty"), String::from("true"));
+                }
+                if let Some(io_buffer_size) = self.io_buffer_size {
+                    m.insert(String::from("io_buffer_size"), io_buffer_size.to_string());
+                }
+                if self.snapshot_id_seq > 0 {
+                    m.insert(
+                        String::from("snapshot_id_seq"),
+                        self.snapshot_id_seq.to_string(),
+                    );
+                }
+                m
+            },
+        }
+        .write_file(&tmp_config_path)?;
+
+        // replaced at once, since the sequence number in it changes with
+        // every snapshot, so an interrupted write never loses the config
+        simplify_result(fs::rename(tmp_config_path, path))
+    }
+}
+
+/// What to do with a file larger than the configured maximum file size when
+/// creating a snapshot.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OnLargeFile {
+    /// leave the file out of the snapshot, with a warning
+    Skip,
+    /// fail the snapshot
+    Abort,
+}
+
+impl Display for OnLargeFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OnLargeFile::Skip => "skip",
+            OnLargeFile::Abort => "abort",
+        })
+    }
+}
+
+impl FromStr for OnLargeFile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(OnLargeFile::Skip),
+            "abort" => Ok(OnLargeFile::Abort),
+            _ => Err(format!(
+                "Invalid large file behavior: '{}' (expected 'skip' or 'abort')",
+                s
+            )),
         }
-        .write_file(CONFIG_PATH)
     }
 }
 
-/// Checks if .jbackup is in the current directory, then checks
+/// Which way snapshots are diffed against their parents.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DiffDirection {
+    /// the newest snapshot has the full payload, and older snapshots are
+    /// stored as diffs from their child, so restoring recent snapshots is
+    /// fast
+    Reverse,
+    /// the first snapshot has the full payload, and newer snapshots are
+    /// stored as diffs from their parent, so restoring old snapshots is fast.
+    /// The head also keeps its full payload, so the next snapshot can be
+    /// diffed against it.
+    Forward,
+}
+
+impl Display for DiffDirection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DiffDirection::Reverse => "reverse",
+            DiffDirection::Forward => "forward",
+        })
+    }
+}
+
+impl FromStr for DiffDirection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reverse" => Ok(DiffDirection::Reverse),
+            "forward" => Ok(DiffDirection::Forward),
+            _ => Err(format!(
+                "Invalid diff direction: '{}' (expected 'reverse' or 'forward')",
+                s
+            )),
+        }
+    }
+}
+
+/// How the ids of new snapshots are made. Existing snapshots keep their ids,
+/// so the format can be changed at any time.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SnapshotIdFormat {
+    /// `<unix timestamp>-<md5 of the full payload>`, which lets a snapshot
+    /// with no changes be detected without reading the head's payload, and
+    /// `verify --deep` check payloads against their ids
+    TimestampHash,
+    /// a ULID: a millisecond timestamp and random bits, in 26 characters
+    /// that sort by time
+    Ulid,
+    /// a zero-padded number, counting up from 1 (see
+    /// `ConfigFile::next_snapshot_seq`)
+    Seq,
+}
+
+impl Display for SnapshotIdFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SnapshotIdFormat::TimestampHash => "timestamp-hash",
+            SnapshotIdFormat::Ulid => "ulid",
+            SnapshotIdFormat::Seq => "seq",
+        })
+    }
+}
+
+impl FromStr for SnapshotIdFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "timestamp-hash" => Ok(SnapshotIdFormat::TimestampHash),
+            "ulid" => Ok(SnapshotIdFormat::Ulid),
+            "seq" => Ok(SnapshotIdFormat::Seq),
+            _ => Err(format!(
+                "Invalid snapshot id format: '{}' (expected 'timestamp-hash', 'ulid' or 'seq')",
+                s
+            )),
+        }
+    }
+}
+
+/// The md5 of the snapshot's full payload, if its id is in the
+/// `timestamp-hash` format (see `SnapshotIdFormat`).
+pub fn get_payload_md5_from_id(id: &str) -> Option<&str> {
+    let (timestamp, md5) = id.split_once('-')?;
+    if timestamp.parse::<i64>().is_ok()
+        && md5.len() == 32
+        && md5.bytes().all(|x| x.is_ascii_hexdigit())
+    {
+        Some(md5)
+    } else {
+        None
+    }
+}
+
+/// Checks if .jbackup is in the repository's root, then checks
 /// if the snapshot directory exists.
 ///
-/// If .jbackup is not in the current directory, an error is returned.
+/// If .jbackup is not in the root, an error is returned.
 ///
 /// If snapshot directory is created if it doesn't exist.
 ///
 /// Otherwise, the function returns Ok
-pub fn ensure_jbackup_snapshots_dir_exists() -> Result<(), String> {
-    if !simplify_result(is_jbackup_in_working_dir())? {
+pub fn ensure_jbackup_snapshots_dir_exists(repo: &Repository) -> Result<(), String> {
+    if !simplify_result(is_jbackup_in_working_dir(repo))? {
         return Err(String::from(
             "Error: a valid jbackup was not found in current working directory. (To make a new backup for this directory, do 'jbackup init')",
         ));
     }
 
-    ensure_snapshots_directory_exists()?;
+    ensure_snapshots_directory_exists(repo)?;
+    simplify_result(fs::create_dir_all(repo.tmp_path()))?;
 
     Ok(())
 }
 
-fn is_jbackup_in_working_dir() -> io::Result<bool> {
-    match fs::read_dir(JBACKUP_PATH) {
+fn is_jbackup_in_working_dir(repo: &Repository) -> io::Result<bool> {
+    match fs::read_dir(repo.path(JBACKUP_PATH)) {
         Err(err) => match err.kind() {
             ErrorKind::NotFound => Ok(false),
             ErrorKind::NotADirectory => Ok(false),
@@ -311,15 +1725,16 @@ fn is_jbackup_in_working_dir() -> io::Result<bool> {
             for item in result {
                 match item.ok() {
                     None => {}
-                    Some(entry) => match entry.file_name().into_string() {
-                        Ok(s) => match s.as_str() {
-                            "branches" => found_branches = true,
-                            "head" => found_head = true,
-                            "config" => found_config = true,
-                            _ => {}
-                        },
-                        Err(_) => {}
-                    },
+                    Some(entry) => {
+                        if let Ok(s) = entry.file_name().into_string() {
+                            match s.as_str() {
+                                "branches" => found_branches = true,
+                                "head" => found_head = true,
+                                "config" => found_config = true,
+                                _ => {}
+                            }
+                        }
+                    }
                 }
             }
 
@@ -336,15 +1751,404 @@ fn is_jbackup_in_working_dir() -> io::Result<bool> {
 }
 
 /// Checks if "./.jbackup/snapshots" exists, otherwise, creates the directory
-fn ensure_snapshots_directory_exists() -> Result<(), String> {
-    match fs::read_dir(SNAPSHOTS_PATH) {
+fn ensure_snapshots_directory_exists(repo: &Repository) -> Result<(), String> {
+    let snapshots_path = repo.snapshots_path();
+    match fs::read_dir(&snapshots_path) {
         Err(err) => match err.kind() {
-            ErrorKind::NotFound => simplify_result(fs::create_dir(SNAPSHOTS_PATH)),
+            ErrorKind::NotFound => simplify_result(fs::create_dir(&snapshots_path)),
             ErrorKind::NotADirectory => {
-                Err(format!("Expected {} to be a directory", SNAPSHOTS_PATH))
+                Err(format!("Expected {} to be a directory", snapshots_path))
             }
             _ => simplify_result(Err(err)),
         },
         Ok(_) => Ok(()),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::{HashMap, HashSet},
+        env, fs, process, thread,
+    };
+
+    use super::{
+        BranchesFile, ConfigFile, HeadFile, OperationsLogEntry, ReflogEntry, SNAPSHOT_META_VERSION,
+        SnapshotFullType, SnapshotIndexFile, SnapshotMetaFile, SnapshotWalk, StashEntry, TagsFile,
+        find_snapshots_between, get_payload_md5_from_id, resolve_ref_with,
+    };
+    use crate::{JBACKUP_PATH, repository::Repository};
+
+    fn resolve(reference: &str) -> Result<String, String> {
+        resolve_ref_with(
+            reference,
+            &HeadFile {
+                curr_snapshot_id: Some(String::from("3-c")),
+                curr_branch: String::from("main"),
+            },
+            &BranchesFile {
+                branches: HashMap::from([
+                    (String::from("main"), String::from("3-c")),
+                    (String::from("both"), String::from("2-b")),
+                ]),
+            },
+            &TagsFile {
+                tags: HashMap::from([
+                    (String::from("v1"), String::from("1-a")),
+                    (String::from("both"), String::from("1-a")),
+                ]),
+            },
+            |id| ["1-a", "2-b", "3-c"].contains(&id),
+            |id| {
+                Ok(match id {
+                    "3-c" => Some(String::from("2-b")),
+                    "2-b" => Some(String::from("1-a")),
+                    _ => None,
+                })
+            },
+        )
+    }
+
+    #[test]
+    pub fn resolves_refs() {
+        assert_eq!(resolve("HEAD"), Ok(String::from("3-c")));
+        assert_eq!(resolve("main"), Ok(String::from("3-c")));
+        assert_eq!(resolve("v1"), Ok(String::from("1-a")));
+        assert_eq!(resolve("2-b"), Ok(String::from("2-b")));
+        assert!(resolve("4-d").is_err());
+        assert!(resolve("").is_err());
+    }
+
+    #[test]
+    pub fn resolves_ancestor_refs() {
+        assert_eq!(resolve("HEAD~0"), Ok(String::from("3-c")));
+        assert_eq!(resolve("HEAD~"), Ok(String::from("2-b")));
+        assert_eq!(resolve("HEAD~1"), Ok(String::from("2-b")));
+        assert_eq!(resolve("main~2"), Ok(String::from("1-a")));
+        assert_eq!(resolve("2-b~1"), Ok(String::from("1-a")));
+        assert!(resolve("HEAD~3").is_err());
+        assert!(resolve("HEAD~x").is_err());
+        assert!(resolve("v1~").is_err());
+    }
+
+    /// 1 <- 2 <- 4 <- 5, and 1 <- 3 <- 4 (4 has two parents)
+    fn snapshot_graph() -> HashMap<String, SnapshotMetaFile> {
+        let edges = [("1", "2"), ("2", "4"), ("1", "3"), ("3", "4"), ("4", "5")];
+        ["1", "2", "3", "4", "5"]
+            .into_iter()
+            .map(|id| {
+                let related = |is_parent: bool| {
+                    edges
+                        .iter()
+                        .filter(|(parent, child)| *if is_parent { child } else { parent } == id)
+                        .map(|(parent, child)| {
+                            String::from(if is_parent { *parent } else { *child })
+                        })
+                        .collect()
+                };
+                let snapshot = SnapshotMetaFile {
+                    id: String::from(id),
+                    version: SNAPSHOT_META_VERSION,
+                    date: 0,
+                    message: None,
+                    full_type: SnapshotFullType::None,
+                    children: related(false),
+                    parents: related(true),
+                    diff_children: Vec::new(),
+                    diff_parents: Vec::new(),
+                    transformers: None,
+                    walk: None,
+                };
+                (String::from(id), snapshot)
+            })
+            .collect()
+    }
+
+    #[test]
+    pub fn finds_snapshots_between() {
+        let snapshots = snapshot_graph();
+        let between = |from: Option<&str>, to: &str| {
+            let mut ids: Vec<String> = find_snapshots_between(&snapshots, from, to)
+                .unwrap()
+                .into_iter()
+                .collect();
+            ids.sort();
+            ids
+        };
+
+        assert_eq!(between(Some("1"), "5"), ["1", "2", "3", "4", "5"]);
+        assert_eq!(between(Some("2"), "5"), ["2", "4", "5"]);
+        assert_eq!(between(Some("2"), "2"), ["2"]);
+        assert_eq!(between(None, "4"), ["1", "2", "3", "4"]);
+        assert!(find_snapshots_between(&snapshots, Some("2"), "3").is_err());
+    }
+
+    #[test]
+    pub fn branches_take_precedence_over_tags() {
+        assert_eq!(resolve("both"), Ok(String::from("2-b")));
+    }
+
+    #[test]
+    pub fn reflog_entry_round_trip() {
+        let entry = ReflogEntry {
+            date: 1748490695,
+            old_snapshot_id: None,
+            new_snapshot_id: Some(String::from("1748490695-d96dbc36c710a6163736f9903b9e5137")),
+            operation: String::from("snapshot"),
+        };
+
+        let line = entry.serialize();
+        assert_eq!(
+            line,
+            "1748490695\tNULL\t1748490695-d96dbc36c710a6163736f9903b9e5137\tsnapshot\n"
+        );
+
+        let parsed = ReflogEntry::parse(line.trim_end()).unwrap();
+        assert_eq!(parsed.date, entry.date);
+        assert_eq!(parsed.old_snapshot_id, entry.old_snapshot_id);
+        assert_eq!(parsed.new_snapshot_id, entry.new_snapshot_id);
+        assert_eq!(parsed.operation, entry.operation);
+    }
+
+    #[test]
+    pub fn reads_newer_meta_versions_read_only() {
+        let unversioned = SnapshotMetaFile::parse("1-a", "date\t1\n").unwrap();
+        assert_eq!(unversioned.version, 1);
+        assert!(unversioned.check_writable().is_ok());
+
+        // unknown keys from newer versions are ignored
+        let newer = SnapshotMetaFile::parse(
+            "2-b",
+            &format!(
+                "version\t{}\ndate\t2\nfuture\tvalue\nparent\t1-a\n",
+                SNAPSHOT_META_VERSION + 1
+            ),
+        )
+        .unwrap();
+        assert_eq!(newer.parents, ["1-a"]);
+        assert!(newer.is_read_only());
+        assert!(newer.check_writable().is_err());
+        assert!(
+            newer
+                .write(&Repository::new(".", ConfigFile::default()))
+                .is_err()
+        );
+
+        assert!(SnapshotMetaFile::parse("3-c", "version\t0\ndate\t3\n").is_err());
+        assert!(SnapshotMetaFile::parse("3-c", "version\tx\ndate\t3\n").is_err());
+    }
+
+    #[test]
+    pub fn records_snapshot_walks() {
+        let parse = |keys: &str| {
+            SnapshotMetaFile::parse("1-a", &format!("date\t1\n{}", keys))
+                .unwrap()
+                .walk
+        };
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("walk\t\n"), Some(SnapshotWalk::default()));
+        assert_eq!(
+            parse("walk\tskip_non_utf8\n"),
+            Some(SnapshotWalk {
+                skip_non_utf8: true,
+                ..SnapshotWalk::default()
+            })
+        );
+        assert_eq!(
+            parse("walk\tmax_file_size=1024\n"),
+            Some(SnapshotWalk {
+                max_file_size: Some(1024),
+                ..SnapshotWalk::default()
+            })
+        );
+        assert_eq!(
+            parse(
+                "walk\texclude_if_present=.nobackup\nwalk\texclude_if_present=.cache\nwalk\tone_file_system\n"
+            ),
+            Some(SnapshotWalk {
+                exclude_if_present: vec![String::from(".nobackup"), String::from(".cache")],
+                one_file_system: true,
+                ..SnapshotWalk::default()
+            })
+        );
+        // a newer version may have left out files this version can't tell
+        assert_eq!(parse("walk\tfuture\n"), None);
+        assert_eq!(parse("walk\tmax_file_size=big\n"), None);
+
+        for walk in [
+            SnapshotWalk::default(),
+            SnapshotWalk {
+                skip_non_utf8: true,
+                max_file_size: Some(1024),
+                exclude_caches: true,
+                exclude_if_present: vec![String::from(".nobackup"), String::from(".cache")],
+                one_file_system: true,
+            },
+        ] {
+            let mut snapshot = SnapshotMetaFile::empty();
+            snapshot.id = String::from("1-a");
+            snapshot.walk = Some(walk.clone());
+            let contents = snapshot.serialize().unwrap();
+            assert_eq!(
+                SnapshotMetaFile::parse("1-a", &contents).unwrap().walk,
+                Some(walk)
+            );
+        }
+    }
+
+    #[test]
+    pub fn snapshot_index_round_trip() {
+        let snapshot = SnapshotMetaFile {
+            id: String::from("2-b"),
+            version: SNAPSHOT_META_VERSION,
+            date: 2,
+            message: Some(String::from("two\nlines\twith a tab \\")),
+            full_type: SnapshotFullType::TarGz,
+            children: Vec::new(),
+            parents: vec![String::from("1-a")],
+            diff_children: Vec::new(),
+            diff_parents: Vec::new(),
+            transformers: Some(Vec::new()),
+            walk: None,
+        };
+        let contents = snapshot.serialize().unwrap();
+
+        // later entries replace earlier ones, and empty entries remove them
+        let index = [
+            SnapshotIndexFile::serialize_entry("1-a", &contents),
+            SnapshotIndexFile::serialize_entry("2-b", "date\t1\n"),
+            SnapshotIndexFile::serialize_entry("2-b", &contents),
+            SnapshotIndexFile::serialize_entry("1-a", ""),
+        ]
+        .concat();
+        assert_eq!(index.lines().count(), 4);
+
+        let snapshots = SnapshotIndexFile::parse(&index).unwrap();
+        assert_eq!(snapshots.len(), 1);
+        let parsed = &snapshots["2-b"];
+        assert_eq!(parsed.id, snapshot.id);
+        assert_eq!(parsed.date, snapshot.date);
+        assert_eq!(parsed.message, snapshot.message);
+        assert!(parsed.full_type == SnapshotFullType::TarGz);
+        assert_eq!(parsed.parents, snapshot.parents);
+        assert_eq!(parsed.transformers, snapshot.transformers);
+
+        assert!(SnapshotIndexFile::parse("2-b\n").is_err());
+    }
+
+    #[test]
+    pub fn reflog_entry_rejects_corrupted_lines() {
+        assert!(ReflogEntry::parse("1748490695\tNULL\tNULL").is_err());
+        assert!(ReflogEntry::parse("not a date\tNULL\tNULL\tsnapshot").is_err());
+    }
+
+    #[test]
+    pub fn serializes_operations_log_entries() {
+        let entry = OperationsLogEntry {
+            date: 1748490695,
+            command: String::from("snapshot"),
+            args: vec![
+                String::from("snapshot"),
+                String::from("-m"),
+                String::from("a \"quoted\"\nmessage"),
+            ],
+            error: None,
+            created_snapshot_ids: vec![String::from("1748490695-a")],
+            removed_snapshot_ids: Vec::new(),
+        };
+        assert_eq!(
+            entry.serialize(),
+            "{\"date\":1748490695,\"command\":\"snapshot\",\"args\":[\"snapshot\",\"-m\",\"a \\\"quoted\\\"\\nmessage\"],\"result\":\"ok\",\"error\":null,\"created\":[\"1748490695-a\"],\"removed\":[]}\n"
+        );
+
+        let failed = OperationsLogEntry {
+            error: Some(String::from("Failed to squash snapshots")),
+            created_snapshot_ids: Vec::new(),
+            ..entry
+        };
+        assert!(failed.serialize().contains(
+            "\"result\":\"failed\",\"error\":\"Failed to squash snapshots\",\"created\":[]"
+        ));
+    }
+
+    #[test]
+    pub fn stash_entry_round_trip() {
+        let entry = StashEntry {
+            id: String::from("1748490695-d96dbc36c710a6163736f9903b9e5137"),
+            date: 1748490695,
+            message: Some(String::from("before\ttrying a\nrisky restore")),
+        };
+
+        let parsed = StashEntry::parse(entry.serialize().strip_suffix('\n').unwrap()).unwrap();
+        assert_eq!(parsed.id, entry.id);
+        assert_eq!(parsed.date, entry.date);
+        assert_eq!(
+            parsed.message.as_deref(),
+            Some("before\ttrying a risky restore")
+        );
+
+        let entry = StashEntry {
+            message: None,
+            ..entry
+        };
+        assert_eq!(
+            StashEntry::parse(entry.serialize().strip_suffix('\n').unwrap()),
+            Ok(entry)
+        );
+        assert!(StashEntry::parse("1748490695-d96dbc36c710a6163736f9903b9e5137").is_err());
+    }
+
+    #[test]
+    pub fn snapshot_full_type_round_trip() {
+        for full_type in [
+            SnapshotFullType::None,
+            SnapshotFullType::Tar,
+            SnapshotFullType::TarGz,
+        ] {
+            assert!(full_type.to_string().parse::<SnapshotFullType>() == Ok(full_type));
+        }
+        assert_eq!(SnapshotFullType::None.file_extension(), None);
+        assert_eq!(SnapshotFullType::TarGz.file_extension(), Some("tar.gz"));
+    }
+
+    #[test]
+    pub fn reads_payload_md5_from_ids() {
+        assert_eq!(
+            get_payload_md5_from_id("1748490695-d96dbc36c710a6163736f9903b9e5137"),
+            Some("d96dbc36c710a6163736f9903b9e5137")
+        );
+        assert_eq!(get_payload_md5_from_id("01ARYZ6S41TSV4RRFFQ69G5FAV"), None);
+        assert_eq!(get_payload_md5_from_id("0000000001"), None);
+        assert_eq!(get_payload_md5_from_id("1748490695-not-an-md5"), None);
+    }
+
+    #[test]
+    pub fn never_reuses_snapshot_seqs() {
+        let root = env::temp_dir().join(format!("jbackup-seq-test-{}", process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join(JBACKUP_PATH)).unwrap();
+        let repo = Repository::new(&root.to_string_lossy(), ConfigFile::default());
+        repo.config.write(&repo.config_path()).unwrap();
+
+        // snapshots made at the same time take numbers at the same time
+        let seqs: Vec<u64> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        (0..10)
+                            .map(|_| ConfigFile::next_snapshot_seq(&repo).unwrap())
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|x| x.join().unwrap())
+                .collect()
+        });
+        assert_eq!(seqs.iter().collect::<HashSet<_>>().len(), 40);
+        assert_eq!(seqs.iter().max(), Some(&40));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
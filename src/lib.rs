@@ -0,0 +1,44 @@
+//! jbackup stores snapshots of a directory, keeping only the newest snapshot
+//! in full and older snapshots as deltas.
+//!
+//! The `api` module has functions for using jbackup from another program, and
+//! the `cli` module implements the `jbackup` command line tool on top of the
+//! same code.
+
+pub mod api;
+mod arguments;
+pub mod cli;
+mod delta_list;
+mod file_structure;
+mod repository;
+mod subcommand;
+mod tab_separated_key_value;
+mod transformer;
+mod util;
+
+/// Internals measured by the benchmarks in `benches`. Not part of the API.
+#[doc(hidden)]
+pub mod bench {
+    pub use crate::util::multithreaded_pipeline::MultithreadPipeline;
+}
+
+pub(crate) const JBACKUP_PATH: &str = ".jbackup";
+/// Where snapshots are stored when `snapshots_dir` is not set in the config.
+/// Use `Repository::snapshots_path` to get the directory that is actually
+/// used.
+pub(crate) const DEFAULT_SNAPSHOTS_PATH: &str = ".jbackup/snapshots";
+pub(crate) const BRANCHES_PATH: &str = ".jbackup/branches";
+pub(crate) const HEAD_PATH: &str = ".jbackup/head";
+pub(crate) const CONFIG_PATH: &str = ".jbackup/config";
+pub(crate) const REFLOG_PATH: &str = ".jbackup/reflog";
+pub(crate) const OPERATIONS_LOG_PATH: &str = ".jbackup/operations.log";
+pub(crate) const TAGS_PATH: &str = ".jbackup/tags";
+pub(crate) const RESTORE_PROGRESS_PATH: &str = ".jbackup/restore_progress";
+pub(crate) const STASHES_PATH: &str = ".jbackup/stashes";
+pub(crate) const STASH_LIST_PATH: &str = ".jbackup/stashes/list";
+
+/// How many bytes archives and delta lists are buffered in when reading and
+/// writing them, when `io_buffer_size` isn't set in the config. Large enough
+/// that the many small writes of tar headers and small files become few
+/// write syscalls.
+pub(crate) const DEFAULT_IO_BUFFER_SIZE: usize = 256 * 1024;
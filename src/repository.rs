@@ -0,0 +1,125 @@
+use std::fs;
+
+use crate::{
+    CONFIG_PATH, DEFAULT_IO_BUFFER_SIZE, DEFAULT_SNAPSHOTS_PATH, JBACKUP_PATH,
+    file_structure::ConfigFile, util::io_util::simplify_result,
+};
+
+/// A repository: the directory it backs up (its root), which has the
+/// `.jbackup` directory in it, and its config. Everything that reads or
+/// writes a repository is given it, instead of working on the current
+/// working directory, so a process can use several repositories at once.
+pub struct Repository {
+    /// the directory the repository backs up. Paths in the repository are
+    /// relative to it (ex. `{root}/.jbackup/head`).
+    root: String,
+    pub config: ConfigFile,
+    /// lets snapshots be removed and replaced in immutable repositories (see
+    /// `ensure_snapshots_removable`), for `--force-immutable`
+    pub force_immutable: bool,
+    /// flushes the files of snapshots to the disk even if the config doesn't
+    /// (see `should_fsync`), for `snapshot --fsync`
+    pub force_fsync: bool,
+}
+
+impl Repository {
+    /// Opens the repository in `root`, reading its config.
+    pub fn open(root: &str) -> Result<Repository, String> {
+        let config_path = format!("{}/{}", root, CONFIG_PATH);
+        if !simplify_result(fs::exists(&config_path))? {
+            return Err(String::from(
+                "Error: a valid jbackup was not found in current working directory. (To make a new backup for this directory, do 'jbackup init')",
+            ));
+        }
+
+        let config = ConfigFile::read(&config_path)
+            .map_err(|err| format!("Failed to read the config: {}", err))?;
+        Ok(Repository::new(root, config))
+    }
+
+    /// A repository in `root` with the given config, which may not be written
+    /// yet (ex. while initializing the repository).
+    pub fn new(root: &str, config: ConfigFile) -> Repository {
+        Repository {
+            root: String::from(root),
+            config,
+            force_immutable: false,
+            force_fsync: false,
+        }
+    }
+
+    pub fn root(&self) -> &str {
+        &self.root
+    }
+
+    /// The path of `path`, which is relative to the root.
+    pub fn path(&self, path: &str) -> String {
+        format!("{}/{}", self.root, path)
+    }
+
+    /// The path of the config file.
+    pub fn config_path(&self) -> String {
+        self.path(CONFIG_PATH)
+    }
+
+    /// The directory snapshots are stored in. This is `snapshots_dir` in the
+    /// config if set, otherwise `DEFAULT_SNAPSHOTS_PATH`.
+    pub fn snapshots_path(&self) -> String {
+        match &self.config.snapshots_dir {
+            Some(dir) => dir.clone(),
+            None => self.path(DEFAULT_SNAPSHOTS_PATH),
+        }
+    }
+
+    pub fn prepend_snapshot_path(&self, path: &str) -> String {
+        self.snapshots_path() + "/" + path
+    }
+
+    /// The directory scratch files (ex. temporary snapshots and tars
+    /// reconstructed while restoring) are written to. This is the
+    /// `JBACKUP_TMPDIR` environment variable if set, otherwise `tmp_dir` in
+    /// the config if set, otherwise `JBACKUP_PATH`.
+    pub fn tmp_path(&self) -> String {
+        match std::env::var("JBACKUP_TMPDIR") {
+            Ok(dir) if !dir.is_empty() => dir,
+            _ => match &self.config.tmp_dir {
+                Some(dir) => dir.clone(),
+                None => self.path(JBACKUP_PATH),
+            },
+        }
+    }
+
+    /// How many bytes archives and delta lists are buffered in. This is
+    /// `io_buffer_size` in the config if set, otherwise
+    /// `DEFAULT_IO_BUFFER_SIZE`.
+    pub fn io_buffer_size(&self) -> usize {
+        self.config
+            .io_buffer_size
+            .map_or(DEFAULT_IO_BUFFER_SIZE, |x| x as usize)
+    }
+
+    /// Whether the files written for new snapshots are flushed to the disk:
+    /// the payload and diffs before the metadata that refers to them, then
+    /// the metadata, head, branches and tags. Set by the `fsync` key or
+    /// `snapshot --fsync` (see `force_fsync`). Off by default, since
+    /// flushing is slow, and most filesystems only lose recent writes on a
+    /// power loss or crash.
+    pub fn should_fsync(&self) -> bool {
+        self.config.fsync || self.force_fsync
+    }
+
+    /// Fails if the repository is immutable, for commands that remove or
+    /// replace snapshots, unless `--force-immutable` was given (see
+    /// `force_immutable`). Adding snapshots is still allowed: the parent's
+    /// metadata gains a child and a diff, but its full payload is kept (see
+    /// `snapshot`).
+    pub fn ensure_snapshots_removable(&self) -> Result<(), String> {
+        if self.config.immutable && !self.force_immutable {
+            Err(String::from(
+                "This repository is immutable, so snapshots can't be removed or replaced. Pass --force-immutable to do it anyway.",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -2,6 +2,23 @@
 //! in the tool.
 
 pub mod __debug_restore;
+pub mod chain_depth;
+pub mod clean;
+pub mod clean_snapshots;
+pub mod diff;
+pub mod extract;
+pub mod gc;
 pub mod init;
 pub mod log;
+pub mod recover_head;
+pub mod reflog;
+pub mod rename_branch;
+pub mod restore;
+pub mod rev_parse;
+pub mod self_test;
+pub mod show_tar;
+pub mod size;
 pub mod snapshot;
+pub mod squash;
+pub mod stash;
+pub mod verify;
@@ -0,0 +1,332 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::{
+    arguments,
+    file_structure::{self, EMPTY_SNAPSHOT_ID, SnapshotFullType, SnapshotMetaFile},
+    repository::Repository,
+    util::{io_util::simplify_result, json_util::json_string},
+};
+
+const DEFAULT_TOP_N: usize = 10;
+
+/// Reports how many delta lists must be applied to restore each snapshot,
+/// which is most of the cost of restoring it. A snapshot's chain depth is the
+/// number of snapshots `find_restore_path` passes through before reaching one
+/// with a full payload (0 for snapshots with a full payload).
+///
+/// Options: `--top <n>` limits the number of snapshots listed, `--sort
+/// <depth|date>` lists the deepest (default) or newest snapshots first, and
+/// `--json` prints the report as JSON.
+pub fn main(repo: &Repository, mut args: VecDeque<String>) -> Result<(), String> {
+    let mut parsed_args = arguments::Parser::new()
+        .flag("--json")
+        .option("--top")
+        .option("--sort")
+        .parse(args.drain(..));
+
+    let top_n = match parsed_args.options.remove("--top") {
+        Some(x) => simplify_result(x.parse::<usize>())?,
+        None => DEFAULT_TOP_N,
+    };
+    let sort_by_date = match parsed_args.options.remove("--sort").as_deref() {
+        None | Some("depth") => false,
+        Some("date") => true,
+        Some(x) => {
+            return Err(format!(
+                "Invalid sort order: '{}' (expected depth or date)",
+                x
+            ));
+        }
+    };
+
+    file_structure::ensure_jbackup_snapshots_dir_exists(repo)?;
+    let report = ChainDepthReport::read(repo, sort_by_date)?;
+
+    if parsed_args.flags.contains("--json") {
+        println!("{}", report.to_json(top_n));
+    } else {
+        report.print(top_n, sort_by_date);
+    }
+
+    Ok(())
+}
+
+struct SnapshotChainDepth {
+    id: String,
+    date: i64,
+    message: Option<String>,
+    /// `None` if the snapshot can't be restored, since no full payload is
+    /// reachable from it
+    depth: Option<usize>,
+}
+
+struct ChainDepthReport {
+    /// sorted deepest (or newest) first, with snapshots that can't be
+    /// restored first
+    snapshots: Vec<SnapshotChainDepth>,
+}
+
+impl ChainDepthReport {
+    fn read(repo: &Repository, sort_by_date: bool) -> Result<ChainDepthReport, String> {
+        let snapshots: HashMap<String, SnapshotMetaFile> =
+            file_structure::get_all_snapshot_meta_files(repo)?
+                .into_iter()
+                .map(|x| (x.id.clone(), x))
+                .collect();
+        let mut depths = get_chain_depths(&snapshots);
+
+        let mut snapshots: Vec<SnapshotChainDepth> = snapshots
+            .into_values()
+            .map(|meta| SnapshotChainDepth {
+                depth: depths.remove(&meta.id).flatten(),
+                id: meta.id,
+                date: meta.date,
+                message: meta.message,
+            })
+            .collect();
+
+        if sort_by_date {
+            snapshots.sort_by(|a, b| b.date.cmp(&a.date).then(a.id.cmp(&b.id)));
+        } else {
+            snapshots.sort_by(|a, b| {
+                let depth = |x: &SnapshotChainDepth| x.depth.unwrap_or(usize::MAX);
+                depth(b).cmp(&depth(a)).then(a.id.cmp(&b.id))
+            });
+        }
+
+        Ok(ChainDepthReport { snapshots })
+    }
+
+    fn max_depth(&self) -> usize {
+        self.snapshots
+            .iter()
+            .filter_map(|x| x.depth)
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn average_depth(&self) -> f64 {
+        let depths: Vec<usize> = self.snapshots.iter().filter_map(|x| x.depth).collect();
+        if depths.is_empty() {
+            0.0
+        } else {
+            depths.iter().sum::<usize>() as f64 / depths.len() as f64
+        }
+    }
+
+    fn print(&self, top_n: usize, sort_by_date: bool) {
+        println!(
+            "{} snapshot(s), deepest chain: {}, average chain: {:.1}",
+            self.snapshots.len(),
+            self.max_depth(),
+            self.average_depth()
+        );
+
+        if !self.snapshots.is_empty() {
+            println!(
+                "\n{}",
+                if sort_by_date {
+                    "Newest snapshots (diffs to apply):"
+                } else {
+                    "Deepest chains (diffs to apply):"
+                }
+            );
+            for snapshot in self.snapshots.iter().take(top_n) {
+                println!(
+                    "  {:>5}  {}{}",
+                    match snapshot.depth {
+                        Some(depth) => depth.to_string(),
+                        None => String::from("-"),
+                    },
+                    snapshot.id,
+                    match &snapshot.message {
+                        Some(message) => format!("  {}", message.replace('\n', " ")),
+                        None => String::new(),
+                    }
+                );
+            }
+        }
+
+        let unrestorable_count = self.snapshots.iter().filter(|x| x.depth.is_none()).count();
+        if unrestorable_count > 0 {
+            println!(
+                "\n{} snapshot(s) can't be restored, since no full payload is reachable from them (shown as -). Run 'jbackup verify' for details.",
+                unrestorable_count
+            );
+        }
+    }
+
+    fn to_json(&self, top_n: usize) -> String {
+        let snapshots: Vec<String> = self
+            .snapshots
+            .iter()
+            .take(top_n)
+            .map(|snapshot| {
+                format!(
+                    "{{\"id\":{},\"date\":{},\"message\":{},\"depth\":{}}}",
+                    json_string(&snapshot.id),
+                    snapshot.date,
+                    match &snapshot.message {
+                        Some(message) => json_string(message),
+                        None => String::from("null"),
+                    },
+                    match snapshot.depth {
+                        Some(depth) => depth.to_string(),
+                        None => String::from("null"),
+                    }
+                )
+            })
+            .collect();
+
+        format!(
+            "{{\"snapshot_count\":{},\"max_depth\":{},\"average_depth\":{:.1},\"snapshots\":[{}]}}",
+            self.snapshots.len(),
+            self.max_depth(),
+            self.average_depth(),
+            snapshots.join(",")
+        )
+    }
+}
+
+/// Finds the chain depth of every snapshot, following the first diff child
+/// like `find_restore_path` does. Snapshots that can't be restored, since the
+/// chain ends (or loops) without a full payload, have a depth of `None`.
+pub fn get_chain_depths(
+    snapshots: &HashMap<String, SnapshotMetaFile>,
+) -> HashMap<String, Option<usize>> {
+    let mut depths: HashMap<String, Option<usize>> = HashMap::new();
+
+    for id in snapshots.keys() {
+        // follow the chain until a snapshot with a known depth, then fill in
+        // the depths of the snapshots on the way back
+        let mut chain: Vec<&str> = Vec::new();
+        let mut in_chain = HashSet::new();
+        let mut curr = Some(id.as_str());
+        let mut end_depth = None;
+
+        while let Some(curr_id) = curr {
+            if let Some(depth) = depths.get(curr_id) {
+                end_depth = *depth;
+                break;
+            }
+            if curr_id == EMPTY_SNAPSHOT_ID {
+                // diffs from the empty snapshot start from nothing
+                end_depth = Some(0);
+                break;
+            }
+            let Some(snapshot) = snapshots.get(curr_id) else {
+                break;
+            };
+            if snapshot.full_type != SnapshotFullType::None {
+                depths.insert(String::from(curr_id), Some(0));
+                end_depth = Some(0);
+                break;
+            }
+            if !in_chain.insert(curr_id) {
+                break;
+            }
+            chain.push(curr_id);
+            curr = snapshot.diff_children.first().map(|x| x.as_str());
+        }
+
+        for (i, chain_id) in chain.iter().rev().enumerate() {
+            depths.insert(String::from(*chain_id), end_depth.map(|x| x + i + 1));
+        }
+    }
+
+    depths
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use crate::file_structure::{
+        EMPTY_SNAPSHOT_ID, SNAPSHOT_META_VERSION, SnapshotFullType, SnapshotMetaFile,
+    };
+
+    use super::get_chain_depths;
+
+    /// Creates snapshots from (id, has a full payload, diff children).
+    fn snapshots(graph: &[(&str, bool, &[&str])]) -> HashMap<String, SnapshotMetaFile> {
+        graph
+            .iter()
+            .map(|(id, full, diff_children)| {
+                let snapshot = SnapshotMetaFile {
+                    id: String::from(*id),
+                    version: SNAPSHOT_META_VERSION,
+                    date: 0,
+                    message: None,
+                    full_type: if *full {
+                        SnapshotFullType::TarGz
+                    } else {
+                        SnapshotFullType::None
+                    },
+                    children: Vec::new(),
+                    parents: Vec::new(),
+                    diff_children: diff_children.iter().map(|x| String::from(*x)).collect(),
+                    diff_parents: Vec::new(),
+                    transformers: None,
+                    walk: None,
+                };
+                (String::from(*id), snapshot)
+            })
+            .collect()
+    }
+
+    fn depth(depths: &HashMap<String, Option<usize>>, id: &str) -> Option<usize> {
+        depths[id]
+    }
+
+    #[test]
+    pub fn finds_chain_depths() {
+        // a <- b <- c (full) -> d, where e branches off b, and f's chain
+        // leads to a missing snapshot
+        let snapshots = snapshots(&[
+            ("a", false, &["b"]),
+            ("b", false, &["c"]),
+            ("c", true, &[]),
+            ("e", false, &["b"]),
+            ("d", false, &["c"]),
+            ("f", false, &["missing"]),
+            ("g", false, &["h"]),
+            ("h", false, &["g"]),
+        ]);
+        let depths = get_chain_depths(&snapshots);
+
+        assert_eq!(depths.len(), snapshots.len());
+        assert_eq!(depth(&depths, "c"), Some(0));
+        assert_eq!(depth(&depths, "b"), Some(1));
+        assert_eq!(depth(&depths, "d"), Some(1));
+        assert_eq!(depth(&depths, "a"), Some(2));
+        assert_eq!(depth(&depths, "e"), Some(2));
+        assert_eq!(depth(&depths, "f"), None);
+        assert_eq!(depth(&depths, "g"), None);
+        assert_eq!(depth(&depths, "h"), None);
+    }
+
+    #[test]
+    pub fn full_payloads_end_the_chain() {
+        // forward diffs keep the first snapshot's full payload, even though
+        // it also has a diff child
+        let snapshots = snapshots(&[("a", true, &["b"]), ("b", true, &[])]);
+        let depths = get_chain_depths(&snapshots);
+
+        assert_eq!(depth(&depths, "a"), Some(0));
+    }
+
+    #[test]
+    pub fn diffs_from_the_empty_snapshot_start_the_chain() {
+        let snapshots = snapshots(&[
+            ("a", false, &[EMPTY_SNAPSHOT_ID]),
+            ("b", false, &["a"]),
+            ("c", true, &["b"]),
+        ]);
+        let depths = get_chain_depths(&snapshots);
+
+        assert_eq!(depths.len(), snapshots.len());
+        assert_eq!(depth(&depths, "a"), Some(1));
+        assert_eq!(depth(&depths, "b"), Some(2));
+        assert_eq!(depth(&depths, "c"), Some(0));
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    ffi::OsStr,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use tar::EntryType;
+
+use crate::{
+    arguments, file_structure,
+    repository::Repository,
+    subcommand::restore::{
+        find_files_not_in_snapshot, find_restore_path, follow_path, remove_files,
+        remove_restored_tar,
+    },
+    util::{
+        archive_utils::open_tar_gz,
+        color_util::{Color, paint},
+        io_util::simplify_result,
+    },
+};
+
+/// Removes files in the working directory that are not in a snapshot
+/// (`HEAD` by default), but would have been stored by it. Files the snapshot
+/// left out on purpose are kept (see `SnapshotWalk`).
+///
+/// `-n`/`--dry-run` only lists the files that would be removed. Since files
+/// are removed for good, nothing is removed unless `-f` is specified.
+pub fn main(repo: &Repository, mut args: VecDeque<String>) -> Result<(), String> {
+    let mut parsed_args = arguments::Parser::new()
+        .flag("-f")
+        .flag("-n")
+        .flag("--dry-run")
+        .parse(args.drain(..));
+    let force = parsed_args.flags.contains("-f");
+    let dry_run = parsed_args.flags.contains("-n") || parsed_args.flags.contains("--dry-run");
+
+    if !force && !dry_run {
+        return Err(String::from(
+            "Refusing to remove files without -f. Use --dry-run to list the files that would be removed.",
+        ));
+    }
+
+    file_structure::ensure_jbackup_snapshots_dir_exists(repo)?;
+    repo.config.ensure_not_bare()?;
+
+    let reference = parsed_args
+        .normal
+        .pop_front()
+        .unwrap_or_else(|| String::from("HEAD"));
+    let snapshot_id = file_structure::resolve_ref(repo, &reference)?;
+    let Some(walk) = file_structure::SnapshotMetaFile::read(repo, &snapshot_id)?.walk else {
+        return Err(format!(
+            "Snapshot {} doesn't record which files it left out, so it can't tell which files to remove",
+            snapshot_id
+        ));
+    };
+
+    let snapshot_paths = read_snapshot_file_paths(repo, &snapshot_id)?;
+    let snapshot_paths: HashSet<&Path> = snapshot_paths.iter().map(|p| p.as_path()).collect();
+    let untracked_paths = find_files_not_in_snapshot(repo, &walk, &snapshot_paths)?;
+
+    if dry_run {
+        for path in &untracked_paths {
+            println!(
+                "{}",
+                paint(
+                    &format!("Would remove: {}", path.to_string_lossy()),
+                    Color::Red
+                )
+            );
+        }
+        return Ok(());
+    }
+
+    remove_files(repo, &untracked_paths)?;
+    println!(
+        "Removed {} file(s) not in snapshot {}",
+        untracked_paths.len(),
+        snapshot_id
+    );
+
+    Ok(())
+}
+
+/// Reads the paths of the regular files in a snapshot, without extracting
+/// them.
+fn read_snapshot_file_paths(repo: &Repository, snapshot_id: &str) -> Result<Vec<PathBuf>, String> {
+    let path = find_restore_path(repo, snapshot_id)?;
+    let tar_path = follow_path(repo, &path)?;
+
+    let read_result = read_tar_file_paths(repo, &tar_path);
+    remove_restored_tar(repo, &path, &tar_path)?;
+    read_result
+}
+
+fn read_tar_file_paths(repo: &Repository, tar_path: &str) -> Result<Vec<PathBuf>, String> {
+    let mut tar_reader = open_tar_gz(repo, tar_path)?;
+    let mut paths = Vec::new();
+
+    for entry in simplify_result(tar_reader.entries())? {
+        let entry = simplify_result(entry)?;
+        let entry_type = entry.header().entry_type();
+        if entry_type == EntryType::Regular || entry_type == EntryType::Link {
+            paths.push(PathBuf::from(OsStr::from_bytes(&entry.path_bytes())));
+        }
+    }
+
+    Ok(paths)
+}
@@ -0,0 +1,340 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::SystemTime,
+};
+
+use crate::{
+    arguments,
+    file_structure::{self, BranchesFile, DiffDirection, HeadFile, SnapshotMetaFile, TagsFile},
+    repository::Repository,
+    subcommand::{
+        log::{read_log, sort_parents_first},
+        snapshot::to_timestamp,
+        squash::{find_range, squash},
+    },
+    util::time_util::parse_duration,
+};
+
+/// Removes the snapshots a retention policy doesn't keep, by squashing each
+/// run of removed snapshots into the kept snapshot after it.
+///
+/// `--keep-last <n>` keeps the newest `n` snapshots, and `--keep-within
+/// <duration>` keeps the snapshots newer than the duration (ex. `90d`). The
+/// kept snapshots are the union of the rules, and the snapshots the head,
+/// branches and tags point to.
+///
+/// `-n`/`--dry-run` only lists the snapshots that would be removed. Since
+/// snapshots are removed for good, nothing is removed unless `-f` is
+/// specified.
+pub fn main(repo: &Repository, mut args: VecDeque<String>) -> Result<(), String> {
+    let mut parsed_args = arguments::Parser::new()
+        .flag("-f")
+        .flag("-n")
+        .flag("--dry-run")
+        .option("--keep-last")
+        .option("--keep-within")
+        .parse(args.drain(..));
+    let force = parsed_args.flags.contains("-f");
+    let dry_run = parsed_args.flags.contains("-n") || parsed_args.flags.contains("--dry-run");
+
+    let keep_last = match parsed_args.options.remove("--keep-last") {
+        Some(x) => match x.parse::<usize>() {
+            Ok(x) => Some(x),
+            Err(_) => return Err(format!("Invalid number of snapshots to keep: '{}'", x)),
+        },
+        None => None,
+    };
+    let keep_within = match parsed_args.options.remove("--keep-within") {
+        Some(x) => Some(parse_duration(&x)?),
+        None => None,
+    };
+
+    if keep_last.is_none() && keep_within.is_none() {
+        return Err(String::from(
+            "No retention rule specified. Usage: clean-snapshots [--keep-last <n>] [--keep-within <duration>]",
+        ));
+    }
+    if !force && !dry_run {
+        return Err(String::from(
+            "Refusing to remove snapshots without -f. Use --dry-run to list the snapshots that would be removed.",
+        ));
+    }
+
+    file_structure::ensure_jbackup_snapshots_dir_exists(repo)?;
+
+    if repo.config.diff_direction != DiffDirection::Reverse {
+        return Err(String::from(
+            "Cleaning snapshots is only supported in repositories that store reverse diffs",
+        ));
+    }
+    if !dry_run {
+        repo.ensure_snapshots_removable()?;
+    }
+
+    // snapshots created in the same second are only ordered by their
+    // parents, so which are the newest can't be told from the date alone
+    let snapshots = sort_parents_first(read_log(repo)?);
+    let min_date = match keep_within {
+        Some(duration) => Some(to_timestamp(SystemTime::now())? - duration),
+        None => None,
+    };
+    let mut retained = find_retained(&snapshots, keep_last, min_date);
+
+    if let Some(head_id) = HeadFile::read(repo)?.curr_snapshot_id {
+        retained.insert(head_id);
+    }
+    retained.extend(BranchesFile::read(repo)?.branches.into_values());
+    retained.extend(TagsFile::read(repo)?.tags.into_values());
+
+    let (runs, unremovable) = plan_removal(&snapshots, &retained);
+    for id in &unremovable {
+        eprintln!(
+            "Warn: Keeping snapshot {}, since only snapshots in linear history can be removed",
+            id
+        );
+    }
+
+    if runs.is_empty() {
+        println!("Nothing to clean.");
+        return Ok(());
+    }
+
+    let mut removed_count = 0;
+    for run in runs {
+        for id in &run.removed {
+            if dry_run {
+                println!("Would remove: {}", id);
+            } else {
+                println!("Removing: {}", id);
+            }
+        }
+        if !dry_run {
+            squash(repo, find_range(repo, &run.removed[0], &run.kept_id)?, None)?;
+        }
+        removed_count += run.removed.len();
+    }
+
+    if !dry_run {
+        println!("Removed {} snapshot(s)", removed_count);
+    }
+    Ok(())
+}
+
+/// Snapshots to remove, which are squashed into `kept_id`, their descendant.
+#[derive(Debug, PartialEq, Eq)]
+struct RemovalRun {
+    /// the removed snapshots, oldest first
+    removed: Vec<String>,
+    kept_id: String,
+}
+
+/// Finds the ids of the newest `keep_last` snapshots, and of the snapshots
+/// created at or after `min_date`, from snapshots sorted oldest first with
+/// children after their parents (see `log::sort_parents_first`).
+fn find_retained(
+    snapshots: &[SnapshotMetaFile],
+    keep_last: Option<usize>,
+    min_date: Option<i64>,
+) -> HashSet<String> {
+    let newest_first: Vec<&SnapshotMetaFile> = snapshots.iter().rev().collect();
+
+    let mut retained = HashSet::new();
+    if let Some(keep_last) = keep_last {
+        retained.extend(newest_first.iter().take(keep_last).map(|x| x.id.clone()));
+    }
+    if let Some(min_date) = min_date {
+        retained.extend(
+            newest_first
+                .iter()
+                .filter(|x| x.date >= min_date)
+                .map(|x| x.id.clone()),
+        );
+    }
+    retained
+}
+
+/// Groups the snapshots that aren't retained into runs that can each be
+/// squashed into the kept snapshot after them (see `squash::find_range`).
+///
+/// Snapshots that can't be squashed, such as those with several parents or
+/// children, are kept too, and their ids are returned separately. Like
+/// `find_retained`, the snapshots are sorted with children after their parents.
+fn plan_removal(
+    snapshots: &[SnapshotMetaFile],
+    retained: &HashSet<String>,
+) -> (Vec<RemovalRun>, Vec<String>) {
+    let by_id: HashMap<&str, &SnapshotMetaFile> =
+        snapshots.iter().map(|x| (x.id.as_str(), x)).collect();
+    let newest_first: Vec<&SnapshotMetaFile> = snapshots.iter().rev().collect();
+
+    // children come after their parents, so whether a snapshot's child is
+    // kept is known by the time the snapshot is checked
+    let mut kept: HashSet<&str> = retained.iter().map(|x| x.as_str()).collect();
+    let mut checked: HashSet<&str> = HashSet::new();
+    let mut unremovable = Vec::new();
+    for snapshot in &newest_first {
+        let id = snapshot.id.as_str();
+        if !kept.contains(id) && !can_remove(snapshot, &by_id, &kept, &checked) {
+            kept.insert(id);
+            unremovable.push(snapshot.id.clone());
+        }
+        checked.insert(id);
+    }
+    unremovable.reverse();
+
+    let mut runs = Vec::new();
+    for snapshot in newest_first.iter().rev() {
+        if !kept.contains(snapshot.id.as_str()) {
+            continue;
+        }
+
+        let mut removed = Vec::new();
+        let mut parent_id = snapshot.parents.first();
+        while let Some(id) = parent_id
+            && !kept.contains(id.as_str())
+            && let Some(parent) = by_id.get(id.as_str())
+        {
+            removed.push(id.clone());
+            parent_id = parent.parents.first();
+        }
+
+        if !removed.is_empty() {
+            removed.reverse();
+            runs.push(RemovalRun {
+                removed,
+                kept_id: snapshot.id.clone(),
+            });
+        }
+    }
+
+    (runs, unremovable)
+}
+
+/// Whether a snapshot can be squashed into its only child, along the same
+/// rules as `squash::find_range`.
+fn can_remove(
+    snapshot: &SnapshotMetaFile,
+    by_id: &HashMap<&str, &SnapshotMetaFile>,
+    kept: &HashSet<&str>,
+    checked: &HashSet<&str>,
+) -> bool {
+    let [child_id] = snapshot.children.as_slice() else {
+        return false;
+    };
+    let Some(child) = by_id.get(child_id.as_str()) else {
+        return false;
+    };
+    if snapshot.parents.len() > 1
+        || snapshot.diff_children != snapshot.children
+        || child.parents.len() != 1
+        || !checked.contains(child_id.as_str())
+    {
+        return false;
+    }
+
+    // a removed child is squashed too, so it was checked already
+    !kept.contains(child_id.as_str())
+        || (child.children.len() <= 1 && child.diff_children == child.children)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use crate::file_structure::{SNAPSHOT_META_VERSION, SnapshotFullType, SnapshotMetaFile};
+
+    use super::{RemovalRun, find_retained, plan_removal};
+
+    /// Creates snapshots from (id, date, parent ids), where reverse diffs are
+    /// stored from each child.
+    fn snapshots(graph: &[(&str, i64, &[&str])]) -> Vec<SnapshotMetaFile> {
+        graph
+            .iter()
+            .map(|(id, date, parents)| {
+                let children: Vec<String> = graph
+                    .iter()
+                    .filter(|x| x.2.contains(id))
+                    .map(|x| String::from(x.0))
+                    .collect();
+                SnapshotMetaFile {
+                    id: String::from(*id),
+                    version: SNAPSHOT_META_VERSION,
+                    date: *date,
+                    message: None,
+                    full_type: SnapshotFullType::None,
+                    diff_children: children.clone(),
+                    children,
+                    parents: parents.iter().map(|x| String::from(*x)).collect(),
+                    diff_parents: Vec::new(),
+                    transformers: None,
+                    walk: None,
+                }
+            })
+            .collect()
+    }
+
+    fn ids(ids: &[&str]) -> HashSet<String> {
+        ids.iter().map(|x| String::from(*x)).collect()
+    }
+
+    fn run(removed: &[&str], kept_id: &str) -> RemovalRun {
+        RemovalRun {
+            removed: removed.iter().map(|x| String::from(*x)).collect(),
+            kept_id: String::from(kept_id),
+        }
+    }
+
+    #[test]
+    pub fn finds_retained_snapshots() {
+        let snapshots = snapshots(&[
+            ("a", 1, &[]),
+            ("b", 2, &["a"]),
+            ("c", 3, &["b"]),
+            ("d", 4, &["c"]),
+        ]);
+
+        assert_eq!(find_retained(&snapshots, Some(2), None), ids(&["c", "d"]));
+        assert_eq!(
+            find_retained(&snapshots, None, Some(2)),
+            ids(&["b", "c", "d"])
+        );
+        assert_eq!(
+            find_retained(&snapshots, Some(1), Some(3)),
+            ids(&["c", "d"])
+        );
+        assert_eq!(find_retained(&snapshots, Some(0), Some(5)), ids(&[]));
+    }
+
+    #[test]
+    pub fn plans_removal_in_linear_runs() {
+        let snapshots = snapshots(&[
+            ("a", 1, &[]),
+            ("b", 2, &["a"]),
+            ("c", 3, &["b"]),
+            ("d", 4, &["c"]),
+            ("e", 5, &["d"]),
+        ]);
+
+        let (runs, unremovable) = plan_removal(&snapshots, &ids(&["c", "e"]));
+        assert_eq!(runs, vec![run(&["a", "b"], "c"), run(&["d"], "e")]);
+        assert!(unremovable.is_empty());
+    }
+
+    #[test]
+    pub fn keeps_snapshots_outside_linear_history() {
+        // b has two children, so neither it nor a can be squashed, and the
+        // tip f isn't retained, but has no child to be squashed into
+        let snapshots = snapshots(&[
+            ("a", 1, &[]),
+            ("b", 2, &["a"]),
+            ("c", 3, &["b"]),
+            ("d", 4, &["c"]),
+            ("e", 5, &["b"]),
+            ("f", 6, &["e"]),
+        ]);
+
+        let (runs, unremovable) = plan_removal(&snapshots, &ids(&["d"]));
+        assert_eq!(runs, vec![run(&["c"], "d"), run(&["e"], "f")]);
+        assert_eq!(unremovable, ["a", "b", "f"].map(String::from));
+    }
+}
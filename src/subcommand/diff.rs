@@ -0,0 +1,139 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{self, BufWriter},
+};
+
+use crate::{
+    arguments,
+    delta_list::{FileChange, PathOrder, format_change, generate_delta_list, list_changes},
+    file_structure,
+    repository::Repository,
+    subcommand::{
+        restore::{find_restore_path, follow_path_to_writer},
+        snapshot::{SnapshotOptions, create_tmp_tar, get_tmp_snapshot_path},
+    },
+    util::{
+        archive_utils::{ArchiveFormat, create_delta_list, open_delta_list, open_tar_gz},
+        io_util::simplify_result,
+    },
+};
+
+/// Lists the files that changed from one snapshot to another.
+///
+/// `diff <ref>` compares the snapshot with the working directory instead,
+/// listing the files that changed since it. The working directory is stored
+/// in a temporary tar the way `snapshot` would store it, transforming the
+/// files, so only real changes are listed.
+///
+/// `diff <from> <to> --output <file>` also writes the delta list that turns
+/// `<from>`'s tar into `<to>`'s tar to `<file>`, as a patch. Patches are
+/// delta lists in the same format snapshots are stored in (see
+/// `JBackupFileDeltaListWriter`), so they can be read by the same or a newer
+/// version of jbackup.
+pub fn main(repo: &Repository, mut args: VecDeque<String>) -> Result<(), String> {
+    let mut parsed_args = arguments::Parser::new()
+        .option("--output")
+        .parse(args.drain(..));
+
+    let Some(from_reference) = parsed_args.normal.pop_front() else {
+        return Err(String::from(
+            "Please specify two snapshots to compare, or one snapshot to compare with the working directory",
+        ));
+    };
+    let from_id = file_structure::resolve_ref(repo, &from_reference)?;
+    // `None` for the working directory
+    let to_id = match parsed_args.normal.pop_front() {
+        Some(to_reference) => Some(file_structure::resolve_ref(repo, &to_reference)?),
+        None => None,
+    };
+
+    file_structure::ensure_jbackup_snapshots_dir_exists(repo)?;
+    if to_id.is_none() {
+        repo.config.ensure_not_bare()?;
+    }
+
+    let from_tar_path = get_tmp_snapshot_path(repo);
+    let to_tar_path = get_tmp_snapshot_path(repo);
+    let output_path = parsed_args.options.remove("--output");
+    let delta_list_path = match &output_path {
+        Some(x) => x.clone(),
+        None => get_tmp_snapshot_path(repo),
+    };
+
+    let result = diff_snapshots(
+        repo,
+        &from_id,
+        to_id.as_deref(),
+        &from_tar_path,
+        &to_tar_path,
+        &delta_list_path,
+    );
+
+    let mut tmp_paths = vec![&from_tar_path, &to_tar_path];
+    if output_path.is_none() {
+        tmp_paths.push(&delta_list_path);
+    }
+    for path in tmp_paths {
+        if let Err(err) = fs::remove_file(path)
+            && err.kind() != io::ErrorKind::NotFound
+        {
+            eprintln!("Warn: Failed to remove '{}': {}", path, err);
+        }
+    }
+
+    let changes = result?;
+    for (path, change) in &changes {
+        println!("{}", format_change(path, change));
+    }
+    println!("{} file(s) changed.", changes.len());
+    if let Some(output_path) = output_path {
+        println!("Wrote patch to: {}", output_path);
+    }
+
+    Ok(())
+}
+
+/// Writes the delta list from one snapshot to another (or to the working
+/// directory, if `to_id` is `None`) to `delta_list_path`, writing their tars
+/// to the given paths, and lists the changes in it.
+fn diff_snapshots(
+    repo: &Repository,
+    from_id: &str,
+    to_id: Option<&str>,
+    from_tar_path: &str,
+    to_tar_path: &str,
+    delta_list_path: &str,
+) -> Result<Vec<(Vec<u8>, FileChange)>, String> {
+    write_snapshot_tar(repo, from_id, from_tar_path)?;
+    match to_id {
+        Some(to_id) => write_snapshot_tar(repo, to_id, to_tar_path)?,
+        None => {
+            let from_snapshot = file_structure::SnapshotMetaFile::read(repo, from_id)?;
+            let options = SnapshotOptions::for_comparing_with(repo, &from_snapshot);
+            let (tmp_tar_path, _) = create_tmp_tar(repo, &options)?;
+            simplify_result(fs::rename(tmp_tar_path, to_tar_path))?;
+        }
+    }
+
+    generate_delta_list(
+        open_tar_gz(repo, from_tar_path)?,
+        open_tar_gz(repo, to_tar_path)?,
+        create_delta_list(repo, delta_list_path, repo.config.compression)?,
+        PathOrder::from_config(&repo.config),
+    )?;
+
+    list_changes(open_delta_list(repo, delta_list_path)?)
+}
+
+/// Reconstructs the tar of a snapshot at `tar_path`.
+fn write_snapshot_tar(repo: &Repository, snapshot_id: &str, tar_path: &str) -> Result<(), String> {
+    let path = find_restore_path(repo, snapshot_id)?;
+    let file = simplify_result(File::create(tar_path))?;
+    follow_path_to_writer(
+        repo,
+        &path,
+        BufWriter::with_capacity(repo.io_buffer_size(), file),
+        ArchiveFormat::TarGz,
+    )
+}
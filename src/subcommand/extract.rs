@@ -0,0 +1,196 @@
+use std::{
+    collections::VecDeque,
+    fs,
+    io::Read,
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path},
+};
+
+use crate::{
+    arguments,
+    delta_list::PathOrder,
+    file_structure,
+    repository::Repository,
+    subcommand::restore::{find_restore_path, follow_path, remove_restored_tar},
+    transformer::get_snapshot_transformers,
+    util::archive_utils::{describe_corruption, get_link_target, open_tar_gz},
+};
+
+/// Writes one file of a snapshot to an output path, reversing the
+/// transformers the snapshot was created with, to pull a file out of an old
+/// snapshot without restoring the working directory.
+///
+/// The path in the snapshot is relative to the working directory (ex.
+/// `world/level.dat` or `./world/level.dat`), and can't be absolute or have
+/// ".." components. The output path can be anywhere, and is overwritten if
+/// it exists. `--no-transform` writes the file as it's stored, like
+/// `restore --no-transform`.
+pub fn main(repo: &Repository, mut args: VecDeque<String>) -> Result<(), String> {
+    let mut parsed_args = arguments::Parser::new()
+        .flag("--no-transform")
+        .parse(args.drain(..));
+
+    let (Some(reference), Some(path), Some(output_path)) = (
+        parsed_args.normal.pop_front(),
+        parsed_args.normal.pop_front(),
+        parsed_args.normal.pop_front(),
+    ) else {
+        return Err(String::from(
+            "Please specify a snapshot, the path of a file in it, and the path to write it to. Usage: extract <ref> <path> <output path>",
+        ));
+    };
+
+    file_structure::ensure_jbackup_snapshots_dir_exists(repo)?;
+    let entry_path = normalize_snapshot_path(&path)?;
+
+    let snapshot_id = file_structure::resolve_ref(repo, &reference)?;
+    let restore_path = find_restore_path(repo, &snapshot_id)?;
+    let transformers = if parsed_args.flags.contains("--no-transform") {
+        Vec::new()
+    } else {
+        get_snapshot_transformers(restore_path.last().expect("Path should not be empty"), repo)?
+    };
+
+    let tar_path = follow_path(repo, &restore_path)?;
+    let result = read_snapshot_file(
+        repo,
+        &tar_path,
+        &entry_path,
+        PathOrder::from_config(&repo.config),
+    );
+    remove_restored_tar(repo, &restore_path, &tar_path)?;
+    let Some(TarEntry {
+        path: stored_path,
+        mut contents,
+        ..
+    }) = result?
+    else {
+        return Err(format!("'{}' isn't in snapshot {}", path, snapshot_id));
+    };
+
+    let stored_path_lossy = String::from_utf8_lossy(&stored_path);
+    for transformer in transformers.iter().rev() {
+        contents = transformer.transform_out(&stored_path_lossy, contents)?;
+    }
+    if let Err(err) = fs::write(&output_path, contents) {
+        return Err(format!("Failed to write '{}': {}", output_path, err));
+    }
+
+    println!(
+        "Extracted '{}' from snapshot {} to '{}'",
+        stored_path_lossy, snapshot_id, output_path
+    );
+    Ok(())
+}
+
+/// The path of a file in snapshot tars, given its path relative to the
+/// working directory, without "." components (ex. "./a/b" is "a/b").
+fn normalize_snapshot_path(path: &str) -> Result<Vec<u8>, String> {
+    let mut names = Vec::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::CurDir => {}
+            Component::Normal(name) => names.push(name.as_bytes()),
+            _ => {
+                return Err(format!(
+                    "'{}' must be relative to the working directory, without '..'",
+                    path
+                ));
+            }
+        }
+    }
+    if names.is_empty() {
+        return Err(format!("'{}' isn't the path of a file", path));
+    }
+    Ok(names.join(&b'/'))
+}
+
+/// An entry of a snapshot tar, with its path as it's stored.
+struct TarEntry {
+    path: Vec<u8>,
+    /// The path of the file it links to, if it's a hard link.
+    link_target: Option<Vec<u8>>,
+    contents: Vec<u8>,
+}
+
+/// Reads the stored contents of the file at `path` in a snapshot tar, or
+/// `None` if the tar doesn't have it. Its stored path may differ by case when
+/// `order` ignores case.
+///
+/// Hard links are stored as links to the first path of the file, so the
+/// contents of a hard link are read from its target.
+fn read_snapshot_file(
+    repo: &Repository,
+    tar_path: &str,
+    path: &[u8],
+    order: PathOrder,
+) -> Result<Option<TarEntry>, String> {
+    let Some(entry) = find_tar_entry(repo, tar_path, path, order)? else {
+        return Ok(None);
+    };
+    let Some(target) = &entry.link_target else {
+        return Ok(Some(entry));
+    };
+
+    match find_tar_entry(repo, tar_path, target, PathOrder::Bytes)? {
+        Some(TarEntry {
+            link_target: None,
+            contents,
+            ..
+        }) => Ok(Some(TarEntry {
+            path: entry.path,
+            link_target: None,
+            contents,
+        })),
+        _ => Err(format!(
+            "The hard link '{}' links to '{}', which isn't a file in the snapshot",
+            String::from_utf8_lossy(&entry.path),
+            String::from_utf8_lossy(target)
+        )),
+    }
+}
+
+/// Finds the entry with the path in a tar.
+fn find_tar_entry(
+    repo: &Repository,
+    tar_path: &str,
+    path: &[u8],
+    order: PathOrder,
+) -> Result<Option<TarEntry>, String> {
+    let mut tar_reader = open_tar_gz(repo, tar_path)?;
+
+    for entry in describe_corruption(tar_path, tar_reader.entries())? {
+        let mut entry = describe_corruption(tar_path, entry)?;
+        let entry_path = entry.path_bytes().into_owned();
+        if !order.is_same_path(&entry_path, path) {
+            continue;
+        }
+
+        let link_target = get_link_target(&entry)?;
+        let mut contents = Vec::new();
+        describe_corruption(tar_path, entry.read_to_end(&mut contents))?;
+        return Ok(Some(TarEntry {
+            path: entry_path,
+            link_target,
+            contents,
+        }));
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::normalize_snapshot_path;
+
+    #[test]
+    pub fn normalizes_snapshot_paths() {
+        assert_eq!(normalize_snapshot_path("a/b").unwrap(), b"a/b");
+        assert_eq!(normalize_snapshot_path("./a//b/").unwrap(), b"a/b");
+        assert_eq!(normalize_snapshot_path("a/./b").unwrap(), b"a/b");
+        assert!(normalize_snapshot_path("../a").is_err());
+        assert!(normalize_snapshot_path("a/../b").is_err());
+        assert!(normalize_snapshot_path("/a").is_err());
+        assert!(normalize_snapshot_path(".").is_err());
+    }
+}
@@ -0,0 +1,102 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    io::Read,
+};
+
+use tar::EntryType;
+
+use crate::{
+    arguments,
+    file_structure::{self, StashListFile},
+    repository::Repository,
+    subcommand::restore::{find_restore_path, follow_path, remove_restored_tar},
+    transformer::{
+        chunking::{get_chunk_hashes, remove_unused_chunks},
+        get_chunks_path, get_config_transformer_names, uses_chunking,
+    },
+    util::{
+        archive_utils::{describe_corruption, open_tar_gz},
+        size_util::format_size,
+    },
+};
+
+/// Removes the chunks in the chunk store (see `ChunkingTransformer`) that no
+/// snapshot or stash uses. Chunks are shared between snapshots, so they're
+/// left behind when snapshots are squashed, cleaned or amended, and by
+/// snapshots that stored chunks, then failed or had no changes.
+///
+/// Every snapshot that uses chunking is reconstructed to read its chunk
+/// manifests, so this is slow in large repositories. It mustn't run while a
+/// snapshot is being created, since no snapshot uses the chunks it stores yet.
+///
+/// `-n`/`--dry-run` only counts the chunks that would be removed.
+pub fn main(repo: &Repository, mut args: VecDeque<String>) -> Result<(), String> {
+    let parsed_args = arguments::Parser::new()
+        .flag("-n")
+        .flag("--dry-run")
+        .parse(args.drain(..));
+    let dry_run = parsed_args.flags.contains("-n") || parsed_args.flags.contains("--dry-run");
+
+    file_structure::ensure_jbackup_snapshots_dir_exists(repo)?;
+
+    let mut used_hashes = HashSet::new();
+    for snapshot in file_structure::get_all_snapshot_meta_files(repo)? {
+        // snapshots that don't record their transformers were made with the
+        // config's (see `get_snapshot_transformers`)
+        let names = match &snapshot.transformers {
+            Some(x) => x.clone(),
+            None => get_config_transformer_names(repo),
+        };
+        if !uses_chunking(&names) {
+            continue;
+        }
+
+        let restore_path = find_restore_path(repo, &snapshot.id)?;
+        let tar_path = follow_path(repo, &restore_path)?;
+        let result = add_chunk_hashes(repo, &tar_path, &mut used_hashes);
+        remove_restored_tar(repo, &restore_path, &tar_path)?;
+        result.map_err(|err| format!("{}: {}", snapshot.id, err))?;
+    }
+
+    // stashes are stored with the config's transformers
+    if uses_chunking(&get_config_transformer_names(repo)) {
+        for stash in StashListFile::read(repo)?.stashes {
+            add_chunk_hashes(repo, &stash.get_payload_path(repo), &mut used_hashes)
+                .map_err(|err| format!("Stash {}: {}", stash.id, err))?;
+        }
+    }
+
+    let (num_chunks, total_size) =
+        remove_unused_chunks(&get_chunks_path(repo), &used_hashes, dry_run)?;
+    println!(
+        "{} {} unused chunk(s) ({})",
+        if dry_run { "Would remove" } else { "Removed" },
+        num_chunks,
+        format_size(total_size)
+    );
+
+    Ok(())
+}
+
+/// Adds the hashes of the chunks used by the files in a tar.gz.
+fn add_chunk_hashes(
+    repo: &Repository,
+    tar_path: &str,
+    used_hashes: &mut HashSet<String>,
+) -> Result<(), String> {
+    let mut tar_reader = open_tar_gz(repo, tar_path)?;
+    for entry in describe_corruption(tar_path, tar_reader.entries())? {
+        let mut entry = describe_corruption(tar_path, entry)?;
+        // hard links use the chunks of their target
+        if entry.header().entry_type() != EntryType::Regular {
+            continue;
+        }
+
+        let mut contents = Vec::new();
+        describe_corruption(tar_path, entry.read_to_end(&mut contents))?;
+        for hash in get_chunk_hashes(&contents)? {
+            used_hashes.insert(String::from(hash));
+        }
+    }
+    Ok(())
+}
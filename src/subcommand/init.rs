@@ -1,47 +1,286 @@
 use std::{
     collections::{HashMap, VecDeque},
     fs,
+    os::unix::fs::MetadataExt,
+    path::Path,
 };
 
 use crate::{
-    JBACKUP_PATH, arguments, file_structure, transformer::get_transformer,
-    util::io_util::simplify_result,
+    JBACKUP_PATH, arguments,
+    delta_list::PathOrder,
+    file_structure::{self, DiffDirection, OnLargeFile, SnapshotIdFormat},
+    repository::Repository,
+    subcommand::snapshot::{
+        CreatedSnapshot, SnapshotOptions, SnapshotOutcome, check_import_archive, check_marker_name,
+        parse_max_diff_chain, snapshot,
+    },
+    transformer::get_transformer,
+    util::{
+        archive_utils::{CompressionLevel, TarFormat},
+        io_util::simplify_result,
+        size_util::parse_size,
+    },
 };
 
-/// The init command creates a .jbackup directory in the current working
-/// directory, if one doesn't already exist.
+/// The init command creates a .jbackup directory in `root`, if one doesn't
+/// already exist.
 ///
 /// The .jbackup directory should contain the files: 'branches', 'head', 'config'.
-pub fn main(mut args: VecDeque<String>) -> Result<(), String> {
+///
+/// `--import <archive.tar.gz>` creates the first snapshot from the files in
+/// the archive instead of the working directory, with the message given by
+/// `-m`, to adopt jbackup for existing backups. The archive is checked before
+/// anything is created, and if the snapshot still can't be created, the
+/// .jbackup directory is removed again, so the import can be retried.
+pub fn main(root: &str, mut args: VecDeque<String>) -> Result<(), String> {
     let mut parsed_args = arguments::Parser::new()
+        .option("--import")
+        .option("-m")
         .option("--transformer")
+        .option("--max-file-size")
+        .option("--on-large")
+        .option("--snapshots-dir")
+        .option("--tar-format")
+        .option("--compression")
+        .option("--tmp-dir")
+        .option("--chunking")
+        .option("--mmap")
+        .option("--diff-direction")
+        .option("--snapshot-id-format")
+        .option("--io-buffer-size")
+        .option("--max-diff-chain")
+        .multi_option("--exclude-if-present")
+        .flag("--bare")
+        .flag("--compact-meta")
+        .flag("--exclude-caches")
+        .flag("--one-file-system")
+        .flag("--xdev")
+        .flag("--fsync")
+        .flag("--gzip-meta")
+        .flag("--immutable")
+        .flag("--ignore-case")
+        .flag("--from-empty")
         .parse(args.drain(..));
 
-    let mut transformers = Vec::new();
+    let max_file_size = match parsed_args.options.remove("--max-file-size") {
+        Some(x) => Some(parse_size(&x)?),
+        None => None,
+    };
+    let on_large_file = match parsed_args.options.remove("--on-large") {
+        Some(x) => x.parse()?,
+        None => OnLargeFile::Skip,
+    };
+
+    let chunking = match parsed_args.options.remove("--chunking") {
+        Some(x) => Some(parse_size(&x)?),
+        None => None,
+    };
+
+    let mmap = match parsed_args.options.remove("--mmap") {
+        Some(x) => Some(parse_size(&x)?),
+        None => None,
+    };
+
+    let diff_direction = match parsed_args.options.remove("--diff-direction") {
+        Some(x) => x.parse()?,
+        None => DiffDirection::Reverse,
+    };
+
+    let io_buffer_size = match parsed_args.options.remove("--io-buffer-size") {
+        Some(x) => match parse_size(&x)? {
+            0 => return Err(String::from("The IO buffer size must be at least 1 byte")),
+            x => Some(x),
+        },
+        None => None,
+    };
+
+    let max_diff_chain = parsed_args
+        .options
+        .remove("--max-diff-chain")
+        .map(|x| parse_max_diff_chain(&x))
+        .transpose()?;
+
+    let snapshot_id_format = match parsed_args.options.remove("--snapshot-id-format") {
+        Some(x) => x.parse()?,
+        None => SnapshotIdFormat::TimestampHash,
+    };
+
+    let tar_format = match parsed_args.options.remove("--tar-format") {
+        Some(x) => x.parse()?,
+        None => TarFormat::Gnu,
+    };
+
+    let compression = match parsed_args.options.remove("--compression") {
+        Some(x) => x.parse()?,
+        None => CompressionLevel::Default,
+    };
+
+    let import = parsed_args.options.remove("--import");
+    let message = parsed_args.options.remove("-m");
+    if message.is_some() && import.is_none() {
+        return Err(String::from(
+            "A message (-m) can only be given with --import, for the imported snapshot",
+        ));
+    }
 
-    if let Some(transformer) = parsed_args.options.remove("--transformer") {
-        if let Some(_) = get_transformer(&transformer) {
-            transformers.push(transformer);
-        } else {
-            return Err(String::from("Invalid transformer: '") + &transformer + "'");
+    let mut config = file_structure::ConfigFile {
+        transformers: parsed_args
+            .options
+            .remove("--transformer")
+            .into_iter()
+            .collect(),
+        max_file_size,
+        on_large_file,
+        snapshots_dir: parsed_args.options.remove("--snapshots-dir"),
+        tar_format,
+        compression,
+        tmp_dir: parsed_args.options.remove("--tmp-dir"),
+        chunking,
+        mmap,
+        diff_direction,
+        diff_from_empty: parsed_args.flags.contains("--from-empty"),
+        bare: parsed_args.flags.contains("--bare"),
+        compact_meta: parsed_args.flags.contains("--compact-meta"),
+        exclude_caches: parsed_args.flags.contains("--exclude-caches"),
+        exclude_if_present: parsed_args
+            .multi_options
+            .remove("--exclude-if-present")
+            .unwrap_or_default(),
+        max_diff_chain,
+        fsync: parsed_args.flags.contains("--fsync"),
+        one_file_system: parsed_args.flags.contains("--one-file-system")
+            || parsed_args.flags.contains("--xdev"),
+        gzip_meta: parsed_args.flags.contains("--gzip-meta"),
+        immutable: parsed_args.flags.contains("--immutable"),
+        snapshot_id_format,
+        snapshot_id_seq: 0,
+        ignore_case: parsed_args.flags.contains("--ignore-case"),
+        io_buffer_size,
+    };
+
+    if let Some(archive_path) = &import {
+        if config.bare {
+            return Err(String::from(
+                "An archive can't be imported into a bare repository",
+            ));
         }
+        // the archive is checked before the repository is created, so a bad
+        // archive doesn't leave one behind
+        let order = PathOrder::from_config(&config);
+        let repo = Repository::new(root, config);
+        check_import_archive(&repo, archive_path, order)?;
+        config = repo.config;
+    }
+
+    let warn_about_case = !config.ignore_case && !config.bare;
+    let repo = init(root, config)?;
+
+    if warn_about_case && is_case_insensitive_dir(Path::new(&repo.path(JBACKUP_PATH))) {
+        eprintln!(
+            "Warn: The working directory ignores the case of names, so it may be worth recreating the repository with --ignore-case"
+        );
+    }
+
+    if let Some(archive_path) = import {
+        let created = match import_archive(&repo, &archive_path, message) {
+            Ok(x) => x,
+            Err(err) => {
+                simplify_result(fs::remove_dir_all(repo.path(JBACKUP_PATH)))?;
+                return Err(format!("Failed to import '{}': {}", archive_path, err));
+            }
+        };
+        println!(
+            "Imported {} file(s) from '{}' as snapshot {}",
+            created.stats.num_files, archive_path, created.snapshot.id
+        );
     }
 
-    simplify_result(fs::create_dir(JBACKUP_PATH))?;
+    println!("Successfully initalized jbackup in the current working directory.");
+    Ok(())
+}
+
+/// Creates the first snapshot of a new repository from the files in the
+/// tar.gz at `archive_path`.
+fn import_archive(
+    repo: &Repository,
+    archive_path: &str,
+    message: Option<String>,
+) -> Result<CreatedSnapshot, String> {
+    file_structure::ensure_jbackup_snapshots_dir_exists(repo)?;
+    let options = SnapshotOptions::for_import(repo, String::from(archive_path));
+
+    match snapshot(repo, &options, message, None)? {
+        SnapshotOutcome::Created(created) => Ok(created),
+        SnapshotOutcome::NoChanges(_) => Err(String::from(
+            "The repository already has a snapshot to import into",
+        )),
+    }
+}
+
+/// Whether the filesystem a directory is on ignores the case of names, since
+/// the directory can be found by its name in upper case.
+fn is_case_insensitive_dir(path: &Path) -> bool {
+    let Some(name) = path.file_name() else {
+        return false;
+    };
+    let upper_path = path.with_file_name(name.to_ascii_uppercase());
+    if upper_path == path {
+        return false;
+    }
+    match (fs::metadata(path), fs::metadata(upper_path)) {
+        (Ok(a), Ok(b)) => (a.dev(), a.ino()) == (b.dev(), b.ino()),
+        _ => false,
+    }
+}
+
+/// Creates the .jbackup directory in `root` with the config, returning the
+/// new repository.
+pub fn init(root: &str, config: file_structure::ConfigFile) -> Result<Repository, String> {
+    for transformer in &config.transformers {
+        if get_transformer(transformer, root).is_none() {
+            return Err(String::from("Invalid transformer: '") + transformer + "'");
+        }
+    }
+    for marker in &config.exclude_if_present {
+        check_marker_name(marker)?;
+    }
+    if config.diff_from_empty && config.diff_direction != DiffDirection::Forward {
+        return Err(String::from(
+            "Storing the first snapshot as a diff (--from-empty) is only supported for forward diffs (--diff-direction forward)",
+        ));
+    }
+    if let Some(dir) = &config.snapshots_dir
+        && !Path::new(dir).is_absolute()
+    {
+        return Err(format!(
+            "The snapshots directory must be an absolute path, but got: '{}'",
+            dir
+        ));
+    }
+    if let Some(dir) = &config.tmp_dir
+        && !Path::new(dir).is_absolute()
+    {
+        return Err(format!(
+            "The temp directory must be an absolute path, but got: '{}'",
+            dir
+        ));
+    }
+
+    let repo = Repository::new(root, config);
+    simplify_result(fs::create_dir(repo.path(JBACKUP_PATH)))?;
+
+    // written first, since it decides how the other files are written
+    repo.config.write(&repo.config_path())?;
 
     file_structure::BranchesFile {
         branches: HashMap::new(),
     }
-    .write()?;
+    .write(&repo)?;
 
     file_structure::HeadFile {
         curr_snapshot_id: None,
         curr_branch: String::from("main"),
     }
-    .write()?;
-
-    file_structure::ConfigFile { transformers }.write()?;
-
-    println!("Successfully initalized jbackup in the current working directory.");
-    Ok(())
+    .write(&repo)?;
+    Ok(repo)
 }
@@ -1,27 +1,273 @@
-use crate::file_structure;
+use std::collections::{BTreeSet, HashMap, VecDeque};
 
-pub fn main() -> Result<(), String> {
-    let mut snapshots = file_structure::get_all_snapshot_meta_files()?;
+use crate::{
+    arguments, file_structure,
+    repository::Repository,
+    util::{
+        color_util::{Color, paint},
+        graph_util::render_graph,
+    },
+};
 
-    let timezone = chrono::Local::now().timezone();
+/// Prints every snapshot, oldest first. The id of the snapshot the head points
+/// to is highlighted, and the branches and tags at each snapshot are listed
+/// next to it.
+///
+/// `log <from>..<to>` only prints the snapshots on a path from `<from>` to
+/// `<to>`, including both (see `file_structure::resolve_range`).
+///
+/// `--branch <name>` only prints the snapshots reachable from the tip of the
+/// branch through their parents, which are the snapshots the branch was built
+/// on.
+///
+/// `--graph` prints one line per snapshot, with ASCII edges from each
+/// snapshot to its children.
+///
+/// `--limit <n>` (or `-n <n>`) only prints the newest `n` snapshots, and
+/// `--reverse` prints them newest first. The graph is always printed oldest
+/// first, since its edges lead down to children.
+pub fn main(repo: &Repository, mut args: VecDeque<String>) -> Result<(), String> {
+    let mut parsed_args = arguments::Parser::new()
+        .flag("--graph")
+        .flag("--reverse")
+        .option("--limit")
+        .option("--branch")
+        .option("-n")
+        .parse(args.drain(..));
 
-    snapshots.sort_by_key(|x| x.date);
+    let graph = parsed_args.flags.contains("--graph");
+    let reverse = parsed_args.flags.contains("--reverse");
+    if graph && reverse {
+        return Err(String::from("--reverse can't be combined with --graph"));
+    }
+    let limit = match (
+        parsed_args.options.remove("--limit"),
+        parsed_args.options.remove("-n"),
+    ) {
+        (Some(_), Some(_)) => {
+            return Err(String::from("Only one of --limit and -n can be given"));
+        }
+        (Some(x), None) | (None, Some(x)) => Some(parse_limit(&x)?),
+        (None, None) => None,
+    };
 
-    for meta in snapshots {
-        let timestamp = match chrono::DateTime::from_timestamp(meta.date, 0) {
-            None => String::from("Invalid date"),
-            Some(d) => d
-                .with_timezone(&timezone)
-                .format("%Y/%m/%d %H:%M:%S")
-                .to_string(),
+    let head_snapshot_id = file_structure::HeadFile::read(repo)?.curr_snapshot_id;
+    let branches_file = file_structure::BranchesFile::read(repo)?;
+    let refs = find_refs(&branches_file, &file_structure::TagsFile::read(repo)?);
+
+    let mut snapshots = read_log(repo)?;
+    if let Some(range) = parsed_args.normal.pop_front() {
+        let (from_id, to_id) = file_structure::resolve_range(repo, &range)?;
+        let snapshots_by_id: HashMap<String, file_structure::SnapshotMetaFile> =
+            snapshots.into_iter().map(|x| (x.id.clone(), x)).collect();
+        let between =
+            file_structure::find_snapshots_between(&snapshots_by_id, from_id.as_deref(), &to_id)?;
+
+        snapshots = snapshots_by_id
+            .into_values()
+            .filter(|x| between.contains(&x.id))
+            .collect();
+        snapshots.sort_by_key(|x| x.date);
+    }
+    if let Some(branch) = parsed_args.options.remove("--branch") {
+        let Some(tip_id) = branches_file.branches.get(&branch) else {
+            return Err(format!("The branch '{}' doesn't exist", branch));
         };
+        let snapshots_by_id: HashMap<String, file_structure::SnapshotMetaFile> = read_log(repo)?
+            .into_iter()
+            .map(|x| (x.id.clone(), x))
+            .collect();
+        let on_branch = file_structure::find_snapshots_between(&snapshots_by_id, None, tip_id)?;
+        snapshots.retain(|x| on_branch.contains(&x.id));
+    }
+
+    // snapshots created in the same second are only in order once children
+    // come after their parents, which the limit and reversing rely on
+    let mut snapshots = sort_parents_first(snapshots);
+    if let Some(limit) = limit {
+        snapshots.drain(..snapshots.len().saturating_sub(limit));
+    }
+
+    if graph {
+        print_graph(snapshots, head_snapshot_id.as_deref(), &refs);
+        return Ok(());
+    }
+    if reverse {
+        snapshots.reverse();
+    }
+
+    for meta in snapshots {
+        let timestamp = format_timestamp(meta.date);
 
         match meta.message {
             None => {}
             Some(s) => println!("Message:   {}", &s),
         }
-        println!("Timestamp: {}\nId:        {}\n", timestamp, meta.id);
+        let id_color = if head_snapshot_id.as_ref() == Some(&meta.id) {
+            Color::Green
+        } else {
+            Color::Yellow
+        };
+        if let Some(names) = refs.get(&meta.id) {
+            println!("Refs:      {}", names.join(", "));
+        }
+        println!(
+            "Timestamp: {}\nId:        {}\n",
+            timestamp,
+            paint(&meta.id, id_color)
+        );
     }
 
     Ok(())
 }
+
+/// Parses the number of snapshots given to `--limit`, which must be positive.
+fn parse_limit(limit: &str) -> Result<usize, String> {
+    match limit.parse::<usize>() {
+        Ok(x) if x > 0 => Ok(x),
+        _ => Err(format!(
+            "Invalid limit: '{}' (expected a positive number)",
+            limit
+        )),
+    }
+}
+
+/// The names of the branches and tags at each snapshot, by snapshot id.
+/// Branches come before tags, which are prefixed with "tag: ", and each are
+/// sorted by name.
+fn find_refs(
+    branches_file: &file_structure::BranchesFile,
+    tags_file: &file_structure::TagsFile,
+) -> HashMap<String, Vec<String>> {
+    let mut branches: Vec<(&String, &String)> = branches_file.branches.iter().collect();
+    let mut tags: Vec<(&String, &String)> = tags_file.tags.iter().collect();
+    branches.sort();
+    tags.sort();
+
+    let mut refs: HashMap<String, Vec<String>> = HashMap::new();
+    for (name, snapshot_id) in branches {
+        refs.entry(snapshot_id.clone())
+            .or_default()
+            .push(name.clone());
+    }
+    for (name, snapshot_id) in tags {
+        refs.entry(snapshot_id.clone())
+            .or_default()
+            .push(format!("tag: {}", name));
+    }
+    refs
+}
+
+/// Prints the snapshots, oldest first, as a graph with ASCII edges from each
+/// snapshot to its children. `snapshots` must be sorted so every snapshot
+/// comes before its children.
+fn print_graph(
+    snapshots: Vec<file_structure::SnapshotMetaFile>,
+    head_snapshot_id: Option<&str>,
+    refs: &HashMap<String, Vec<String>>,
+) {
+    let indices: HashMap<&str, usize> = snapshots
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (x.id.as_str(), i))
+        .collect();
+    let children: Vec<Vec<usize>> = snapshots
+        .iter()
+        .map(|x| {
+            x.children
+                .iter()
+                .filter_map(|child| indices.get(child.as_str()).copied())
+                .collect()
+        })
+        .collect();
+
+    for line in render_graph(&children) {
+        let Some(node) = line.node else {
+            println!("{}", line.graph);
+            continue;
+        };
+        let meta = &snapshots[node];
+        let id_color = if head_snapshot_id == Some(meta.id.as_str()) {
+            Color::Green
+        } else {
+            Color::Yellow
+        };
+        println!(
+            "{} {}{} {}{}",
+            line.graph,
+            paint(&meta.id, id_color),
+            match refs.get(&meta.id) {
+                Some(names) => format!(" ({})", names.join(", ")),
+                None => String::new(),
+            },
+            format_timestamp(meta.date),
+            match &meta.message {
+                Some(message) => format!(" {}", message),
+                None => String::new(),
+            }
+        );
+    }
+}
+
+/// Sorts snapshots that are sorted oldest first so every snapshot also comes
+/// after its parents, which snapshots created in the same second may not.
+pub fn sort_parents_first(
+    snapshots: Vec<file_structure::SnapshotMetaFile>,
+) -> Vec<file_structure::SnapshotMetaFile> {
+    let indices: HashMap<&str, usize> = snapshots
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (x.id.as_str(), i))
+        .collect();
+    let mut num_unsorted_parents: Vec<usize> = snapshots
+        .iter()
+        .map(|x| {
+            x.parents
+                .iter()
+                .filter(|parent| indices.contains_key(parent.as_str()))
+                .count()
+        })
+        .collect();
+
+    // the oldest snapshot with no unsorted parents is always sorted next
+    let mut ready: BTreeSet<usize> = (0..snapshots.len())
+        .filter(|&i| num_unsorted_parents[i] == 0)
+        .collect();
+    let mut order = Vec::new();
+    while let Some(i) = ready.pop_first() {
+        order.push(i);
+        for child in &snapshots[i].children {
+            if let Some(&child) = indices.get(child.as_str()) {
+                num_unsorted_parents[child] -= 1;
+                if num_unsorted_parents[child] == 0 {
+                    ready.insert(child);
+                }
+            }
+        }
+    }
+
+    let mut snapshots: Vec<Option<file_structure::SnapshotMetaFile>> =
+        snapshots.into_iter().map(Some).collect();
+    order
+        .into_iter()
+        .filter_map(|i| snapshots[i].take())
+        .collect()
+}
+
+/// Reads all snapshots, oldest first.
+pub fn read_log(repo: &Repository) -> Result<Vec<file_structure::SnapshotMetaFile>, String> {
+    let mut snapshots = file_structure::get_all_snapshot_meta_files(repo)?;
+    snapshots.sort_by_key(|x| x.date);
+    Ok(snapshots)
+}
+
+/// Formats a unix timestamp (in seconds) in the local timezone.
+pub fn format_timestamp(timestamp: i64) -> String {
+    match chrono::DateTime::from_timestamp(timestamp, 0) {
+        None => String::from("Invalid date"),
+        Some(d) => d
+            .with_timezone(&chrono::Local::now().timezone())
+            .format("%Y/%m/%d %H:%M:%S")
+            .to_string(),
+    }
+}
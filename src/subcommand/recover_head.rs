@@ -0,0 +1,190 @@
+use std::{collections::VecDeque, fs, path::Path, time::SystemTime};
+
+use crate::{
+    BRANCHES_PATH, CONFIG_PATH, arguments,
+    file_structure::{BranchesFile, HeadFile, ReflogEntry, ReflogFile, SnapshotMetaFile},
+    repository::Repository,
+    subcommand::snapshot::to_timestamp,
+};
+
+/// Rebuilds the head file when it's missing or corrupt, from the branches and
+/// the reflog (see `guess_head`).
+///
+/// Since the head is a guess, it's only printed unless `-f` is specified. The
+/// head is never rewritten if it can be read.
+pub fn main(root: &str, mut args: VecDeque<String>) -> Result<(), String> {
+    let parsed_args = arguments::Parser::new().flag("-f").parse(args.drain(..));
+    let force = parsed_args.flags.contains("-f");
+
+    // the head file is usually checked with the rest of the repository, which
+    // fails without it
+    let repo_path = |path| format!("{}/{}", root, path);
+    if !Path::new(&repo_path(BRANCHES_PATH)).exists()
+        || !Path::new(&repo_path(CONFIG_PATH)).exists()
+    {
+        return Err(String::from(
+            "A jbackup repository with branches was not found in the current working directory",
+        ));
+    }
+    let repo = Repository::open(root)?;
+
+    match HeadFile::read_without_hint(&repo) {
+        Ok(head) => {
+            println!(
+                "The head file is fine (on branch '{}'), so there's nothing to recover.",
+                head.curr_branch
+            );
+            return Ok(());
+        }
+        Err(err) => println!("The head file couldn't be read: {}", err),
+    }
+
+    let branches = BranchesFile::read(&repo)?;
+    let last_head_id = match ReflogFile::read(&repo) {
+        Ok(entries) => entries.last().and_then(|x| x.new_snapshot_id.clone()),
+        Err(err) => {
+            eprintln!(
+                "Warn: Ignoring the reflog, since it couldn't be read: {}",
+                err
+            );
+            None
+        }
+    };
+    let (head, reason) = guess_head(
+        &branches,
+        last_head_id.as_deref(),
+        |id| fs::exists(SnapshotMetaFile::get_meta_file_path(&repo, id)).unwrap_or(false),
+        |id| Ok(SnapshotMetaFile::read(&repo, id)?.date),
+    )?;
+
+    println!(
+        "Guessed head: branch '{}' at snapshot {}, since {}.",
+        head.curr_branch,
+        head.curr_snapshot_id.as_deref().unwrap_or("NULL"),
+        reason
+    );
+    if !force {
+        println!("Run 'jbackup recover-head -f' to write it to the head file.");
+        return Ok(());
+    }
+
+    let new_snapshot_id = head.curr_snapshot_id.clone();
+    head.write(&repo)?;
+    ReflogFile::append(
+        &repo,
+        ReflogEntry {
+            date: to_timestamp(SystemTime::now())?,
+            old_snapshot_id: None,
+            new_snapshot_id,
+            operation: String::from("recover-head"),
+        },
+    )?;
+    println!("Wrote the head file.");
+    Ok(())
+}
+
+/// Guesses what the head was from the branches, since the head always points
+/// to the tip of its branch after a snapshot. Returns the head, and why it
+/// was chosen. In order of preference, the head is on:
+///
+/// - a branch at `last_head_id`, the snapshot the head last moved to
+///   according to the reflog
+/// - `main`, the branch new repositories start on
+/// - the branch whose tip is the newest snapshot
+///
+/// Ties are broken by preferring `main`, then by name. Without branches,
+/// there are no snapshots on any branch, so the head is on `main` at the
+/// snapshot from the reflog, if it exists.
+fn guess_head(
+    branches: &BranchesFile,
+    last_head_id: Option<&str>,
+    snapshot_exists: impl Fn(&str) -> bool,
+    snapshot_date: impl Fn(&str) -> Result<i64, String>,
+) -> Result<(HeadFile, &'static str), String> {
+    let mut names: Vec<&String> = branches.branches.keys().collect();
+    names.sort_by_key(|name| (*name != "main", *name));
+
+    let on_branch = |name: &str, reason| {
+        let head = HeadFile {
+            curr_snapshot_id: Some(branches.branches[name].clone()),
+            curr_branch: String::from(name),
+        };
+        Ok((head, reason))
+    };
+
+    if let Some(last_head_id) = last_head_id
+        && let Some(name) = names
+            .iter()
+            .find(|x| branches.branches[**x] == last_head_id)
+    {
+        return on_branch(name, "the reflog shows the head last moved to it");
+    }
+
+    let Some(first_name) = names.first() else {
+        let curr_snapshot_id = last_head_id.filter(|id| snapshot_exists(id));
+        let reason = match curr_snapshot_id {
+            Some(_) => "there are no branches, and the reflog shows the head last moved to it",
+            None => "there are no branches or snapshots in the reflog",
+        };
+        let head = HeadFile {
+            curr_snapshot_id: curr_snapshot_id.map(String::from),
+            curr_branch: String::from("main"),
+        };
+        return Ok((head, reason));
+    };
+    if *first_name == "main" {
+        return on_branch("main", "it's the main branch");
+    }
+
+    let mut newest = (first_name, snapshot_date(&branches.branches[*first_name])?);
+    for name in &names[1..] {
+        let date = snapshot_date(&branches.branches[*name])?;
+        if date > newest.1 {
+            newest = (name, date);
+        }
+    }
+    on_branch(newest.0, "its tip is the newest snapshot on a branch")
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use crate::file_structure::BranchesFile;
+
+    use super::guess_head;
+
+    fn guess(branches: &[(&str, &str)], last_head_id: Option<&str>) -> (String, Option<String>) {
+        let branches = BranchesFile {
+            branches: branches
+                .iter()
+                .map(|(name, id)| (String::from(*name), String::from(*id)))
+                .collect::<HashMap<_, _>>(),
+        };
+        // snapshot ids start with their date
+        let (head, _) = guess_head(
+            &branches,
+            last_head_id,
+            |_| false,
+            |id| Ok(id.split('-').next().unwrap().parse().unwrap()),
+        )
+        .unwrap();
+        (head.curr_branch, head.curr_snapshot_id)
+    }
+
+    #[test]
+    pub fn guesses_head_from_branches() {
+        let branches = [("main", "1-a"), ("nether", "3-c"), ("end", "2-b")];
+        let on = |branch: &str, id: &str| (String::from(branch), Some(String::from(id)));
+
+        assert_eq!(guess(&branches, Some("2-b")), on("end", "2-b"));
+        assert_eq!(guess(&branches, Some("4-d")), on("main", "1-a"));
+        assert_eq!(guess(&branches, None), on("main", "1-a"));
+        assert_eq!(guess(&branches[1..], None), on("nether", "3-c"));
+        assert_eq!(
+            guess(&[("main", "1-a"), ("copy", "1-a")], Some("1-a")),
+            on("main", "1-a")
+        );
+        assert_eq!(guess(&[], None), (String::from("main"), None));
+    }
+}
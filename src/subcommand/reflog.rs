@@ -0,0 +1,20 @@
+use crate::{
+    file_structure::ReflogFile, repository::Repository, subcommand::log::format_timestamp,
+};
+
+/// Prints every movement of the head, newest first.
+pub fn main(repo: &Repository) -> Result<(), String> {
+    let entries = ReflogFile::read(repo)?;
+
+    for entry in entries.iter().rev() {
+        println!(
+            "{}  {}: {} -> {}",
+            format_timestamp(entry.date),
+            entry.operation,
+            entry.old_snapshot_id.as_deref().unwrap_or("NULL"),
+            entry.new_snapshot_id.as_deref().unwrap_or("NULL"),
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,16 @@
+use std::collections::VecDeque;
+
+use crate::{file_structure, repository::Repository};
+
+/// Renames a branch (see `file_structure::rename_branch`).
+pub fn main(repo: &Repository, mut args: VecDeque<String>) -> Result<(), String> {
+    let (Some(old_name), Some(new_name)) = (args.pop_front(), args.pop_front()) else {
+        return Err(String::from(
+            "Please specify the branch to rename and its new name",
+        ));
+    };
+
+    file_structure::rename_branch(repo, &old_name, &new_name)?;
+    println!("Renamed branch '{}' to '{}'", old_name, new_name);
+    Ok(())
+}
@@ -0,0 +1,1125 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, Read, Write},
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Component, Path, PathBuf},
+    process,
+};
+
+use tar::EntryType;
+
+use crate::{
+    arguments,
+    delta_list::{PathOrder, fold_case, restore_from_delta_list},
+    file_structure::{
+        self, EMPTY_SNAPSHOT_ID, RestoreProgressFile, SnapshotFullType, SnapshotMetaFile,
+        SnapshotWalk,
+    },
+    repository::Repository,
+    subcommand::snapshot::{DirExclusions, get_ignored_dirs, walk_file_tree},
+    transformer::{FileTransformer, get_snapshot_transformers},
+    util::{
+        archive_utils::{
+            ArchiveFormat, TarStream, create_gz_writer, create_tar_gz, create_tar_gz_writer,
+            create_tar_writer, describe_corruption, get_link_target, open_delta_list, open_payload,
+            open_tar_gz, verify_gz, write_payload_tar_gz,
+        },
+        color_util::{Color, paint},
+        glob_util::glob_matches,
+        io_util::{is_process_running, simplify_result, write_sparse},
+        progress_util::{self, ProgressEvent},
+    },
+};
+
+/// Restores the working directory to the state of a snapshot.
+///
+/// If `--path <glob>` is specified, only the files matching the glob are
+/// restored, and all other files are left untouched. Otherwise, the working
+/// directory is overwritten with the contents of the snapshot, and files
+/// that are not in the snapshot are removed, if the snapshot would have stored
+/// them (see `SnapshotWalk`). Files the snapshot left out on purpose (ex.
+/// with `--skip-non-utf8`) are kept.
+///
+/// Reconstructing a snapshot through a long chain of diffs can take a while,
+/// so if a restore is interrupted, restoring the same snapshot again continues
+/// from the last snapshot in the chain that was reconstructed. `--abort`
+/// removes the files of an interrupted restore instead.
+///
+/// Files are restored with the transformers the snapshot was created with,
+/// which may differ from the config. `--no-transform` restores files as
+/// they're stored, without reversing any transformers, to debug transformers
+/// or recover files when a transformer is broken.
+///
+/// `--keep-going` skips files that can't be restored, and fails after the
+/// rest are restored, listing them. The skipped files are left as they are.
+///
+/// `--backup` moves files that would be overwritten with other contents or
+/// removed aside instead (see `back_up_file`), and lists them at the end.
+///
+/// `--sparse` writes runs of zero bytes as holes (see `write_sparse`), for
+/// sparse files like VM disk images.
+pub fn main(repo: &Repository, mut args: VecDeque<String>) -> Result<(), String> {
+    let mut parsed_args = arguments::Parser::new()
+        .flag("--abort")
+        .flag("--no-transform")
+        .flag("--backup")
+        .flag("--keep-going")
+        .flag("--sparse")
+        .option("--path")
+        .parse(args.drain(..));
+
+    if parsed_args.flags.contains("--abort") {
+        file_structure::ensure_jbackup_snapshots_dir_exists(repo)?;
+        let num_removed = remove_interrupted_restore(repo)?;
+        println!(
+            "Removed {} file(s) from an interrupted restore",
+            num_removed
+        );
+        return Ok(());
+    }
+
+    let Some(reference) = parsed_args.normal.pop_front() else {
+        return Err(String::from("Please specify a snapshot"));
+    };
+    let path_glob = parsed_args.options.remove("--path");
+
+    restore(
+        repo,
+        &reference,
+        path_glob.as_deref(),
+        parsed_args.flags.contains("--no-transform"),
+        parsed_args.flags.contains("--backup"),
+        parsed_args.flags.contains("--keep-going"),
+        parsed_args.flags.contains("--sparse"),
+    )
+}
+
+/// Restores the working directory to the state of the snapshot `reference`
+/// points to. If `path_glob` is set, only the matching files are restored.
+/// If `no_transform` is set, the configured transformers aren't reversed.
+/// If `backup` is set, files are moved aside instead of being overwritten or
+/// removed. If `keep_going` is set, files that can't be restored are skipped,
+/// and the restore fails after restoring the other files. If `sparse` is set,
+/// runs of zero bytes are written as holes.
+pub fn restore(
+    repo: &Repository,
+    reference: &str,
+    path_glob: Option<&str>,
+    no_transform: bool,
+    backup: bool,
+    keep_going: bool,
+    sparse: bool,
+) -> Result<(), String> {
+    repo.config.ensure_not_bare()?;
+
+    let snapshot_id = file_structure::resolve_ref(repo, reference)?;
+    let path = find_restore_path(repo, &snapshot_id)?;
+    let target_snapshot = path.last().expect("Path should not be empty");
+    let transformers = if no_transform {
+        Vec::new()
+    } else {
+        get_snapshot_transformers(target_snapshot, repo)?
+    };
+    let walk = target_snapshot.walk.clone();
+
+    let tar_path = follow_path(repo, &path)?;
+
+    // (original path, backup path) of the files that were moved aside
+    let mut backed_up_paths = Vec::new();
+    let mut failed_paths = Vec::new();
+    let extract_result = extract_tar(
+        repo,
+        &tar_path,
+        repo.root(),
+        &transformers,
+        |entry_path| match path_glob {
+            Some(glob) => glob_matches(glob, entry_path),
+            None => true,
+        },
+        |output_path, contents| {
+            if backup
+                && is_changed_file(output_path, contents)?
+                && let Some(backup_path) = back_up_file(output_path)?
+            {
+                backed_up_paths.push((output_path.to_path_buf(), backup_path));
+            }
+            Ok(())
+        },
+        keep_going.then_some(&mut failed_paths),
+        sparse,
+    );
+
+    remove_restored_tar(repo, &path, &tar_path)?;
+
+    let restored_paths = extract_result?;
+
+    if path_glob.is_none()
+        && let Some(walk) = &walk
+    {
+        // files that couldn't be restored are in the snapshot, so they're kept
+        let snapshot_paths: HashSet<&Path> = restored_paths
+            .iter()
+            .chain(failed_paths.iter().map(|(path, _)| path))
+            .map(|p| p.as_path())
+            .collect();
+        let paths_not_in_snapshot = find_files_not_in_snapshot(repo, walk, &snapshot_paths)?;
+
+        if backup {
+            for file_path in paths_not_in_snapshot {
+                // backups from earlier restores are kept as they are
+                if is_backup_path(&file_path) {
+                    continue;
+                }
+                if let Some(backup_path) = back_up_file(&file_path)? {
+                    backed_up_paths.push((file_path, backup_path));
+                }
+            }
+        } else {
+            remove_files(repo, &paths_not_in_snapshot)?;
+        }
+    }
+
+    println!(
+        "Restored {} file(s) from snapshot {}",
+        restored_paths.len(),
+        snapshot_id
+    );
+    if path_glob.is_none() && walk.is_none() {
+        println!(
+            "Kept the files that aren't in the snapshot, since it doesn't record which files it left out"
+        );
+    }
+    if !backed_up_paths.is_empty() {
+        println!(
+            "Backed up {} file(s) that were overwritten or removed:",
+            backed_up_paths.len()
+        );
+        for (file_path, backup_path) in &backed_up_paths {
+            println!(
+                "  {} -> {}",
+                file_path.to_string_lossy(),
+                backup_path.to_string_lossy()
+            );
+        }
+    }
+
+    if !failed_paths.is_empty() {
+        for (path, err) in &failed_paths {
+            eprintln!(
+                "Error: Failed to restore '{}': {}",
+                path.to_string_lossy(),
+                err
+            );
+        }
+        return Err(format!(
+            "{} file(s) couldn't be restored, and were left as they are",
+            failed_paths.len()
+        ));
+    }
+
+    Ok(())
+}
+
+const BACKUP_SUFFIX: &str = ".jbackup-orig";
+
+/// Whether the file at `path` exists, and differs from `contents` (or is
+/// about to be replaced with a hard link, if `contents` is `None`).
+fn is_changed_file(path: &Path, contents: Option<&[u8]>) -> Result<bool, String> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_file() => {}
+        // a directory that became a file is replaced by the file
+        Ok(metadata) if metadata.is_dir() => return Ok(true),
+        Ok(_) => return Ok(false),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err.to_string()),
+    }
+    match contents {
+        Some(contents) => Ok(simplify_result(fs::read(path))? != contents),
+        None => Ok(true),
+    }
+}
+
+/// Moves a file aside to `{path}.jbackup-orig`, or `{path}.jbackup-orig.N`
+/// if an older backup has that name, so it's never overwritten. Returns the
+/// path of the backup, or `None` if there's no file at `path`.
+fn back_up_file(path: &Path) -> Result<Option<PathBuf>, String> {
+    if !simplify_result(fs::exists(path))? {
+        return Ok(None);
+    }
+
+    let mut backup_path = path.as_os_str().to_owned();
+    backup_path.push(BACKUP_SUFFIX);
+    let mut backup_path = PathBuf::from(backup_path);
+    let mut n = 1;
+    while simplify_result(fs::exists(&backup_path))? {
+        let mut numbered_path = path.as_os_str().to_owned();
+        numbered_path.push(format!("{}.{}", BACKUP_SUFFIX, n));
+        backup_path = PathBuf::from(numbered_path);
+        n += 1;
+    }
+
+    simplify_result(fs::rename(path, &backup_path))?;
+    Ok(Some(backup_path))
+}
+
+/// Whether the file is a backup made by `back_up_file`, or is in a directory
+/// that was backed up.
+fn is_backup_path(path: &Path) -> bool {
+    path.components().any(|component| {
+        component
+            .as_os_str()
+            .as_bytes()
+            .windows(BACKUP_SUFFIX.len())
+            .any(|x| x == BACKUP_SUFFIX.as_bytes())
+    })
+}
+
+/// Removes files in the working directory that were not restored from the
+/// snapshot, but would have been stored by `walk`.
+pub fn remove_files_not_in_snapshot(
+    repo: &Repository,
+    walk: &SnapshotWalk,
+    restored_paths: &[PathBuf],
+) -> Result<(), String> {
+    let restored_paths: HashSet<&Path> = restored_paths.iter().map(|p| p.as_path()).collect();
+    remove_files(
+        repo,
+        &find_files_not_in_snapshot(repo, walk, &restored_paths)?,
+    )
+}
+
+/// Finds the files in the working directory that are not in `snapshot_paths`
+/// (relative to the working directory), but would have been stored by a
+/// snapshot with `walk`. The returned paths start with the repository's root.
+///
+/// If the repository ignores case, a file whose path differs only by case
+/// from one in the snapshot is the same file, so it's not returned.
+pub fn find_files_not_in_snapshot(
+    repo: &Repository,
+    walk: &SnapshotWalk,
+    snapshot_paths: &HashSet<&Path>,
+) -> Result<Vec<PathBuf>, String> {
+    let mut paths_not_in_snapshot = Vec::new();
+
+    // directories left out of the snapshot aren't removed either
+    let exclusions = DirExclusions::from_walk(repo, walk);
+    let order = PathOrder::from_config(&repo.config);
+    let folded_snapshot_paths: HashSet<Vec<u8>> = match order {
+        PathOrder::Bytes => HashSet::new(),
+        PathOrder::IgnoreCase => snapshot_paths
+            .iter()
+            .map(|path| fold_case(path.as_os_str().as_bytes()))
+            .collect(),
+    };
+    walk_file_tree(
+        repo.root().into(),
+        &get_ignored_dirs(repo)?,
+        &exclusions,
+        order,
+        &mut |file_path| {
+            let file_path = PathBuf::from(file_path);
+            let relative_path = file_path.strip_prefix(repo.root()).unwrap_or(&file_path);
+
+            let in_snapshot = match order {
+                PathOrder::Bytes => snapshot_paths.contains(relative_path),
+                PathOrder::IgnoreCase => {
+                    folded_snapshot_paths.contains(&fold_case(relative_path.as_os_str().as_bytes()))
+                }
+            };
+            if !in_snapshot && is_walked(walk, &file_path) {
+                paths_not_in_snapshot.push(file_path);
+            }
+            Ok(())
+        },
+    )?;
+
+    Ok(paths_not_in_snapshot)
+}
+
+/// Whether a snapshot with `walk` would have stored the file at `path`, one of
+/// the files `walk_file_tree` visits.
+fn is_walked(walk: &SnapshotWalk, path: &Path) -> bool {
+    if walk.skip_non_utf8 && path.to_str().is_none() {
+        return false;
+    }
+    match walk.max_file_size {
+        // a file whose size can't be read is kept, like the large files
+        Some(max_size) => fs::metadata(path).is_ok_and(|x| x.len() <= max_size),
+        None => true,
+    }
+}
+
+/// Removes the files, and the directories that are left empty by removing
+/// them.
+pub fn remove_files(repo: &Repository, paths_to_remove: &[PathBuf]) -> Result<(), String> {
+    for file_path in paths_to_remove {
+        println!(
+            "{}",
+            paint(
+                &format!("Removing: {}", file_path.to_string_lossy()),
+                Color::Red
+            )
+        );
+        simplify_result(fs::remove_file(file_path))?;
+    }
+
+    // snapshots only store files, so directories that are left empty by
+    // removing files aren't in the snapshot either
+    for file_path in paths_to_remove {
+        for dir in file_path.ancestors().skip(1) {
+            if dir == Path::new(repo.root()) || !is_empty_dir(dir)? {
+                break;
+            }
+            simplify_result(fs::remove_dir(dir))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn is_empty_dir(path: &Path) -> Result<bool, String> {
+    match fs::read_dir(path) {
+        Ok(mut entries) => Ok(entries.next().is_none()),
+        // already removed while cleaning up another file
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Finds the snapshots that need to be restored, in order, to reconstruct
+/// the specified snapshot. The first snapshot in the returned path always has
+/// a full payload, or is the empty snapshot (see `SnapshotMetaFile::empty`),
+/// and the last snapshot is the specified snapshot.
+pub fn find_restore_path(
+    repo: &Repository,
+    snapshot_id: &str,
+) -> Result<Vec<SnapshotMetaFile>, String> {
+    let mut snapshots = HashMap::new();
+    for snapshot in file_structure::get_all_snapshot_meta_files(repo)? {
+        snapshots.insert(String::from(&snapshot.id), snapshot);
+    }
+
+    if snapshots.is_empty() {
+        return Err(String::from("There are no snapshots in this repository."));
+    }
+
+    let mut path = Vec::new();
+    let mut path_found = false;
+
+    let mut curr = snapshots.remove(snapshot_id);
+
+    // very simple algorithm of following the child until we find a full snapshot
+    while let Some(snapshot) = curr.take() {
+        curr = snapshot
+            .diff_children
+            .first()
+            .and_then(|x| snapshots.remove(x));
+
+        let is_full_type = snapshot.full_type != SnapshotFullType::None;
+        let is_from_empty = snapshot
+            .diff_children
+            .first()
+            .is_some_and(|x| x == EMPTY_SNAPSHOT_ID);
+
+        path.push(snapshot);
+
+        if is_full_type {
+            path_found = true;
+            break;
+        }
+        if is_from_empty {
+            path.push(SnapshotMetaFile::empty());
+            path_found = true;
+            break;
+        }
+    }
+
+    if !path_found {
+        return Err(format!("Path not found to {}", snapshot_id));
+    }
+
+    path.reverse();
+    Ok(path)
+}
+
+/// Returns a string with the final generated file
+pub fn follow_path(repo: &Repository, path: &[SnapshotMetaFile]) -> Result<String, String> {
+    if path.is_empty() {
+        return Err(String::from("Generated snapshot path was empty"));
+    }
+
+    let first_snapshot = path.first().expect("Path should not be empty");
+    // delta lists are applied to tar.gz files, so other payloads (and the
+    // empty snapshot) are written to one first
+    let is_tar_gz = first_snapshot.full_type == SnapshotFullType::TarGz;
+
+    let target_snapshot = path.last().expect("Path should not be empty");
+    let resume_point = find_resume_point(repo, path)?;
+    let start_i = resume_point.as_ref().map_or(0, |(i, _)| *i);
+
+    let mut prev_snapshot_id = path[start_i].id.clone();
+    let mut prev_tar_path = if let Some((_, tar_path)) = resume_point {
+        progress_util::report_text(&format!(
+            "Resuming an interrupted restore from snapshot {}",
+            &prev_snapshot_id
+        ));
+
+        // the tar was left behind by another process, so it's checked before
+        // the rest of the chain is replayed on it
+        if let Err(err) = verify_gz(repo, &tar_path) {
+            return Err(format!(
+                "Can't resume the interrupted restore: {}. Use 'restore --abort' to remove it.",
+                err
+            ));
+        }
+        tar_path
+    } else if is_tar_gz {
+        repo.prepend_snapshot_path(&first_snapshot.get_full_payload_filename()?)
+    } else {
+        let tar_path = get_restored_tar_path(repo, process::id(), &first_snapshot.id);
+        write_payload_tar_gz(repo, first_snapshot, &tar_path)?;
+        tar_path
+    };
+    let mut delete_prev_tar_path = start_i != 0 || !is_tar_gz; // don't delete the full payload
+    let order = PathOrder::from_config(&repo.config);
+
+    for (i, next_snapshot) in path.iter().enumerate().skip(start_i + 1) {
+        let new_tar_path = get_restored_tar_path(repo, process::id(), &next_snapshot.id);
+
+        restore_from_delta_list(
+            open_tar_gz(repo, &prev_tar_path)?,
+            create_tar_gz(repo, &new_tar_path)?,
+            open_delta_list(
+                repo,
+                &repo.prepend_snapshot_path(
+                    &next_snapshot.get_diff_path_from_child_snapshot(&prev_snapshot_id),
+                ),
+            )?,
+            order,
+        )?;
+
+        progress_util::report(ProgressEvent::Snapshot {
+            id: &next_snapshot.id,
+            done: i as u64,
+            total: (path.len() - 1) as u64,
+        });
+
+        RestoreProgressFile {
+            target_snapshot_id: target_snapshot.id.clone(),
+            completed_snapshot_id: next_snapshot.id.clone(),
+            pid: Some(process::id()),
+        }
+        .write(repo)?;
+
+        if delete_prev_tar_path {
+            progress_util::report_text(&format!("Deleting {}", &prev_tar_path));
+            simplify_result(fs::remove_file(prev_tar_path))?;
+        }
+
+        prev_snapshot_id = next_snapshot.id.clone();
+        prev_tar_path = new_tar_path;
+        delete_prev_tar_path = true;
+    }
+
+    Ok(prev_tar_path)
+}
+
+/// Removes the tar returned by `follow_path` once it's no longer needed, which
+/// also completes the restore.
+pub fn remove_restored_tar(
+    repo: &Repository,
+    path: &[SnapshotMetaFile],
+    tar_path: &str,
+) -> Result<(), String> {
+    // the first snapshot in the path is the full payload, which must be kept,
+    // unless it isn't a tar.gz, so its tar was written by `follow_path`
+    if path.len() > 1 || path[0].full_type != SnapshotFullType::TarGz {
+        simplify_result(fs::remove_file(tar_path))?;
+    }
+    RestoreProgressFile::remove(repo)
+}
+
+/// Tars reconstructed while restoring are named
+/// `tmp-restored-{pid}-{snapshot id}`, so an interrupted restore's tars can be
+/// told apart from the tars of a restore that's still running.
+const RESTORED_TAR_PREFIX: &str = "tmp-restored-";
+
+fn get_restored_tar_path(repo: &Repository, pid: u32, snapshot_id: &str) -> String {
+    format!(
+        "{}/{}{}-{}",
+        repo.tmp_path(),
+        RESTORED_TAR_PREFIX,
+        pid,
+        snapshot_id
+    )
+}
+
+/// Whether the files named with `pid` may be in use by a restore in another
+/// process that's still running.
+fn is_restoring_elsewhere(pid: u32) -> bool {
+    pid != process::id() && is_process_running(pid)
+}
+
+/// Finds where in the path to continue an interrupted restore of the same
+/// snapshot from, and the tar it reconstructed there. Files left by an
+/// interrupted restore of another snapshot are removed, since they can't be
+/// used. Fails if another process is still restoring.
+fn find_resume_point(
+    repo: &Repository,
+    path: &[SnapshotMetaFile],
+) -> Result<Option<(usize, String)>, String> {
+    let Some(progress) = RestoreProgressFile::read(repo)? else {
+        return Ok(None);
+    };
+    if let Some(pid) = progress.pid
+        && is_restoring_elsewhere(pid)
+    {
+        return Err(format!(
+            "Another restore of snapshot {} is running (pid {})",
+            progress.target_snapshot_id, pid
+        ));
+    }
+
+    let target_snapshot = path.last().expect("Path should not be empty");
+    if progress.target_snapshot_id == target_snapshot.id
+        && let Some(pid) = progress.pid
+        && let Some(i) = path
+            .iter()
+            .position(|x| x.id == progress.completed_snapshot_id)
+        && i > 0
+    {
+        let tar_path = get_restored_tar_path(repo, pid, &path[i].id);
+        if simplify_result(fs::exists(&tar_path))? {
+            return Ok(Some((i, tar_path)));
+        }
+    }
+
+    eprintln!(
+        "Warn: Found files from an interrupted restore of snapshot {}. Removing them.",
+        progress.target_snapshot_id
+    );
+    remove_interrupted_restore(repo)?;
+    Ok(None)
+}
+
+/// Removes the reconstructed tars and progress of an interrupted restore.
+/// The files of restores that are still running in other processes (by the
+/// pid in their names) are kept. Returns the number of tars removed.
+fn remove_interrupted_restore(repo: &Repository) -> Result<usize, String> {
+    let mut num_removed = 0;
+
+    for item in simplify_result(fs::read_dir(repo.tmp_path()))? {
+        let entry = simplify_result(item)?;
+        let file_name = entry.file_name();
+        let Some(name) = file_name
+            .as_bytes()
+            .strip_prefix(RESTORED_TAR_PREFIX.as_bytes())
+        else {
+            continue;
+        };
+        // tars written by older versions don't have a pid
+        let pid = name
+            .split(|&x| x == b'-')
+            .next()
+            .and_then(|x| std::str::from_utf8(x).ok())
+            .and_then(|x| x.parse::<u32>().ok());
+        if !pid.is_some_and(is_restoring_elsewhere) {
+            simplify_result(fs::remove_file(entry.path()))?;
+            num_removed += 1;
+        }
+    }
+
+    let progress_pid = RestoreProgressFile::read(repo)
+        .ok()
+        .flatten()
+        .and_then(|x| x.pid);
+    if !progress_pid.is_some_and(is_restoring_elsewhere) {
+        RestoreProgressFile::remove(repo)?;
+    }
+    Ok(num_removed)
+}
+
+/// Like `follow_path`, but the tar of the final snapshot is written to
+/// `output` instead of a file, in `format`.
+pub fn follow_path_to_writer(
+    repo: &Repository,
+    path: &[SnapshotMetaFile],
+    mut output: impl Write,
+    format: ArchiveFormat,
+) -> Result<(), String> {
+    let Some((last_snapshot, prev_path)) = path.split_last() else {
+        return Err(String::from("Generated snapshot path was empty"));
+    };
+
+    if prev_path.is_empty() {
+        // the snapshot has a full payload, so there's nothing to restore
+        if format == ArchiveFormat::TarGz && last_snapshot.full_type == SnapshotFullType::TarGz {
+            let payload_path =
+                repo.prepend_snapshot_path(&last_snapshot.get_full_payload_filename()?);
+            let mut payload = simplify_result(File::open(&payload_path))?;
+            simplify_result(io::copy(&mut payload, &mut output))?;
+            return simplify_result(output.flush());
+        }
+
+        let mut payload = open_payload(repo, last_snapshot)?;
+        return match format {
+            ArchiveFormat::TarGz => {
+                let mut gz = create_gz_writer(output);
+                describe_corruption(&last_snapshot.id, io::copy(&mut payload, &mut gz))?;
+                simplify_result(gz.finish_stream())
+            }
+            ArchiveFormat::Tar => {
+                describe_corruption(&last_snapshot.id, io::copy(&mut payload, &mut output))?;
+                simplify_result(output.flush())
+            }
+        };
+    }
+
+    let prev_snapshot = prev_path.last().expect("Path should not be empty");
+    let prev_tar_path = follow_path(repo, prev_path)?;
+
+    let start_tar = open_tar_gz(repo, &prev_tar_path)?;
+    let delta_list = open_delta_list(
+        repo,
+        &repo.prepend_snapshot_path(
+            &last_snapshot.get_diff_path_from_child_snapshot(&prev_snapshot.id),
+        ),
+    )?;
+    let order = PathOrder::from_config(&repo.config);
+    match format {
+        ArchiveFormat::TarGz => {
+            restore_from_delta_list(start_tar, create_tar_gz_writer(output), delta_list, order)?
+        }
+        ArchiveFormat::Tar => {
+            restore_from_delta_list(start_tar, create_tar_writer(output), delta_list, order)?
+        }
+    }
+
+    remove_restored_tar(repo, prev_path, &prev_tar_path)
+}
+
+/// Extracts the regular files in a tar.gz into `output_dir`, reversing the
+/// transformers on each file. Only entries with paths accepted by `filter`
+/// are extracted. Paths that aren't UTF-8 are given to `filter` and the
+/// transformers lossily, but extracted as they are.
+///
+/// Hard links are extracted as hard links to their target, which must have
+/// been extracted before them. If the target isn't accepted by `filter`, the
+/// first link to it is extracted with its contents instead, and the others
+/// link to that one.
+///
+/// `before_overwrite` is called with the output path of each file before
+/// it's written, and the contents it will be written with (`None` for hard
+/// links), so the existing file can be moved aside.
+///
+/// If `failed_paths` is given, files that can't be extracted are skipped, and
+/// their paths (relative to `output_dir`) and errors are added to it.
+/// Otherwise, the first file that can't be extracted fails the extraction.
+///
+/// If `sparse` is set, files are written with `write_sparse`.
+///
+/// Returns the paths (relative to `output_dir`) of the extracted files.
+#[allow(clippy::too_many_arguments)]
+pub fn extract_tar(
+    repo: &Repository,
+    tar_path: &str,
+    output_dir: &str,
+    transformers: &[Box<dyn FileTransformer + Sync + Send>],
+    filter: impl Fn(&str) -> bool,
+    mut before_overwrite: impl FnMut(&Path, Option<&[u8]>) -> Result<(), String>,
+    mut failed_paths: Option<&mut Vec<(PathBuf, String)>>,
+    sparse: bool,
+) -> Result<Vec<PathBuf>, String> {
+    let mut tar_reader = open_tar_gz(repo, tar_path)?;
+    let mut dir_tree_builder = DirectoryTreeBuilder::new();
+    let mut extracted_paths = Vec::new();
+    // regular files that were extracted, which hard links can link to
+    let mut extracted_path_set = HashSet::new();
+    // the hard links to each target that wasn't accepted by `filter`, which
+    // are extracted with the target's contents after the other files
+    let mut links_to_filtered_out: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+
+    for entry in describe_corruption(tar_path, tar_reader.entries())? {
+        // skipping an entry that can't be read would make restoring remove
+        // the file, as if it wasn't in the snapshot
+        let mut entry = describe_corruption(tar_path, entry)?;
+        let path = PathBuf::from(OsStr::from_bytes(&entry.path_bytes()));
+        let path_lossy = path.to_string_lossy().into_owned();
+
+        let entry_type = entry.header().entry_type();
+        if entry_type != EntryType::Regular && entry_type != EntryType::Link {
+            eprintln!(
+                "Warn: Ignoring item: '{}' since it's not a regular file",
+                &path_lossy
+            );
+            continue;
+        }
+
+        if !filter(&path_lossy) {
+            continue;
+        }
+
+        validate_no_parent_references(&path)?;
+
+        let link_target = get_link_target(&entry)?.map(|x| PathBuf::from(OsStr::from_bytes(&x)));
+        if let Some(target) = &link_target {
+            validate_no_parent_references(target)?;
+
+            if !filter(&target.to_string_lossy()) {
+                links_to_filtered_out
+                    .entry(target.clone())
+                    .or_default()
+                    .push(path);
+                continue;
+            }
+            if !extracted_path_set.contains(target) {
+                eprintln!(
+                    "Warn: Skipping '{}' since it's a hard link to '{}', which wasn't restored",
+                    &path_lossy,
+                    target.to_string_lossy()
+                );
+                continue;
+            }
+        }
+
+        let output_path = Path::new(output_dir).join(&path);
+        let extract_result = prepare_parent_dir(
+            output_dir,
+            &output_path,
+            &mut dir_tree_builder,
+            &mut before_overwrite,
+        )
+        .and_then(|_| match &link_target {
+            Some(target) => extract_link(
+                repo,
+                &Path::new(output_dir).join(target),
+                &output_path,
+                &mut before_overwrite,
+            ),
+            None => extract_file(
+                repo,
+                &mut entry,
+                &output_path,
+                &path_lossy,
+                transformers,
+                &mut before_overwrite,
+                sparse,
+            ),
+        });
+
+        if let Err(err) = extract_result {
+            match &mut failed_paths {
+                Some(failed_paths) => {
+                    failed_paths.push((path, err));
+                    continue;
+                }
+                None => return Err(err),
+            }
+        }
+
+        if link_target.is_none() {
+            extracted_path_set.insert(path.clone());
+        }
+        extracted_paths.push(path);
+
+        progress_util::report(ProgressEvent::File {
+            path: &path_lossy,
+            done: extracted_paths.len() as u64,
+            total: None,
+        });
+    }
+
+    if links_to_filtered_out.is_empty() {
+        return Ok(extracted_paths);
+    }
+
+    let mut tar_reader = open_tar_gz(repo, tar_path)?;
+    for entry in describe_corruption(tar_path, tar_reader.entries())? {
+        let mut entry = describe_corruption(tar_path, entry)?;
+        let target = PathBuf::from(OsStr::from_bytes(&entry.path_bytes()));
+        let Some(link_paths) = links_to_filtered_out.remove(&target) else {
+            continue;
+        };
+        // transformed with the target's path when snapshotted
+        let target_lossy = target.to_string_lossy().into_owned();
+        let mut contents = Vec::new();
+        simplify_result(entry.read_to_end(&mut contents))?;
+
+        let mut first_link: Option<PathBuf> = None;
+        for path in link_paths {
+            let output_path = Path::new(output_dir).join(&path);
+            let extract_result = prepare_parent_dir(
+                output_dir,
+                &output_path,
+                &mut dir_tree_builder,
+                &mut before_overwrite,
+            )
+            .and_then(|_| match &first_link {
+                Some(first_link) => extract_link(
+                    repo,
+                    &Path::new(output_dir).join(first_link),
+                    &output_path,
+                    &mut before_overwrite,
+                ),
+                None => extract_file(
+                    repo,
+                    &mut contents.as_slice(),
+                    &output_path,
+                    &target_lossy,
+                    transformers,
+                    &mut before_overwrite,
+                    sparse,
+                ),
+            });
+
+            if let Err(err) = extract_result {
+                match &mut failed_paths {
+                    Some(failed_paths) => {
+                        failed_paths.push((path, err));
+                        continue;
+                    }
+                    None => return Err(err),
+                }
+            }
+
+            if first_link.is_none() {
+                first_link = Some(path.clone());
+            }
+            progress_util::report(ProgressEvent::File {
+                path: &path.to_string_lossy(),
+                done: extracted_paths.len() as u64 + 1,
+                total: None,
+            });
+            extracted_paths.push(path);
+        }
+
+        if links_to_filtered_out.is_empty() {
+            break;
+        }
+    }
+
+    for (target, link_paths) in links_to_filtered_out {
+        for path in link_paths {
+            eprintln!(
+                "Warn: Skipping '{}' since it's a hard link to '{}', which isn't in the snapshot",
+                path.to_string_lossy(),
+                target.to_string_lossy()
+            );
+        }
+    }
+
+    Ok(extracted_paths)
+}
+
+/// Creates the parent directories of a file being extracted to `output_path`,
+/// removing files in the way (see `remove_files_in_the_way`).
+fn prepare_parent_dir(
+    output_dir: &str,
+    output_path: &Path,
+    dir_tree_builder: &mut DirectoryTreeBuilder,
+    before_overwrite: &mut impl FnMut(&Path, Option<&[u8]>) -> Result<(), String>,
+) -> Result<(), String> {
+    match output_path.parent() {
+        Some(parent_dir_path) => match dir_tree_builder.prepare_dir(parent_dir_path) {
+            // a file that became a directory is in the way
+            Err(_) => remove_files_in_the_way(output_dir, parent_dir_path, before_overwrite)
+                .and_then(|_| dir_tree_builder.prepare_dir(parent_dir_path)),
+            result => result,
+        },
+        None => Ok(()),
+    }
+}
+
+/// Extracts a hard link to `target_path` (the path of an extracted file) at
+/// `output_path`. See `extract_tar`.
+fn extract_link(
+    repo: &Repository,
+    target_path: &Path,
+    output_path: &Path,
+    before_overwrite: &mut impl FnMut(&Path, Option<&[u8]>) -> Result<(), String>,
+) -> Result<(), String> {
+    before_overwrite(output_path, None)?;
+    remove_dir_in_the_way(repo, output_path)?;
+
+    // linking fails if there's already a file at the path
+    if let Err(err) = fs::remove_file(output_path)
+        && err.kind() != io::ErrorKind::NotFound
+    {
+        return Err(err.to_string());
+    }
+    simplify_result(fs::hard_link(target_path, output_path))
+}
+
+/// Extracts a regular file, reversing the transformers on it. See
+/// `extract_tar`.
+fn extract_file(
+    repo: &Repository,
+    entry: &mut impl Read,
+    output_path: &Path,
+    path_lossy: &str,
+    transformers: &[Box<dyn FileTransformer + Sync + Send>],
+    before_overwrite: &mut impl FnMut(&Path, Option<&[u8]>) -> Result<(), String>,
+    sparse: bool,
+) -> Result<(), String> {
+    let mut curr = Vec::new();
+    simplify_result(entry.read_to_end(&mut curr))?;
+
+    for transformer in transformers.iter().rev() {
+        curr = transformer.transform_out(path_lossy, curr)?;
+    }
+
+    before_overwrite(output_path, Some(&curr))?;
+    remove_dir_in_the_way(repo, output_path)?;
+
+    // writing to a file with other hard links would also change them, so
+    // the file is replaced instead
+    remove_if_hard_linked(output_path)?;
+
+    if sparse {
+        write_sparse(output_path, &curr)
+    } else {
+        simplify_result(fs::write(output_path, curr))
+    }
+}
+
+/// Removes the files at the paths of the parent directories of a file being
+/// extracted, which are left in the way when a file became a directory since
+/// the working directory was snapshotted. `before_overwrite` is called on each
+/// file first (see `extract_tar`).
+fn remove_files_in_the_way(
+    output_dir: &str,
+    dir_path: &Path,
+    before_overwrite: &mut impl FnMut(&Path, Option<&[u8]>) -> Result<(), String>,
+) -> Result<(), String> {
+    let relative_dir_path = dir_path.strip_prefix(output_dir).unwrap_or(dir_path);
+    let mut path = PathBuf::from(output_dir);
+    for component in relative_dir_path.components() {
+        path.push(component);
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if !metadata.is_dir() => {}
+            _ => continue,
+        }
+
+        before_overwrite(&path, None)?;
+        if fs::symlink_metadata(&path).is_ok() {
+            println!(
+                "{}",
+                paint(&format!("Removing: {}", path.to_string_lossy()), Color::Red)
+            );
+            simplify_result(fs::remove_file(&path))?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes the directory at the path of a file being extracted, which is
+/// left in the way when a directory became a file since the working
+/// directory was snapshotted. Nothing in the directory can be in the snapshot,
+/// so its files are removed like other files that aren't in the snapshot.
+///
+/// Fails if the directory has files that are never snapshotted (ex. excluded
+/// caches or symlinks), which are left as they are.
+fn remove_dir_in_the_way(repo: &Repository, path: &Path) -> Result<(), String> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => {}
+        _ => return Ok(()),
+    }
+
+    let mut file_paths = Vec::new();
+    walk_file_tree(
+        path.as_os_str().to_owned(),
+        &get_ignored_dirs(repo)?,
+        &DirExclusions::from_config(repo),
+        PathOrder::from_config(&repo.config),
+        &mut |file_path| {
+            file_paths.push(PathBuf::from(file_path));
+            Ok(())
+        },
+    )?;
+    for file_path in &file_paths {
+        println!(
+            "{}",
+            paint(
+                &format!("Removing: {}", file_path.to_string_lossy()),
+                Color::Red
+            )
+        );
+        simplify_result(fs::remove_file(file_path))?;
+    }
+
+    if simplify_result(remove_empty_dirs(path))? {
+        Ok(())
+    } else {
+        Err(format!(
+            "'{}' is a directory with files that aren't snapshotted, so the file can't be restored in its place",
+            path.to_string_lossy()
+        ))
+    }
+}
+
+/// Removes a directory if it only contains empty directories. Returns whether
+/// it was removed.
+fn remove_empty_dirs(path: &Path) -> io::Result<bool> {
+    let mut is_empty = true;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() || !remove_empty_dirs(&entry.path())? {
+            is_empty = false;
+        }
+    }
+    if is_empty {
+        fs::remove_dir(path)?;
+    }
+    Ok(is_empty)
+}
+
+fn remove_if_hard_linked(path: &Path) -> Result<(), String> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_file() && metadata.nlink() > 1 => {
+            simplify_result(fs::remove_file(path))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Validate the path does not contain any ".." directories, and is not
+/// absolute. We should refuse to extract these files.
+fn validate_no_parent_references(path: &Path) -> Result<(), String> {
+    if path
+        .components()
+        .any(|x| matches!(x, Component::ParentDir | Component::RootDir))
+    {
+        return Err(format!(
+            "Archive entry has path '{}', which attempts to reference a parent directory. The archive may be malicious, so extraction was canceled.",
+            path.to_string_lossy()
+        ));
+    }
+    Ok(())
+}
+
+/// Given directory tree specified by a collection of paths,
+/// performs the minimum amount of `mkdir` syscalls to construct the directory
+/// tree.
+struct DirectoryTreeBuilder(HashSet<PathBuf>);
+
+impl DirectoryTreeBuilder {
+    pub fn new() -> DirectoryTreeBuilder {
+        DirectoryTreeBuilder(HashSet::new())
+    }
+
+    pub fn prepare_dir(&mut self, dir_path: &Path) -> Result<(), String> {
+        if self.0.contains(dir_path) {
+            return Ok(());
+        }
+
+        simplify_result(fs::create_dir_all(dir_path))?;
+
+        for dir in dir_path.ancestors() {
+            self.0.insert(dir.to_path_buf());
+        }
+
+        Ok(())
+    }
+}
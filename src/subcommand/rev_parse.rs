@@ -0,0 +1,16 @@
+use std::collections::VecDeque;
+
+use crate::{file_structure, repository::Repository};
+
+/// Prints the snapshot id that a reference (`HEAD`, branch, tag or snapshot
+/// id) points to.
+pub fn main(repo: &Repository, mut args: VecDeque<String>) -> Result<(), String> {
+    let Some(reference) = args.pop_front() else {
+        return Err(String::from("Please specify a reference"));
+    };
+
+    file_structure::ensure_jbackup_snapshots_dir_exists(repo)?;
+
+    println!("{}", file_structure::resolve_ref(repo, &reference)?);
+    Ok(())
+}
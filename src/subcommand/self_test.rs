@@ -0,0 +1,156 @@
+use std::{collections::VecDeque, env, fs, process};
+
+use crate::{
+    arguments, file_structure,
+    repository::Repository,
+    transformer::{
+        FileTransformer, RoundTrip, check_round_trip, chunking::ChunkingTransformer,
+        get_config_transformer_names, get_transformer,
+    },
+    util::io_util::simplify_result,
+};
+
+/// Files at least this large are chunked by the chunking transformer while
+/// self-testing, no matter the configured size, so both the inline and the
+/// chunked samples are tested.
+const SELF_TEST_CHUNKING_MIN_SIZE: u64 = 256 * 1024;
+
+/// Checks that transformers restore the files they transform, by running
+/// sample files through `transform_in`, then `transform_out` (see
+/// `check_round_trip`). Each transformer is tested on its own sample files
+/// (see `FileTransformer::sample_files`), and reports whether it passed.
+/// Samples restored with other bytes that transform to the same contents
+/// (ex. recompressed region files) pass with a warning.
+///
+/// The configured transformers are tested, unless `--transformer <name>`
+/// (which can be given more than once) is specified, in which case it can be
+/// run outside of a repository. `--file <path>` (which can also be given
+/// more than once) adds the file to every transformer's samples, to test
+/// real files.
+///
+/// Chunks are stored in a temporary directory, not the repository's chunk
+/// store.
+pub fn main(root: &str, mut args: VecDeque<String>) -> Result<(), String> {
+    let mut parsed_args = arguments::Parser::new()
+        .multi_option("--transformer")
+        .multi_option("--file")
+        .parse(args.drain(..));
+
+    let names = match parsed_args.multi_options.remove("--transformer") {
+        Some(names) => names,
+        None => {
+            let repo = Repository::open(root)?;
+            file_structure::ensure_jbackup_snapshots_dir_exists(&repo)?;
+            get_config_transformer_names(&repo)
+        }
+    };
+    if names.is_empty() {
+        println!("No transformers are configured, so there's nothing to test.");
+        return Ok(());
+    }
+
+    let mut extra_samples = Vec::new();
+    for path in parsed_args
+        .multi_options
+        .remove("--file")
+        .unwrap_or_default()
+    {
+        match fs::read(&path) {
+            Ok(contents) => extra_samples.push((path, contents)),
+            Err(err) => return Err(format!("Failed to read '{}': {}", path, err)),
+        }
+    }
+
+    let chunks_dir = env::temp_dir().join(format!("jbackup-self-test-{}", process::id()));
+    let result = self_test(root, &names, &extra_samples, &chunks_dir.to_string_lossy());
+    if chunks_dir.exists() {
+        simplify_result(fs::remove_dir_all(&chunks_dir))?;
+    }
+
+    let failed_count = result?;
+    if failed_count > 0 {
+        return Err(format!(
+            "{} of {} transformer(s) failed the self-test",
+            failed_count,
+            names.len()
+        ));
+    }
+    println!("All {} transformer(s) passed.", names.len());
+    Ok(())
+}
+
+/// Tests each transformer, printing the results. Returns how many
+/// transformers failed. `exec:` transformers are run in `working_dir`.
+fn self_test(
+    working_dir: &str,
+    names: &[String],
+    extra_samples: &[(String, Vec<u8>)],
+    chunks_dir: &str,
+) -> Result<usize, String> {
+    let mut failed_count = 0;
+
+    for name in names {
+        let transformer: Box<dyn FileTransformer> = if name == "chunking" {
+            Box::from(ChunkingTransformer::new(
+                SELF_TEST_CHUNKING_MIN_SIZE,
+                String::from(chunks_dir),
+            ))
+        } else {
+            match get_transformer(name, working_dir) {
+                Some(x) => x,
+                None => return Err(format!("Unknown transformer '{}'", name)),
+            }
+        };
+
+        let mut samples = transformer.sample_files();
+        samples.extend(extra_samples.iter().cloned());
+
+        let mut failures = Vec::new();
+        let mut notes = Vec::new();
+        for (path, contents) in &samples {
+            match check_round_trip(transformer.as_ref(), path, contents.clone()) {
+                Ok(RoundTrip::Identical) => {}
+                Ok(RoundTrip::Equivalent) => notes.push(format!(
+                    "  Warn: {}: restored with other bytes, which transform to the same contents",
+                    path
+                )),
+                Err(err) => failures.push(format!("  {}: {}", path, err)),
+            }
+        }
+
+        if failures.is_empty() {
+            println!("{}: passed ({} sample(s))", name, samples.len());
+        } else {
+            failed_count += 1;
+            println!(
+                "{}: FAILED ({} of {} sample(s))",
+                name,
+                failures.len(),
+                samples.len()
+            );
+        }
+        for line in failures.iter().chain(notes.iter()) {
+            println!("{}", line);
+        }
+    }
+
+    Ok(failed_count)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, process};
+
+    use super::self_test;
+
+    #[test]
+    pub fn builtin_transformers_pass() {
+        let chunks_dir = env::temp_dir().join(format!("jbackup-self-test-test-{}", process::id()));
+        let names = [String::from("minecraft_mca"), String::from("chunking")];
+        let failed_count = self_test(".", &names, &[], &chunks_dir.to_string_lossy()).unwrap();
+        let _ = fs::remove_dir_all(&chunks_dir);
+
+        assert_eq!(failed_count, 0);
+        assert!(self_test(".", &[String::from("unknown")], &[], "").is_err());
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    collections::VecDeque,
+    io::{self, BufWriter, Read},
+};
+
+use tar::EntryType;
+
+use crate::{
+    arguments, file_structure,
+    repository::Repository,
+    subcommand::restore::{
+        find_restore_path, follow_path, follow_path_to_writer, remove_restored_tar,
+    },
+    transformer::{FileTransformer, get_snapshot_transformers},
+    util::{
+        archive_utils::{
+            ArchiveFormat, TarStream, append_tar_entry, append_tar_link, create_tar_gz_writer,
+            create_tar_writer, describe_corruption, get_link_target, open_tar_gz,
+        },
+        io_util::simplify_result,
+    },
+};
+
+/// Writes the tar.gz of a snapshot to stdout, without extracting it.
+///
+/// The files in the tar are in their stored representation, that is, after
+/// `transform_in` has been applied by the configured transformers, to debug
+/// transformers or check what's stored. `--payload-only` asks for this
+/// explicitly. `--transform-out` reverses the transformers the snapshot was
+/// created with instead, like `restore`, so the tar has the original files.
+///
+/// `--format tar` writes the tar without gzipping it, for piping into tools
+/// that compress it themselves. Snapshots reconstructed from diffs are
+/// written uncompressed directly, rather than gzipped and then decompressed.
+pub fn main(repo: &Repository, mut args: VecDeque<String>) -> Result<(), String> {
+    let mut parsed_args = arguments::Parser::new()
+        .flag("--payload-only")
+        .flag("--transform-out")
+        .option("--format")
+        .parse(args.drain(..));
+
+    let snapshot_id = match parsed_args.normal.pop_front() {
+        None => {
+            return Err(String::from("Please specify a snapshot"));
+        }
+        Some(x) => file_structure::resolve_ref(repo, &x)?,
+    };
+
+    let format = match parsed_args.options.remove("--format") {
+        Some(x) => x.parse()?,
+        None => ArchiveFormat::TarGz,
+    };
+
+    let transform_out = parsed_args.flags.contains("--transform-out");
+    if transform_out && parsed_args.flags.contains("--payload-only") {
+        return Err(String::from(
+            "--payload-only and --transform-out can't be used together",
+        ));
+    }
+
+    let path = find_restore_path(repo, &snapshot_id)?;
+    let transformers = if transform_out {
+        get_snapshot_transformers(path.last().expect("Path should not be empty"), repo)?
+    } else {
+        Vec::new()
+    };
+
+    let output = BufWriter::new(io::stdout().lock());
+    // without transformers, the stored tar is already the original files
+    if transformers.is_empty() {
+        return follow_path_to_writer(repo, &path, output, format);
+    }
+
+    let tar_path = follow_path(repo, &path)?;
+    let result = match format {
+        ArchiveFormat::TarGz => {
+            write_transformed_out_tar(repo, &tar_path, &transformers, create_tar_gz_writer(output))
+        }
+        ArchiveFormat::Tar => {
+            write_transformed_out_tar(repo, &tar_path, &transformers, create_tar_writer(output))
+        }
+    };
+    remove_restored_tar(repo, &path, &tar_path)?;
+    result
+}
+
+/// Copies the snapshot tar at `tar_path` into `output`, reversing the
+/// transformers on each regular file. Other entries are copied as they are.
+fn write_transformed_out_tar<W: TarStream>(
+    repo: &Repository,
+    tar_path: &str,
+    transformers: &[Box<dyn FileTransformer + Sync + Send>],
+    mut output: tar::Builder<W>,
+) -> Result<(), String> {
+    let mut tar_reader = open_tar_gz(repo, tar_path)?;
+
+    for entry in describe_corruption(tar_path, tar_reader.entries())? {
+        let mut entry = describe_corruption(tar_path, entry)?;
+        let path = entry.path_bytes().into_owned();
+        let mut header = entry.header().clone();
+
+        if let Some(target) = get_link_target(&entry)? {
+            simplify_result(append_tar_link(&mut output, &mut header, &path, &target))?;
+        } else if header.entry_type() == EntryType::Regular {
+            let mut contents = Vec::new();
+            describe_corruption(tar_path, entry.read_to_end(&mut contents))?;
+            let path_lossy = String::from_utf8_lossy(&path);
+            for transformer in transformers.iter().rev() {
+                contents = transformer.transform_out(&path_lossy, contents)?;
+            }
+            header.set_size(contents.len() as u64);
+            simplify_result(append_tar_entry(
+                &mut output,
+                &mut header,
+                &path,
+                contents.as_slice(),
+            ))?;
+        } else {
+            simplify_result(append_tar_entry(&mut output, &mut header, &path, entry))?;
+        }
+    }
+
+    simplify_result(simplify_result(output.into_inner())?.finish_stream())
+}
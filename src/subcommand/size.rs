@@ -0,0 +1,196 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::Path,
+};
+
+use crate::{
+    arguments, file_structure,
+    repository::Repository,
+    transformer::{chunking::chunk_store_size, get_chunks_path},
+    util::{io_util::simplify_result, json_util::json_string, size_util::format_size},
+};
+
+const DEFAULT_TOP_N: usize = 10;
+
+/// Reports the disk usage of the snapshots directory.
+///
+/// Each snapshot's stored size is its full payload (if it has one) plus the
+/// delta lists used to restore it. Files in the snapshots directory that are
+/// not referenced by any snapshot metadata are reported as orphaned. Chunks
+/// in the chunk store are shared between snapshots, so they're reported
+/// separately.
+///
+/// Options: `--top <n>` limits the number of snapshots listed, and `--json`
+/// prints the report as JSON.
+pub fn main(repo: &Repository, mut args: VecDeque<String>) -> Result<(), String> {
+    let mut parsed_args = arguments::Parser::new()
+        .flag("--json")
+        .option("--top")
+        .parse(args.drain(..));
+
+    let top_n = match parsed_args.options.remove("--top") {
+        Some(x) => simplify_result(x.parse::<usize>())?,
+        None => DEFAULT_TOP_N,
+    };
+
+    let report = SizeReport::read(repo)?;
+
+    if parsed_args.flags.contains("--json") {
+        println!("{}", report.to_json(top_n));
+    } else {
+        report.print(top_n);
+    }
+
+    Ok(())
+}
+
+struct SnapshotSize {
+    id: String,
+    message: Option<String>,
+    /// size of the full payload and diffs of the snapshot
+    stored_size: u64,
+}
+
+struct SizeReport {
+    total_size: u64,
+    /// sorted from largest to smallest
+    snapshots: Vec<SnapshotSize>,
+    orphaned_files: Vec<(String, u64)>,
+    num_chunks: usize,
+    /// size of the chunk store, which is included in `total_size`
+    chunks_size: u64,
+}
+
+impl SizeReport {
+    fn read(repo: &Repository) -> Result<SizeReport, String> {
+        let mut file_sizes = HashMap::new();
+        let (num_chunks, chunks_size) = chunk_store_size(&get_chunks_path(repo))?;
+        let mut total_size = chunks_size;
+
+        for item in simplify_result(fs::read_dir(repo.snapshots_path()))? {
+            let entry = simplify_result(item)?;
+            if Path::new(&get_chunks_path(repo)) == entry.path() {
+                continue;
+            }
+            let size = simplify_result(entry.metadata())?.len();
+            total_size += size;
+            // the index isn't part of any snapshot, but isn't orphaned either
+            if Path::new(&file_structure::SnapshotIndexFile::get_path(repo)) == entry.path() {
+                continue;
+            }
+            file_sizes.insert(entry.file_name().to_string_lossy().into_owned(), size);
+        }
+
+        let mut snapshots = Vec::new();
+
+        for meta in file_structure::get_all_snapshot_meta_files(repo)? {
+            let mut stored_files = vec![file_structure::SnapshotMetaFile::get_meta_file_name(
+                &meta.id,
+            )];
+            if let Ok(payload) = meta.get_full_payload_filename() {
+                stored_files.push(payload);
+            }
+            for diff_child in &meta.diff_children {
+                stored_files.push(meta.get_diff_path_from_child_snapshot(diff_child));
+            }
+
+            let stored_size = stored_files
+                .iter()
+                .filter_map(|file| file_sizes.remove(file))
+                .sum();
+
+            snapshots.push(SnapshotSize {
+                id: meta.id,
+                message: meta.message,
+                stored_size,
+            });
+        }
+
+        snapshots.sort_by(|a, b| b.stored_size.cmp(&a.stored_size).then(a.id.cmp(&b.id)));
+
+        let mut orphaned_files: Vec<(String, u64)> = file_sizes.into_iter().collect();
+        orphaned_files.sort();
+
+        Ok(SizeReport {
+            total_size,
+            snapshots,
+            orphaned_files,
+            num_chunks,
+            chunks_size,
+        })
+    }
+
+    fn print(&self, top_n: usize) {
+        println!(
+            "Total: {} in {} snapshot(s)",
+            format_size(self.total_size),
+            self.snapshots.len()
+        );
+
+        if self.num_chunks > 0 {
+            println!(
+                "Chunks: {} in {} chunk(s)",
+                format_size(self.chunks_size),
+                self.num_chunks
+            );
+        }
+
+        if !self.snapshots.is_empty() {
+            println!("\nLargest snapshots:");
+            for snapshot in self.snapshots.iter().take(top_n) {
+                println!(
+                    "  {:>10}  {}{}",
+                    format_size(snapshot.stored_size),
+                    snapshot.id,
+                    match &snapshot.message {
+                        Some(message) => format!("  {}", message.replace('\n', " ")),
+                        None => String::new(),
+                    }
+                );
+            }
+        }
+
+        if !self.orphaned_files.is_empty() {
+            println!("\nOrphaned files (not referenced by any snapshot):");
+            for (file, size) in &self.orphaned_files {
+                println!("  {:>10}  {}", format_size(*size), file);
+            }
+        }
+    }
+
+    fn to_json(&self, top_n: usize) -> String {
+        let snapshots: Vec<String> = self
+            .snapshots
+            .iter()
+            .take(top_n)
+            .map(|snapshot| {
+                format!(
+                    "{{\"id\":{},\"message\":{},\"stored_size\":{}}}",
+                    json_string(&snapshot.id),
+                    match &snapshot.message {
+                        Some(message) => json_string(message),
+                        None => String::from("null"),
+                    },
+                    snapshot.stored_size
+                )
+            })
+            .collect();
+
+        let orphaned_files: Vec<String> = self
+            .orphaned_files
+            .iter()
+            .map(|(file, size)| format!("{{\"file\":{},\"size\":{}}}", json_string(file), size))
+            .collect();
+
+        format!(
+            "{{\"total_size\":{},\"snapshot_count\":{},\"chunks_size\":{},\"chunk_count\":{},\"largest_snapshots\":[{}],\"orphaned_files\":[{}]}}",
+            self.total_size,
+            self.snapshots.len(),
+            self.chunks_size,
+            self.num_chunks,
+            snapshots.join(","),
+            orphaned_files.join(",")
+        )
+    }
+}
@@ -1,10 +1,22 @@
 use std::{
-    collections::VecDeque,
+    collections::{HashMap, HashSet, VecDeque, hash_map::Entry},
+    env,
     ffi::OsString,
+    fmt::{self, Display},
     fs::{self, File, Metadata},
+    io::{self, BufWriter, IsTerminal, Read, Seek, SeekFrom, Write},
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::MetadataExt,
+    },
+    path::{Component, Path},
     process,
-    sync::Arc,
-    time::SystemTime,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
 use flate2::Compression;
@@ -12,17 +24,38 @@ use gzp::{
     deflate::Gzip,
     par::compress::{ParCompress, ParCompressBuilder},
 };
+use sha2::{Digest, Sha256};
 
 use crate::{
-    JBACKUP_PATH, SNAPSHOTS_PATH, arguments,
-    delta_list::generate_delta_list,
-    file_structure::{self, ConfigFile},
-    prepend_snapshot_path,
-    transformer::get_transformers,
+    JBACKUP_PATH, arguments,
+    delta_list::{
+        FileChange, PathOrder, fold_case, format_change, generate_delta_list, list_changes,
+    },
+    file_structure::{
+        self, ConfigFile, DiffDirection, EMPTY_SNAPSHOT_ID, OnLargeFile, SnapshotIdFormat,
+        SnapshotWalk,
+    },
+    repository::Repository,
+    subcommand::{
+        chain_depth::get_chain_depths,
+        squash::{find_range, squash},
+    },
+    transformer::{
+        FileTransformer, get_config_transformer_names, get_config_transformers,
+        get_lossy_transformer_names,
+    },
     util::{
-        archive_utils::{create_delta_list, open_tar_gz},
-        io_util::{self, simplify_result},
+        archive_utils::{
+            TarEntry, append_tar_entry, append_tar_link, create_delta_list, create_tar_gz,
+            describe_corruption, get_link_target, open_delta_list, open_payload, open_tar_gz,
+        },
+        io_util::{
+            self, FileContents, RetryPolicy, is_process_running, map_file, retry_transient,
+            run_editor, simplify_result,
+        },
         multithreaded_pipeline::MultithreadPipeline,
+        progress_util::{self, ProgressEvent},
+        size_util::{format_size, parse_size},
     },
 };
 
@@ -32,226 +65,2245 @@ use crate::{
 /// a snapshot.
 ///
 /// Will read the arguments to find an optional message for the snapshot.
+/// Without `-m`, the message is written in `$EDITOR` when it's set and
+/// jbackup is run from a terminal (see `edit_message`). Otherwise, the
+/// snapshot has no message.
+///
+/// `--tag <name>` tags the new snapshot. The tag is checked before the
+/// snapshot is created, so an existing tag never leaves behind an untagged
+/// snapshot.
+///
+/// `--max-file-size` and `--on-large` override the repository's config for
+/// files that are too large to snapshot.
+///
+/// `-v`/`--verbose` prints each file as it's inserted into the snapshot.
+///
+/// `--print-id` prints only the new snapshot's id to stdout, and everything
+/// else to stderr, for scripts (ex. `ID=$(jbackup snapshot --print-id)`).
+/// Nothing is printed to stdout if no snapshot is created.
+///
+/// `--skip-non-utf8` leaves out files with paths that aren't UTF-8, which
+/// may not be restorable by other tools.
+///
+/// `--exclude-caches` leaves out directories tagged as caches, like the
+/// `exclude_caches` config key (see `is_cache_dir`), and `--exclude-if-present
+/// <name>` (which can be given more than once) leaves out directories
+/// containing a file with the name, along with the config's markers (see
+/// `DirExclusions`).
+///
+/// `--one-file-system` (or `--xdev`) leaves out directories on other
+/// filesystems than the working directory, like the `one_file_system` config
+/// key.
+///
+/// `--follow-symlinks` stores the contents of the files and directories that
+/// symlinks point to. Otherwise, symlinks are skipped.
+///
+/// `--no-transform` stores files without the configured transformers, to
+/// debug transformers. The snapshot records that it has no transformers, so
+/// it's restored without them.
 ///
-pub fn main(mut args: VecDeque<String>) -> Result<(), String> {
-    let mut parsed_args = arguments::Parser::new().option("-m").parse(args.drain(..));
-    let mut snapshot_message_arg = parsed_args.options.remove("-m");
+/// `--keep-going` leaves out files that can't be read or transformed, and
+/// fails after the snapshot is created, listing them. Otherwise, the first
+/// such file fails the snapshot.
+///
+/// `--stdin-file-list` snapshots the files listed on stdin, one per line (or
+/// separated by NUL with `-0`), instead of every file in the working
+/// directory (see `check_file_list`).
+///
+/// `--parent <ref>` (which can be given more than once) records the snapshot
+/// as a merge, with the given snapshots as parents after the head. The
+/// snapshot is still only diffed against the head, its first parent.
+///
+/// `--threads-transform` and `--threads-compress` override how many threads
+/// read and transform files, and compress the snapshot (see `ThreadBudget`).
+///
+/// `--jobs-per-file <n>` lets transformers split the work on one file across
+/// up to `n` threads, for files that take much longer than the others (see
+/// `FileTransformer::transform_in_parallel`).
+///
+/// `--amend` replaces the head's snapshot with the new snapshot, keeping the
+/// old snapshot's message unless `-m` is given (see `SnapshotOptions::amend`).
+///
+/// `--allow-lossy` snapshots with lossy transformers, with a warning, instead
+/// of failing (see `check_lossless`).
+///
+/// `--fsync` flushes the snapshot's files to the disk, like the `fsync`
+/// config key (see `Repository::should_fsync`).
+///
+/// `--max-diff-chain <n>` keeps the parent's full payload if replacing it
+/// with a diff would make a chain of diffs longer than `n`, like the
+/// `max_diff_chain` config key (see `exceeds_max_diff_chain`).
+///
+/// `--on-branch <name>` adds the snapshot to the branch, as a child of its
+/// tip, without moving the head (see `SnapshotOptions::on_branch`).
+///
+/// `--io-retries <n>` sets how many times reading a file is retried after a
+/// transient error (see `RetryPolicy`).
+///
+/// `--stats` also prints how many bytes went into and came out of each
+/// transformer (see `TransformerStats`), to tell whether a transformer is
+/// worth it.
+///
+pub fn main(repo: &mut Repository, mut args: VecDeque<String>) -> Result<(), String> {
+    let mut parsed_args = arguments::Parser::new()
+        .flag("-v")
+        .flag("--verbose")
+        .flag("--print-id")
+        .flag("--skip-non-utf8")
+        .flag("--follow-symlinks")
+        .flag("--no-transform")
+        .flag("--keep-going")
+        .flag("--stdin-file-list")
+        .flag("-0")
+        .flag("--amend")
+        .flag("--exclude-caches")
+        .flag("--one-file-system")
+        .flag("--xdev")
+        .flag("--stats")
+        .flag("--allow-lossy")
+        .flag("--fsync")
+        .option("-m")
+        .option("--tag")
+        .option("--max-file-size")
+        .option("--on-large")
+        .option("--threads-transform")
+        .option("--threads-compress")
+        .option("--jobs-per-file")
+        .option("--io-retries")
+        .option("--on-branch")
+        .option("--max-diff-chain")
+        .multi_option("--parent")
+        .multi_option("--exclude-if-present")
+        .parse(args.drain(..));
+    let snapshot_message_arg = parsed_args.options.remove("-m");
+    let tag_arg = parsed_args.options.remove("--tag");
+    let verbose = parsed_args.flags.contains("-v") || parsed_args.flags.contains("--verbose");
+    let skip_non_utf8 = parsed_args.flags.contains("--skip-non-utf8");
+    let print_stats = parsed_args.flags.contains("--stats");
 
-    file_structure::ensure_jbackup_snapshots_dir_exists()?;
+    file_structure::ensure_jbackup_snapshots_dir_exists(repo)?;
+    repo.config.ensure_not_bare()?;
+
+    let mut options = SnapshotOptions::from_config(repo);
+    options.verbose = verbose;
+    options.print_id = parsed_args.flags.contains("--print-id");
+    options.skip_non_utf8 = skip_non_utf8;
+    options.follow_symlinks = parsed_args.flags.contains("--follow-symlinks");
+    options.exclusions.caches |= parsed_args.flags.contains("--exclude-caches");
+    if parsed_args.flags.contains("--one-file-system") || parsed_args.flags.contains("--xdev") {
+        options.exclusions.device = Some(get_working_dir_device(repo.root())?);
+    }
+    for marker in parsed_args
+        .multi_options
+        .remove("--exclude-if-present")
+        .unwrap_or_default()
+    {
+        check_marker_name(&marker)?;
+        options.exclusions.markers.push(marker);
+    }
+    options.no_transform = parsed_args.flags.contains("--no-transform");
+    options.allow_lossy = parsed_args.flags.contains("--allow-lossy");
+    if parsed_args.flags.contains("--fsync") {
+        repo.force_fsync = true;
+    }
+    options.keep_going = parsed_args.flags.contains("--keep-going");
+    options.amend = parsed_args.flags.contains("--amend");
+    options.on_branch = parsed_args.options.remove("--on-branch");
+    if let Some(x) = parsed_args.options.remove("--max-diff-chain") {
+        options.max_diff_chain = Some(parse_max_diff_chain(&x)?);
+    }
+    if parsed_args.flags.contains("--stdin-file-list") {
+        let separator = if parsed_args.flags.contains("-0") {
+            b'\0'
+        } else {
+            b'\n'
+        };
+        options.file_list = Some(read_file_list(io::stdin().lock(), separator)?);
+    }
+    options.merge_parents = parsed_args
+        .multi_options
+        .remove("--parent")
+        .unwrap_or_default()
+        .iter()
+        .map(|x| file_structure::resolve_ref(repo, x))
+        .collect::<Result<_, _>>()?;
+
+    let max_file_size = match parsed_args.options.remove("--max-file-size") {
+        Some(x) => Some(parse_size(&x)?),
+        None => repo.config.max_file_size,
+    };
+    let on_large_file = match parsed_args.options.remove("--on-large") {
+        Some(x) => x.parse()?,
+        None => repo.config.on_large_file,
+    };
+    options.large_file_limit = max_file_size.map(|max_size| LargeFileLimit {
+        max_size,
+        on_large_file,
+    });
+
+    if let Some(x) = parsed_args.options.remove("--threads-transform") {
+        options.threads.transform = parse_thread_count(&x)?;
+    }
+    if let Some(x) = parsed_args.options.remove("--threads-compress") {
+        options.threads.compress = parse_thread_count(&x)?;
+    }
+    if let Some(x) = parsed_args.options.remove("--jobs-per-file") {
+        options.jobs_per_file = parse_thread_count(&x)?;
+    }
+    if let Some(x) = parsed_args.options.remove("--io-retries") {
+        options.retry_policy.attempts = match x.parse::<u32>() {
+            Ok(retries) => retries.saturating_add(1),
+            Err(_) => return Err(format!("Invalid number of retries: '{}'", x)),
+        };
+    }
+    if snapshot_message_arg.is_none() && io::stdin().is_terminal() && io::stdout().is_terminal() {
+        options.editor = env::var("EDITOR").ok().filter(|x| !x.is_empty());
+    }
+
+    let print_id = options.print_id;
+    print_info(
+        print_id,
+        &format!(
+            "Using {} thread(s) to read and transform files, and {} to compress.",
+            options.threads.transform, options.threads.compress
+        ),
+    );
+
+    let tagged = tag_arg.is_some();
+    match snapshot(repo, &options, snapshot_message_arg, tag_arg)? {
+        SnapshotOutcome::Created(created) => {
+            print_info(
+                print_id,
+                &format!(
+                    "Snapshot contains {} file(s), {} uncompressed. Payload: {}{}. Took {:.2}s.",
+                    created.stats.num_files,
+                    format_size(created.stats.total_bytes),
+                    format_size(created.payload_size),
+                    match created.diff_size {
+                        Some(x) => format!(", diff from previous snapshot: {}", format_size(x)),
+                        None => String::new(),
+                    },
+                    created.elapsed.as_secs_f64()
+                ),
+            );
+            if print_stats {
+                for transformer in &created.stats.transformers {
+                    print_info(print_id, &transformer.to_string());
+                }
+            }
+            if let Some(amended_id) = &created.amended_id {
+                print_info(
+                    print_id,
+                    &format!(
+                        "Amended snapshot {}, replacing it with id: {}",
+                        amended_id, &created.snapshot.id
+                    ),
+                );
+            } else {
+                print_info(
+                    print_id,
+                    &format!("Created snapshot with id: {}", &created.snapshot.id),
+                );
+            }
+            if let Some(tag) = created.tag {
+                print_info(print_id, &format!("Tagged snapshot as: {}", &tag));
+            }
+            if print_id {
+                println!("{}", &created.snapshot.id);
+            }
+            if !created.stats.errors.is_empty() {
+                return Err(describe_file_errors(&created.stats.errors));
+            }
+        }
+        SnapshotOutcome::NoChanges(head) if options.amend => {
+            print_info(
+                print_id,
+                &format!(
+                    "No changes to the files since snapshot {}, so it was amended in place.",
+                    head.id
+                ),
+            );
+            if print_id {
+                println!("{}", head.id);
+            }
+        }
+        SnapshotOutcome::NoChanges(head) => {
+            print_info(
+                print_id,
+                &format!(
+                    "No changes since snapshot {}, so no snapshot was created{}.",
+                    head.id,
+                    if tagged { " or tagged" } else { "" }
+                ),
+            );
+            // the head's snapshot has the files, so scripts can use its id
+            // like a new snapshot's
+            if print_id {
+                println!("{}", head.id);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints a line of output that isn't the new snapshot's id, which goes to
+/// stderr with `--print-id` (see `SnapshotOptions::print_id`).
+fn print_info(print_id: bool, message: &str) {
+    if print_id {
+        eprintln!("{}", message);
+    } else {
+        println!("{}", message);
+    }
+}
+
+/// The result of `snapshot`.
+pub enum SnapshotOutcome {
+    Created(CreatedSnapshot),
+    /// The working directory is the same as the snapshot the head points to,
+    /// so no snapshot was created. Contains the head's snapshot.
+    NoChanges(file_structure::SnapshotMetaFile),
+}
+
+pub struct CreatedSnapshot {
+    pub snapshot: file_structure::SnapshotMetaFile,
+    /// the tag added to the snapshot
+    pub tag: Option<String>,
+    /// the id of the snapshot the new snapshot replaced, when amending
+    pub amended_id: Option<String>,
+    pub stats: SnapshotStats,
+    /// size of the snapshot's full payload, in bytes
+    pub payload_size: u64,
+    /// size of the delta list from the new snapshot to the previous snapshot,
+    /// in bytes
+    pub diff_size: Option<u64>,
+    pub elapsed: Duration,
+}
+
+/// Creates a snapshot of the current working directory and moves the head
+/// to it, unless nothing changed since the head's snapshot. With
+/// `SnapshotOptions::on_branch`, the branch's tip is used instead of the
+/// head, which doesn't move.
+///
+/// When amending, the head's snapshot is replaced instead. If nothing
+/// changed, the head's snapshot is given the message and tag in place.
+pub fn snapshot(
+    repo: &Repository,
+    options: &SnapshotOptions,
+    message: Option<String>,
+    tag: Option<String>,
+) -> Result<SnapshotOutcome, String> {
+    repo.config.ensure_not_bare()?;
+
+    let mut tags_file = file_structure::TagsFile::read(repo)?;
+    if let Some(tag) = &tag {
+        if tag.is_empty() {
+            return Err(String::from("Tag names cannot be empty"));
+        }
+        if let Some(tagged_snapshot_id) = tags_file.tags.get(tag) {
+            return Err(format!(
+                "The tag '{}' already exists (on snapshot {})",
+                tag, tagged_snapshot_id
+            ));
+        }
+    }
+
+    check_lossless(repo, options)?;
+
+    remove_interrupted_tmp_snapshots(repo)?;
 
     let mut files_to_delete = FilesToDelete::new();
 
-    let mut staged_snapshot = create_full_snapshot()?;
+    let start_time = Instant::now();
 
-    if simplify_result(fs::exists(
-        file_structure::SnapshotMetaFile::get_meta_file_path(&staged_snapshot.id),
-    ))? {
+    let mut head_file = file_structure::HeadFile::read(repo)?;
+    let mut branch_file = file_structure::BranchesFile::read(repo)?;
+    let (branch, parent_snapshot_id) = get_target_branch(options, &head_file, &branch_file)?;
+    let moves_head = branch == head_file.curr_branch;
+    let creates_branch = !moves_head && !branch_file.branches.contains_key(&branch);
+    let merge_parent_ids = get_merge_parent_ids(options, parent_snapshot_id.as_deref())?;
+
+    let message = if options.amend {
+        let amended = check_amendable(repo, options, parent_snapshot_id.as_deref())?;
+        message.or(amended.message)
+    } else {
+        message
+    };
+
+    // the parents' metadata is changed after the new snapshot is written, so
+    // check it can be before writing anything
+    for parent_id in parent_snapshot_id.iter().chain(&merge_parent_ids) {
+        file_structure::SnapshotMetaFile::read(repo, parent_id)?.check_writable()?;
+    }
+
+    let Some((mut staged_snapshot, stats)) =
+        create_full_snapshot(repo, options, parent_snapshot_id.as_deref())?
+    else {
+        let head_snapshot_id = parent_snapshot_id.unwrap_or_default();
+        let mut head_snapshot = file_structure::SnapshotMetaFile::read(repo, &head_snapshot_id)?;
+        if options.amend {
+            if head_snapshot.message != message {
+                head_snapshot.message = message;
+                head_snapshot.write(repo)?;
+            }
+            if let Some(tag) = tag {
+                tags_file.tags.insert(tag, head_snapshot_id.clone());
+                tags_file.write(repo)?;
+            }
+        }
+        // the new branch is still created, at the head's snapshot
+        if creates_branch {
+            branch_file.branches.insert(branch, head_snapshot_id);
+            branch_file.write(repo)?;
+        }
+        return Ok(SnapshotOutcome::NoChanges(head_snapshot));
+    };
+
+    let message = match (message, &options.editor) {
+        (None, Some(editor)) => {
+            let edited = edit_message(
+                editor,
+                repo,
+                parent_snapshot_id.as_deref(),
+                &staged_snapshot,
+            );
+            if !matches!(edited, Ok(Some(_))) {
+                simplify_result(fs::remove_file(
+                    repo.prepend_snapshot_path(&staged_snapshot.get_full_payload_filename()?),
+                ))?;
+            }
+            match edited? {
+                Some(x) => Some(x),
+                None => {
+                    return Err(String::from(
+                        "Aborted the snapshot, since the message is empty",
+                    ));
+                }
+            }
+        }
+        (message, _) => message,
+    };
+    staged_snapshot.message = message;
+
+    // the parent's full payload is still needed to diff the next snapshot on
+    // another branch against it
+    let keeps_parent_full = parent_snapshot_id.as_ref().is_some_and(|parent_id| {
+        branch_file
+            .branches
+            .iter()
+            .any(|(name, tip_id)| *name != branch && tip_id == parent_id)
+    });
+    let staged_payload_path =
+        repo.prepend_snapshot_path(&staged_snapshot.get_full_payload_filename()?);
+    let diff_path = match link_staged_snapshot(
+        repo,
+        options,
+        &mut staged_snapshot,
+        &parent_snapshot_id,
+        &merge_parent_ids,
+        keeps_parent_full,
+        &mut files_to_delete,
+    ) {
+        Ok(x) => x,
+        Err(err) => {
+            if let Err(remove_err) = fs::remove_file(&staged_payload_path) {
+                eprintln!(
+                    "Warn: Failed to remove '{}': {}",
+                    staged_payload_path, remove_err
+                );
+            }
+            return Err(err);
+        }
+    };
+
+    let payload_size = simplify_result(fs::metadata(
+        repo.prepend_snapshot_path(&staged_snapshot.get_full_payload_filename()?),
+    ))?
+    .len();
+    let diff_size = match &diff_path {
+        Some(path) => Some(simplify_result(fs::metadata(path))?.len()),
+        None => None,
+    };
+
+    branch_file
+        .branches
+        .insert(branch, staged_snapshot.id.clone());
+
+    // the reflog only records the head moving, so snapshots on other
+    // branches aren't in it
+    if moves_head {
+        head_file.curr_snapshot_id = Some(staged_snapshot.id.clone());
+        head_file.write(repo)?;
+    }
+    branch_file.write(repo)?;
+
+    if moves_head {
+        file_structure::ReflogFile::append(
+            repo,
+            file_structure::ReflogEntry {
+                date: staged_snapshot.date,
+                old_snapshot_id: parent_snapshot_id.clone(),
+                new_snapshot_id: Some(staged_snapshot.id.clone()),
+                operation: String::from(if options.amend {
+                    "snapshot (amend)"
+                } else {
+                    "snapshot"
+                }),
+            },
+        )?;
+    }
+
+    if let Some(tag) = &tag {
+        tags_file
+            .tags
+            .insert(tag.clone(), staged_snapshot.id.clone());
+        tags_file.write(repo)?;
+    }
+
+    files_to_delete.delete_files(repo);
+
+    let amended_id = if options.amend {
+        parent_snapshot_id
+    } else {
+        None
+    };
+    if let Some(amended_id) = &amended_id
+        && let Err(err) = replace_amended_snapshot(repo, amended_id, &staged_snapshot.id)
+    {
         return Err(format!(
-            "A snapshot with the same id ({}) already exists.",
-            &staged_snapshot.id
+            "Created snapshot {}, but couldn't replace snapshot {} with it: {}",
+            staged_snapshot.id, amended_id, err
         ));
     }
 
-    staged_snapshot.message = snapshot_message_arg.take();
+    Ok(SnapshotOutcome::Created(CreatedSnapshot {
+        snapshot: staged_snapshot,
+        tag,
+        amended_id,
+        stats,
+        payload_size,
+        diff_size,
+        elapsed: start_time.elapsed(),
+    }))
+}
 
-    let mut head_file = file_structure::HeadFile::read()?;
-    let mut branch_file = file_structure::BranchesFile::read()?;
+/// Writes the diff between the staged snapshot and its parent, and links
+/// them in their metadata. The parent's full payload is replaced by the diff
+/// (and added to `files_to_delete`) unless it's still needed, or
+/// `keeps_parent_full` is set. Returns the path of the diff, if one was
+/// written.
+fn link_staged_snapshot(
+    repo: &Repository,
+    options: &SnapshotOptions,
+    staged_snapshot: &mut file_structure::SnapshotMetaFile,
+    parent_snapshot_id: &Option<String>,
+    merge_parent_ids: &[String],
+    keeps_parent_full: bool,
+    files_to_delete: &mut FilesToDelete,
+) -> Result<Option<String>, String> {
+    let mut diff_path = None;
 
-    match &head_file.curr_snapshot_id {
+    match &parent_snapshot_id {
+        None if repo.config.diff_from_empty => {
+            // the first snapshot is stored as a diff from the empty snapshot,
+            // so it doesn't need its full payload once the next snapshot is
+            // diffed against it, like any other snapshot
+            let staged_snapshot_diff_path = repo.prepend_snapshot_path(
+                &staged_snapshot.get_diff_path_from_child_snapshot(EMPTY_SNAPSHOT_ID),
+            );
+            generate_delta_list(
+                tar::Archive::new(io::empty()),
+                open_tar_gz(
+                    repo,
+                    &repo.prepend_snapshot_path(&staged_snapshot.get_full_payload_filename()?),
+                )?,
+                create_delta_list(repo, &staged_snapshot_diff_path, repo.config.compression)?,
+                PathOrder::from_config(&repo.config),
+            )?;
+            if repo.should_fsync() {
+                io_util::sync_file(&staged_snapshot_diff_path)?;
+            }
+            diff_path = Some(staged_snapshot_diff_path);
+
+            staged_snapshot
+                .diff_children
+                .push(String::from(EMPTY_SNAPSHOT_ID));
+            staged_snapshot.write(repo)?;
+        }
         None => {
-            staged_snapshot.write()?;
+            staged_snapshot.write(repo)?;
         }
         Some(curr_snapshot_id) => {
-            let mut curr_snapshot_meta = file_structure::SnapshotMetaFile::read(&curr_snapshot_id)?;
-            if curr_snapshot_meta.full_type != file_structure::SnapshotFullType::TarGz {
-                todo!("Not implemented: Current snapshot is not a tar.gz snapshot type");
-            }
+            let mut curr_snapshot_meta =
+                file_structure::SnapshotMetaFile::read(repo, curr_snapshot_id)?;
 
             if staged_snapshot.full_type != file_structure::SnapshotFullType::TarGz {
                 todo!("Not implemented: Staged snapshot is not a tar.gz snapshot type");
             }
 
+            // a parent that's already a diff from another child kept its full
+            // payload on purpose (ex. since it was a branch tip), so the
+            // snapshots diffed against it can still be
+            let keeps_parent_full = keeps_parent_full
+                || (repo.config.diff_direction == DiffDirection::Reverse
+                    && !curr_snapshot_meta.diff_children.is_empty());
+
             // add parent-child relations for staged snapshot
             curr_snapshot_meta.children.push(staged_snapshot.id.clone());
             staged_snapshot.parents.push(curr_snapshot_id.clone());
+            staged_snapshot
+                .parents
+                .extend(merge_parent_ids.iter().cloned());
 
-            // create diff
             let curr_snapshot_payload_full_name = curr_snapshot_meta.get_full_payload_filename()?;
+            let staged_snapshot_payload_path =
+                repo.prepend_snapshot_path(&staged_snapshot.get_full_payload_filename()?);
 
-            generate_delta_list(
-                open_tar_gz(&prepend_snapshot_path(
-                    &staged_snapshot.get_full_payload_filename()?,
-                ))?,
-                open_tar_gz(&prepend_snapshot_path(&curr_snapshot_payload_full_name))?,
-                create_delta_list(&prepend_snapshot_path(
-                    &curr_snapshot_meta.get_diff_path_from_child_snapshot(&staged_snapshot.id),
-                ))?,
+            match repo.config.diff_direction {
+                DiffDirection::Reverse => {
+                    // the current snapshot is stored as a diff from the staged
+                    // snapshot
+                    let curr_snapshot_diff_path = repo.prepend_snapshot_path(
+                        &curr_snapshot_meta.get_diff_path_from_child_snapshot(&staged_snapshot.id),
+                    );
+                    generate_delta_list(
+                        open_tar_gz(repo, &staged_snapshot_payload_path)?,
+                        tar::Archive::new(open_payload(repo, &curr_snapshot_meta)?),
+                        create_delta_list(repo, &curr_snapshot_diff_path, repo.config.compression)?,
+                        PathOrder::from_config(&repo.config),
+                    )?;
+                    diff_path = Some(curr_snapshot_diff_path);
+
+                    curr_snapshot_meta
+                        .diff_children
+                        .push(staged_snapshot.id.clone());
+                    staged_snapshot.diff_parents.push(curr_snapshot_id.clone());
+                }
+                DiffDirection::Forward => {
+                    // the staged snapshot is stored as a diff from the current
+                    // snapshot, but keeps its full payload until the next
+                    // snapshot is diffed against it
+                    let staged_snapshot_diff_path = repo.prepend_snapshot_path(
+                        &staged_snapshot.get_diff_path_from_child_snapshot(curr_snapshot_id),
+                    );
+                    generate_delta_list(
+                        tar::Archive::new(open_payload(repo, &curr_snapshot_meta)?),
+                        open_tar_gz(repo, &staged_snapshot_payload_path)?,
+                        create_delta_list(
+                            repo,
+                            &staged_snapshot_diff_path,
+                            repo.config.compression,
+                        )?,
+                        PathOrder::from_config(&repo.config),
+                    )?;
+                    diff_path = Some(staged_snapshot_diff_path);
+
+                    staged_snapshot.diff_children.push(curr_snapshot_id.clone());
+                    curr_snapshot_meta
+                        .diff_parents
+                        .push(staged_snapshot.id.clone());
+                }
+            }
+
+            // the diff is flushed before the metadata that refers to it
+            if let Some(path) = &diff_path
+                && repo.should_fsync()
+            {
+                io_util::sync_file(path)?;
+            }
+
+            // the first snapshot keeps its full payload, since every other
+            // snapshot is restored from it when diffing forward (unless it's
+            // a diff from the empty snapshot). So does a snapshot that would
+            // make a chain of diffs too long, a snapshot another branch still
+            // needs (see `keeps_parent_full`), and every snapshot in an
+            // immutable repository, since its payload can't be removed.
+            // Otherwise, mark the snapshot as having no full payload, but we
+            // will only delete the file after all snapshot metadata have been
+            // written
+            if !repo.config.immutable
+                && !keeps_parent_full
+                && !curr_snapshot_meta.diff_children.is_empty()
+                && !exceeds_max_diff_chain(repo, options, &curr_snapshot_meta, staged_snapshot)?
+            {
+                curr_snapshot_meta.full_type = file_structure::SnapshotFullType::None;
+                files_to_delete
+                    .snapshots_files
+                    .push(curr_snapshot_payload_full_name);
+            }
+
+            staged_snapshot.write(repo)?;
+            curr_snapshot_meta.write(repo)?;
+
+            // merge parents are only linked as parents, not diffed against
+            for merge_parent_id in merge_parent_ids {
+                let mut merge_parent_meta =
+                    file_structure::SnapshotMetaFile::read(repo, merge_parent_id)?;
+                merge_parent_meta.children.push(staged_snapshot.id.clone());
+                merge_parent_meta.write(repo)?;
+            }
+        }
+    }
+
+    Ok(diff_path)
+}
+
+/// Fails if a configured transformer is lossy (see
+/// `FileTransformer::is_lossless`), since its files couldn't be restored as
+/// they were. With `SnapshotOptions::allow_lossy`, warns instead.
+fn check_lossless(repo: &Repository, options: &SnapshotOptions) -> Result<(), String> {
+    if options.no_transform {
+        return Ok(());
+    }
+    let lossy_names = get_lossy_transformer_names(
+        &get_config_transformer_names(repo),
+        &get_config_transformers(repo)?,
+    );
+    if lossy_names.is_empty() {
+        return Ok(());
+    }
+
+    let message = format!(
+        "The transformers [{}] are lossy, so files they change can't be restored exactly as they were",
+        lossy_names.join(", ")
+    );
+    if !options.allow_lossy {
+        return Err(format!(
+            "{}. Use --allow-lossy to snapshot anyway.",
+            message
+        ));
+    }
+    eprintln!("Warn: {}.", message);
+    Ok(())
+}
+
+/// Where the message of a snapshot is written for the editor (see
+/// `edit_message`).
+const SNAPSHOT_MESSAGE_FILE_NAME: &str = "SNAPSHOT_MSG";
+
+/// Asks for the message of the staged snapshot in an editor, like `git
+/// commit` without `-m`. The file opened lists the changes since the head's
+/// snapshot in comments, which are lines starting with '#'. Comments are left
+/// out of the message, and `None` is returned if nothing else is written.
+fn edit_message(
+    editor: &str,
+    repo: &Repository,
+    head_snapshot_id: Option<&str>,
+    staged_snapshot: &file_structure::SnapshotMetaFile,
+) -> Result<Option<String>, String> {
+    let changes = list_staged_changes(repo, head_snapshot_id, staged_snapshot)?;
+    let path = format!("{}/{}", repo.path(JBACKUP_PATH), SNAPSHOT_MESSAGE_FILE_NAME);
+    simplify_result(fs::write(
+        &path,
+        format_message_template(head_snapshot_id, &changes),
+    ))?;
+
+    let result = run_editor(editor, &path).and_then(|_| simplify_result(fs::read(&path)));
+    if let Err(err) = fs::remove_file(&path) {
+        eprintln!("Warn: Failed to remove '{}': {}", path, err);
+    }
+    Ok(parse_message(&String::from_utf8_lossy(&result?)))
+}
+
+/// Lists the changes from the head's snapshot to the staged snapshot, or all
+/// files in the staged snapshot as added if there's no head.
+fn list_staged_changes(
+    repo: &Repository,
+    head_snapshot_id: Option<&str>,
+    staged_snapshot: &file_structure::SnapshotMetaFile,
+) -> Result<Vec<(Vec<u8>, FileChange)>, String> {
+    let delta_list_path = get_tmp_snapshot_path(repo);
+    let result = diff_staged_snapshot(repo, head_snapshot_id, staged_snapshot, &delta_list_path)
+        .and_then(|_| list_changes(open_delta_list(repo, &delta_list_path)?));
+
+    if let Err(err) = fs::remove_file(&delta_list_path)
+        && err.kind() != io::ErrorKind::NotFound
+    {
+        eprintln!("Warn: Failed to remove '{}': {}", delta_list_path, err);
+    }
+    result
+}
+
+/// Writes the delta list from the head's snapshot (or an empty tar) to the
+/// staged snapshot to `delta_list_path`.
+fn diff_staged_snapshot(
+    repo: &Repository,
+    head_snapshot_id: Option<&str>,
+    staged_snapshot: &file_structure::SnapshotMetaFile,
+    delta_list_path: &str,
+) -> Result<(), String> {
+    let staged_tar = open_tar_gz(
+        repo,
+        &repo.prepend_snapshot_path(&staged_snapshot.get_full_payload_filename()?),
+    )?;
+    let delta_list = create_delta_list(repo, delta_list_path, repo.config.compression)?;
+    let order = PathOrder::from_config(&repo.config);
+    let Some(head_snapshot_id) = head_snapshot_id else {
+        return generate_delta_list(
+            tar::Archive::new(io::empty()),
+            staged_tar,
+            delta_list,
+            order,
+        );
+    };
+
+    let head_snapshot = file_structure::SnapshotMetaFile::read(repo, head_snapshot_id)?;
+    let head_tar = tar::Archive::new(open_payload(repo, &head_snapshot)?);
+    generate_delta_list(head_tar, staged_tar, delta_list, order)
+}
+
+/// The contents of the file the message is edited in, with the changes in the
+/// snapshot in comments.
+fn format_message_template(
+    head_snapshot_id: Option<&str>,
+    changes: &[(Vec<u8>, FileChange)],
+) -> String {
+    let mut template = String::from(
+        "\n# Please enter the message for the snapshot. Lines starting with '#'\n# are ignored, and an empty message aborts the snapshot.\n#\n",
+    );
+    match head_snapshot_id {
+        Some(id) => template.push_str(&format!("# Changes since snapshot {}:\n", id)),
+        None => template.push_str("# Files in the first snapshot:\n"),
+    }
+    for (path, change) in changes {
+        template.push_str(&format!("#   {}\n", format_change(path, change)));
+    }
+    template
+}
+
+/// The message written in the editor, without comment lines or the blank
+/// lines around it, or `None` if it's empty.
+fn parse_message(contents: &str) -> Option<String> {
+    let lines: Vec<&str> = contents
+        .lines()
+        .filter(|x| !x.starts_with('#'))
+        .map(str::trim_end)
+        .collect();
+    let message = lines.join("\n");
+    let message = message.trim_matches('\n');
+    if message.trim().is_empty() {
+        None
+    } else {
+        Some(String::from(message))
+    }
+}
+
+/// Checks that the head's snapshot can be replaced by a new snapshot, which
+/// is squashed into it after it's created (see `replace_amended_snapshot`).
+/// Returns the head's snapshot.
+fn check_amendable(
+    repo: &Repository,
+    options: &SnapshotOptions,
+    head_snapshot_id: Option<&str>,
+) -> Result<file_structure::SnapshotMetaFile, String> {
+    let Some(head_snapshot_id) = head_snapshot_id else {
+        return Err(String::from("There's no snapshot to amend"));
+    };
+    if repo.config.diff_direction != DiffDirection::Reverse {
+        return Err(String::from(
+            "Amending is only supported in repositories that store reverse diffs",
+        ));
+    }
+    if !options.merge_parents.is_empty() {
+        return Err(String::from("Can't add parents when amending a snapshot"));
+    }
+    repo.ensure_snapshots_removable()?;
+
+    let amended = file_structure::SnapshotMetaFile::read(repo, head_snapshot_id)?;
+    if !amended.children.is_empty() {
+        return Err(format!(
+            "Can't amend snapshot {}, since other snapshots descend from it",
+            amended.id
+        ));
+    }
+    if amended.parents.len() > 1 {
+        return Err(format!(
+            "Can't amend snapshot {}, since amending merge snapshots is not supported",
+            amended.id
+        ));
+    }
+    amended.check_writable()?;
+    Ok(amended)
+}
+
+/// Replaces the amended snapshot with its new child, by moving the branches
+/// and tags on it to the child, then squashing it into the child.
+fn replace_amended_snapshot(
+    repo: &Repository,
+    amended_id: &str,
+    new_id: &str,
+) -> Result<(), String> {
+    let mut branches_file = file_structure::BranchesFile::read(repo)?;
+    let mut tags_file = file_structure::TagsFile::read(repo)?;
+    let mut tags_changed = false;
+    for id in branches_file.branches.values_mut() {
+        if id == amended_id {
+            *id = String::from(new_id);
+        }
+    }
+    for id in tags_file.tags.values_mut() {
+        if id == amended_id {
+            *id = String::from(new_id);
+            tags_changed = true;
+        }
+    }
+    branches_file.write(repo)?;
+    // the tags file only exists once there are tags
+    if tags_changed {
+        tags_file.write(repo)?;
+    }
+
+    squash(repo, find_range(repo, amended_id, new_id)?, None)?;
+    Ok(())
+}
+
+/// The branch a new snapshot is added to, and the snapshot it's a child of:
+/// the head's branch and snapshot, unless another branch is given (see
+/// `SnapshotOptions::on_branch`). A branch that doesn't exist yet is
+/// created at the head's snapshot.
+fn get_target_branch(
+    options: &SnapshotOptions,
+    head_file: &file_structure::HeadFile,
+    branches_file: &file_structure::BranchesFile,
+) -> Result<(String, Option<String>), String> {
+    match &options.on_branch {
+        Some(name) if *name != head_file.curr_branch => {
+            if options.amend {
+                return Err(String::from(
+                    "Can't amend a snapshot on another branch than the head's",
+                ));
+            }
+            match branches_file.branches.get(name) {
+                Some(tip_id) => Ok((name.clone(), Some(tip_id.clone()))),
+                // a new branch starts at the head's snapshot
+                None => {
+                    file_structure::check_branch_name(name)?;
+                    Ok((name.clone(), head_file.curr_snapshot_id.clone()))
+                }
+            }
+        }
+        _ => Ok((
+            head_file.curr_branch.clone(),
+            head_file.curr_snapshot_id.clone(),
+        )),
+    }
+}
+
+/// Whether replacing the full payload of the new snapshot's parent with a
+/// diff would make the chain of diffs (see `get_chain_depths`) of any
+/// snapshot longer than `SnapshotOptions::max_diff_chain`. Chains that were
+/// already longer, since the limit was set after they were made, only count
+/// if they'd get longer still.
+fn exceeds_max_diff_chain(
+    repo: &Repository,
+    options: &SnapshotOptions,
+    parent: &file_structure::SnapshotMetaFile,
+    staged_snapshot: &file_structure::SnapshotMetaFile,
+) -> Result<bool, String> {
+    let Some(max_diff_chain) = options.max_diff_chain else {
+        return Ok(false);
+    };
+
+    let mut snapshots: HashMap<String, file_structure::SnapshotMetaFile> =
+        file_structure::get_all_snapshot_meta_files(repo)?
+            .into_iter()
+            .map(|x| (x.id.clone(), x))
+            .collect();
+    let depths_before = get_chain_depths(&snapshots);
+
+    snapshots.insert(
+        parent.id.clone(),
+        file_structure::SnapshotMetaFile {
+            full_type: file_structure::SnapshotFullType::None,
+            ..parent.clone()
+        },
+    );
+    snapshots.insert(staged_snapshot.id.clone(), staged_snapshot.clone());
+    let depths_after = get_chain_depths(&snapshots);
+
+    Ok(depths_after.iter().any(|(id, depth)| {
+        depth.is_some_and(|depth| {
+            depth > max_diff_chain
+                && depths_before
+                    .get(id)
+                    .copied()
+                    .flatten()
+                    .is_none_or(|before| depth > before)
+        })
+    }))
+}
+
+/// Parses a maximum length of chains of diffs (see
+/// `SnapshotOptions::max_diff_chain`).
+pub fn parse_max_diff_chain(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(x) => Ok(x),
+        Err(_) => Err(format!(
+            "Invalid maximum diff chain length: '{}' (expected a number)",
+            value
+        )),
+    }
+}
+
+/// The extra parents a snapshot should be recorded with, in the order they
+/// were given, without the head (which is always the first parent) or
+/// duplicates.
+fn get_merge_parent_ids(
+    options: &SnapshotOptions,
+    head_snapshot_id: Option<&str>,
+) -> Result<Vec<String>, String> {
+    let Some(head_snapshot_id) = head_snapshot_id else {
+        if options.merge_parents.is_empty() {
+            return Ok(Vec::new());
+        }
+        return Err(String::from(
+            "Can't add parents to the first snapshot, since there is no head to diff against",
+        ));
+    };
+
+    let mut merge_parent_ids: Vec<String> = Vec::new();
+    for id in &options.merge_parents {
+        if id != head_snapshot_id && !merge_parent_ids.contains(id) {
+            merge_parent_ids.push(id.clone());
+        }
+    }
+    Ok(merge_parent_ids)
+}
+
+struct FilesToDelete {
+    snapshots_files: Vec<String>,
+}
+
+impl FilesToDelete {
+    fn new() -> FilesToDelete {
+        FilesToDelete {
+            snapshots_files: Vec::new(),
+        }
+    }
+
+    /// Wrapper of _delete_files that prints a warning when
+    /// the child function fails.
+    fn delete_files(&self, repo: &Repository) {
+        match self._delete_files(repo) {
+            Ok(_) => {}
+            Err(err) => eprintln!("Warn: Error when cleaning files up: {}", err),
+        }
+    }
+
+    fn _delete_files(&self, repo: &Repository) -> Result<(), String> {
+        for filepath in &self.snapshots_files {
+            simplify_result(fs::remove_file(repo.prepend_snapshot_path(filepath)))?;
+        }
+        Ok(())
+    }
+}
+
+pub struct SnapshotOptions {
+    large_file_limit: Option<LargeFileLimit>,
+    /// print each file as it's inserted
+    verbose: bool,
+    /// print only the new snapshot's id to stdout, and everything else to
+    /// stderr
+    print_id: bool,
+    /// leave out files with non-UTF-8 paths, with a warning
+    skip_non_utf8: bool,
+    /// store the contents of symlinks' targets, instead of skipping symlinks
+    follow_symlinks: bool,
+    /// directories to leave out, by what they contain
+    exclusions: DirExclusions,
+    /// the order files are stored in (see `PathOrder`)
+    order: PathOrder,
+    /// store files as they are, without the configured transformers
+    no_transform: bool,
+    /// leave out files that can't be read or transformed, instead of failing
+    keep_going: bool,
+    /// the paths of the files to snapshot, as given, instead of every file in
+    /// the working directory
+    file_list: Option<Vec<OsString>>,
+    /// ids of snapshots to record as parents after the head, making the
+    /// snapshot a merge. Only the head is diffed against.
+    merge_parents: Vec<String>,
+    /// for reading files
+    retry_policy: RetryPolicy,
+    threads: ThreadBudget,
+    /// threads each transform worker may split the work on one file across
+    jobs_per_file: usize,
+    /// files at least this large (in bytes) that no transformer changes are
+    /// mapped into memory instead of read (see `io_util::map_file`)
+    mmap_min_size: Option<u64>,
+    /// replace the head's snapshot instead of adding a child to it. The head's
+    /// snapshot can't have children or several parents, since it's squashed
+    /// into the new snapshot.
+    amend: bool,
+    /// unix timestamp (in seconds) to date the snapshot with, instead of the
+    /// current time
+    date: Option<i64>,
+    /// path of a tar.gz to snapshot the files of, instead of the working
+    /// directory (see `create_tmp_tar_from_archive`)
+    import: Option<String>,
+    /// the editor to write the message in, when no message is given (see
+    /// `edit_message`)
+    editor: Option<String>,
+    /// the branch to add the snapshot to, as a child of its tip, instead of
+    /// the head's branch. The head stays where it is. A branch that doesn't
+    /// exist is created at the head's snapshot.
+    on_branch: Option<String>,
+    /// snapshot with lossy transformers, with a warning, instead of failing
+    /// (see `check_lossless`)
+    allow_lossy: bool,
+    /// keep the parent's full payload instead of replacing it with a diff
+    /// when that would make a chain of diffs longer than this (see
+    /// `exceeds_max_diff_chain`)
+    max_diff_chain: Option<usize>,
+}
+
+impl SnapshotOptions {
+    /// The options used when none are given on the command line.
+    pub fn from_config(repo: &Repository) -> SnapshotOptions {
+        SnapshotOptions {
+            large_file_limit: repo.config.max_file_size.map(|max_size| LargeFileLimit {
+                max_size,
+                on_large_file: repo.config.on_large_file,
+            }),
+            verbose: false,
+            print_id: false,
+            skip_non_utf8: false,
+            follow_symlinks: false,
+            exclusions: DirExclusions::from_config(repo),
+            order: PathOrder::from_config(&repo.config),
+            no_transform: false,
+            keep_going: false,
+            file_list: None,
+            merge_parents: Vec::new(),
+            retry_policy: RetryPolicy::default(),
+            threads: ThreadBudget::split(thread::available_parallelism().map_or(1, |x| x.get())),
+            jobs_per_file: 1,
+            mmap_min_size: repo.config.mmap,
+            amend: false,
+            date: None,
+            import: None,
+            editor: None,
+            on_branch: None,
+            allow_lossy: false,
+            max_diff_chain: repo.config.max_diff_chain,
+        }
+    }
+
+    /// The options for snapshotting the files in a tar.gz instead of the
+    /// working directory (see `create_tmp_tar_from_archive`).
+    pub fn for_import(repo: &Repository, archive_path: String) -> SnapshotOptions {
+        SnapshotOptions {
+            import: Some(archive_path),
+            ..SnapshotOptions::from_config(repo)
+        }
+    }
+
+    /// The options for a snapshot dated `date` (a unix timestamp) instead of
+    /// the current time, so it has a known id.
+    pub fn dated(repo: &Repository, date: Option<i64>) -> SnapshotOptions {
+        SnapshotOptions {
+            date,
+            ..SnapshotOptions::from_config(repo)
+        }
+    }
+
+    /// The options for comparing the working directory with a snapshot, which
+    /// stores files without transformers if the snapshot was created without
+    /// them. Other transformers than the config's can't be used, so files may
+    /// show up as changed, with a warning.
+    pub fn for_comparing_with(
+        repo: &Repository,
+        snapshot: &file_structure::SnapshotMetaFile,
+    ) -> SnapshotOptions {
+        let no_transform = snapshot.transformers.as_ref().is_some_and(|x| x.is_empty());
+        if let Some(names) = &snapshot.transformers
+            && !no_transform
+            && *names != get_config_transformer_names(repo)
+        {
+            eprintln!(
+                "Warn: Snapshot {} was created with the transformers [{}], which differ from the config. Files are transformed with the config's transformers, so they may show up as modified.",
+                snapshot.id,
+                names.join(", ")
+            );
+        }
+
+        SnapshotOptions {
+            no_transform,
+            ..SnapshotOptions::from_config(repo)
+        }
+    }
+
+    /// The settings of the walk that lists the files to snapshot, which are
+    /// recorded in the snapshot.
+    pub fn get_walk(&self) -> SnapshotWalk {
+        SnapshotWalk {
+            skip_non_utf8: self.skip_non_utf8,
+            // files over the limit only make it abort when `on_large` is
+            // `abort`, so the snapshot has every file if it was made
+            max_file_size: self
+                .large_file_limit
+                .filter(|limit| limit.on_large_file == OnLargeFile::Skip)
+                .map(|limit| limit.max_size),
+            exclude_caches: self.exclusions.caches,
+            exclude_if_present: self.exclusions.markers.clone(),
+            one_file_system: self.exclusions.device.is_some(),
+        }
+    }
+}
+
+/// Reading and transforming files, and compressing the snapshot, both use
+/// every thread they're given. Running more threads than there are cores only
+/// makes them compete, so the cores are split between the two.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct ThreadBudget {
+    /// workers that read and transform files
+    transform: usize,
+    /// gzip compression threads
+    compress: usize,
+}
+
+impl ThreadBudget {
+    /// Splits the cores evenly, giving the extra core to the transform
+    /// workers. Both always get at least one thread.
+    fn split(available_threads: usize) -> ThreadBudget {
+        ThreadBudget {
+            transform: available_threads.div_ceil(2).max(1),
+            compress: (available_threads / 2).max(1),
+        }
+    }
+}
+
+fn parse_thread_count(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(x) if x > 0 => Ok(x),
+        _ => Err(format!(
+            "Invalid thread count: '{}' (expected a positive number)",
+            value
+        )),
+    }
+}
+
+/// Files larger than `max_size` bytes are skipped or abort the snapshot,
+/// depending on `on_large_file`.
+#[derive(Clone, Copy)]
+struct LargeFileLimit {
+    max_size: u64,
+    on_large_file: OnLargeFile,
+}
+
+/// Totals for the files inserted into a snapshot.
+#[derive(Default)]
+pub struct SnapshotStats {
+    pub num_files: usize,
+    /// size of the files before transforming and compressing
+    pub total_bytes: u64,
+    /// why each file that was left out with `--keep-going` couldn't be
+    /// snapshotted
+    pub errors: Vec<String>,
+    /// how much each transformer changed the files, in the order they're
+    /// applied
+    pub transformers: Vec<TransformerStats>,
+}
+
+/// How many bytes went into and came out of a transformer's `transform_in`,
+/// over the files it accepts (see `FileTransformer::accepts_file`). A
+/// transformer may make files larger (ex. `minecraft_mca` decompresses
+/// chunks) so that they diff and compress better.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TransformerStats {
+    pub name: String,
+    pub num_files: usize,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+impl Display for TransformerStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Transformer {}: {} file(s), {} in, {} out",
+            self.name,
+            self.num_files,
+            format_size(self.bytes_in),
+            format_size(self.bytes_out)
+        )?;
+        if self.bytes_in > 0 {
+            write!(f, " ({:.2}x)", self.bytes_out as f64 / self.bytes_in as f64)?;
+        }
+        Ok(())
+    }
+}
+
+/// `TransformerStats` of one transformer, which transform threads add to.
+#[derive(Default)]
+struct TransformerCounters {
+    num_files: AtomicUsize,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+impl TransformerCounters {
+    fn to_stats(&self, name: String) -> TransformerStats {
+        TransformerStats {
+            name,
+            num_files: self.num_files.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Creates counters for the transformers `get_config_transformers` returns,
+/// unless files are stored without transforming them.
+fn create_transformer_counters(
+    repo: &Repository,
+    options: &SnapshotOptions,
+) -> Vec<TransformerCounters> {
+    if options.no_transform {
+        return Vec::new();
+    }
+    get_config_transformer_names(repo)
+        .iter()
+        .map(|_| TransformerCounters::default())
+        .collect()
+}
+
+/// Collects the `TransformerStats` counted by `transform_file`.
+fn collect_transformer_stats(
+    repo: &Repository,
+    counters: &[TransformerCounters],
+) -> Vec<TransformerStats> {
+    counters
+        .iter()
+        .zip(get_config_transformer_names(repo))
+        .map(|(counter, name)| counter.to_stats(name))
+        .collect()
+}
+
+/// Transforms a file with each transformer in turn, adding the sizes of the
+/// files each transformer accepts to its counter.
+fn transform_file(
+    transformers: &[Box<dyn FileTransformer + Sync + Send>],
+    counters: &[TransformerCounters],
+    file_path: &str,
+    mut data: Vec<u8>,
+    jobs_per_file: usize,
+) -> Result<Vec<u8>, String> {
+    for (transformer, counter) in transformers.iter().zip(counters) {
+        let accepted = transformer.accepts_file(file_path);
+        let bytes_in = data.len() as u64;
+        data = match transformer.transform_in_parallel(file_path, data, jobs_per_file) {
+            Ok(x) => x,
+            Err(err) => {
+                return Err(format!("Failed to transform file {}: {}", file_path, err));
+            }
+        };
+        if accepted {
+            counter.num_files.fetch_add(1, Ordering::Relaxed);
+            counter.bytes_in.fetch_add(bytes_in, Ordering::Relaxed);
+            counter
+                .bytes_out
+                .fetch_add(data.len() as u64, Ordering::Relaxed);
+        }
+    }
+    Ok(data)
+}
+
+/// Lists the files left out with `--keep-going`, and returns the error the
+/// snapshot fails with.
+fn describe_file_errors(errors: &[String]) -> String {
+    for err in errors {
+        eprintln!("Error: {}", err);
+    }
+    format!(
+        "{} file(s) couldn't be snapshotted, and were left out",
+        errors.len()
+    )
+}
+
+/// Creates a `tar` of the current working directly, excluding "./.jbackup",
+/// and commits it as a new snapshot's full payload. Returns `None` if the
+/// `tar` is the same as the full payload of the head's snapshot.
+fn create_full_snapshot(
+    repo: &Repository,
+    options: &SnapshotOptions,
+    head_snapshot_id: Option<&str>,
+) -> Result<Option<(file_structure::SnapshotMetaFile, SnapshotStats)>, String> {
+    let (tmp_tar_path, stats) = match &options.import {
+        Some(archive_path) => create_tmp_tar_from_archive(repo, options, archive_path)?,
+        None => create_tmp_tar(repo, options)?,
+    };
+    let md5 = calc_md5(&tmp_tar_path)?;
+
+    let unchanged = match head_snapshot_id {
+        Some(id) => has_payload_md5(repo, id, &md5),
+        None => Ok(false),
+    };
+    let unchanged = match unchanged {
+        Ok(x) => x,
+        Err(err) => {
+            simplify_result(fs::remove_file(&tmp_tar_path))?;
+            return Err(err);
+        }
+    };
+    if unchanged {
+        simplify_result(fs::remove_file(&tmp_tar_path))?;
+        if !stats.errors.is_empty() {
+            print_info(
+                options.print_id,
+                "No changes in the files that could be read, so no snapshot was created.",
+            );
+            return Err(describe_file_errors(&stats.errors));
+        }
+        return Ok(None);
+    }
+
+    let now = SystemTime::now();
+    let timestamp = match options.date {
+        Some(x) => Ok(x),
+        None => to_timestamp(now),
+    };
+    let id = timestamp.and_then(|timestamp| {
+        // ULIDs are dated to the millisecond
+        let timestamp_ms = match options.date {
+            Some(date) => date.saturating_mul(1000),
+            None => timestamp * 1000 + i64::from(to_subsec_millis(now)),
+        };
+        Ok((
+            timestamp,
+            new_snapshot_id(repo, timestamp, timestamp_ms, &md5)?,
+        ))
+    });
+    let (timestamp, id) = match id {
+        Ok(x) => x,
+        Err(err) => {
+            simplify_result(fs::remove_file(&tmp_tar_path))?;
+            return Err(err);
+        }
+    };
+
+    let snapshot_metadata = file_structure::SnapshotMetaFile {
+        id: id.clone(),
+        version: file_structure::SNAPSHOT_META_VERSION,
+        full_type: file_structure::SnapshotFullType::TarGz,
+        date: timestamp,
+        message: None,
+        children: Vec::new(),
+        parents: Vec::new(),
+        diff_children: Vec::new(),
+        diff_parents: Vec::new(),
+        transformers: Some(if options.no_transform {
+            Vec::new()
+        } else {
+            get_config_transformer_names(repo)
+        }),
+        // imported and listed files weren't walked from the working
+        // directory, and files that couldn't be read were left out, so the
+        // snapshot is partial: restoring it mustn't remove the other files
+        walk: if options.import.is_some() || options.file_list.is_some() || !stats.errors.is_empty()
+        {
+            None
+        } else {
+            Some(options.get_walk())
+        },
+    };
+
+    // checked before committing, so the existing snapshot's payload is never
+    // replaced by (or left next to) the new one
+    let commit_result = if simplify_result(fs::exists(
+        file_structure::SnapshotMetaFile::get_meta_file_path(repo, &id),
+    ))? {
+        Err(format!(
+            "A snapshot with the same id ({}) already exists. Identical snapshots made within the same second have the same id.",
+            &id
+        ))
+    } else {
+        commit_tmp_snapshot(repo, &tmp_tar_path, &snapshot_metadata)
+    };
+
+    if let Err(err) = commit_result {
+        simplify_result(fs::remove_file(&tmp_tar_path))?;
+        return Err(err);
+    }
+
+    Ok(Some((snapshot_metadata, stats)))
+}
+
+/// Whether the full payload of the snapshot has the md5. Snapshot ids in the
+/// `timestamp-hash` format end with it, while for other ids, it's calculated
+/// from the payload. Snapshots without a full payload are taken to differ.
+fn has_payload_md5(repo: &Repository, snapshot_id: &str, md5: &str) -> Result<bool, String> {
+    if let Some(id_md5) = file_structure::get_payload_md5_from_id(snapshot_id) {
+        return Ok(id_md5 == md5);
+    }
+
+    let snapshot = file_structure::SnapshotMetaFile::read(repo, snapshot_id)?;
+    if snapshot.full_type == file_structure::SnapshotFullType::None {
+        return Ok(false);
+    }
+    let payload_path = repo.prepend_snapshot_path(&snapshot.get_full_payload_filename()?);
+    Ok(calc_md5(&payload_path)? == md5)
+}
+
+/// Makes the id of a new snapshot in the configured format (see
+/// `SnapshotIdFormat`), for a snapshot dated `timestamp` (`timestamp_ms` in
+/// milliseconds) with a full payload with the md5.
+fn new_snapshot_id(
+    repo: &Repository,
+    timestamp: i64,
+    timestamp_ms: i64,
+    md5: &str,
+) -> Result<String, String> {
+    match repo.config.snapshot_id_format {
+        SnapshotIdFormat::TimestampHash => Ok(timestamp.to_string() + "-" + md5),
+        SnapshotIdFormat::Ulid => {
+            // the random bits only need to differ between snapshots made in
+            // the same millisecond
+            let nanos = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+                Ok(n) => n.as_nanos(),
+                Err(_) => 0,
+            };
+            let mut hasher = Sha256::new();
+            hasher.update(md5.as_bytes());
+            hasher.update(nanos.to_le_bytes());
+            hasher.update(process::id().to_le_bytes());
+            let hash = hasher.finalize();
+            let mut random = [0u8; 16];
+            random.copy_from_slice(&hash[..16]);
+
+            Ok(format_ulid(
+                u64::try_from(timestamp_ms).unwrap_or(0),
+                u128::from_le_bytes(random),
+            ))
+        }
+        SnapshotIdFormat::Seq => Ok(format!("{:010}", ConfigFile::next_snapshot_seq(repo)?)),
+    }
+}
+
+const CROCKFORD_BASE32: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// Formats a ULID (see https://github.com/ulid/spec) from a unix timestamp in
+/// milliseconds, of which the low 48 bits are used, and random bits, of which
+/// the low 80 bits are used.
+fn format_ulid(timestamp_ms: u64, random: u128) -> String {
+    let value =
+        (u128::from(timestamp_ms & 0xFFFF_FFFF_FFFF) << 80) | (random & ((1u128 << 80) - 1));
+    // 26 characters of 5 bits is 130 bits, so the first character only has
+    // 3 bits of the value
+    (0..26)
+        .map(|i| CROCKFORD_BASE32[((value >> (125 - 5 * i)) & 31) as usize] as char)
+        .collect()
+}
+
+fn to_subsec_millis(time: SystemTime) -> u32 {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(x) => x.subsec_millis(),
+        Err(_) => 0,
+    }
+}
+
+/// Converts a time to a unix timestamp (in seconds), for dating snapshots.
+/// Fails instead of dating a snapshot 1970 if the clock is set before 1970.
+pub fn to_timestamp(time: SystemTime) -> Result<i64, String> {
+    let secs = match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(x) => x.as_secs(),
+        Err(_) => {
+            return Err(String::from(
+                "The system clock is set before 1970. Please check the system's date and time.",
+            ));
+        }
+    };
+    match i64::try_from(secs) {
+        Ok(x) => Ok(x),
+        Err(_) => Err(format!(
+            "The system clock is set too far in the future ({} seconds after 1970). Please check the system's date and time.",
+            secs
+        )),
+    }
+}
+
+/// Creates a `tar` of the current working directly, excluding "./.jbackup".
+/// The `tar` is placed in the returned path.
+pub fn create_tmp_tar(
+    repo: &Repository,
+    options: &SnapshotOptions,
+) -> Result<(String, SnapshotStats), String> {
+    // the files are listed up front, so progress can report the total
+    let mut list_errors = Vec::new();
+    let file_paths = list_files_to_snapshot(repo, options, &mut list_errors)?;
+    let total_files = file_paths.len() as u64;
+    // paths are stored relative to the root, without the "{root}/" they're
+    // listed with
+    let root_len = repo.root().len() + 1;
+
+    let output_path = get_tmp_snapshot_path(repo);
+    let output_file = BufWriter::with_capacity(
+        repo.io_buffer_size(),
+        simplify_result(File::create(&output_path))?,
+    );
+
+    let gz_builder: ParCompress<Gzip> = simplify_result(
+        ParCompressBuilder::new()
+            .compression_level(Compression::fast()) // todo: this should be configurable
+            .num_threads(options.threads.compress),
+    )?
+    .from_writer(output_file);
+    let tar_builder = Box::new(tar::Builder::new(gz_builder));
+    let tar_format = repo.config.tar_format;
+    let keep_going = options.keep_going;
+
+    let mut transformer_pipeline = MultithreadPipeline::<
+        (OsString, Option<OsString>),
+        Result<(FileContents, Metadata, OsString, Option<OsString>), String>,
+        _,
+    >::new(
+        (
+            tar_builder,
+            SnapshotStats {
+                errors: list_errors,
+                ..SnapshotStats::default()
+            },
+        ),
+        Box::new(move |(tar_builder, stats), res| match res {
+            Ok((transformed_data, file_metadata, file_path, link_target)) => {
+                stats.num_files += 1;
+
+                progress_util::report(ProgressEvent::File {
+                    path: &file_path.to_string_lossy()[root_len..],
+                    done: stats.num_files as u64,
+                    total: Some(total_files),
+                });
+
+                let mut header = tar_format.new_header();
+                header.set_metadata(&file_metadata);
+
+                match link_target {
+                    Some(link_target) => simplify_result(append_tar_link(
+                        tar_builder,
+                        &mut header,
+                        &file_path.as_bytes()[root_len..],
+                        &link_target.as_bytes()[root_len..],
+                    )),
+                    None => {
+                        stats.total_bytes += file_metadata.len();
+                        header.set_size(transformed_data.len() as u64);
+                        simplify_result(append_tar_entry(
+                            tar_builder,
+                            &mut header,
+                            &file_path.as_bytes()[root_len..],
+                            &*transformed_data,
+                        ))
+                    }
+                }
+            }
+            Err(err) if keep_going => {
+                stats.errors.push(err);
+                Ok(())
+            }
+            // returned by `finalize`, after which the tar is removed
+            Err(err) => Err(err),
+        }),
+    );
+
+    let transformers = if options.no_transform {
+        Vec::new()
+    } else {
+        get_config_transformers(repo)?
+    };
+    let counters = Arc::new(create_transformer_counters(repo, options));
+    let worker_state = (
+        Arc::new(transformers),
+        Arc::clone(&counters),
+        repo.tmp_path(),
+    );
+    let verbose = options.verbose;
+    let print_id = options.print_id;
+    let retry_policy = options.retry_policy;
+    let jobs_per_file = options.jobs_per_file;
+    let mmap_min_size = options.mmap_min_size;
+
+    transformer_pipeline.spawn_workers(
+        options.threads.transform,
+        worker_state,
+        move |(transformers, counters, tmp_dir), (file_path, link_target)| {
+            // paths may not be UTF-8, but transformers only need the path to
+            // identify the type of file
+            let file_path_lossy = file_path.to_string_lossy();
+
+            // files on network mounts may fail to read temporarily
+            let file_metadata = match retry_transient(retry_policy, || fs::metadata(&file_path)) {
+                Ok(x) => x,
+                Err(err) => {
+                    return Err(format!(
+                        "Failed to read file metadata for file {}: {}",
+                        file_path_lossy, err
+                    ));
+                }
+            };
+            // large files that no transformer changes are stored as they
+            // are, so they're mapped instead of copied into memory
+            let map = link_target.is_none()
+                && mmap_min_size.is_some_and(|min_size| file_metadata.len() >= min_size)
+                && !transformers
+                    .iter()
+                    .any(|x| x.accepts_file(&file_path_lossy));
+            let file_contents = match retry_transient(retry_policy, || {
+                if map {
+                    map_file(&file_path, file_metadata.len(), tmp_dir)
+                } else {
+                    fs::read(&file_path).map(FileContents::Read)
+                }
+            }) {
+                Ok(x) => x,
+                Err(err) => {
+                    return Err(format!("Failed to read file {}: {}", file_path_lossy, err));
+                }
+            };
+
+            // hard links are stored as a link to the first path of the file
+            if link_target.is_some() {
+                if verbose {
+                    print_info(print_id, &format!("Linking: {}", file_path_lossy));
+                }
+                return Ok((
+                    FileContents::Read(Vec::new()),
+                    file_metadata,
+                    file_path,
+                    link_target,
+                ));
+            }
+
+            if verbose {
+                print_info(print_id, &format!("Inserting: {}", file_path_lossy));
+            }
+
+            let FileContents::Read(data) = file_contents else {
+                return Ok((file_contents, file_metadata, file_path, None));
+            };
+            let transformed_data = transform_file(
+                transformers,
+                counters,
+                &file_path_lossy,
+                data,
+                jobs_per_file,
             )?;
 
-            curr_snapshot_meta
-                .diff_children
-                .push(staged_snapshot.id.clone());
-            staged_snapshot.diff_parents.push(curr_snapshot_id.clone());
+            Ok((
+                FileContents::Read(transformed_data),
+                file_metadata,
+                file_path,
+                None,
+            ))
+        },
+    );
 
-            // mark snapshot as having no full payload, but we will only delete the file
-            // after all snapshot metadata have been written
-            curr_snapshot_meta.full_type = file_structure::SnapshotFullType::None;
-            files_to_delete
-                .snapshots_files
-                .push(curr_snapshot_payload_full_name);
+    for file_path in file_paths {
+        transformer_pipeline.write(file_path);
+        transformer_pipeline.poll();
+    }
 
-            staged_snapshot.write()?;
-            curr_snapshot_meta.write()?;
+    let (tar_builder, mut stats) = match transformer_pipeline.finalize() {
+        Ok(x) => x,
+        Err(err) => {
+            simplify_result(fs::remove_file(&output_path))?;
+            return Err(err);
         }
+    };
+    simplify_result(tar_builder.into_inner())?;
+    stats.transformers = collect_transformer_stats(repo, &counters);
+
+    Ok((output_path, stats))
+}
+
+/// A file read from an archive by `create_tmp_tar_from_archive`, before it's
+/// stored in the snapshot.
+struct ImportedFile {
+    path: Vec<u8>,
+    /// the path of the file it's a hard link to, if it's a hard link
+    link_target: Option<Vec<u8>>,
+    mode: u32,
+    mtime: u64,
+    uid: u64,
+    gid: u64,
+    /// size before transforming
+    size: u64,
+    /// where the transformed contents are in the spool file
+    offset: u64,
+    len: u64,
+}
+
+/// Creates a `tar` of the files in the tar.gz at `archive_path`, like
+/// `create_tmp_tar` does for the working directory, transforming them with
+/// the configured transformers. The `tar` is placed in the returned path.
+///
+/// Archives aren't in the order snapshots are (see `walk_file_tree`), so the
+/// transformed files are spooled to a temporary file, then stored in order.
+/// The archive should be checked with `check_import_archive` first.
+pub fn create_tmp_tar_from_archive(
+    repo: &Repository,
+    options: &SnapshotOptions,
+    archive_path: &str,
+) -> Result<(String, SnapshotStats), String> {
+    let transformers = if options.no_transform {
+        Vec::new()
+    } else {
+        get_config_transformers(repo)?
+    };
+
+    let counters = create_transformer_counters(repo, options);
+
+    let order = PathOrder::from_config(&repo.config);
+    let output_path = get_tmp_snapshot_path(repo);
+    let spool_path = get_tmp_spool_path(&output_path);
+    let spool = File::options()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&spool_path);
+    let result = simplify_result(spool).and_then(|mut spool| {
+        let mut files = Vec::new();
+        read_import_archive(
+            repo,
+            archive_path,
+            order,
+            false,
+            |path, entry, link_target| {
+                // tools leave out fields they don't have, which are read as
+                // the defaults of a new file
+                let header = entry.header();
+                let mut file = ImportedFile {
+                    path: path.to_vec(),
+                    link_target: link_target.map(|x| x.to_vec()),
+                    mode: header.mode().unwrap_or(0o644),
+                    mtime: header.mtime().unwrap_or(0),
+                    uid: header.uid().unwrap_or(0),
+                    gid: header.gid().unwrap_or(0),
+                    size: 0,
+                    offset: simplify_result(spool.stream_position())?,
+                    len: 0,
+                };
+
+                if file.link_target.is_none() {
+                    let mut data = Vec::new();
+                    describe_corruption(archive_path, entry.read_to_end(&mut data))?;
+                    file.size = data.len() as u64;
+
+                    let file_path_lossy = format!("./{}", String::from_utf8_lossy(path));
+                    data = transform_file(
+                        &transformers,
+                        &counters,
+                        &file_path_lossy,
+                        data,
+                        options.jobs_per_file,
+                    )?;
+                    simplify_result(spool.write_all(&data))?;
+                    file.len = data.len() as u64;
+                }
+
+                files.push(file);
+                Ok(())
+            },
+        )?;
+
+        files.sort_by(|a, b| order.cmp_paths(&a.path, &b.path));
+        write_imported_tar(repo, spool, &files, &output_path)
+    });
+
+    if fs::exists(&spool_path).unwrap_or(false) {
+        simplify_result(fs::remove_file(&spool_path))?;
     }
+    match result {
+        Ok(mut stats) => {
+            stats.transformers = collect_transformer_stats(repo, &counters);
+            Ok((output_path, stats))
+        }
+        Err(err) => {
+            if fs::exists(&output_path).unwrap_or(false) {
+                simplify_result(fs::remove_file(&output_path))?;
+            }
+            Err(err)
+        }
+    }
+}
 
-    println!("Created snapshot with id: {}", &staged_snapshot.id);
+/// Writes the files spooled by `create_tmp_tar_from_archive`, sorted in the
+/// order they're stored, to a `tar` at `output_path`. Hard links are stored
+/// as a link to the first path of the file, like `find_hard_link_target`
+/// does, which may not be the path the archive linked to.
+fn write_imported_tar(
+    repo: &Repository,
+    mut spool: File,
+    files: &[ImportedFile],
+    output_path: &str,
+) -> Result<SnapshotStats, String> {
+    let by_path: HashMap<&[u8], &ImportedFile> =
+        files.iter().map(|x| (x.path.as_slice(), x)).collect();
+    // path of the file each hard linked file was first stored as
+    let mut stored_paths: HashMap<&[u8], &[u8]> = HashMap::new();
+    let mut stats = SnapshotStats::default();
 
-    head_file.curr_snapshot_id = Some(staged_snapshot.id.clone());
-    branch_file
-        .branches
-        .insert(head_file.curr_branch.clone(), staged_snapshot.id.clone());
+    let mut tar_builder = create_tar_gz(repo, output_path)?;
+    for file in files {
+        let contents_file = match &file.link_target {
+            Some(target) => by_path[target.as_slice()],
+            None => file,
+        };
 
-    head_file.write()?;
-    branch_file.write()?;
+        let mut header = repo.config.tar_format.new_header();
+        header.set_mode(contents_file.mode);
+        header.set_mtime(contents_file.mtime);
+        header.set_uid(contents_file.uid);
+        header.set_gid(contents_file.gid);
+
+        stats.num_files += 1;
+        match stored_paths.entry(&contents_file.path) {
+            Entry::Occupied(stored_path) => {
+                simplify_result(append_tar_link(
+                    &mut tar_builder,
+                    &mut header,
+                    &file.path,
+                    stored_path.get(),
+                ))?;
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(&file.path);
+                stats.total_bytes += contents_file.size;
+                header.set_size(contents_file.len);
+                simplify_result(spool.seek(SeekFrom::Start(contents_file.offset)))?;
+                simplify_result(append_tar_entry(
+                    &mut tar_builder,
+                    &mut header,
+                    &file.path,
+                    (&mut spool).take(contents_file.len),
+                ))?;
+            }
+        }
+    }
+    let mut writer = simplify_result(simplify_result(tar_builder.into_inner())?.finish())?;
+    simplify_result(writer.flush())?;
 
-    files_to_delete.delete_files();
+    Ok(stats)
+}
+
+/// Checks that the tar.gz at `archive_path` can be imported as a snapshot
+/// (see `read_import_archive`), reading it to the end.
+pub fn check_import_archive(
+    repo: &Repository,
+    archive_path: &str,
+    order: PathOrder,
+) -> Result<(), String> {
+    read_import_archive(repo, archive_path, order, true, |_, _, _| Ok(()))
+}
+
+/// Reads the files in a tar.gz being imported as a snapshot, calling
+/// `on_file` with each file's path (relative to the working directory,
+/// without a leading "./"), its entry, and the path of the file it's a hard
+/// link to, if it's a hard link.
+///
+/// Fails if the archive is truncated or corrupt, or has a path that can't be
+/// restored into the working directory (an absolute path, or one with a ".."
+/// component), a path more than once (or paths that differ only by case, if
+/// `order` ignores case), or a hard link to a file it doesn't have.
+/// Directories are skipped, since snapshots only store files, and so are
+/// symlinks, special files and .jbackup, with a warning if `warn`.
+fn read_import_archive(
+    repo: &Repository,
+    archive_path: &str,
+    order: PathOrder,
+    warn: bool,
+    mut on_file: impl FnMut(&[u8], &mut TarEntry, Option<&[u8]>) -> Result<(), String>,
+) -> Result<(), String> {
+    let mut tar_reader = open_tar_gz(repo, archive_path)?;
+    // the file each path holds the contents of, since hard links may link to
+    // other hard links, by `path_key`
+    let mut contents_paths: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+    let path_key = |path: &[u8]| match order {
+        PathOrder::Bytes => path.to_vec(),
+        PathOrder::IgnoreCase => fold_case(path),
+    };
+
+    for entry in describe_corruption(archive_path, tar_reader.entries())? {
+        let mut entry = describe_corruption(archive_path, entry)?;
+        let entry_path = entry.path_bytes().into_owned();
+        let entry_path_lossy = String::from_utf8_lossy(&entry_path).into_owned();
+        let entry_type = entry.header().entry_type();
+
+        if entry_type.is_dir() {
+            continue;
+        }
+        let path = normalize_import_path(&entry_path)?;
+        if path.is_empty() || path.split(|x| *x == b'/').next() == Some(b".jbackup") {
+            if warn {
+                eprintln!("Warn: Skipping '{}' in the archive", entry_path_lossy);
+            }
+            continue;
+        }
+        if !entry_type.is_file() && !entry_type.is_hard_link() {
+            if warn {
+                eprintln!(
+                    "Warn: Skipping '{}' in the archive, since it isn't a file",
+                    entry_path_lossy
+                );
+            }
+            continue;
+        }
+        if contents_paths.contains_key(&path_key(&path)) {
+            return Err(format!(
+                "'{}' is in the archive more than once{}",
+                entry_path_lossy,
+                match order {
+                    PathOrder::Bytes => "",
+                    PathOrder::IgnoreCase => " (ignoring case)",
+                }
+            ));
+        }
+
+        let link_target = match get_link_target(&entry)? {
+            Some(target) => match contents_paths.get(&path_key(&normalize_import_path(&target)?)) {
+                Some(contents_path) => Some(contents_path.clone()),
+                None => {
+                    return Err(format!(
+                        "The hard link '{}' links to '{}', which isn't a file earlier in the archive",
+                        entry_path_lossy,
+                        String::from_utf8_lossy(&target)
+                    ));
+                }
+            },
+            None => None,
+        };
 
-    Ok(())
-}
+        on_file(&path, &mut entry, link_target.as_deref())?;
+        describe_corruption(archive_path, io::copy(&mut entry, &mut io::sink()))?;
+        let contents_path = link_target.unwrap_or_else(|| path.clone());
+        contents_paths.insert(path_key(&path), contents_path);
+    }
 
-struct FilesToDelete {
-    snapshots_files: Vec<String>,
+    // the tar ends before the gzip stream does, so the end of the gzip
+    // stream (with its checksum) is read separately
+    let result = io::copy(&mut tar_reader.into_inner(), &mut io::sink());
+    describe_corruption(archive_path, result.map(|_| ()))
 }
 
-impl FilesToDelete {
-    fn new() -> FilesToDelete {
-        FilesToDelete {
-            snapshots_files: Vec::new(),
+/// The path of an archive's entry, relative to the working directory, without
+/// "." components (ex. "./a/b" is "a/b").
+fn normalize_import_path(path: &[u8]) -> Result<Vec<u8>, String> {
+    let mut components = Vec::new();
+    for component in path.split(|x| *x == b'/') {
+        match component {
+            b"" | b"." => {}
+            b".." => {
+                return Err(format!(
+                    "'{}' in the archive is outside of the working directory",
+                    String::from_utf8_lossy(path)
+                ));
+            }
+            _ => components.push(component),
         }
     }
+    if path.starts_with(b"/") {
+        return Err(format!(
+            "'{}' in the archive is an absolute path",
+            String::from_utf8_lossy(path)
+        ));
+    }
+    Ok(components.join(&b'/'))
+}
 
-    /// Wrapper of _delete_files that prints a warning when
-    /// the child function fails.
-    fn delete_files(&self) {
-        match self._delete_files() {
-            Ok(_) => {}
-            Err(err) => eprintln!("Warn: Error when cleaning files up: {}", err),
+/// Lists the files in the working directory that `create_tmp_tar` stores, in
+/// the order they're stored, with the path each hard link is stored as a link
+/// to (see `find_hard_link_target`).
+///
+/// Files that are left out (see `SnapshotOptions`) are left out of the list,
+/// with a warning. With `keep_going`, files that can't be read are left out,
+/// and why is added to `errors`.
+fn list_files_to_snapshot(
+    repo: &Repository,
+    options: &SnapshotOptions,
+    errors: &mut Vec<String>,
+) -> Result<Vec<(OsString, Option<OsString>)>, String> {
+    let ignored_dirs = get_ignored_dirs(repo)?;
+    let file_paths = match &options.file_list {
+        Some(file_list) => check_file_list(
+            repo,
+            file_list,
+            &ignored_dirs,
+            options.follow_symlinks,
+            options.order,
+            options.keep_going.then_some(&mut *errors),
+        )?,
+        None => list_file_tree(
+            repo.root().into(),
+            &ignored_dirs,
+            options.follow_symlinks,
+            &options.exclusions,
+            options.order,
+        )?,
+    };
+    // the first path of each file with several hard links, by (device, inode)
+    let mut hard_links = HashMap::new();
+    let mut files = Vec::new();
+    let mut skip_file = |err: String| {
+        if options.keep_going {
+            errors.push(err);
+            Ok(())
+        } else {
+            Err(err)
         }
-    }
+    };
 
-    fn _delete_files(&self) -> Result<(), String> {
-        for filepath in &self.snapshots_files {
-            simplify_result(fs::remove_file(
-                String::from(SNAPSHOTS_PATH) + "/" + &filepath,
-            ))?;
+    for file_path in file_paths {
+        if options.skip_non_utf8 && file_path.to_str().is_none() {
+            eprintln!("Warn: Skipping {:?} since its path is not UTF-8", file_path);
+            continue;
         }
-        Ok(())
-    }
-}
 
-/// Creates a `tar` of the current working directly, excluding "./.jbackup".
-/// The `tar` is placed in the returned path.
-fn create_full_snapshot() -> Result<file_structure::SnapshotMetaFile, String> {
-    let tmp_tar_path = create_tmp_tar()?;
-    let md5 = calc_md5(&tmp_tar_path)?;
-    let timestamp = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
-        Ok(n) => n.as_secs().try_into().unwrap(),
-        Err(_) => 0,
-    };
+        // check the size before the file is read, so huge files are never buffered
+        if let Some(limit) = options.large_file_limit {
+            let file_size = match fs::metadata(&file_path) {
+                Ok(x) => x.len(),
+                Err(err) => {
+                    skip_file(format!(
+                        "Failed to read file metadata for file {}: {}",
+                        file_path.to_string_lossy(),
+                        err
+                    ))?;
+                    continue;
+                }
+            };
+            if file_size > limit.max_size {
+                let file_path = file_path.to_string_lossy();
+                match limit.on_large_file {
+                    OnLargeFile::Skip => {
+                        eprintln!(
+                            "Warn: Skipping '{}' since it's larger than the max file size ({} > {} bytes)",
+                            file_path, file_size, limit.max_size
+                        );
+                        continue;
+                    }
+                    OnLargeFile::Abort => {
+                        return Err(format!(
+                            "'{}' is larger than the max file size ({} > {} bytes). Use '--on-large skip' to leave it out of the snapshot.",
+                            file_path, file_size, limit.max_size
+                        ));
+                    }
+                }
+            }
+        }
 
-    let id: String = timestamp.to_string() + "-" + &md5;
+        let link_target = match find_hard_link_target(&mut hard_links, &file_path) {
+            Ok(x) => x,
+            Err(err) => {
+                skip_file(format!(
+                    "Failed to read file metadata for file {}: {}",
+                    file_path.to_string_lossy(),
+                    err
+                ))?;
+                continue;
+            }
+        };
+        files.push((file_path, link_target));
+    }
 
-    let snapshot_metadata = file_structure::SnapshotMetaFile {
-        id: id.clone(),
-        full_type: file_structure::SnapshotFullType::TarGz,
-        date: timestamp,
-        message: None,
-        children: Vec::new(),
-        parents: Vec::new(),
-        diff_children: Vec::new(),
-        diff_parents: Vec::new(),
-    };
+    Ok(files)
+}
 
-    commit_tmp_snapshot(&tmp_tar_path, &snapshot_metadata)?;
+/// If `file_path` is a hard link to a file that was already visited, returns
+/// the path it was visited at. Otherwise, remembers the path if the file has
+/// other hard links.
+///
+/// Symlinks (when following symlinks) aren't hard links, so they're always
+/// stored as their own file.
+fn find_hard_link_target(
+    hard_links: &mut HashMap<(u64, u64), OsString>,
+    file_path: &OsString,
+) -> io::Result<Option<OsString>> {
+    let metadata = fs::symlink_metadata(file_path)?;
+    if !metadata.is_file() || metadata.nlink() < 2 {
+        return Ok(None);
+    }
 
-    Ok(snapshot_metadata)
+    match hard_links.entry((metadata.dev(), metadata.ino())) {
+        Entry::Occupied(entry) => Ok(Some(entry.get().clone())),
+        Entry::Vacant(entry) => {
+            entry.insert(file_path.clone());
+            Ok(None)
+        }
+    }
 }
 
-/// Creates a `tar` of the current working directly, excluding "./.jbackup".
-/// The `tar` is placed in the returned path.
-fn create_tmp_tar() -> Result<String, String> {
-    let output_path = String::from(JBACKUP_PATH) + "/tmp_snapshot.tar.gz";
-    let output_file = simplify_result(File::create(&output_path))?;
+/// Temporary snapshot files are named `tmp_snapshot-{pid}-{nanos}.tar.gz`
+/// (or `tmp_snapshot-{pid}-{nanos}-spool.tar.gz`, see `get_tmp_spool_path`)
+/// so that a snapshot never writes over another snapshot's temporary file.
+const TMP_SNAPSHOT_PREFIX: &str = "tmp_snapshot";
+const TMP_SNAPSHOT_SUFFIX: &str = ".tar.gz";
 
-    let gz_builder: ParCompress<Gzip> = ParCompressBuilder::new()
-        .compression_level(Compression::fast()) // todo: this should be configurable
-        .from_writer(output_file);
-    let tar_builder = Box::new(tar::Builder::new(gz_builder));
+/// A unique path for a scratch file, which is removed by the next snapshot if
+/// it's left behind (see `remove_interrupted_tmp_snapshots`).
+pub fn get_tmp_snapshot_path(repo: &Repository) -> String {
+    let nanos = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(n) => n.as_nanos(),
+        Err(_) => 0,
+    };
 
-    let mut transformer_pipeline =
-        MultithreadPipeline::<OsString, Result<(Vec<u8>, Metadata, String), String>, _>::new(
-            tar_builder,
-            Box::new(move |tar_builder, res| match res {
-                Ok((transformed_data, file_metadata, file_path)) => {
-                    let mut header = tar::Header::new_gnu();
-                    header.set_metadata(&file_metadata);
-                    header.set_size(transformed_data.len().try_into().unwrap());
-
-                    tar_builder
-                        .append_data(&mut header, &file_path[2..], transformed_data.as_slice())
-                        .unwrap();
-                }
-                Err(err) => panic!("{}", err),
-            }),
-        );
+    format!(
+        "{}/{}-{}-{}{}",
+        repo.tmp_path(),
+        TMP_SNAPSHOT_PREFIX,
+        process::id(),
+        nanos,
+        TMP_SNAPSHOT_SUFFIX
+    )
+}
 
-    let transformer_names = ConfigFile::read()?.transformers;
-    let transformers_arc = Arc::new(get_transformers(&transformer_names)?);
+/// A path for a scratch file used while writing `tmp_snapshot_path`. Two
+/// paths from `get_tmp_snapshot_path` can be the same when they're made in the
+/// same tick, so the spool is named after the file it's for instead.
+fn get_tmp_spool_path(tmp_snapshot_path: &str) -> String {
+    let name = tmp_snapshot_path
+        .strip_suffix(TMP_SNAPSHOT_SUFFIX)
+        .unwrap_or(tmp_snapshot_path);
+    format!("{}-spool{}", name, TMP_SNAPSHOT_SUFFIX)
+}
 
-    transformer_pipeline.spawn_workers(8, transformers_arc, |transformers, file_path| {
-        let Some(file_path) = file_path.to_str() else {
-            return Err(format!(
-                "Failed to convert file path '{:?}' to UTF-8",
-                file_path,
-            ));
-        };
+/// Removes temporary snapshot files left behind by a snapshot that was
+/// interrupted before it could be committed. Files of snapshots that are
+/// still running (by the pid in their name) are kept.
+pub fn remove_interrupted_tmp_snapshots(repo: &Repository) -> Result<(), String> {
+    let dir = simplify_result(fs::read_dir(repo.tmp_path()))?;
 
-        let Ok(file_metadata) = simplify_result(fs::metadata(&file_path)) else {
-            return Err(format!(
-                "Failed to read file metadata for file {}",
-                file_path
-            ));
+    for item in dir {
+        let Ok(entry) = item else {
+            continue;
         };
-        let Ok(file_contents) = simplify_result(fs::read(&file_path)) else {
-            return Err(format!("Failed to read file {}", file_path));
+        let Ok(file_name) = entry.file_name().into_string() else {
+            continue;
         };
 
-        println!("Inserting: {}", file_path);
-
-        let mut transformed_data = file_contents;
-
-        for transformer in transformers.iter() {
-            transformed_data = transformer.transform_in(&file_path, transformed_data)?;
+        let Some(name) = file_name
+            .strip_prefix(TMP_SNAPSHOT_PREFIX)
+            .and_then(|x| x.strip_suffix(TMP_SNAPSHOT_SUFFIX))
+        else {
+            continue;
+        };
+        let pid = name
+            .strip_prefix('-')
+            .and_then(|x| x.split('-').next())
+            .and_then(|x| x.parse::<u32>().ok());
+        if !pid.is_some_and(is_process_running) {
+            eprintln!(
+                "Warn: Found '{}' from a previous snapshot that was interrupted. Removing it.",
+                file_name
+            );
+            simplify_result(fs::remove_file(entry.path()))?;
         }
+    }
 
-        Ok((transformed_data, file_metadata, String::from(file_path)))
-    });
-
-    walk_file_tree(".".into(), &mut |new_file_path| {
-        transformer_pipeline.write(new_file_path);
-        transformer_pipeline.poll();
-        Ok(())
-    })?;
-
-    simplify_result(transformer_pipeline.finalize().into_inner())?;
-
-    Ok(output_path)
+    Ok(())
 }
 
-fn calc_md5(file_path: &str) -> Result<String, String> {
+pub fn calc_md5(file_path: &str) -> Result<String, String> {
     let output =
-        io_util::run_command_handle_failures(process::Command::new("md5sum").arg(&file_path))?;
+        io_util::run_command_handle_failures(process::Command::new("md5sum").arg(file_path))?;
 
     let output_str = simplify_result(String::from_utf8(output.stdout))?;
     match output_str.find(' ') {
@@ -263,11 +2315,11 @@ fn calc_md5(file_path: &str) -> Result<String, String> {
 }
 
 fn commit_tmp_snapshot(
+    repo: &Repository,
     tmp_snapshot_path: &str,
     data: &file_structure::SnapshotMetaFile,
 ) -> Result<(), String> {
-    let snapshot_payload_path =
-        String::from(SNAPSHOTS_PATH) + "/" + &data.get_full_payload_filename()?;
+    let snapshot_payload_path = repo.prepend_snapshot_path(&data.get_full_payload_filename()?);
 
     let file_exists = simplify_result(fs::exists(&snapshot_payload_path))?;
     if file_exists {
@@ -275,23 +2327,326 @@ fn commit_tmp_snapshot(
             "Tried to commit snapshot to '{}', but the file already exists",
             &snapshot_payload_path
         ))
+    } else if repo.should_fsync() {
+        // the payload is flushed before it's renamed, so it's never in place
+        // with missing contents, and the rename is flushed after
+        io_util::sync_file(tmp_snapshot_path)?;
+        io_util::move_file(tmp_snapshot_path, &snapshot_payload_path)?;
+        io_util::sync_file(&snapshot_payload_path)
     } else {
-        simplify_result(fs::rename(tmp_snapshot_path, snapshot_payload_path))?;
-        Ok(())
+        // the snapshots directory may be on another drive
+        io_util::move_file(tmp_snapshot_path, &snapshot_payload_path)
     }
 }
 
 /// Walks the file tree for some directory.
 ///
-/// The paths visited are always in UTF-8 ascending order.
+/// The entries in each directory are visited in `order` (see
+/// `PathOrder::cmp_names`), so the paths visited are in the order of
+/// `PathOrder::cmp_paths`. Snapshot tars are in this order, which diffing
+/// them relies on. When ignoring case, only the first of the names in a
+/// directory that differ only by case is visited, with a warning.
 ///
 /// Ignores .jbackup directories that are a direct child of
-/// the specified directory.
+/// the specified directory, and the directories in `ignored_dirs`, by
+/// (device, inode) (see `get_ignored_dirs`), and the directories
+/// `exclusions` leaves out.
+///
+/// Symlinks are skipped.
 pub fn walk_file_tree(
     dir_path: OsString,
+    ignored_dirs: &HashSet<(u64, u64)>,
+    exclusions: &DirExclusions,
+    order: PathOrder,
+    file_handler: &mut impl FnMut(OsString) -> Result<(), String>,
+) -> Result<(), String> {
+    _walk_file_tree(
+        dir_path,
+        0,
+        ignored_dirs,
+        exclusions,
+        order,
+        None,
+        file_handler,
+    )
+}
+
+/// Reads paths separated by `separator`, leaving out empty paths.
+fn read_file_list(mut reader: impl io::BufRead, separator: u8) -> Result<Vec<OsString>, String> {
+    let mut paths = Vec::new();
+    loop {
+        let mut path = Vec::new();
+        if simplify_result(reader.read_until(separator, &mut path))? == 0 {
+            return Ok(paths);
+        }
+        if path.last() == Some(&separator) {
+            path.pop();
+        }
+        if !path.is_empty() {
+            paths.push(OsString::from_vec(path));
+        }
+    }
+}
+
+/// Turns paths given by the user into the paths `list_file_tree` would list
+/// for the same files, sorted in the same order and without duplicates.
+///
+/// Paths must be relative to the repository's root, or absolute paths in it,
+/// and can't be in `.jbackup` or the directories in `ignored_dirs`.
+/// Directories, and symlinks (unless following symlinks), are left out, like
+/// when walking the working directory. If `unreadable_errors` is given, files
+/// that can't be read are left out too, and why is added to it.
+fn check_file_list(
+    repo: &Repository,
+    file_list: &[OsString],
+    ignored_dirs: &HashSet<(u64, u64)>,
+    follow_symlinks: bool,
+    order: PathOrder,
+    mut unreadable_errors: Option<&mut Vec<String>>,
+) -> Result<Vec<OsString>, String> {
+    let root = simplify_result(fs::canonicalize(repo.root()))?;
+    // paths are listed as "{root}/{path}", like `list_file_tree` lists them
+    let root_len = repo.root().len() + 1;
+    let mut file_paths = Vec::new();
+
+    for given_path in file_list {
+        let invalid_path = |reason: &str| {
+            Err(format!(
+                "Can't snapshot '{}', since {}",
+                given_path.to_string_lossy(),
+                reason
+            ))
+        };
+
+        let mut path = Path::new(given_path);
+        if path.is_absolute() {
+            match path.strip_prefix(&root) {
+                Ok(x) => path = x,
+                Err(_) => return invalid_path("it's not in the working directory"),
+            }
+        }
+
+        let mut file_path = OsString::from(repo.root());
+        for component in path.components() {
+            match component {
+                Component::CurDir => {}
+                Component::Normal(name) => {
+                    if file_path == repo.root() && name == ".jbackup" {
+                        return invalid_path("it's in .jbackup");
+                    }
+                    file_path.push("/");
+                    file_path.push(name);
+                }
+                _ => return invalid_path("it's not in the working directory"),
+            }
+        }
+
+        let metadata = if follow_symlinks {
+            fs::metadata(&file_path)
+        } else {
+            fs::symlink_metadata(&file_path)
+        };
+        match metadata {
+            Ok(metadata) if metadata.is_file() => {}
+            Ok(_) => continue,
+            Err(err) => {
+                let err = format!(
+                    "Can't snapshot '{}', since it couldn't be read: {}",
+                    given_path.to_string_lossy(),
+                    err
+                );
+                match &mut unreadable_errors {
+                    Some(errors) => {
+                        errors.push(err);
+                        continue;
+                    }
+                    None => return Err(err),
+                }
+            }
+        }
+
+        let parent_dirs = Path::new(&file_path).ancestors().skip(1);
+        for dir in parent_dirs.take_while(|dir| *dir != Path::new(repo.root())) {
+            let metadata = simplify_result(fs::metadata(dir))?;
+            if ignored_dirs.contains(&(metadata.dev(), metadata.ino())) {
+                return invalid_path("it's in jbackup's snapshots or temp directory");
+            }
+        }
+
+        file_paths.push(file_path);
+    }
+
+    file_paths
+        .sort_by(|a, b| order.cmp_paths(&a.as_bytes()[root_len..], &b.as_bytes()[root_len..]));
+    file_paths.dedup_by(|a, b| order.is_same_path(a.as_bytes(), b.as_bytes()));
+    Ok(file_paths)
+}
+
+/// Lists the files `walk_file_tree` (or `walk_file_tree_following_symlinks`)
+/// visits, in the order it visits them.
+pub fn list_file_tree(
+    dir_path: OsString,
+    ignored_dirs: &HashSet<(u64, u64)>,
+    follow_symlinks: bool,
+    exclusions: &DirExclusions,
+    order: PathOrder,
+) -> Result<Vec<OsString>, String> {
+    let mut file_paths = Vec::new();
+    let mut add_file = |file_path| {
+        file_paths.push(file_path);
+        Ok(())
+    };
+    if follow_symlinks {
+        walk_file_tree_following_symlinks(
+            dir_path,
+            ignored_dirs,
+            exclusions,
+            order,
+            &mut add_file,
+        )?;
+    } else {
+        walk_file_tree(dir_path, ignored_dirs, exclusions, order, &mut add_file)?;
+    }
+    Ok(file_paths)
+}
+
+/// Like `walk_file_tree`, but follows symlinks: a symlink to a file is visited
+/// like a file, and a symlink to a directory is walked like a directory.
+///
+/// A symlink to a directory that is already being walked (ex. to one of its
+/// parents) would be walked forever, so it's skipped with a warning.
+pub fn walk_file_tree_following_symlinks(
+    dir_path: OsString,
+    ignored_dirs: &HashSet<(u64, u64)>,
+    exclusions: &DirExclusions,
+    order: PathOrder,
     file_handler: &mut impl FnMut(OsString) -> Result<(), String>,
 ) -> Result<(), String> {
-    _walk_file_tree(dir_path, 0, file_handler)
+    let mut dirs_being_walked = HashSet::new();
+    _walk_file_tree(
+        dir_path,
+        0,
+        ignored_dirs,
+        exclusions,
+        order,
+        Some(&mut dirs_being_walked),
+        file_handler,
+    )
+}
+
+/// The (device, inode) of the directories jbackup stores snapshots and
+/// scratch files in, which are configurable, so they may be in the working
+/// directory. Walking them would store snapshots in snapshots, and restoring
+/// would remove them.
+pub fn get_ignored_dirs(repo: &Repository) -> Result<HashSet<(u64, u64)>, String> {
+    let mut ignored_dirs = HashSet::new();
+    for dir in [repo.snapshots_path(), repo.tmp_path()] {
+        match fs::metadata(&dir) {
+            Ok(metadata) => {
+                ignored_dirs.insert((metadata.dev(), metadata.ino()));
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(format!("Failed to read '{}': {}", dir, err)),
+        }
+    }
+    Ok(ignored_dirs)
+}
+
+/// The first line of a `CACHEDIR.TAG` file, as specified by the Cache
+/// Directory Tagging Specification (https://bford.info/cachedir/).
+const CACHEDIR_TAG_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// Whether a directory is tagged as a cache, since it contains a
+/// `CACHEDIR.TAG` file starting with `CACHEDIR_TAG_SIGNATURE`. Caches can be
+/// regenerated by the programs that made them, so they're usually not worth
+/// backing up.
+fn is_cache_dir(dir_path: &OsString) -> bool {
+    let mut tag_path = dir_path.clone();
+    tag_path.push("/CACHEDIR.TAG");
+    let Ok(file) = File::open(tag_path) else {
+        return false;
+    };
+
+    let mut signature = Vec::new();
+    match file
+        .take(CACHEDIR_TAG_SIGNATURE.len() as u64)
+        .read_to_end(&mut signature)
+    {
+        Ok(_) => signature == CACHEDIR_TAG_SIGNATURE,
+        Err(_) => false,
+    }
+}
+
+/// Directories left out when walking the file tree, since they contain a
+/// file marking them as not worth backing up.
+#[derive(Clone, Default)]
+pub struct DirExclusions {
+    /// leave out directories tagged as caches (see `is_cache_dir`)
+    pub caches: bool,
+    /// names of marker files (ex. `.nobackup`). Directories containing a
+    /// file with any of the names are left out.
+    pub markers: Vec<String>,
+    /// the device of the working directory, if directories on other devices
+    /// (ex. mounted drives) are left out, like `tar --one-file-system`
+    pub device: Option<u64>,
+}
+
+impl DirExclusions {
+    pub fn from_config(repo: &Repository) -> DirExclusions {
+        DirExclusions {
+            caches: repo.config.exclude_caches,
+            markers: repo.config.exclude_if_present.clone(),
+            // the working directory is read anyway, so failing to read it
+            // here fails the walk instead
+            device: if repo.config.one_file_system {
+                get_working_dir_device(repo.root()).ok()
+            } else {
+                None
+            },
+        }
+    }
+
+    /// The exclusions of a snapshot's walk, for finding the files in the
+    /// working directory it would have stored.
+    pub fn from_walk(repo: &Repository, walk: &SnapshotWalk) -> DirExclusions {
+        DirExclusions {
+            caches: walk.exclude_caches,
+            markers: walk.exclude_if_present.clone(),
+            device: if walk.one_file_system {
+                get_working_dir_device(repo.root()).ok()
+            } else {
+                None
+            },
+        }
+    }
+
+    fn excludes(&self, dir_path: &OsString) -> bool {
+        self.device.is_some_and(|device| {
+            fs::metadata(dir_path).is_ok_and(|metadata| metadata.dev() != device)
+        }) || (self.caches && is_cache_dir(dir_path))
+            || self.markers.iter().any(|marker| {
+                let mut marker_path = dir_path.clone();
+                marker_path.push("/");
+                marker_path.push(marker);
+                fs::symlink_metadata(marker_path).is_ok()
+            })
+    }
+}
+
+/// The device the working directory (`root`) is on (see
+/// `DirExclusions::device`).
+pub fn get_working_dir_device(root: &str) -> Result<u64, String> {
+    Ok(simplify_result(fs::metadata(root))?.dev())
+}
+
+/// Fails if a marker file name for `DirExclusions::markers` isn't the name of
+/// a file in the directory itself.
+pub fn check_marker_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\t', '\n']) {
+        Err(format!("Invalid marker file name: '{}'", name))
+    } else {
+        Ok(())
+    }
 }
 
 enum FileType {
@@ -299,11 +2654,33 @@ enum FileType {
     Directory,
 }
 
+/// `dirs_being_walked` contains the (device, inode) of `dir_path` and its
+/// parents, if symlinks are followed.
 fn _walk_file_tree(
     dir_path: OsString,
     depth: usize,
+    ignored_dirs: &HashSet<(u64, u64)>,
+    exclusions: &DirExclusions,
+    order: PathOrder,
+    mut dirs_being_walked: Option<&mut HashSet<(u64, u64)>>,
     file_handler: &mut impl FnMut(OsString) -> Result<(), String>,
 ) -> Result<(), String> {
+    let dir_id = match &mut dirs_being_walked {
+        Some(dirs_being_walked) => {
+            let metadata = simplify_result(fs::metadata(&dir_path))?;
+            let dir_id = (metadata.dev(), metadata.ino());
+            if !dirs_being_walked.insert(dir_id) {
+                eprintln!(
+                    "Warning: skipping '{}' since it links to a directory that contains it",
+                    dir_path.to_string_lossy()
+                );
+                return Ok(());
+            }
+            Some(dir_id)
+        }
+        None => None,
+    };
+
     let files = simplify_result(fs::read_dir(&dir_path))?;
     let mut sorted_files = Vec::new();
 
@@ -325,22 +2702,52 @@ fn _walk_file_tree(
                         err
                     )
                 }
-                Ok(file_type) => {
+                Ok(mut file_type) => {
+                    if file_type.is_symlink() && dirs_being_walked.is_some() {
+                        let mut path = dir_path.clone();
+                        path.push("/");
+                        path.push(file.file_name());
+                        match fs::metadata(&path) {
+                            Ok(metadata) => file_type = metadata.file_type(),
+                            Err(err) => {
+                                eprintln!(
+                                    "Warning: skipping symlink '{}' since its target couldn't be read: {}",
+                                    path.to_string_lossy(),
+                                    err
+                                );
+                                continue;
+                            }
+                        }
+                    }
+
                     if file_type.is_file() {
                         sorted_files.push((FileType::Regular, file.file_name()))
-                    } else if file_type.is_dir() {
-                        if depth != 0 || file.file_name() != ".jbackup" {
-                            sorted_files.push((FileType::Directory, file.file_name()));
-                        }
+                    } else if file_type.is_dir() && (depth != 0 || file.file_name() != ".jbackup") {
+                        sorted_files.push((FileType::Directory, file.file_name()));
                     }
                 }
             },
         }
     }
 
-    sorted_files.sort_by(|a, b| a.1.cmp(&b.1));
+    sorted_files.sort_by(|a, b| order.cmp_names(a.1.as_bytes(), b.1.as_bytes()));
 
+    let mut prev_name: Option<OsString> = None;
     for (file_type, file) in sorted_files {
+        // names that differ only by case are next to each other
+        if let Some(prev_name) = &prev_name
+            && order.is_same_path(prev_name.as_bytes(), file.as_bytes())
+        {
+            eprintln!(
+                "Warn: Skipping '{}/{}', since its name differs only by case from '{}'",
+                dir_path.to_string_lossy(),
+                file.to_string_lossy(),
+                prev_name.to_string_lossy()
+            );
+            continue;
+        }
+        prev_name = Some(file.clone());
+
         let mut path = dir_path.clone();
         path.push("/");
         path.push(file);
@@ -349,10 +2756,416 @@ fn _walk_file_tree(
                 file_handler(path)?;
             }
             FileType::Directory => {
-                _walk_file_tree(path, depth + 1, file_handler)?;
+                if !ignored_dirs.is_empty() {
+                    let metadata = simplify_result(fs::metadata(&path))?;
+                    if ignored_dirs.contains(&(metadata.dev(), metadata.ino())) {
+                        continue;
+                    }
+                }
+                if exclusions.excludes(&path) {
+                    continue;
+                }
+
+                _walk_file_tree(
+                    path,
+                    depth + 1,
+                    ignored_dirs,
+                    exclusions,
+                    order,
+                    dirs_being_walked.as_deref_mut(),
+                    file_handler,
+                )?;
             }
         };
     }
 
+    if let (Some(dirs_being_walked), Some(dir_id)) = (dirs_being_walked, dir_id) {
+        dirs_being_walked.remove(&dir_id);
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        cmp::Ordering,
+        collections::HashSet,
+        env,
+        ffi::OsString,
+        fs,
+        os::unix::{
+            ffi::OsStrExt,
+            fs::{MetadataExt, symlink},
+        },
+        process,
+        time::{Duration, SystemTime},
+    };
+
+    use crate::delta_list::{FileChange, PathOrder, cmp_paths};
+
+    use super::{
+        DirExclusions, ThreadBudget, format_message_template, format_ulid, get_tmp_spool_path,
+        list_file_tree, normalize_import_path, parse_message, parse_thread_count, to_timestamp,
+        walk_file_tree, walk_file_tree_following_symlinks,
+    };
+
+    #[test]
+    pub fn walks_in_the_order_delta_lists_expect() {
+        let dir = env::temp_dir().join(format!("jbackup-walk-test-{}", process::id()));
+        let files = [
+            "a.txt",
+            "a/b",
+            "a-b/c",
+            "A",
+            "z",
+            "é",
+            "日本/語",
+            "e\u{301}",
+            "a b",
+        ];
+        for file in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+
+        let mut walked = Vec::new();
+        walk_file_tree(
+            dir.clone().into_os_string(),
+            &HashSet::new(),
+            &DirExclusions::default(),
+            PathOrder::Bytes,
+            &mut |path| {
+                walked.push(path.as_bytes()[dir.as_os_str().len() + 1..].to_vec());
+                Ok(())
+            },
+        )
+        .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(walked.len(), files.len());
+        for pair in walked.windows(2) {
+            assert_eq!(cmp_paths(&pair[0], &pair[1]), Ordering::Less);
+        }
+    }
+
+    #[test]
+    pub fn walks_ignoring_case() {
+        let dir = env::temp_dir().join(format!("jbackup-case-walk-test-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for file in ["b", "C/x", "README", "readme", "a.txt", "A/y"] {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+
+        let listed = list_file_tree(
+            dir.clone().into_os_string(),
+            &HashSet::new(),
+            false,
+            &DirExclusions::default(),
+            PathOrder::IgnoreCase,
+        )
+        .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let listed: Vec<String> = listed
+            .iter()
+            .map(|path| path.to_string_lossy()[dir.as_os_str().len() + 1..].to_string())
+            .collect();
+        // only the first of the names that differ only by case is walked
+        assert_eq!(listed, ["A/y", "a.txt", "b", "C/x", "README"]);
+        for pair in listed.windows(2) {
+            assert_eq!(
+                PathOrder::IgnoreCase.cmp_paths(pair[0].as_bytes(), pair[1].as_bytes()),
+                Ordering::Less
+            );
+        }
+    }
+
+    #[test]
+    pub fn follows_symlinks_without_looping() {
+        let dir = env::temp_dir().join(format!("jbackup-symlink-walk-test-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("a/b")).unwrap();
+        fs::write(dir.join("a/b/file"), b"").unwrap();
+        symlink("../..", dir.join("a/b/loop")).unwrap();
+        symlink("a/b/file", dir.join("file_link")).unwrap();
+        symlink("a/b", dir.join("dir_link")).unwrap();
+        symlink("missing", dir.join("broken_link")).unwrap();
+
+        let walk = |follow_symlinks| {
+            let mut walked = Vec::new();
+            let mut record = |path: OsString| {
+                walked.push(path.to_string_lossy()[dir.as_os_str().len() + 1..].to_string());
+                Ok(())
+            };
+            let root = dir.clone().into_os_string();
+            if follow_symlinks {
+                walk_file_tree_following_symlinks(
+                    root,
+                    &HashSet::new(),
+                    &DirExclusions::default(),
+                    PathOrder::Bytes,
+                    &mut record,
+                )
+                .unwrap();
+            } else {
+                walk_file_tree(
+                    root,
+                    &HashSet::new(),
+                    &DirExclusions::default(),
+                    PathOrder::Bytes,
+                    &mut record,
+                )
+                .unwrap();
+            }
+            walked
+        };
+        let walked = walk(true);
+        let walked_without_following = walk(false);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(walked, ["a/b/file", "dir_link/file", "file_link"]);
+        assert_eq!(walked_without_following, ["a/b/file"]);
+    }
+
+    #[test]
+    pub fn lists_file_tree() {
+        let dir = env::temp_dir().join(format!("jbackup-list-test-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for file in ["b", "a/c", "a/b", ".jbackup/head", "ignored/d"] {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+        let ignored_metadata = fs::metadata(dir.join("ignored")).unwrap();
+        let ignored_dirs = HashSet::from([(ignored_metadata.dev(), ignored_metadata.ino())]);
+
+        let listed = list_file_tree(
+            dir.clone().into_os_string(),
+            &ignored_dirs,
+            false,
+            &DirExclusions::default(),
+            PathOrder::Bytes,
+        )
+        .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let listed: Vec<String> = listed
+            .iter()
+            .map(|path| path.to_string_lossy()[dir.as_os_str().len() + 1..].to_string())
+            .collect();
+        assert_eq!(listed, ["a/b", "a/c", "b"]);
+    }
+
+    #[test]
+    pub fn excludes_tagged_cache_dirs() {
+        let dir = env::temp_dir().join(format!("jbackup-cachedir-test-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let files: [(&str, &[u8]); 4] = [
+            ("a", b""),
+            (
+                "cache/CACHEDIR.TAG",
+                b"Signature: 8a477f597d28d172789f06886806bc55\n# comment",
+            ),
+            ("cache/file", b""),
+            ("not_cache/CACHEDIR.TAG", b"Signature: not the signature"),
+        ];
+        for (file, contents) in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+
+        let list = |caches| {
+            list_file_tree(
+                dir.clone().into_os_string(),
+                &HashSet::new(),
+                false,
+                &DirExclusions {
+                    caches,
+                    markers: Vec::new(),
+                    device: None,
+                },
+                PathOrder::Bytes,
+            )
+            .unwrap()
+            .iter()
+            .map(|path| path.to_string_lossy()[dir.as_os_str().len() + 1..].to_string())
+            .collect::<Vec<String>>()
+        };
+        let listed = list(true);
+        let listed_with_caches = list(false);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(listed, ["a", "not_cache/CACHEDIR.TAG"]);
+        assert_eq!(listed_with_caches.len(), 4);
+    }
+
+    #[test]
+    pub fn excludes_dirs_with_marker_files() {
+        let dir = env::temp_dir().join(format!("jbackup-marker-test-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for file in [
+            "a",
+            "excluded/.nobackup",
+            "excluded/b",
+            "included/c",
+            "nested/d",
+            "nested/skip/.skip",
+            "nested/skip/e",
+        ] {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+
+        let listed = list_file_tree(
+            dir.clone().into_os_string(),
+            &HashSet::new(),
+            false,
+            &DirExclusions {
+                caches: false,
+                markers: vec![String::from(".nobackup"), String::from(".skip")],
+                device: None,
+            },
+            PathOrder::Bytes,
+        )
+        .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let listed: Vec<String> = listed
+            .iter()
+            .map(|path| path.to_string_lossy()[dir.as_os_str().len() + 1..].to_string())
+            .collect();
+        assert_eq!(listed, ["a", "included/c", "nested/d"]);
+    }
+
+    #[test]
+    pub fn excludes_dirs_on_other_devices() {
+        let dir = env::temp_dir().join(format!("jbackup-device-test-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for file in ["a", "sub/b"] {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+        let device = fs::metadata(&dir).unwrap().dev();
+
+        // a directory looks like it's on another filesystem when the recorded
+        // device differs from its own
+        let list = |device| {
+            list_file_tree(
+                dir.clone().into_os_string(),
+                &HashSet::new(),
+                false,
+                &DirExclusions {
+                    caches: false,
+                    markers: Vec::new(),
+                    device: Some(device),
+                },
+                PathOrder::Bytes,
+            )
+            .unwrap()
+            .iter()
+            .map(|path| path.to_string_lossy()[dir.as_os_str().len() + 1..].to_string())
+            .collect::<Vec<String>>()
+        };
+        let listed_same_device = list(device);
+        let listed_other_device = list(device.wrapping_add(1));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(listed_same_device, ["a", "sub/b"]);
+        assert_eq!(listed_other_device, ["a"]);
+    }
+
+    #[test]
+    pub fn splits_threads_between_transform_and_compress() {
+        let split = |transform, compress| ThreadBudget {
+            transform,
+            compress,
+        };
+        assert_eq!(ThreadBudget::split(0), split(1, 1));
+        assert_eq!(ThreadBudget::split(1), split(1, 1));
+        assert_eq!(ThreadBudget::split(2), split(1, 1));
+        assert_eq!(ThreadBudget::split(7), split(4, 3));
+        assert_eq!(ThreadBudget::split(8), split(4, 4));
+    }
+
+    #[test]
+    pub fn parses_thread_counts() {
+        assert_eq!(parse_thread_count("3"), Ok(3));
+        assert!(parse_thread_count("0").is_err());
+        assert!(parse_thread_count("-1").is_err());
+        assert!(parse_thread_count("many").is_err());
+    }
+
+    #[test]
+    pub fn rejects_timestamps_before_1970() {
+        let epoch = SystemTime::UNIX_EPOCH;
+        assert_eq!(
+            to_timestamp(epoch + Duration::from_secs(1700000000)),
+            Ok(1700000000)
+        );
+        assert!(to_timestamp(epoch - Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    pub fn names_spools_after_their_snapshot() {
+        assert_eq!(
+            get_tmp_spool_path("tmp/tmp_snapshot-12-345.tar.gz"),
+            "tmp/tmp_snapshot-12-345-spool.tar.gz"
+        );
+    }
+
+    #[test]
+    pub fn normalizes_import_paths() {
+        assert_eq!(normalize_import_path(b"./a/b.txt").unwrap(), b"a/b.txt");
+        assert_eq!(normalize_import_path(b"a//./b.txt").unwrap(), b"a/b.txt");
+        assert_eq!(normalize_import_path(b"./").unwrap(), b"");
+        assert!(normalize_import_path(b"/etc/passwd").is_err());
+        assert!(normalize_import_path(b"a/../../b.txt").is_err());
+    }
+
+    #[test]
+    pub fn formats_ulids() {
+        // the example in the ULID spec
+        assert!(format_ulid(1469918176385, 0).starts_with("01ARYZ6S41"));
+        assert_eq!(format_ulid(0, 0), "0".repeat(26));
+        assert_eq!(
+            format_ulid(0, u128::MAX),
+            format!("{}{}", "0".repeat(10), "Z".repeat(16))
+        );
+        assert!(format_ulid(1000, u128::MAX) < format_ulid(1001, 0));
+    }
+
+    #[test]
+    pub fn parses_edited_messages() {
+        let template = format_message_template(
+            Some("1700000000-abc"),
+            &[
+                (b"a.txt".to_vec(), FileChange::Added),
+                (b"b.txt".to_vec(), FileChange::Deleted),
+            ],
+        );
+        assert!(template.contains("#   Added:    a.txt\n"));
+        assert!(template.contains("#   Deleted:  b.txt\n"));
+        assert_eq!(parse_message(&template), None);
+
+        assert_eq!(
+            parse_message(&format!(
+                "\nBuilt the castle  \n\nWith a moat\n{}",
+                template
+            ))
+            .as_deref(),
+            Some("Built the castle\n\nWith a moat")
+        );
+        assert_eq!(
+            parse_message("# comment\n  indented\n").as_deref(),
+            Some("  indented")
+        );
+        assert_eq!(parse_message(" \n\t\n# comment"), None);
+    }
+}
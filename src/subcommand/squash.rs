@@ -0,0 +1,301 @@
+use std::{collections::VecDeque, fs};
+
+use crate::{
+    arguments,
+    delta_list::{PathOrder, generate_delta_list, restore_from_delta_list},
+    file_structure::{self, BranchesFile, DiffDirection, HeadFile, SnapshotMetaFile, TagsFile},
+    repository::Repository,
+    subcommand::restore::{find_restore_path, follow_path, remove_restored_tar},
+    util::{
+        archive_utils::{create_delta_list, create_tar_gz, open_delta_list, open_tar_gz},
+        io_util::simplify_result,
+    },
+};
+
+/// Collapses a range of snapshots `<from>..<to>` into one snapshot with the
+/// contents of `<to>`, whose parent becomes `<from>`'s parent.
+///
+/// The squashed snapshot keeps `<to>`'s id, since it has the same contents.
+/// The other snapshots in the range are removed, so nothing is squashed if a
+/// branch, tag or the head points to one of them.
+///
+/// `-m <message>` replaces the squashed snapshot's message. `-n`/`--dry-run`
+/// only lists the snapshots that would be removed. Since snapshots are removed
+/// for good, nothing is squashed unless `-f` is specified.
+pub fn main(repo: &Repository, mut args: VecDeque<String>) -> Result<(), String> {
+    let mut parsed_args = arguments::Parser::new()
+        .flag("-f")
+        .flag("-n")
+        .flag("--dry-run")
+        .option("-m")
+        .parse(args.drain(..));
+    let force = parsed_args.flags.contains("-f");
+    let dry_run = parsed_args.flags.contains("-n") || parsed_args.flags.contains("--dry-run");
+
+    let Some(range) = parsed_args.normal.pop_front() else {
+        return Err(String::from(
+            "No range specified. Usage: squash <from>..<to>",
+        ));
+    };
+    if !force && !dry_run {
+        return Err(String::from(
+            "Refusing to squash snapshots without -f. Use --dry-run to list the snapshots that would be removed.",
+        ));
+    }
+
+    file_structure::ensure_jbackup_snapshots_dir_exists(repo)?;
+
+    if repo.config.diff_direction != DiffDirection::Reverse {
+        return Err(String::from(
+            "Squashing is only supported in repositories that store reverse diffs",
+        ));
+    }
+
+    let (Some(from_id), to_id) = file_structure::resolve_range(repo, &range)? else {
+        return Err(String::from(
+            "Please specify the first snapshot to squash, as in <from>..<to>",
+        ));
+    };
+    let range = find_range(repo, &from_id, &to_id)?;
+    check_no_refs_into_range(repo, &range)?;
+
+    if dry_run {
+        for snapshot in &range[..range.len() - 1] {
+            println!("Would remove: {}", snapshot.id);
+        }
+        return Ok(());
+    }
+
+    let squashed_count = range.len();
+    let to_id = squash(repo, range, parsed_args.options.remove("-m"))?;
+    println!("Squashed {} snapshot(s) into {}", squashed_count, to_id);
+    Ok(())
+}
+
+/// Finds the snapshots from `from_id` to `to_id` (inclusive), oldest first,
+/// following first parents from `to_id`. Fails if the range can't be
+/// squashed.
+pub fn find_range(
+    repo: &Repository,
+    from_id: &str,
+    to_id: &str,
+) -> Result<Vec<SnapshotMetaFile>, String> {
+    if from_id == to_id {
+        return Err(String::from(
+            "The range only contains one snapshot, so there's nothing to squash.",
+        ));
+    }
+
+    let mut range = vec![SnapshotMetaFile::read(repo, to_id)?];
+    loop {
+        let curr = range.last().expect("Range should not be empty");
+        if curr.id == from_id {
+            break;
+        }
+        let Some(parent_id) = curr.parents.first() else {
+            return Err(format!(
+                "Snapshot {} is not an ancestor of snapshot {}",
+                from_id, to_id
+            ));
+        };
+        range.push(SnapshotMetaFile::read(repo, parent_id)?);
+    }
+    range.reverse();
+
+    // the storage of a snapshot with several parents or children can't be
+    // rewritten this simply
+    for (i, snapshot) in range.iter().enumerate() {
+        let is_to = i == range.len() - 1;
+        let expected_children = if is_to { 0..=1 } else { 1..=1 };
+        if snapshot.parents.len() > 1
+            || !expected_children.contains(&snapshot.children.len())
+            || snapshot.diff_children != snapshot.children
+        {
+            return Err(format!(
+                "Can't squash snapshot {}, since squashing snapshots with several parents or children is not supported",
+                snapshot.id
+            ));
+        }
+    }
+
+    Ok(range)
+}
+
+fn check_no_refs_into_range(repo: &Repository, range: &[SnapshotMetaFile]) -> Result<(), String> {
+    let squashed_ids: Vec<&str> = range[..range.len() - 1]
+        .iter()
+        .map(|x| x.id.as_str())
+        .collect();
+    let mut refs = Vec::new();
+
+    if let Some(head_id) = HeadFile::read(repo)?.curr_snapshot_id
+        && squashed_ids.contains(&head_id.as_str())
+    {
+        refs.push(String::from("HEAD"));
+    }
+    for (name, id) in BranchesFile::read(repo)?.branches {
+        if squashed_ids.contains(&id.as_str()) {
+            refs.push(format!("branch '{}'", name));
+        }
+    }
+    for (name, id) in TagsFile::read(repo)?.tags {
+        if squashed_ids.contains(&id.as_str()) {
+            refs.push(format!("tag '{}'", name));
+        }
+    }
+
+    if refs.is_empty() {
+        Ok(())
+    } else {
+        refs.sort();
+        Err(format!(
+            "Can't squash, since these point to snapshots that would be removed: {}",
+            refs.join(", ")
+        ))
+    }
+}
+
+/// Squashes a range from `find_range`, removing every snapshot in it except
+/// the last. Returns the id of the squashed snapshot.
+pub fn squash(
+    repo: &Repository,
+    mut range: Vec<SnapshotMetaFile>,
+    message: Option<String>,
+) -> Result<String, String> {
+    let mut to = range
+        .pop()
+        .expect("Range should have at least two snapshots");
+    let from = range
+        .first()
+        .expect("Range should have at least two snapshots");
+    let mut parent = match from.parents.first() {
+        Some(parent_id) => Some(SnapshotMetaFile::read(repo, parent_id)?),
+        None => None,
+    };
+    for snapshot in range.iter().chain([&to]).chain(&parent) {
+        snapshot.check_writable()?;
+    }
+    repo.ensure_snapshots_removable()?;
+
+    let mut files_to_delete: Vec<String> = Vec::new();
+    for snapshot in &range {
+        for diff_child in &snapshot.diff_children {
+            files_to_delete.push(snapshot.get_diff_path_from_child_snapshot(diff_child));
+        }
+    }
+
+    // the parent is stored as a diff from `from`, which is removed, so store
+    // it as a diff from `to` instead
+    if let Some(parent) = &mut parent {
+        create_diff_from(repo, parent, &to, &range)?;
+        files_to_delete.push(parent.get_diff_path_from_child_snapshot(&from.id));
+
+        for x in parent.children.iter_mut().chain(&mut parent.diff_children) {
+            if *x == from.id {
+                x.clone_from(&to.id);
+            }
+        }
+    }
+
+    to.parents.clone_from(&from.parents);
+    to.diff_parents = parent.iter().map(|x| x.id.clone()).collect();
+    if message.is_some() {
+        to.message = message;
+    }
+
+    // only delete files after all snapshot metadata has been written
+    to.write(repo)?;
+    if let Some(parent) = &parent {
+        parent.write(repo)?;
+    }
+    for snapshot in &range {
+        snapshot.remove(repo)?;
+    }
+    for file_name in &files_to_delete {
+        simplify_result(fs::remove_file(repo.prepend_snapshot_path(file_name)))?;
+    }
+
+    Ok(to.id)
+}
+
+/// Creates the delta list `{parent}-diff-{to}`, by reconstructing `to`, then
+/// `parent` by applying the delta lists of the squashed snapshots.
+fn create_diff_from(
+    repo: &Repository,
+    parent: &SnapshotMetaFile,
+    to: &SnapshotMetaFile,
+    squashed: &[SnapshotMetaFile],
+) -> Result<(), String> {
+    let restore_path = find_restore_path(repo, &to.id)?;
+    let to_tar_path = follow_path(repo, &restore_path)?;
+
+    let order = PathOrder::from_config(&repo.config);
+    let result = reconstruct_parent(repo, parent, to, &to_tar_path, squashed, order).and_then(
+        |parent_tar_path| {
+            let result = generate_delta_list(
+                open_tar_gz(repo, &to_tar_path)?,
+                open_tar_gz(repo, &parent_tar_path)?,
+                create_delta_list(
+                    repo,
+                    &repo.prepend_snapshot_path(&parent.get_diff_path_from_child_snapshot(&to.id)),
+                    repo.config.compression,
+                )?,
+                order,
+            );
+            simplify_result(fs::remove_file(parent_tar_path))?;
+            result
+        },
+    );
+
+    remove_restored_tar(repo, &restore_path, &to_tar_path)?;
+    result
+}
+
+/// Applies the delta lists of the squashed snapshots, newest first, then the
+/// parent's to `to`'s tar. Returns the path to the parent's tar.
+fn reconstruct_parent(
+    repo: &Repository,
+    parent: &SnapshotMetaFile,
+    to: &SnapshotMetaFile,
+    to_tar_path: &str,
+    squashed: &[SnapshotMetaFile],
+    order: PathOrder,
+) -> Result<String, String> {
+    let mut prev_id = &to.id;
+    let mut prev_tar_path = String::from(to_tar_path);
+
+    for snapshot in squashed.iter().rev().chain([parent]) {
+        let tar_path = get_squash_tar_path(repo, &snapshot.id);
+        let result = open_tar_gz(repo, &prev_tar_path).and_then(|prev_tar| {
+            restore_from_delta_list(
+                prev_tar,
+                create_tar_gz(repo, &tar_path)?,
+                open_delta_list(
+                    repo,
+                    &repo.prepend_snapshot_path(
+                        &snapshot.get_diff_path_from_child_snapshot(prev_id),
+                    ),
+                )?,
+                order,
+            )
+        });
+
+        // don't delete `to`'s tar, which is removed with the restore
+        if prev_tar_path != to_tar_path {
+            simplify_result(fs::remove_file(&prev_tar_path))?;
+        }
+        if let Err(err) = result {
+            let _ = fs::remove_file(&tar_path);
+            return Err(err);
+        }
+
+        prev_id = &snapshot.id;
+        prev_tar_path = tar_path;
+    }
+
+    Ok(prev_tar_path)
+}
+
+fn get_squash_tar_path(repo: &Repository, snapshot_id: &str) -> String {
+    format!("{}/tmp-squash-{}.tar.gz", repo.tmp_path(), snapshot_id)
+}
@@ -0,0 +1,128 @@
+use std::{collections::VecDeque, fs, time::SystemTime};
+
+use crate::{
+    STASHES_PATH, arguments,
+    file_structure::{self, StashEntry, StashListFile},
+    repository::Repository,
+    subcommand::{
+        log::format_timestamp,
+        restore::{extract_tar, remove_files_not_in_snapshot},
+        snapshot::{
+            SnapshotOptions, calc_md5, create_tmp_tar, remove_interrupted_tmp_snapshots,
+            to_timestamp,
+        },
+    },
+    transformer::get_config_transformers,
+    util::io_util::{move_file, simplify_result},
+};
+
+/// Saves the working directory to a stash, which is kept outside of the
+/// snapshot history, so it doesn't show up in `log` or move the head.
+///
+/// `stash [-m <message>]` saves the working directory without changing it.
+/// `stash pop` restores the working directory to the newest stash, then
+/// removes the stash. `stash list` lists the stashes, newest first.
+pub fn main(repo: &Repository, mut args: VecDeque<String>) -> Result<(), String> {
+    let mut parsed_args = arguments::Parser::new().option("-m").parse(args.drain(..));
+
+    file_structure::ensure_jbackup_snapshots_dir_exists(repo)?;
+    repo.config.ensure_not_bare()?;
+
+    match parsed_args.normal.pop_front().as_deref() {
+        None | Some("push") => push(repo, parsed_args.options.remove("-m")),
+        Some("pop") => pop(repo),
+        Some("list") => list(repo),
+        Some(action) => Err(format!(
+            "Unknown stash action: '{}' (expected push, pop or list)",
+            action
+        )),
+    }
+}
+
+fn push(repo: &Repository, message: Option<String>) -> Result<(), String> {
+    let mut stash_list = StashListFile::read(repo)?;
+
+    remove_interrupted_tmp_snapshots(repo)?;
+
+    let (tmp_tar_path, stats) = create_tmp_tar(repo, &SnapshotOptions::from_config(repo))?;
+    let md5 = calc_md5(&tmp_tar_path)?;
+    let date = match to_timestamp(SystemTime::now()) {
+        Ok(x) => x,
+        Err(err) => {
+            simplify_result(fs::remove_file(&tmp_tar_path))?;
+            return Err(err);
+        }
+    };
+
+    let entry = StashEntry {
+        id: date.to_string() + "-" + &md5,
+        date,
+        message,
+    };
+
+    if stash_list.stashes.iter().any(|x| x.id == entry.id) {
+        simplify_result(fs::remove_file(&tmp_tar_path))?;
+        return Err(format!(
+            "A stash with the same id ({}) already exists.",
+            entry.id
+        ));
+    }
+
+    simplify_result(fs::create_dir_all(repo.path(STASHES_PATH)))?;
+    // the temp directory may be on another drive
+    move_file(&tmp_tar_path, entry.get_payload_path(repo))?;
+
+    println!("Stashed {} file(s) as {}", stats.num_files, entry.id);
+    stash_list.stashes.push(entry);
+    stash_list.write(repo)
+}
+
+fn pop(repo: &Repository) -> Result<(), String> {
+    let mut stash_list = StashListFile::read(repo)?;
+    let Some(entry) = stash_list.stashes.last() else {
+        return Err(String::from("There are no stashes."));
+    };
+
+    let transformers = get_config_transformers(repo)?;
+    let payload_path = entry.get_payload_path(repo);
+
+    // the stash is only removed once it's fully restored
+    let restored_paths = extract_tar(
+        repo,
+        &payload_path,
+        repo.root(),
+        &transformers,
+        |_| true,
+        |_, _| Ok(()),
+        None,
+        false,
+    )?;
+    // stashes are walked with the config's settings
+    let walk = SnapshotOptions::from_config(repo).get_walk();
+    remove_files_not_in_snapshot(repo, &walk, &restored_paths)?;
+
+    println!(
+        "Restored {} file(s) from stash {}",
+        restored_paths.len(),
+        entry.id
+    );
+
+    simplify_result(fs::remove_file(payload_path))?;
+    stash_list.stashes.pop();
+    stash_list.write(repo)
+}
+
+fn list(repo: &Repository) -> Result<(), String> {
+    let stash_list = StashListFile::read(repo)?;
+
+    for entry in stash_list.stashes.iter().rev() {
+        println!(
+            "{}  {}  {}",
+            format_timestamp(entry.date),
+            entry.id,
+            entry.message.as_deref().unwrap_or("")
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,280 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs, io, process,
+};
+
+use crate::{
+    arguments,
+    delta_list::{PathOrder, try_restore_from_delta_list},
+    file_structure::{
+        self, EMPTY_SNAPSHOT_ID, SnapshotFullType, SnapshotMetaFile, get_payload_md5_from_id,
+    },
+    repository::Repository,
+    subcommand::{restore::find_restore_path, snapshot::calc_md5},
+    util::{
+        archive_utils::{
+            create_empty_tar_gz, create_tar_gz, describe_corruption, open_delta_list, open_payload,
+            open_tar_gz, verify_gz, verify_tar_gz, write_payload_tar_gz,
+        },
+        io_util::simplify_result,
+    },
+};
+
+/// Checks that every snapshot can be restored.
+///
+/// By default, checks that the full payloads and delta lists of all snapshots
+/// exist and decompress, and that every snapshot has a chain of delta lists
+/// to a full payload.
+///
+/// `--deep` also reconstructs every snapshot by applying its delta list, which
+/// checks that every xdelta applies. Each reconstructed snapshot is read back
+/// to check that its entries are in the order diffing relies on. The full
+/// payloads are checked against the md5 in their snapshot id, if it's in the
+/// `timestamp-hash` format (see `SnapshotIdFormat`). Older snapshots
+/// have no recorded checksum, so applying their delta list cleanly is the
+/// strongest check possible for them.
+pub fn main(repo: &Repository, mut args: VecDeque<String>) -> Result<(), String> {
+    let parsed_args = arguments::Parser::new()
+        .flag("--deep")
+        .parse(args.drain(..));
+    let deep = parsed_args.flags.contains("--deep");
+
+    file_structure::ensure_jbackup_snapshots_dir_exists(repo)?;
+
+    let snapshots: HashMap<String, SnapshotMetaFile> =
+        file_structure::get_all_snapshot_meta_files(repo)?
+            .into_iter()
+            .map(|x| (x.id.clone(), x))
+            .collect();
+
+    let read_only_count = snapshots.values().filter(|x| x.is_read_only()).count();
+    if read_only_count > 0 {
+        eprintln!(
+            "Warn: {} snapshot(s) were created by a newer version of jbackup, so they can be read, but not changed",
+            read_only_count
+        );
+    }
+
+    let mut problems = check_stored_files(repo, &snapshots);
+    if deep {
+        problems.extend(reconstruct_all(
+            repo,
+            &snapshots,
+            PathOrder::from_config(&repo.config),
+        )?);
+    }
+
+    if problems.is_empty() {
+        println!("Verified {} snapshot(s)", snapshots.len());
+        Ok(())
+    } else {
+        for problem in &problems {
+            println!("Problem: {}", problem);
+        }
+        Err(format!(
+            "Found {} problem(s) in {} snapshot(s)",
+            problems.len(),
+            snapshots.len()
+        ))
+    }
+}
+
+/// Checks that the files of each snapshot exist and decompress, and that each
+/// snapshot can be restored from a full payload. Returns the problems found.
+fn check_stored_files(
+    repo: &Repository,
+    snapshots: &HashMap<String, SnapshotMetaFile>,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    for snapshot in snapshots.values() {
+        if snapshot.full_type != SnapshotFullType::None
+            && let Err(err) = verify_payload(repo, snapshot)
+        {
+            problems.push(format!("{}: {}", snapshot.id, err));
+        }
+
+        let mut stored_files = Vec::new();
+        for diff_child in &snapshot.diff_children {
+            stored_files.push(snapshot.get_diff_path_from_child_snapshot(diff_child));
+        }
+
+        for file_name in stored_files {
+            if let Err(err) = verify_gz(repo, &repo.prepend_snapshot_path(&file_name)) {
+                problems.push(format!("{}: {}", snapshot.id, err));
+            }
+        }
+
+        if let Err(err) = find_restore_path(repo, &snapshot.id) {
+            problems.push(format!("{}: {}", snapshot.id, err));
+        }
+    }
+
+    problems
+}
+
+/// Reads the full payload of a snapshot to the end, which checks that it
+/// isn't truncated, and that its checksum matches if it's compressed.
+fn verify_payload(repo: &Repository, snapshot: &SnapshotMetaFile) -> Result<(), String> {
+    let result = io::copy(&mut open_payload(repo, snapshot)?, &mut io::sink());
+    let payload_path = repo.prepend_snapshot_path(&snapshot.get_full_payload_filename()?);
+    describe_corruption(&payload_path, result.map(|_| ()))
+}
+
+/// Reconstructs every snapshot, starting from the full payloads and applying
+/// the delta lists to them. Returns the problems found.
+fn reconstruct_all(
+    repo: &Repository,
+    snapshots: &HashMap<String, SnapshotMetaFile>,
+    order: PathOrder,
+) -> Result<Vec<String>, String> {
+    let mut problems = Vec::new();
+
+    // snapshots with a reconstructed tar that still need their diff parents
+    // reconstructed: (snapshot id, tar path, whether the tar is temporary)
+    let mut to_visit = Vec::new();
+
+    for snapshot in snapshots.values() {
+        if snapshot.full_type == SnapshotFullType::None {
+            continue;
+        }
+        let payload_path = repo.prepend_snapshot_path(&snapshot.get_full_payload_filename()?);
+
+        // only ids in the timestamp-hash format have the md5
+        if let Some(id_md5) = get_payload_md5_from_id(&snapshot.id) {
+            match calc_md5(&payload_path) {
+                Ok(md5) if md5 == id_md5 => {}
+                Ok(md5) => problems.push(format!(
+                    "{}: the full payload's md5 is {}, which doesn't match the snapshot id",
+                    snapshot.id, md5
+                )),
+                Err(err) => problems.push(format!("{}: {}", snapshot.id, err)),
+            }
+        }
+
+        // delta lists are applied to tar.gz files (see `restore::follow_path`)
+        if snapshot.full_type == SnapshotFullType::TarGz {
+            to_visit.push((snapshot.id.clone(), payload_path, false));
+        } else {
+            let tar_path = get_verify_tar_path(repo, &snapshot.id);
+            match write_payload_tar_gz(repo, snapshot, &tar_path) {
+                Ok(()) => to_visit.push((snapshot.id.clone(), tar_path, true)),
+                Err(err) => problems.push(format!("{}: {}", snapshot.id, err)),
+            }
+        }
+    }
+
+    // snapshots stored as a diff from the empty snapshot are reconstructed
+    // from a tar without files
+    let from_empty: Vec<String> = snapshots
+        .values()
+        .filter(|x| x.diff_children.iter().any(|x| x == EMPTY_SNAPSHOT_ID))
+        .map(|x| x.id.clone())
+        .collect();
+    if !from_empty.is_empty() {
+        let tar_path = get_verify_tar_path(repo, EMPTY_SNAPSHOT_ID);
+        create_empty_tar_gz(repo, &tar_path)?;
+        to_visit.push((String::from(EMPTY_SNAPSHOT_ID), tar_path, true));
+    }
+
+    while let Some((snapshot_id, tar_path, is_tmp)) = to_visit.pop() {
+        if let Err(err) = check_tar(repo, &tar_path, order) {
+            problems.push(format!("{}: {}", snapshot_id, err));
+        }
+
+        let diff_parents = match snapshots.get(&snapshot_id) {
+            Some(snapshot) => &snapshot.diff_parents,
+            None => &from_empty,
+        };
+        for parent_id in diff_parents {
+            let Some(parent) = snapshots.get(parent_id) else {
+                problems.push(format!(
+                    "{}: diff parent {} doesn't exist",
+                    snapshot_id, parent_id
+                ));
+                continue;
+            };
+
+            let parent_tar_path = get_verify_tar_path(repo, parent_id);
+            let result = open_tar_gz(repo, &tar_path).and_then(|start_tar| {
+                try_restore_from_delta_list(
+                    start_tar,
+                    create_tar_gz(repo, &parent_tar_path)?,
+                    open_delta_list(
+                        repo,
+                        &repo.prepend_snapshot_path(
+                            &parent.get_diff_path_from_child_snapshot(&snapshot_id),
+                        ),
+                    )?,
+                    order,
+                )
+            });
+
+            match result {
+                Ok(conflicts) if conflicts.is_empty() => {
+                    to_visit.push((parent_id.clone(), parent_tar_path, true))
+                }
+                Ok(conflicts) => {
+                    // list every conflict, since they show how the tar
+                    // differs from the one the delta list was generated from
+                    for conflict in conflicts {
+                        problems.push(format!(
+                            "{}: the delta list from {} doesn't apply: {}",
+                            parent_id, snapshot_id, conflict
+                        ));
+                    }
+                    let _ = fs::remove_file(&parent_tar_path);
+                }
+                Err(err) => {
+                    problems.push(format!(
+                        "{}: failed to apply the delta list from {}: {}",
+                        parent_id, snapshot_id, err
+                    ));
+                    let _ = fs::remove_file(&parent_tar_path);
+                }
+            }
+        }
+
+        if is_tmp {
+            simplify_result(fs::remove_file(&tar_path))?;
+        }
+    }
+
+    Ok(problems)
+}
+
+/// Reads every entry of a snapshot tar, and checks that they're in the
+/// repository's `PathOrder`. When ignoring case, paths that differ only by
+/// case would be restored to the same file, so they're reported too.
+fn check_tar(repo: &Repository, tar_path: &str, order: PathOrder) -> Result<(), String> {
+    let mut prev_path: Option<Vec<u8>> = None;
+
+    verify_tar_gz(repo, tar_path, |path| {
+        if let Some(prev_path) = &prev_path {
+            if order.cmp_paths(prev_path, path).is_ge() {
+                return Err(format!(
+                    "'{}' is out of order",
+                    String::from_utf8_lossy(path)
+                ));
+            }
+            if order.is_same_path(prev_path, path) {
+                return Err(format!(
+                    "'{}' and '{}' differ only by case",
+                    String::from_utf8_lossy(prev_path),
+                    String::from_utf8_lossy(path)
+                ));
+            }
+        }
+        prev_path = Some(path.to_vec());
+        Ok(())
+    })
+}
+
+fn get_verify_tar_path(repo: &Repository, snapshot_id: &str) -> String {
+    format!(
+        "{}/tmp-verify-{}-{}.tar.gz",
+        repo.tmp_path(),
+        process::id(),
+        snapshot_id
+    )
+}
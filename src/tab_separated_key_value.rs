@@ -1,9 +1,15 @@
 use crate::util::io_util::simplify_result;
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{HashMap, HashSet, hash_map::Entry},
     fs,
+    io::{Read, Write},
 };
 
+/// The first bytes of gzip files, used to tell gzipped files from plaintext
+/// ones when reading.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
 pub struct Config {
     pub multivalue_keys: HashSet<String>,
 }
@@ -22,9 +28,9 @@ impl Config {
     }
 
     /// Reads a simple tab separated file and inserts the key/value pairs in a
-    /// HashMap.
+    /// HashMap. The file may be gzipped (see `Contents::write_gzip_file`).
     pub fn read_file(&self, path: &str) -> Result<Contents, String> {
-        let data = simplify_result(String::from_utf8(simplify_result(fs::read(path))?))?;
+        let data = read_data(path)?;
         match self.read_string(&data) {
             Err(e) => Err(format!(
                 "Failed to parse contents of file '{}': {}",
@@ -49,16 +55,19 @@ impl Config {
                     let key = unescape_string(&line[..i])?;
                     let val = unescape_string(&line[i + 1..])?;
                     if self.multivalue_keys.contains(&key) {
-                        let list = multi_value.entry(key).or_insert(Vec::new());
-                        list.push(String::from(val));
+                        let list = multi_value.entry(key).or_default();
+                        list.push(val);
                     } else {
-                        if single_value.contains_key(&key) {
-                            return Err(format!(
-                                "Multiple values found for key '{}', however, the key is not defined as multivalued.",
-                                key
-                            ));
-                        } else {
-                            single_value.insert(key, val);
+                        match single_value.entry(key) {
+                            Entry::Occupied(entry) => {
+                                return Err(format!(
+                                    "Multiple values found for key '{}', however, the key is not defined as multivalued.",
+                                    entry.key()
+                                ));
+                            }
+                            Entry::Vacant(entry) => {
+                                entry.insert(val);
+                            }
                         }
                     }
                 }
@@ -77,6 +86,12 @@ impl Contents {
         simplify_result(fs::write(path, self.write_string()?))
     }
 
+    /// Writes the file like `write_file`, but gzipped. `Config::read_file`
+    /// reads both.
+    pub fn write_gzip_file(&self, path: &str) -> Result<(), String> {
+        write_gzip_data(path, &self.write_string()?)
+    }
+
     pub fn write_string(&self) -> Result<String, String> {
         let mut sorted_singles = self.single_value.iter().collect::<Vec<_>>();
         sorted_singles.sort();
@@ -118,11 +133,32 @@ impl Contents {
     }
 }
 
-fn escape_string(s: &str) -> String {
+/// Reads a file written by `Contents::write_file` or `write_gzip_data`.
+pub(crate) fn read_data(path: &str) -> Result<String, String> {
+    let bytes = simplify_result(fs::read(path))?;
+    if !bytes.starts_with(&GZIP_MAGIC) {
+        return simplify_result(String::from_utf8(bytes));
+    }
+
+    let mut data = String::new();
+    match GzDecoder::new(bytes.as_slice()).read_to_string(&mut data) {
+        Err(err) => Err(format!("Failed to decompress file '{}': {}", path, err)),
+        Ok(_) => Ok(data),
+    }
+}
+
+/// Writes the data to a gzipped file.
+pub(crate) fn write_gzip_data(path: &str, data: &str) -> Result<(), String> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    simplify_result(encoder.write_all(data.as_bytes()))?;
+    simplify_result(fs::write(path, simplify_result(encoder.finish())?))
+}
+
+pub(crate) fn escape_string(s: &str) -> String {
     s.replace('\\', "\\\\").replace('\n', "\\n")
 }
 
-fn unescape_string(s: &str) -> Result<String, String> {
+pub(crate) fn unescape_string(s: &str) -> Result<String, String> {
     let mut final_str = String::with_capacity(s.len());
     let mut is_escaped = false;
 
@@ -161,11 +197,14 @@ fn unescape_string(s: &str) -> Result<String, String> {
 #[cfg(test)]
 mod tests {
     use core::panic;
-    use std::collections::{HashMap, HashSet};
+    use std::{
+        collections::{HashMap, HashSet},
+        env, fs, process,
+    };
 
     use crate::tab_separated_key_value::unescape_string;
 
-    use super::{Config, Contents, escape_string};
+    use super::{Config, Contents, GZIP_MAGIC, escape_string};
 
     #[test]
     fn read_tskv() {
@@ -440,4 +479,43 @@ aa\t2";
             }
         }
     }
+
+    #[test]
+    fn read_gzipped_tskv() {
+        let path = env::temp_dir().join(format!("jbackup-tskv-test-{}", process::id()));
+        let path = path.to_str().unwrap();
+        let contents = Contents {
+            single_value: {
+                let mut m = HashMap::new();
+                m.insert(String::from("a"), String::from("b\nc"));
+                m
+            },
+            multi_value: {
+                let mut m = HashMap::new();
+                m.insert(
+                    String::from("d"),
+                    vec![String::from("e"), String::from("f")],
+                );
+                m
+            },
+        };
+        let config = Config {
+            multivalue_keys: {
+                let mut s = HashSet::new();
+                s.insert(String::from("d"));
+                s
+            },
+        };
+
+        contents.write_gzip_file(path).unwrap();
+        assert!(fs::read(path).unwrap().starts_with(&GZIP_MAGIC));
+        assert_eq!(config.read_file(path).unwrap(), contents);
+
+        // plaintext files are still read
+        contents.write_file(path).unwrap();
+        assert!(!fs::read(path).unwrap().starts_with(&GZIP_MAGIC));
+        assert_eq!(config.read_file(path).unwrap(), contents);
+
+        fs::remove_file(path).unwrap();
+    }
 }
@@ -1,21 +1,143 @@
+pub mod chunking;
+pub mod exec;
 pub mod minecraft_mca;
 
-pub fn get_transformers(
-    transformer_names: &Vec<String>,
+use crate::{file_structure::SnapshotMetaFile, repository::Repository};
+
+/// The name chunking is recorded as in the transformers of snapshots.
+const CHUNKING_TRANSFORMER_NAME: &str = "chunking";
+
+/// Gets the transformers a repository's snapshots are stored with: the
+/// configured transformers, then chunking if it's enabled.
+pub fn get_config_transformers(
+    repo: &Repository,
+) -> Result<Vec<Box<dyn FileTransformer + Sync + Send>>, String> {
+    get_recorded_transformers(&get_config_transformer_names(repo), repo)
+}
+
+/// The names of the transformers returned by `get_config_transformers`, which
+/// are recorded in the metadata of snapshots.
+pub fn get_config_transformer_names(repo: &Repository) -> Vec<String> {
+    let mut names = repo.config.transformers.clone();
+    if repo.config.chunking.is_some() {
+        names.push(String::from(CHUNKING_TRANSFORMER_NAME));
+    }
+    names
+}
+
+/// Whether files transformed with the named transformers are stored as chunk
+/// manifests, when chunking is the last transformer (see
+/// `get_config_transformer_names`).
+pub fn uses_chunking(names: &[String]) -> bool {
+    names
+        .last()
+        .is_some_and(|name| name == CHUNKING_TRANSFORMER_NAME)
+}
+
+/// Gets the transformers with names recorded by `get_config_transformer_names`.
+/// Chunks are read from the repository's chunk store.
+pub fn get_recorded_transformers(
+    names: &[String],
+    repo: &Repository,
 ) -> Result<Vec<Box<dyn FileTransformer + Sync + Send>>, String> {
-    let mut transformers = Vec::with_capacity(transformer_names.len());
+    let mut transformers: Vec<Box<dyn FileTransformer + Sync + Send>> =
+        Vec::with_capacity(names.len());
 
-    for name in transformer_names {
-        match get_transformer(&name) {
-            Some(t) => transformers.push(t),
-            None => return Err(format!("Error: unknown transformer '{}'", name)),
+    for name in names {
+        if name == CHUNKING_TRANSFORMER_NAME {
+            // the minimum size only matters when storing files, and chunking
+            // may have been disabled since the snapshot was made
+            transformers.push(Box::from(chunking::ChunkingTransformer::new(
+                repo.config.chunking.unwrap_or(u64::MAX),
+                get_chunks_path(repo),
+            )));
+        } else {
+            match get_transformer(name, repo.root()) {
+                Some(t) => transformers.push(t),
+                None => return Err(format!("Error: unknown transformer '{}'", name)),
+            }
         }
     }
 
     Ok(transformers)
 }
 
-pub fn get_transformer(name: &str) -> Option<Box<dyn FileTransformer + Sync + Send>> {
+/// Gets the transformers to restore a snapshot with: the transformers it was
+/// created with, or the configured transformers if they weren't recorded.
+/// Warns if the recorded transformers differ from the config, since restoring
+/// with other transformers would corrupt files.
+pub fn get_snapshot_transformers(
+    snapshot: &SnapshotMetaFile,
+    repo: &Repository,
+) -> Result<Vec<Box<dyn FileTransformer + Sync + Send>>, String> {
+    let Some(names) = &snapshot.transformers else {
+        return get_config_transformers(repo);
+    };
+
+    let config_names = get_config_transformer_names(repo);
+    if *names != config_names {
+        let format_names = |names: &[String]| {
+            if names.is_empty() {
+                String::from("none")
+            } else {
+                names.join(", ")
+            }
+        };
+        eprintln!(
+            "Warn: Snapshot {} was created with the transformers [{}], but the config has [{}]. Restoring with the snapshot's transformers.",
+            snapshot.id,
+            format_names(names),
+            format_names(&config_names)
+        );
+    }
+
+    get_recorded_transformers(names, repo)
+}
+
+/// The names of the lossy transformers (see `FileTransformer::is_lossless`),
+/// given the names the transformers are recorded with.
+pub fn get_lossy_transformer_names(
+    names: &[String],
+    transformers: &[Box<dyn FileTransformer + Sync + Send>],
+) -> Vec<String> {
+    names
+        .iter()
+        .zip(transformers)
+        .filter(|(_, transformer)| !transformer.is_lossless())
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+/// Path of the chunk store, where chunks of files split by the chunking
+/// transformer are stored.
+pub fn get_chunks_path(repo: &Repository) -> String {
+    repo.prepend_snapshot_path("chunks")
+}
+
+/// Gets a built-in transformer by name, or an `exec:<program>` or
+/// `exec-lossy:<program>` transformer (see `exec::ExecTransformer`), which
+/// runs in `working_dir`.
+pub fn get_transformer(
+    name: &str,
+    working_dir: &str,
+) -> Option<Box<dyn FileTransformer + Sync + Send>> {
+    let exec_program = match name.strip_prefix(exec::EXEC_TRANSFORMER_PREFIX) {
+        Some(program) => Some((program, true)),
+        None => name
+            .strip_prefix(exec::EXEC_LOSSY_TRANSFORMER_PREFIX)
+            .map(|program| (program, false)),
+    };
+    if let Some((program, lossless)) = exec_program {
+        if program.is_empty() {
+            return None;
+        }
+        return Some(Box::from(exec::ExecTransformer::new(
+            program,
+            working_dir,
+            lossless,
+        )));
+    }
+
     match name {
         "minecraft_mca" => Some(Box::from(minecraft_mca::McaTransformer::new())),
         _ => None,
@@ -26,10 +148,149 @@ pub trait FileTransformer: Sync + Send {
     /// Transform a file before it's inserted into the archive.
     fn transform_in(&self, file_path: &str, raw_contents: Vec<u8>) -> Result<Vec<u8>, String>;
 
+    /// Transform a file like `transform_in`, splitting the work on the file
+    /// across up to `num_threads` threads. The result must be the same as
+    /// `transform_in`'s. Transformers that can't split the work on one file
+    /// transform it on the calling thread.
+    fn transform_in_parallel(
+        &self,
+        file_path: &str,
+        raw_contents: Vec<u8>,
+        _num_threads: usize,
+    ) -> Result<Vec<u8>, String> {
+        self.transform_in(file_path, raw_contents)
+    }
+
     /// Transform a file from an archive to the contents to be restored.
+    /// Transformers are applied in the reverse order of `transform_in`.
     fn transform_out(
         &self,
         file_path: &str,
         transformed_contents: Vec<u8>,
     ) -> Result<Vec<u8>, String>;
+
+    /// Whether every file transformed in, then out, comes back with the same
+    /// contents, or contents that are transformed in to the same thing (see
+    /// `RoundTrip`). Snapshots refuse lossy transformers unless
+    /// `--allow-lossy` is given, since their files can't be restored as
+    /// they were.
+    fn is_lossless(&self) -> bool;
+
+    /// Whether `transform_in` may change the file. Files that no transformer
+    /// accepts are stored as they are, so they can be mapped into memory
+    /// instead of read (see `ConfigFile::mmap`).
+    fn accepts_file(&self, _file_path: &str) -> bool {
+        true
+    }
+
+    /// Sample files, as (path, contents), that the self-test subcommand
+    /// round-trips through the transformer (see `check_round_trip`).
+    /// Transformers that handle a file format should add samples of it.
+    fn sample_files(&self) -> Vec<(String, Vec<u8>)> {
+        generic_sample_files()
+    }
+}
+
+/// Sample files that aren't in any particular format, which every
+/// transformer must round-trip.
+pub fn generic_sample_files() -> Vec<(String, Vec<u8>)> {
+    vec![
+        (String::from("empty.txt"), Vec::new()),
+        (
+            String::from("notes.txt"),
+            b"Some notes\nwith a few lines\n".repeat(50),
+        ),
+        (String::from("random.bin"), random_bytes(300 * 1024, 1)),
+    ]
+}
+
+/// Pseudo-random bytes, which don't compress, for sample files.
+pub(crate) fn random_bytes(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed;
+    (0..len)
+        .map(|_| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 56) as u8
+        })
+        .collect()
+}
+
+/// How a file came back after being transformed in, then out.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RoundTrip {
+    /// the restored file is the same as the original
+    Identical,
+    /// the restored file has other bytes than the original (ex. recompressed
+    /// at another level), but is transformed in to the same contents, so
+    /// nothing is lost
+    Equivalent,
+}
+
+/// Checks that `transform_out(transform_in(x))` gives back `x`, or a file
+/// with the same transformed contents.
+pub fn check_round_trip(
+    transformer: &dyn FileTransformer,
+    file_path: &str,
+    contents: Vec<u8>,
+) -> Result<RoundTrip, String> {
+    let transformed = transformer.transform_in(file_path, contents.clone())?;
+    let restored = transformer.transform_out(file_path, transformed.clone())?;
+    if restored == contents {
+        return Ok(RoundTrip::Identical);
+    }
+
+    match transformer.transform_in(file_path, restored) {
+        Ok(x) if x == transformed => Ok(RoundTrip::Equivalent),
+        Ok(_) => Err(String::from("The restored file differs from the original")),
+        Err(err) => Err(format!(
+            "The restored file couldn't be transformed again: {}",
+            err
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FileTransformer, get_lossy_transformer_names, get_transformer};
+
+    /// Drops the last byte of files, so they can't be restored.
+    struct TruncatingTransformer;
+
+    impl FileTransformer for TruncatingTransformer {
+        fn transform_in(&self, _file_path: &str, mut contents: Vec<u8>) -> Result<Vec<u8>, String> {
+            contents.pop();
+            Ok(contents)
+        }
+
+        fn transform_out(&self, _file_path: &str, contents: Vec<u8>) -> Result<Vec<u8>, String> {
+            Ok(contents)
+        }
+
+        fn is_lossless(&self) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    pub fn finds_lossy_transformers() {
+        let names = [
+            String::from("minecraft_mca"),
+            String::from("truncating"),
+            String::from("exec:./transformer"),
+            String::from("exec-lossy:./transformer"),
+        ];
+        let transformers: Vec<Box<dyn FileTransformer + Sync + Send>> = vec![
+            get_transformer("minecraft_mca", ".").unwrap(),
+            Box::from(TruncatingTransformer),
+            get_transformer("exec:./transformer", ".").unwrap(),
+            get_transformer("exec-lossy:./transformer", ".").unwrap(),
+        ];
+        assert_eq!(
+            get_lossy_transformer_names(&names, &transformers),
+            ["truncating", "exec-lossy:./transformer"]
+        );
+        assert!(get_lossy_transformer_names(&names[..1], &transformers[..1]).is_empty());
+    }
 }
@@ -0,0 +1,339 @@
+use std::{
+    collections::HashSet,
+    fmt::Write as _,
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use fastcdc::v2020::FastCDC;
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    transformer::{FileTransformer, generic_sample_files, random_bytes},
+    util::io_util::simplify_result,
+};
+
+/// Bounds of the chunk sizes (in bytes) that files are split into. Smaller
+/// chunks store less when a small part of a file changes, but make manifests
+/// longer.
+const MIN_CHUNK_SIZE: u32 = 16 * 1024;
+const AVG_CHUNK_SIZE: u32 = 64 * 1024;
+const MAX_CHUNK_SIZE: u32 = 256 * 1024;
+
+/// First byte of a transformed file, followed by the file's contents
+const INLINE_TAG: u8 = 0;
+/// First byte of a transformed file, followed by the file's manifest: the
+/// sha256 of each of the file's chunks, in hex, one per line
+const CHUNKED_TAG: u8 = 1;
+
+/// Splits large files into content-defined chunks, which are stored once in
+/// a chunk store outside of snapshots, by their hash. The snapshot only
+/// stores a manifest of the chunks, so a small change in a large file only
+/// stores the changed chunks, and diffing snapshots only diffs the manifests.
+///
+/// Files smaller than `min_file_size` are stored in the snapshot. Every file
+/// starts with a tag saying how it's stored, so this transformer must be used
+/// for all snapshots in a repository.
+pub struct ChunkingTransformer {
+    min_file_size: u64,
+    chunks_dir: String,
+}
+
+impl ChunkingTransformer {
+    pub fn new(min_file_size: u64, chunks_dir: String) -> ChunkingTransformer {
+        ChunkingTransformer {
+            min_file_size,
+            chunks_dir,
+        }
+    }
+
+    fn get_chunk_path(&self, hash: &str) -> String {
+        format!("{}/{}", self.chunks_dir, hash)
+    }
+
+    /// Stores a chunk, unless a chunk with the same hash is already stored.
+    fn store_chunk(&self, hash: &str, chunk: &[u8]) -> Result<(), String> {
+        let chunk_path = self.get_chunk_path(hash);
+        if simplify_result(fs::exists(&chunk_path))? {
+            return Ok(());
+        }
+
+        // chunks are written to a temporary file, then renamed, so other
+        // workers storing the same chunk never see a partial chunk
+        static NEXT_TMP_ID: AtomicUsize = AtomicUsize::new(0);
+        let tmp_path = format!(
+            "{}.tmp-{}-{}",
+            chunk_path,
+            std::process::id(),
+            NEXT_TMP_ID.fetch_add(1, Ordering::Relaxed)
+        );
+
+        simplify_result(fs::create_dir_all(&self.chunks_dir))?;
+        let mut encoder = GzEncoder::new(
+            simplify_result(File::create(&tmp_path))?,
+            Compression::fast(),
+        );
+        simplify_result(encoder.write_all(chunk))?;
+        simplify_result(encoder.finish())?;
+        simplify_result(fs::rename(&tmp_path, &chunk_path))
+    }
+
+    fn read_chunk(&self, hash: &str) -> Result<Vec<u8>, String> {
+        let chunk_path = self.get_chunk_path(hash);
+        let file = match File::open(&chunk_path) {
+            Ok(x) => x,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(format!("Chunk {} is missing from the chunk store", hash));
+            }
+            Err(err) => return Err(err.to_string()),
+        };
+
+        let mut chunk = Vec::new();
+        simplify_result(GzDecoder::new(file).read_to_end(&mut chunk))?;
+
+        if hash_chunk(&chunk) != hash {
+            return Err(format!("Chunk {} is corrupted", hash));
+        }
+        Ok(chunk)
+    }
+}
+
+impl FileTransformer for ChunkingTransformer {
+    fn transform_in(&self, file_path: &str, contents: Vec<u8>) -> Result<Vec<u8>, String> {
+        if (contents.len() as u64) < self.min_file_size {
+            let mut transformed = Vec::with_capacity(contents.len() + 1);
+            transformed.push(INLINE_TAG);
+            transformed.extend_from_slice(&contents);
+            return Ok(transformed);
+        }
+
+        let mut manifest = String::new();
+        for chunk in FastCDC::new(&contents, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE) {
+            let chunk_data = &contents[chunk.offset..chunk.offset + chunk.length];
+            let hash = hash_chunk(chunk_data);
+
+            if let Err(err) = self.store_chunk(&hash, chunk_data) {
+                return Err(format!(
+                    "Failed to store a chunk of '{}': {}",
+                    file_path, err
+                ));
+            }
+            writeln!(manifest, "{}", hash).unwrap();
+        }
+
+        let mut transformed = Vec::with_capacity(manifest.len() + 1);
+        transformed.push(CHUNKED_TAG);
+        transformed.extend_from_slice(manifest.as_bytes());
+        Ok(transformed)
+    }
+
+    fn transform_out(
+        &self,
+        file_path: &str,
+        mut transformed_contents: Vec<u8>,
+    ) -> Result<Vec<u8>, String> {
+        match transformed_contents.first() {
+            Some(&INLINE_TAG) => {
+                transformed_contents.remove(0);
+                Ok(transformed_contents)
+            }
+            Some(&CHUNKED_TAG) => {
+                let manifest = match std::str::from_utf8(&transformed_contents[1..]) {
+                    Ok(x) => x,
+                    Err(_) => {
+                        return Err(format!(
+                            "The chunk manifest of '{}' is corrupted",
+                            file_path
+                        ));
+                    }
+                };
+
+                let mut contents = Vec::new();
+                for hash in manifest.lines() {
+                    match self.read_chunk(hash) {
+                        Ok(chunk) => contents.extend_from_slice(&chunk),
+                        Err(err) => {
+                            return Err(format!("Failed to restore '{}': {}", file_path, err));
+                        }
+                    }
+                }
+                Ok(contents)
+            }
+            _ => Err(format!(
+                "'{}' was not stored by the chunking transformer",
+                file_path
+            )),
+        }
+    }
+
+    fn is_lossless(&self) -> bool {
+        true
+    }
+
+    /// A large file, and a copy with an insertion, which shares most of its
+    /// chunks with it.
+    fn sample_files(&self) -> Vec<(String, Vec<u8>)> {
+        let large = random_bytes(2 * 1024 * 1024, 2);
+        let mut modified = large.clone();
+        modified.splice(1_000_000..1_000_000, b"inserted".iter().copied());
+
+        let mut samples = generic_sample_files();
+        samples.push((String::from("disk.img"), large));
+        samples.push((String::from("disk-modified.img"), modified));
+        samples
+    }
+}
+
+fn hash_chunk(chunk: &[u8]) -> String {
+    let mut hash = String::with_capacity(64);
+    for byte in Sha256::digest(chunk) {
+        write!(hash, "{:02x}", byte).unwrap();
+    }
+    hash
+}
+
+/// The hashes of the chunks a file stored by `ChunkingTransformer` is made
+/// of, given its stored contents. Files stored inline have none.
+pub fn get_chunk_hashes(transformed_contents: &[u8]) -> Result<Vec<&str>, String> {
+    match transformed_contents.split_first() {
+        Some((&CHUNKED_TAG, manifest)) => match std::str::from_utf8(manifest) {
+            Ok(x) => Ok(x.lines().collect()),
+            Err(_) => Err(String::from("A chunk manifest is corrupted")),
+        },
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// Removes the chunks in the chunk store that aren't in `used_hashes`, or
+/// only counts them if `dry_run` is set. Chunks that are still being stored
+/// are kept. Returns the number of unused chunks, and their total size in
+/// bytes.
+pub fn remove_unused_chunks(
+    chunks_dir: &str,
+    used_hashes: &HashSet<String>,
+    dry_run: bool,
+) -> Result<(usize, u64), String> {
+    if !Path::new(chunks_dir).exists() {
+        return Ok((0, 0));
+    }
+
+    let mut num_chunks = 0;
+    let mut total_size = 0;
+    for item in simplify_result(fs::read_dir(chunks_dir))? {
+        let entry = simplify_result(item)?;
+        let file_name = entry.file_name();
+        let Some(hash) = file_name.to_str() else {
+            continue;
+        };
+        // temporary files are renamed to the chunk's hash once they're written
+        if hash.contains(".tmp-") || used_hashes.contains(hash) {
+            continue;
+        }
+
+        num_chunks += 1;
+        total_size += simplify_result(entry.metadata())?.len();
+        if !dry_run {
+            simplify_result(fs::remove_file(entry.path()))?;
+        }
+    }
+    Ok((num_chunks, total_size))
+}
+
+/// Counts the chunks in the chunk store, and their total size in bytes.
+pub fn chunk_store_size(chunks_dir: &str) -> Result<(usize, u64), String> {
+    if !Path::new(chunks_dir).exists() {
+        return Ok((0, 0));
+    }
+
+    let mut num_chunks = 0;
+    let mut total_size = 0;
+    for item in simplify_result(fs::read_dir(chunks_dir))? {
+        let entry = simplify_result(item)?;
+        num_chunks += 1;
+        total_size += simplify_result(entry.metadata())?.len();
+    }
+    Ok((num_chunks, total_size))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs};
+
+    use crate::transformer::{FileTransformer, random_bytes};
+
+    use super::{ChunkingTransformer, chunk_store_size, get_chunk_hashes, remove_unused_chunks};
+
+    fn create_transformer(name: &str) -> ChunkingTransformer {
+        let chunks_dir = env::temp_dir().join(format!(
+            "jbackup-chunking-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&chunks_dir);
+        ChunkingTransformer::new(1024 * 1024, chunks_dir.to_string_lossy().into_owned())
+    }
+
+    #[test]
+    pub fn stores_small_files_inline() {
+        let transformer = create_transformer("inline");
+        let contents = b"small file".to_vec();
+
+        let transformed = transformer.transform_in("a.txt", contents.clone()).unwrap();
+        assert_eq!(transformed.len(), contents.len() + 1);
+        assert_eq!(
+            transformer.transform_out("a.txt", transformed).unwrap(),
+            contents
+        );
+        assert_eq!(chunk_store_size(&transformer.chunks_dir).unwrap(), (0, 0));
+    }
+
+    #[test]
+    pub fn only_stores_changed_chunks() {
+        let transformer = create_transformer("changed");
+        let contents = random_bytes(4 * 1024 * 1024, 1);
+
+        let transformed = transformer.transform_in("a.img", contents.clone()).unwrap();
+        assert!(transformed.len() < 10_000);
+        assert_eq!(
+            transformer.transform_out("a.img", transformed).unwrap(),
+            contents
+        );
+        let (num_chunks, _) = chunk_store_size(&transformer.chunks_dir).unwrap();
+
+        // an insertion only changes the chunks around it
+        let mut modified = contents.clone();
+        modified.splice(2_000_000..2_000_000, b"inserted".iter().copied());
+        let transformed = transformer.transform_in("a.img", modified.clone()).unwrap();
+        assert_eq!(
+            transformer
+                .transform_out("a.img", transformed.clone())
+                .unwrap(),
+            modified
+        );
+        let (num_chunks_after, _) = chunk_store_size(&transformer.chunks_dir).unwrap();
+        assert!(num_chunks_after - num_chunks <= 2);
+
+        // the chunks only the original file used are unused once it's gone
+        let used_hashes = get_chunk_hashes(&transformed)
+            .unwrap()
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let (num_unused, _) =
+            remove_unused_chunks(&transformer.chunks_dir, &used_hashes, false).unwrap();
+        assert!((1..=2).contains(&num_unused));
+        assert_eq!(
+            chunk_store_size(&transformer.chunks_dir).unwrap().0,
+            used_hashes.len()
+        );
+        assert_eq!(
+            transformer.transform_out("a.img", transformed).unwrap(),
+            modified
+        );
+
+        fs::remove_dir_all(&transformer.chunks_dir).unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+use std::process;
+
+use crate::{transformer::FileTransformer, util::io_util::run_command_with_input_handle_failures};
+
+/// The prefix of the names of transformers that run an external program.
+pub const EXEC_TRANSFORMER_PREFIX: &str = "exec:";
+/// Like `EXEC_TRANSFORMER_PREFIX`, for programs that don't give back exactly
+/// what they were given (see `FileTransformer::is_lossless`).
+pub const EXEC_LOSSY_TRANSFORMER_PREFIX: &str = "exec-lossy:";
+
+/// Transforms files with an external program, so transformers can be
+/// written in any language without recompiling jbackup. It's configured as
+/// `exec:<program>` (ex. `exec:./my-transformer`), where relative paths are
+/// relative to the repository's working directory, which the program is run
+/// in.
+///
+/// The program is run once per file, as `<program> in <file path>` to
+/// transform a file in and `<program> out <file path>` to transform it out.
+/// It's given the file's contents on stdin, and writes the transformed
+/// contents to stdout. The file path is relative to the working directory,
+/// and is only for the program to decide how to transform the file. A
+/// non-zero exit status fails the file, with the program's stderr printed.
+///
+/// Programs configured as `exec-lossy:<program>` are lossy, so snapshots
+/// with them need `--allow-lossy`.
+pub struct ExecTransformer {
+    program: String,
+    working_dir: String,
+    lossless: bool,
+}
+
+impl ExecTransformer {
+    pub fn new(program: &str, working_dir: &str, lossless: bool) -> ExecTransformer {
+        ExecTransformer {
+            program: String::from(program),
+            working_dir: String::from(working_dir),
+            lossless,
+        }
+    }
+
+    fn run(&self, direction: &str, file_path: &str, contents: &[u8]) -> Result<Vec<u8>, String> {
+        let output = run_command_with_input_handle_failures(
+            process::Command::new(&self.program)
+                .current_dir(&self.working_dir)
+                .arg(direction)
+                .arg(file_path),
+            contents,
+        )?;
+        Ok(output.stdout)
+    }
+}
+
+impl FileTransformer for ExecTransformer {
+    fn transform_in(&self, file_path: &str, raw_contents: Vec<u8>) -> Result<Vec<u8>, String> {
+        self.run("in", file_path, &raw_contents)
+    }
+
+    fn transform_out(
+        &self,
+        file_path: &str,
+        transformed_contents: Vec<u8>,
+    ) -> Result<Vec<u8>, String> {
+        self.run("out", file_path, &transformed_contents)
+    }
+
+    /// The program is trusted to give back what it was given unless it's
+    /// configured as lossy, since there's no way to ask it. `self-test`
+    /// checks that it does for sample files.
+    fn is_lossless(&self) -> bool {
+        self.lossless
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, os::unix::fs::PermissionsExt};
+
+    use crate::transformer::{FileTransformer, RoundTrip, check_round_trip, generic_sample_files};
+
+    use super::ExecTransformer;
+
+    /// Writes an executable script, returning its path.
+    fn create_script(name: &str, contents: &str) -> String {
+        let path =
+            env::temp_dir().join(format!("jbackup-exec-test-{}-{}", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    pub fn round_trips_through_a_program() {
+        // gzips files in, and gunzips them out
+        let script = create_script(
+            "gzip",
+            "#!/bin/sh\nif [ \"$1\" = in ]; then exec gzip -c; else exec gzip -dc; fi\n",
+        );
+        let transformer = ExecTransformer::new(&script, ".", true);
+
+        for (path, contents) in generic_sample_files() {
+            assert_eq!(
+                check_round_trip(&transformer, &path, contents),
+                Ok(RoundTrip::Identical)
+            );
+        }
+        fs::remove_file(script).unwrap();
+    }
+
+    #[test]
+    pub fn fails_when_the_program_fails() {
+        let script = create_script(
+            "fail",
+            "#!/bin/sh\ncat >/dev/null\necho broken >&2\nexit 3\n",
+        );
+        let transformer = ExecTransformer::new(&script, ".", true);
+
+        assert!(transformer.transform_in("a.txt", b"a".to_vec()).is_err());
+        assert!(
+            ExecTransformer::new("/nonexistent/transformer", ".", true)
+                .transform_out("a.txt", b"a".to_vec())
+                .is_err()
+        );
+        fs::remove_file(script).unwrap();
+    }
+}
@@ -1,36 +1,74 @@
-use std::io::{Read, Write};
-
-use flate2::{read::ZlibDecoder, write::ZlibEncoder};
-
-use crate::{transformer::FileTransformer, util::io_util::simplify_result};
+use std::{
+    io::{Read, Write},
+    ops::Range,
+    panic, thread,
+};
+
+use flate2::{
+    read::{GzDecoder, ZlibDecoder},
+    write::{GzEncoder, ZlibEncoder},
+};
+
+use crate::{
+    transformer::{FileTransformer, generic_sample_files, random_bytes},
+    util::io_util::simplify_result,
+};
 
 const REGION_WIDTH_CHUNK: usize = 32;
 const REGION_HEIGHT_CHUNK: usize = 32;
 const CHUNKS_IN_REGION: usize = REGION_WIDTH_CHUNK * REGION_HEIGHT_CHUNK;
 const SECTOR_SIZE: usize = 4096;
+/// The sector count of a chunk is stored in one byte
+const MAX_SECTOR_COUNT: usize = u8::MAX as usize;
+
+/// Compression scheme used in transformed region files for chunks that
+/// couldn't be decoded. The payload is the chunk's original sectors, which are
+/// written back unchanged on `transform_out`. Not a scheme Minecraft uses.
+const VERBATIM_SCHEME: u8 = 0xFF;
 
 // #[derive(Clone)]
-pub struct McaTransformer {}
+pub struct McaTransformer {
+    /// zlib compression level used when chunks are compressed again on
+    /// `transform_out`
+    compression_level: flate2::Compression,
+}
 
 impl McaTransformer {
+    /// Chunks are compressed with the default zlib level, which is closer to
+    /// what Minecraft writes than the fast level, so fewer bytes change if
+    /// Minecraft later rewrites a restored region.
     pub fn new() -> McaTransformer {
-        McaTransformer {}
+        McaTransformer {
+            compression_level: flate2::Compression::default(),
+        }
     }
 
-    fn accepts_file(file_path: &str) -> bool {
+    fn is_region_file(file_path: &str) -> bool {
         file_path.ends_with(".mca")
     }
 }
 
 impl FileTransformer for McaTransformer {
     fn transform_in(&self, file_path: &str, contents: Vec<u8>) -> Result<Vec<u8>, String> {
-        // this transformer only works with .mca files
-        if !McaTransformer::accepts_file(file_path) {
+        self.transform_in_parallel(file_path, contents, 1)
+    }
+
+    /// Chunks are decoded in parallel, since each chunk is compressed
+    /// separately.
+    fn transform_in_parallel(
+        &self,
+        file_path: &str,
+        contents: Vec<u8>,
+        num_threads: usize,
+    ) -> Result<Vec<u8>, String> {
+        // this transformer only works with .mca files. Minecraft leaves empty
+        // region files behind, which would otherwise be restored as a header.
+        if !McaTransformer::is_region_file(file_path) || contents.is_empty() {
             return Ok(contents);
         }
 
         let region = RegionFileFormatReader::new(contents);
-        match transform_region_file_to_uncompressed(&region) {
+        match transform_region_file_to_uncompressed(file_path, &region, num_threads) {
             Ok(x) => Ok(x),
             Err(err) => Err(format!(
                 "Failed to uncompress file '{}': {}",
@@ -45,42 +83,184 @@ impl FileTransformer for McaTransformer {
         transformed_contents: Vec<u8>,
     ) -> Result<Vec<u8>, String> {
         // this transformer only works with .mca files
-        if !McaTransformer::accepts_file(file_path) {
+        if !McaTransformer::is_region_file(file_path) || transformed_contents.is_empty() {
             return Ok(transformed_contents);
         }
 
         let region = RegionFileFormatReader::new(transformed_contents);
-        match transform_region_file_to_compressed(&region) {
+        match transform_region_file_to_compressed(&region, self.compression_level) {
             Ok(x) => Ok(x),
             Err(err) => Err(format!("Failed to compress file '{}': {}", file_path, err)),
         }
     }
+
+    /// Chunks are recompressed, so restored region files may have other bytes
+    /// than the originals, but hold the same chunks.
+    fn is_lossless(&self) -> bool {
+        true
+    }
+
+    fn accepts_file(&self, file_path: &str) -> bool {
+        McaTransformer::is_region_file(file_path)
+    }
+
+    fn sample_files(&self) -> Vec<(String, Vec<u8>)> {
+        let mut samples = generic_sample_files();
+        samples.extend(sample_region_files());
+        samples
+    }
+}
+
+/// Synthetic region files: one like Minecraft writes, one with chunks in the
+/// other compression schemes, and an empty one. Chunks that can't be decoded
+/// aren't sampled, since they're stored with a warning.
+fn sample_region_files() -> Vec<(String, Vec<u8>)> {
+    fn compress(scheme: u8, data: &[u8]) -> Vec<u8> {
+        let compression = flate2::Compression::default();
+        match scheme {
+            1 => {
+                let mut encoder = GzEncoder::new(Vec::new(), compression);
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            2 => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), compression);
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            _ => data.to_vec(),
+        }
+    }
+
+    fn region(chunks: &[(usize, u8, Vec<u8>)]) -> Vec<u8> {
+        let mut writer = RegionFileFormatWriter::new();
+        for (chunk_i, scheme, payload) in chunks {
+            writer.add_chunk(
+                *chunk_i,
+                1700000000 + *chunk_i as u32,
+                *scheme,
+                payload.clone(),
+            );
+        }
+        writer
+            .serialize()
+            .expect("sample region files should be serializable")
+    }
+
+    let nbt_like = b"\x0a\x00\x00\x03\x00\x0bDataVersion\x00\x00\x0f\x3d".repeat(400);
+    // zlib at the default level, like Minecraft writes
+    let minecraft = region(&[
+        (0, 2, compress(2, &nbt_like)),
+        (1, 2, compress(2, &random_bytes(20_000, 2))),
+        (33, 2, compress(2, b"")),
+        (1023, 2, compress(2, &nbt_like[..1000])),
+    ]);
+    let other_schemes = region(&[
+        (0, 1, compress(1, &nbt_like)),
+        (5, 3, compress(3, &nbt_like)),
+        (6, 2, compress(2, &random_bytes(5_000, 3))),
+    ]);
+
+    vec![
+        (String::from("region/r.0.0.mca"), minecraft),
+        (String::from("region/r.0.1.mca"), other_schemes),
+        (String::from("region/r.1.0.mca"), Vec::new()),
+    ]
 }
 
+/// Decodes the chunks of a region file, splitting the chunks into
+/// `num_threads` ranges that are decoded in parallel. The chunks are added in
+/// order afterwards, so the result doesn't depend on the number of threads.
 fn transform_region_file_to_uncompressed(
+    file_path: &str,
     reader: &RegionFileFormatReader,
+    num_threads: usize,
 ) -> Result<Vec<u8>, String> {
+    let chunks_per_thread = CHUNKS_IN_REGION.div_ceil(num_threads.clamp(1, CHUNKS_IN_REGION));
+    let mut ranges = (0..CHUNKS_IN_REGION)
+        .step_by(chunks_per_thread)
+        .map(|start| start..(start + chunks_per_thread).min(CHUNKS_IN_REGION));
+
+    let chunks = thread::scope(|scope| {
+        // the first range is decoded on this thread
+        let first_range = ranges.next().unwrap();
+        let handles: Vec<_> = ranges
+            .map(|range| scope.spawn(|| decode_chunks(file_path, reader, range)))
+            .collect();
+
+        let mut chunks = decode_chunks(file_path, reader, first_range);
+        for handle in handles {
+            match handle.join() {
+                Ok(x) => chunks.extend(x),
+                Err(err) => panic::resume_unwind(err),
+            }
+        }
+        chunks
+    });
+
     let mut writer = RegionFileFormatWriter::new();
+    for (i, timestamp, compression_scheme, payload) in chunks {
+        writer.add_chunk(i, timestamp, compression_scheme, payload);
+    }
+    writer.serialize()
+}
 
-    for i in 0..CHUNKS_IN_REGION {
+/// Decodes the existing chunks in a range of chunk indices, returning the
+/// index, timestamp, compression scheme and payload to store each chunk with.
+fn decode_chunks(
+    file_path: &str,
+    reader: &RegionFileFormatReader,
+    chunk_indices: Range<usize>,
+) -> Vec<(usize, u32, u8, Vec<u8>)> {
+    let mut chunks = Vec::new();
+
+    for i in chunk_indices {
         let desc = reader.get_chunk_i(i);
-        if desc.is_exists() {
-            writer.add_chunk(i, desc.timestamp, 3, reader.read_chunk_uncompressed(&desc)?);
+        if !desc.is_exists() {
+            continue;
+        }
+
+        match reader.read_chunk_uncompressed(&desc) {
+            Ok(payload) => chunks.push((i, desc.timestamp, 3, payload)),
+            Err(err) => {
+                // one bad chunk shouldn't fail the whole snapshot
+                eprintln!(
+                    "Warning: storing chunk {} in '{}' as-is, since it couldn't be decoded: {}",
+                    i, file_path, err
+                );
+                chunks.push((
+                    i,
+                    desc.timestamp,
+                    VERBATIM_SCHEME,
+                    reader.read_chunk_sectors(&desc).to_vec(),
+                ));
+            }
         }
     }
 
-    writer.serialize()
+    chunks
 }
 
-fn transform_region_file_to_compressed(reader: &RegionFileFormatReader) -> Result<Vec<u8>, String> {
+/// Chunks are always compressed with zlib, Minecraft's default scheme, no
+/// matter which scheme they were originally read with.
+fn transform_region_file_to_compressed(
+    reader: &RegionFileFormatReader,
+    compression_level: flate2::Compression,
+) -> Result<Vec<u8>, String> {
     let mut writer = RegionFileFormatWriter::new();
 
     for i in 0..CHUNKS_IN_REGION {
         let desc = reader.get_chunk_i(i);
 
         if desc.is_exists() {
+            if reader.read_compression_scheme(&desc) == Some(VERBATIM_SCHEME) {
+                let sectors = reader.read_chunk_uncompressed(&desc)?;
+                writer.add_chunk_sectors(i, desc.timestamp, sectors);
+                continue;
+            }
+
             let payload = reader.read_chunk_uncompressed(&desc)?;
-            let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::fast());
+            let mut encoder = ZlibEncoder::new(Vec::new(), compression_level);
             simplify_result(encoder.write_all(&payload))?;
             let compressed_payload = simplify_result(encoder.finish())?;
 
@@ -109,6 +289,9 @@ impl RegionFileFormatReader {
         }
 
         let offset_bytes = descriptor.offset as usize * SECTOR_SIZE;
+        if offset_bytes + 5 > self.contents.len() {
+            return Err(String::from("Chunk offset is past the end of the file"));
+        }
         let length = i32::from_be_bytes([
             self.contents[offset_bytes],
             self.contents[offset_bytes + 1],
@@ -119,25 +302,52 @@ impl RegionFileFormatReader {
             return Err(String::from("Length must be a positive number"));
         }
         let length = length as usize;
-        if length > (descriptor.sector_count as usize) * SECTOR_SIZE {
+        // A full sector count may be a larger chunk. Minecraft moves chunks
+        // that large to separate files, but transformed region files store
+        // uncompressed chunks, which can be larger.
+        let sector_count = descriptor.sector_count as usize;
+        if sector_count < MAX_SECTOR_COUNT && length > sector_count * SECTOR_SIZE {
             return Err(String::from("Chunk length is larger than the sector count"));
         }
+        if offset_bytes + 4 + length > self.contents.len() {
+            return Err(String::from("Chunk length is past the end of the file"));
+        }
 
         let compression_type = self.contents[offset_bytes + 4];
         let data = &self.contents[offset_bytes + 5..offset_bytes + 5 + length - 1];
 
         match compression_type {
+            1 => {
+                let mut vec = Vec::new();
+                let mut dec = GzDecoder::new(data);
+                simplify_result(dec.read_to_end(&mut vec))?;
+                Ok(vec)
+            }
             2 => {
                 let mut vec = Vec::new();
                 let mut dec = ZlibDecoder::new(data);
                 simplify_result(dec.read_to_end(&mut vec))?;
                 Ok(vec)
             }
-            3 => Ok(data.to_vec()),
+            3 | VERBATIM_SCHEME => Ok(data.to_vec()),
+            4 => decode_lz4_block_stream(data),
             _ => Err(String::from("Unsupported compression algorithm")),
         }
     }
 
+    fn read_compression_scheme(&self, descriptor: &ChunkDescriptor) -> Option<u8> {
+        let offset_bytes = descriptor.offset as usize * SECTOR_SIZE;
+        self.contents.get(offset_bytes + 4).copied()
+    }
+
+    /// Reads the raw sectors of a chunk, including its length and compression
+    /// scheme. Sectors past the end of the file are left out.
+    fn read_chunk_sectors(&self, descriptor: &ChunkDescriptor) -> &[u8] {
+        let start = (descriptor.offset as usize * SECTOR_SIZE).min(self.contents.len());
+        let end = (start + descriptor.sector_count as usize * SECTOR_SIZE).min(self.contents.len());
+        &self.contents[start..end]
+    }
+
     fn get_chunk_i(&self, i: usize) -> ChunkDescriptor {
         // file too small, must be empty or corrupt
         if self.contents.len() < SECTOR_SIZE * 2 {
@@ -170,6 +380,63 @@ impl RegionFileFormatReader {
     }
 }
 
+/// Decodes a chunk compressed with scheme 4, which Minecraft writes with
+/// lz4-java's `LZ4BlockOutputStream`. Each block starts with the magic
+/// "LZ4Block", a token (compression method | level), then the compressed
+/// length, decompressed length and checksum as little endian u32s. The stream
+/// ends with an empty block.
+///
+/// Checksums aren't verified, since the chunk is compressed again before it's
+/// restored anyway.
+fn decode_lz4_block_stream(mut data: &[u8]) -> Result<Vec<u8>, String> {
+    const MAGIC: &[u8] = b"LZ4Block";
+    const HEADER_LENGTH: usize = MAGIC.len() + 13;
+    const METHOD_RAW: u8 = 0x10;
+    const METHOD_LZ4: u8 = 0x20;
+
+    let read_u32 = |bytes: &[u8]| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    let mut result = Vec::new();
+
+    while !data.is_empty() {
+        if data.len() < HEADER_LENGTH || &data[..MAGIC.len()] != MAGIC {
+            return Err(String::from("Invalid LZ4 block header"));
+        }
+
+        let method = data[8] & 0xF0;
+        let compressed_length = read_u32(&data[9..]) as usize;
+        let decompressed_length = read_u32(&data[13..]) as usize;
+        data = &data[HEADER_LENGTH..];
+
+        if compressed_length > data.len() {
+            return Err(String::from("LZ4 block is longer than the chunk"));
+        }
+        let block = &data[..compressed_length];
+        data = &data[compressed_length..];
+
+        match method {
+            METHOD_RAW if compressed_length == decompressed_length => {
+                result.extend_from_slice(block)
+            }
+            METHOD_LZ4 => {
+                let decompressed =
+                    simplify_result(lz4_flex::block::decompress(block, decompressed_length))?;
+                if decompressed.len() != decompressed_length {
+                    return Err(String::from("LZ4 block decompressed to the wrong length"));
+                }
+                result.extend(decompressed);
+            }
+            _ => return Err(String::from("Invalid LZ4 block")),
+        }
+
+        // empty block marks the end of the stream
+        if decompressed_length == 0 {
+            break;
+        }
+    }
+
+    Ok(result)
+}
+
 #[derive(Clone, Copy)]
 struct ChunkDescriptor {
     /// in number of sectors
@@ -240,10 +507,11 @@ impl RegionFileFormatWriter {
 
         self.chunks[chunk_i] = ChunkDescriptor {
             offset: self.next_sector_i as u32,
-            sector_count: sector_count as u8,
-            timestamp: timestamp,
+            sector_count: sector_count.min(MAX_SECTOR_COUNT) as u8,
+            timestamp,
         };
 
+        self.next_chunk_i_must_be_ge = chunk_i + 1;
         self.next_sector_i += sector_count;
 
         // Calculate the new target payload length. Each chunk must be sector-aligned.
@@ -259,6 +527,38 @@ impl RegionFileFormatWriter {
         }
     }
 
+    /// Add a chunk from its raw sectors, which already include the chunk's
+    /// length and compression scheme. Has the same ordering limitation as
+    /// `add_chunk`.
+    fn add_chunk_sectors(&mut self, chunk_i: usize, timestamp: u32, mut sectors: Vec<u8>) {
+        if chunk_i < self.next_chunk_i_must_be_ge {
+            panic!(
+                "RegionFileFormatWriter only accepts chunks in left-to-right, then up-down order."
+            )
+        }
+
+        let sector_count = sectors.len().div_ceil(SECTOR_SIZE).max(1);
+        sectors.resize(sector_count * SECTOR_SIZE, 0);
+
+        self.chunks[chunk_i] = ChunkDescriptor {
+            offset: self.next_sector_i as u32,
+            sector_count: sector_count.min(MAX_SECTOR_COUNT) as u8,
+            timestamp,
+        };
+
+        self.next_chunk_i_must_be_ge = chunk_i + 1;
+        self.next_sector_i += sector_count;
+        self.payload.extend(sectors);
+    }
+
+    /// Serializes the region file. The layout only depends on the chunks
+    /// added, so the same chunks always serialize to the same bytes:
+    ///
+    /// - chunks are placed one after another in the order they were added,
+    ///   starting right after the header, with no unused sectors in between
+    /// - the unused part of a chunk's last sector is filled with zeros
+    /// - chunks that weren't added have an offset, sector count and
+    ///   timestamp of 0
     fn serialize(&self) -> Result<Vec<u8>, String> {
         let mut result = Vec::new();
 
@@ -284,3 +584,295 @@ impl RegionFileFormatWriter {
         Ok(result)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use flate2::write::{GzEncoder, ZlibEncoder};
+
+    use crate::transformer::{FileTransformer, RoundTrip, check_round_trip};
+
+    use super::{
+        McaTransformer, RegionFileFormatReader, RegionFileFormatWriter, sample_region_files,
+    };
+
+    fn zlib_compress(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::best());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// Creates a region file with zlib compressed chunks, like Minecraft does.
+    fn create_region(chunks: &[(usize, u32, Vec<u8>)]) -> Vec<u8> {
+        let mut writer = RegionFileFormatWriter::new();
+        for (chunk_i, timestamp, payload) in chunks {
+            writer.add_chunk(*chunk_i, *timestamp, 2, zlib_compress(payload));
+        }
+        writer.serialize().unwrap()
+    }
+
+    #[test]
+    pub fn region_round_trip_is_loadable() {
+        let chunks = vec![
+            (0, 1700000000, b"chunk zero".repeat(100)),
+            (33, 1700000001, vec![]),
+            (
+                1023,
+                1700000002,
+                (0..20_000u32).flat_map(|x| x.to_be_bytes()).collect(),
+            ),
+        ];
+        let region = create_region(&chunks);
+
+        let transformer = McaTransformer::new();
+        let transformed = transformer
+            .transform_in("region/r.0.0.mca", region)
+            .unwrap();
+        let restored = transformer
+            .transform_out("region/r.0.0.mca", transformed)
+            .unwrap();
+
+        let reader = RegionFileFormatReader::new(restored);
+        for i in 0..super::CHUNKS_IN_REGION {
+            let desc = reader.get_chunk_i(i);
+            match chunks.iter().find(|(chunk_i, _, _)| *chunk_i == i) {
+                None => assert!(!desc.is_exists()),
+                Some((_, timestamp, payload)) => {
+                    assert!(desc.is_exists());
+                    assert_eq!(desc.timestamp, *timestamp);
+
+                    let offset = desc.offset as usize * super::SECTOR_SIZE;
+                    // chunks are stored zlib compressed again
+                    assert_eq!(reader.contents[offset + 4], 2);
+                    assert_eq!(&reader.read_chunk_uncompressed(&desc).unwrap(), payload);
+                }
+            }
+        }
+    }
+
+    fn gzip_compress(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::best());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// Compresses like lz4-java's `LZ4BlockOutputStream`, with a compressed
+    /// block, a raw block and the end block. Checksums are left as 0.
+    fn lz4_block_compress(data: &[u8]) -> Vec<u8> {
+        let (first, second) = data.split_at(data.len() / 2);
+        let mut result = Vec::new();
+        let mut add_block = |method: u8, block: &[u8], decompressed_length: usize| {
+            result.extend_from_slice(b"LZ4Block");
+            result.push(method);
+            result.extend_from_slice(&(block.len() as u32).to_le_bytes());
+            result.extend_from_slice(&(decompressed_length as u32).to_le_bytes());
+            result.extend_from_slice(&0u32.to_le_bytes());
+            result.extend_from_slice(block);
+        };
+        add_block(0x20, &lz4_flex::block::compress(first), first.len());
+        add_block(0x10, second, second.len());
+        add_block(0x10, &[], 0);
+        result
+    }
+
+    #[test]
+    pub fn decodes_all_compression_schemes() {
+        let payload = b"chunk data ".repeat(200);
+        type Compress = fn(&[u8]) -> Vec<u8>;
+        let schemes: [(u8, Compress); 4] = [
+            (1, gzip_compress),
+            (2, zlib_compress),
+            (3, |data| data.to_vec()),
+            (4, lz4_block_compress),
+        ];
+
+        for (scheme, compress) in schemes {
+            let mut writer = RegionFileFormatWriter::new();
+            writer.add_chunk(5, 1700000000, scheme, compress(&payload));
+            let region = writer.serialize().unwrap();
+
+            let transformer = McaTransformer::new();
+            let transformed = transformer
+                .transform_in("region/r.0.0.mca", region)
+                .unwrap();
+            let restored = transformer
+                .transform_out("region/r.0.0.mca", transformed)
+                .unwrap();
+
+            let reader = RegionFileFormatReader::new(restored);
+            let desc = reader.get_chunk_i(5);
+            // all chunks are restored zlib compressed
+            assert_eq!(reader.read_compression_scheme(&desc), Some(2));
+            assert_eq!(reader.read_chunk_uncompressed(&desc).unwrap(), payload);
+        }
+    }
+
+    #[test]
+    pub fn preserves_undecodable_chunks() {
+        let unknown_scheme = [&5i32.to_be_bytes()[..], &[42], b"abcd"].concat();
+        let bad_length = [&100_000i32.to_be_bytes()[..], &[2], b"efgh"].concat();
+        let bad_zlib = [&5i32.to_be_bytes()[..], &[2], b"ijkl"].concat();
+
+        let mut writer = RegionFileFormatWriter::new();
+        writer.add_chunk(0, 1, 2, zlib_compress(b"good chunk"));
+        writer.add_chunk_sectors(1, 2, unknown_scheme.clone());
+        writer.add_chunk_sectors(2, 3, bad_length.clone());
+        writer.add_chunk_sectors(3, 4, bad_zlib.clone());
+        let region = writer.serialize().unwrap();
+
+        let transformer = McaTransformer::new();
+        let transformed = transformer
+            .transform_in("region/r.0.0.mca", region)
+            .unwrap();
+        let restored = transformer
+            .transform_out("region/r.0.0.mca", transformed)
+            .unwrap();
+
+        let reader = RegionFileFormatReader::new(restored);
+        let good = reader.get_chunk_i(0);
+        assert_eq!(
+            reader.read_chunk_uncompressed(&good).unwrap(),
+            b"good chunk"
+        );
+
+        for (i, sectors) in [(1, unknown_scheme), (2, bad_length), (3, bad_zlib)] {
+            let desc = reader.get_chunk_i(i);
+            assert_eq!(desc.timestamp, i as u32 + 1);
+            let restored_sectors = reader.read_chunk_sectors(&desc);
+            assert_eq!(restored_sectors.len(), super::SECTOR_SIZE);
+            assert_eq!(&restored_sectors[..sectors.len()], sectors.as_slice());
+        }
+    }
+
+    #[test]
+    pub fn transformed_layout_is_deterministic() {
+        let payloads: Vec<Vec<u8>> = (0..3u8).map(|i| vec![i; 3000 * i as usize]).collect();
+
+        // same chunks, but in different sectors with junk in the unused space
+        // of each sector, like region files that Minecraft has rewritten
+        let mut first = RegionFileFormatWriter::new();
+        let mut second = RegionFileFormatWriter::new();
+        second.next_sector_i = 7;
+        second.payload = vec![0xAB; 5 * super::SECTOR_SIZE];
+        for (i, payload) in payloads.iter().enumerate() {
+            first.add_chunk(i * 10, 1700000000, 2, zlib_compress(payload));
+
+            let mut sectors = [&0i32.to_be_bytes()[..], &[2], &zlib_compress(payload)].concat();
+            sectors.splice(0..4, ((sectors.len() - 4) as i32).to_be_bytes());
+            sectors.resize(
+                sectors.len().div_ceil(super::SECTOR_SIZE) * super::SECTOR_SIZE,
+                0xCD,
+            );
+            second.add_chunk_sectors(i * 10, 1700000000, sectors);
+        }
+
+        let transformer = McaTransformer::new();
+        let transform = |region: Vec<u8>| {
+            transformer
+                .transform_in("region/r.0.0.mca", region)
+                .unwrap()
+        };
+
+        let first = transform(first.serialize().unwrap());
+        let second = transform(second.serialize().unwrap());
+        assert!(first == second);
+        assert!(transform(first.clone()) == first);
+
+        let restored = transformer
+            .transform_out("region/r.0.0.mca", first.clone())
+            .unwrap();
+        assert!(transform(restored) == first);
+    }
+
+    #[test]
+    pub fn decodes_chunks_in_parallel() {
+        let chunks: Vec<_> = (0..1024)
+            .step_by(7)
+            .map(|i| (i, 1700000000 + i as u32, vec![i as u8; 100 + i]))
+            .collect();
+        let region = create_region(&chunks);
+        let transformer = McaTransformer::new();
+
+        let transformed = transformer
+            .transform_in("r.0.0.mca", region.clone())
+            .unwrap();
+        for num_threads in [2, 3, 64, 2000] {
+            let transformed_in_parallel = transformer
+                .transform_in_parallel("r.0.0.mca", region.clone(), num_threads)
+                .unwrap();
+            assert!(transformed_in_parallel == transformed);
+        }
+    }
+
+    #[test]
+    pub fn stores_chunks_larger_than_max_sector_count() {
+        let payload: Vec<u8> = (0..2_000_000u32).map(|x| (x % 7) as u8).collect();
+        let region = create_region(&[(3, 1700000000, payload.clone())]);
+
+        let transformer = McaTransformer::new();
+        let transformed = transformer
+            .transform_in("region/r.0.0.mca", region)
+            .unwrap();
+
+        let reader = RegionFileFormatReader::new(transformed.clone());
+        let desc = reader.get_chunk_i(3);
+        assert_eq!(desc.sector_count, u8::MAX);
+        assert_eq!(reader.read_chunk_uncompressed(&desc).unwrap(), payload);
+
+        let restored = transformer
+            .transform_out("region/r.0.0.mca", transformed)
+            .unwrap();
+        let reader = RegionFileFormatReader::new(restored);
+        let desc = reader.get_chunk_i(3);
+        assert_eq!(reader.read_chunk_uncompressed(&desc).unwrap(), payload);
+    }
+
+    #[test]
+    #[should_panic(expected = "only accepts chunks in left-to-right")]
+    pub fn writer_requires_ascending_chunk_order() {
+        let mut writer = RegionFileFormatWriter::new();
+        writer.add_chunk(5, 0, 3, vec![1]);
+        writer.add_chunk(4, 0, 3, vec![1]);
+    }
+
+    #[test]
+    pub fn ignores_other_files() {
+        let transformer = McaTransformer::new();
+        let contents = b"not a region".to_vec();
+        assert_eq!(
+            transformer
+                .transform_in("level.dat", contents.clone())
+                .unwrap(),
+            contents
+        );
+    }
+
+    #[test]
+    pub fn keeps_empty_region_files_empty() {
+        let transformer = McaTransformer::new();
+        let transformed = transformer.transform_in("r.0.0.mca", Vec::new()).unwrap();
+        let restored = transformer.transform_out("r.0.0.mca", transformed).unwrap();
+        assert!(restored.is_empty());
+    }
+
+    #[test]
+    pub fn sample_region_files_round_trip() {
+        let transformer = McaTransformer::new();
+        let results: Vec<RoundTrip> = sample_region_files()
+            .into_iter()
+            .map(|(path, contents)| check_round_trip(&transformer, &path, contents).unwrap())
+            .collect();
+
+        // regions like Minecraft writes are restored byte for byte, and ones
+        // with other compression schemes are recompressed with zlib
+        assert_eq!(
+            results,
+            [
+                RoundTrip::Identical,
+                RoundTrip::Equivalent,
+                RoundTrip::Identical
+            ]
+        );
+    }
+}
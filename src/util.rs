@@ -1,4 +1,11 @@
 pub mod archive_utils;
 pub mod collections_util;
+pub mod color_util;
+pub mod glob_util;
+pub mod graph_util;
 pub mod io_util;
+pub mod json_util;
 pub mod multithreaded_pipeline;
+pub mod progress_util;
+pub mod size_util;
+pub mod time_util;
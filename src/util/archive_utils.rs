@@ -1,36 +1,621 @@
-use std::{fs::File, io::BufReader};
+use std::{
+    ffi::OsStr,
+    fmt::{self, Display},
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    str::FromStr,
+};
 
 use flate2::{GzBuilder, bufread::GzDecoder, write::GzEncoder};
 use gzp::Compression;
+use tar::EntryType;
 
 use crate::{
     delta_list::{JBackupFileDeltaListReader, JBackupFileDeltaListWriter},
+    file_structure::{SnapshotFullType, SnapshotMetaFile},
+    repository::Repository,
     util::io_util::simplify_result,
 };
 
 pub type TarReader = tar::Archive<GzDecoder<BufReader<File>>>;
-pub type TarWriter = tar::Builder<GzEncoder<File>>;
+pub type TarWriter<W = BufWriter<File>> = tar::Builder<GzEncoder<W>>;
+pub type TarEntry<'a> = tar::Entry<'a, GzDecoder<BufReader<File>>>;
 
-pub fn open_tar_gz(filename: &str) -> Result<TarReader, String> {
+pub fn open_tar_gz(repo: &Repository, filename: &str) -> Result<TarReader, String> {
     let file = simplify_result(File::open(filename))?;
-    let gz_dec = GzDecoder::new(BufReader::new(file));
+    let gz_dec = GzDecoder::new(BufReader::with_capacity(repo.io_buffer_size(), file));
     Ok(tar::Archive::new(gz_dec))
 }
 
-pub fn create_tar_gz(filename: &str) -> Result<TarWriter, String> {
+/// Reads a gzip file to the end, which checks that it isn't truncated and
+/// that the checksum in its trailer matches.
+pub fn verify_gz(repo: &Repository, filename: &str) -> Result<(), String> {
+    let file = simplify_result(File::open(filename))?;
+    let reader = BufReader::with_capacity(repo.io_buffer_size(), file);
+    let result = io::copy(&mut GzDecoder::new(reader), &mut io::sink());
+    describe_corruption(filename, result.map(|_| ()))
+}
+
+/// Reads every entry of a tar.gz, then the rest of the gzip stream, which
+/// checks that it isn't truncated and that the checksum in its trailer
+/// matches. `check_entry` is called with the path of each entry, to check
+/// the entries as they're read.
+pub fn verify_tar_gz(
+    repo: &Repository,
+    filename: &str,
+    mut check_entry: impl FnMut(&[u8]) -> Result<(), String>,
+) -> Result<(), String> {
+    let mut tar_reader = open_tar_gz(repo, filename)?;
+
+    for entry in describe_corruption(filename, tar_reader.entries())? {
+        let mut entry = describe_corruption(filename, entry)?;
+        check_entry(&entry.path_bytes())?;
+        describe_corruption(filename, io::copy(&mut entry, &mut io::sink()))?;
+    }
+
+    // the tar ends before the gzip stream does, so the end of the gzip
+    // stream (with its checksum) is read separately
+    let result = io::copy(&mut tar_reader.into_inner(), &mut io::sink());
+    describe_corruption(filename, result.map(|_| ()))
+}
+
+/// Opens the full payload of a snapshot, returning a reader of its tar. The
+/// payload is decompressed according to the snapshot's `full_type`, so callers
+/// don't depend on how it's stored. The empty snapshot is read as an empty
+/// stream, which `tar::Archive` reads as a tar without files.
+pub fn open_payload(
+    repo: &Repository,
+    snapshot: &SnapshotMetaFile,
+) -> Result<Box<dyn Read>, String> {
+    if snapshot.full_type == SnapshotFullType::Empty {
+        return Ok(Box::new(io::empty()));
+    }
+    let payload_path = repo.prepend_snapshot_path(&snapshot.get_full_payload_filename()?);
+    open_payload_file(repo, &payload_path, &snapshot.full_type)
+}
+
+/// Opens a payload file stored as `full_type` (see `open_payload`).
+pub fn open_payload_file(
+    repo: &Repository,
+    filename: &str,
+    full_type: &SnapshotFullType,
+) -> Result<Box<dyn Read>, String> {
+    match full_type {
+        SnapshotFullType::None => Err(format!("'{}' isn't a full payload", filename)),
+        SnapshotFullType::Empty => Ok(Box::new(io::empty())),
+        SnapshotFullType::Tar | SnapshotFullType::TarGz => {
+            let file = simplify_result(File::open(filename))?;
+            let reader = BufReader::with_capacity(repo.io_buffer_size(), file);
+            if *full_type == SnapshotFullType::Tar {
+                Ok(Box::new(reader))
+            } else {
+                Ok(Box::new(GzDecoder::new(reader)))
+            }
+        }
+    }
+}
+
+/// Writes the full payload of a snapshot to `filename` as a tar.gz, which
+/// delta lists are applied to (see `delta_list::restore_from_delta_list`),
+/// for payloads that aren't stored as one.
+pub fn write_payload_tar_gz(
+    repo: &Repository,
+    snapshot: &SnapshotMetaFile,
+    filename: &str,
+) -> Result<(), String> {
+    let mut payload = open_payload(repo, snapshot)?;
+    let file = simplify_result(File::create(filename))?;
+    let mut gz = create_gz_writer(BufWriter::with_capacity(repo.io_buffer_size(), file));
+    describe_corruption(&snapshot.id, io::copy(&mut payload, &mut gz))?;
+    simplify_result(gz.finish_stream())
+}
+
+/// Describes an error reading `filename` as it being truncated or corrupt.
+pub fn describe_corruption<T>(filename: &str, result: io::Result<T>) -> Result<T, String> {
+    match result {
+        Ok(x) => Ok(x),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+            Err(format!("'{}' is truncated", filename))
+        }
+        Err(err) => Err(format!("'{}' is corrupt: {}", filename, err)),
+    }
+}
+
+/// Creates a tar.gz file. Writes are buffered, so the writer returned by
+/// finishing the gzip stream must be flushed (see `TarStream`).
+pub fn create_tar_gz(repo: &Repository, filename: &str) -> Result<TarWriter, String> {
     let file = simplify_result(File::create(filename))?;
-    let gz_builder = GzBuilder::new().write(file, Compression::fast());
-    Ok(tar::Builder::new(gz_builder))
+    Ok(create_tar_gz_writer(BufWriter::with_capacity(
+        repo.io_buffer_size(),
+        file,
+    )))
+}
+
+/// Creates a tar.gz without files, which is what the empty snapshot is
+/// restored as (see `SnapshotMetaFile::empty`).
+pub fn create_empty_tar_gz(repo: &Repository, filename: &str) -> Result<(), String> {
+    let tar = create_tar_gz(repo, filename)?;
+    simplify_result(simplify_result(tar.into_inner())?.finish_stream())
+}
+
+pub fn create_tar_gz_writer<W: Write>(writer: W) -> TarWriter<W> {
+    tar::Builder::new(create_gz_writer(writer))
+}
+
+/// Creates the gzip stream of a tar.gz, compressed the same as snapshot tars.
+pub fn create_gz_writer<W: Write>(writer: W) -> GzEncoder<W> {
+    GzBuilder::new().write(writer, Compression::fast())
+}
+
+/// Creates a tar that's written to `writer` as it is, without compressing it.
+pub fn create_tar_writer<W: Write>(writer: W) -> tar::Builder<StoredTar<W>> {
+    tar::Builder::new(StoredTar(writer))
+}
+
+/// The stream a tar is written into, which is finished after the tar is
+/// (ex. the gzip stream of a tar.gz, which ends with a checksum).
+pub trait TarStream: Write {
+    /// Finishes the stream, and flushes what it's written to.
+    fn finish_stream(self) -> io::Result<()>;
+}
+
+impl<W: Write> TarStream for GzEncoder<W> {
+    fn finish_stream(self) -> io::Result<()> {
+        self.finish()?.flush()
+    }
+}
+
+/// A stream that stores a tar without compressing it (see
+/// `create_tar_writer`).
+pub struct StoredTar<W: Write>(pub W);
+
+impl<W: Write> Write for StoredTar<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W: Write> TarStream for StoredTar<W> {
+    fn finish_stream(mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// How a snapshot's tar is written out, when it's exported (see
+/// `show-tar --format`).
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ArchiveFormat {
+    /// gzipped, as snapshots are stored
+    #[default]
+    TarGz,
+    /// uncompressed, for tools that compress it themselves
+    Tar,
+}
+
+impl Display for ArchiveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::Tar => "tar",
+        })
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tar.gz" => Ok(ArchiveFormat::TarGz),
+            "tar" => Ok(ArchiveFormat::Tar),
+            _ => Err(format!(
+                "Invalid archive format: '{}' (expected 'tar.gz' or 'tar')",
+                s
+            )),
+        }
+    }
+}
+
+/// The header format used for entries in snapshot tars.
+///
+/// Both formats store paths of any length losslessly. GNU stores long paths
+/// in a GNU-specific long name entry, while PAX stores them in a standard pax
+/// extended header, which more tools understand.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TarFormat {
+    Gnu,
+    Pax,
+}
+
+impl TarFormat {
+    pub fn new_header(&self) -> tar::Header {
+        match self {
+            TarFormat::Gnu => tar::Header::new_gnu(),
+            TarFormat::Pax => tar::Header::new_ustar(),
+        }
+    }
+}
+
+impl Display for TarFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TarFormat::Gnu => "gnu",
+            TarFormat::Pax => "pax",
+        })
+    }
+}
+
+impl FromStr for TarFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gnu" => Ok(TarFormat::Gnu),
+            "pax" => Ok(TarFormat::Pax),
+            _ => Err(format!(
+                "Invalid tar format: '{}' (expected 'gnu' or 'pax')",
+                s
+            )),
+        }
+    }
+}
+
+/// How much delta lists are compressed. Delta lists are always gzip streams,
+/// so any level can be read without knowing which level it was written with.
+/// `Store` writes uncompressed gzip blocks, for delta lists that wouldn't
+/// compress well anyway (ex. xdelta output, and added files that are already
+/// compressed).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CompressionLevel {
+    Store,
+    Fast,
+    Default,
+    Best,
+}
+
+impl CompressionLevel {
+    fn to_gzip_compression(self) -> Compression {
+        match self {
+            CompressionLevel::Store => Compression::none(),
+            CompressionLevel::Fast => Compression::fast(),
+            CompressionLevel::Default => Compression::default(),
+            CompressionLevel::Best => Compression::best(),
+        }
+    }
+}
+
+impl Display for CompressionLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CompressionLevel::Store => "store",
+            CompressionLevel::Fast => "fast",
+            CompressionLevel::Default => "default",
+            CompressionLevel::Best => "best",
+        })
+    }
+}
+
+impl FromStr for CompressionLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "store" => Ok(CompressionLevel::Store),
+            "fast" => Ok(CompressionLevel::Fast),
+            "default" => Ok(CompressionLevel::Default),
+            "best" => Ok(CompressionLevel::Best),
+            _ => Err(format!(
+                "Invalid compression: '{}' (expected 'store', 'fast', 'default' or 'best')",
+                s
+            )),
+        }
+    }
+}
+
+/// Appends an entry to a tar, storing the path losslessly in the format of
+/// `header` (see `TarFormat`). The path is raw bytes, since it may not be
+/// UTF-8.
+///
+/// GNU headers use `tar::Builder::append_data`'s long name entries. Since the
+/// `tar` crate can't write pax extended headers, they're written here for
+/// ustar headers with paths that don't fit.
+pub fn append_tar_entry<W: Write>(
+    builder: &mut tar::Builder<W>,
+    header: &mut tar::Header,
+    path: &[u8],
+    data: impl Read,
+) -> io::Result<()> {
+    append_tar_entry_with_link(builder, header, path, None, data)
+}
+
+/// Appends a hard link to `target`, the path of an earlier entry, to a tar.
+/// Both paths are stored losslessly, like in `append_tar_entry`.
+pub fn append_tar_link<W: Write>(
+    builder: &mut tar::Builder<W>,
+    header: &mut tar::Header,
+    path: &[u8],
+    target: &[u8],
+) -> io::Result<()> {
+    header.set_entry_type(EntryType::Link);
+    header.set_size(0);
+    append_tar_entry_with_link(builder, header, path, Some(target), io::empty())
+}
+
+fn append_tar_entry_with_link<W: Write>(
+    builder: &mut tar::Builder<W>,
+    header: &mut tar::Header,
+    path: &[u8],
+    link_target: Option<&[u8]>,
+    data: impl Read,
+) -> io::Result<()> {
+    let path = Path::new(OsStr::from_bytes(path));
+
+    if header.as_ustar().is_none() {
+        return match link_target {
+            None => builder.append_data(header, path, data),
+            Some(target) => builder.append_link(header, path, OsStr::from_bytes(target)),
+        };
+    }
+
+    let mut pax_records = Vec::new();
+    if header.set_path(path).is_err() {
+        pax_records.extend(pax_record("path", path.as_os_str().as_bytes()));
+
+        // the path in the header is ignored by readers when the pax header
+        // has a path, but should still be something meaningful
+        let file_name = path.file_name().map_or(&b"_"[..], |x| x.as_bytes());
+        header.set_path(OsStr::from_bytes(&file_name[..file_name.len().min(100)]))?;
+    }
+    if let Some(target) = link_target
+        && header.set_link_name(OsStr::from_bytes(target)).is_err()
+    {
+        pax_records.extend(pax_record("linkpath", target));
+        header.set_link_name("_")?;
+    }
+
+    if !pax_records.is_empty() {
+        let mut pax_header = tar::Header::new_ustar();
+        pax_header.set_entry_type(EntryType::XHeader);
+        pax_header.set_path("././@PaxHeader")?;
+        pax_header.set_mode(0o644);
+        pax_header.set_size(pax_records.len() as u64);
+        pax_header.set_cksum();
+        builder.append(&pax_header, pax_records.as_slice())?;
+    }
+
+    header.set_cksum();
+    builder.append(header, data)
 }
 
-pub fn open_delta_list(filename: &str) -> Result<JBackupFileDeltaListReader, String> {
+/// Returns the target of a hard link entry, or `None` if the entry isn't a
+/// hard link.
+pub fn get_link_target<R: Read>(entry: &tar::Entry<'_, R>) -> Result<Option<Vec<u8>>, String> {
+    if entry.header().entry_type() != EntryType::Link {
+        return Ok(None);
+    }
+    match entry.link_name_bytes() {
+        Some(x) => Ok(Some(x.into_owned())),
+        None => Err(format!(
+            "The hard link '{}' has no target",
+            String::from_utf8_lossy(&entry.path_bytes())
+        )),
+    }
+}
+
+/// Creates a pax extended header record: `{length} {key}={value}\n`, where
+/// the length includes itself.
+fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+    let rest_len = key.len() + value.len() + 3; // ' ', '=', '\n'
+    let mut len = rest_len + 1;
+    while len.to_string().len() + rest_len != len {
+        len = len.to_string().len() + rest_len;
+    }
+
+    let mut record = format!("{} {}=", len, key).into_bytes();
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
+
+pub fn open_delta_list(
+    repo: &Repository,
+    filename: &str,
+) -> Result<JBackupFileDeltaListReader, String> {
     let file = simplify_result(File::open(filename))?;
-    let gz_dec = GzDecoder::new(BufReader::new(file));
-    Ok(JBackupFileDeltaListReader::new(gz_dec)?)
+    let gz_dec = GzDecoder::new(BufReader::with_capacity(repo.io_buffer_size(), file));
+    JBackupFileDeltaListReader::new(gz_dec)
+}
+
+pub fn create_delta_list(
+    repo: &Repository,
+    filename: &str,
+    compression: CompressionLevel,
+) -> Result<JBackupFileDeltaListWriter, String> {
+    let output_file = BufWriter::with_capacity(
+        repo.io_buffer_size(),
+        simplify_result(File::create(filename))?,
+    );
+    let output_builder = GzBuilder::new().write(output_file, compression.to_gzip_compression());
+    JBackupFileDeltaListWriter::new(output_builder)
 }
 
-pub fn create_delta_list(filename: &str) -> Result<JBackupFileDeltaListWriter, String> {
-    let output_file = simplify_result(File::create(filename))?;
-    let output_builder = GzBuilder::new().write(output_file, Compression::default()); // todo: probably don't need global compression, since xdelta output might already be compressed
-    Ok(JBackupFileDeltaListWriter::new(output_builder)?)
+#[cfg(test)]
+mod test {
+    use std::{env, fs, io::Read, process};
+
+    use crate::{
+        file_structure::{ConfigFile, SnapshotFullType},
+        repository::Repository,
+    };
+
+    use super::{
+        TarFormat, append_tar_entry, append_tar_link, create_tar_gz, get_link_target,
+        open_payload_file, pax_record, verify_gz, verify_tar_gz,
+    };
+
+    fn round_trip(format: TarFormat, path: &[u8]) {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = format.new_header();
+        header.set_size(5);
+        append_tar_entry(&mut builder, &mut header, path, "hello".as_bytes()).unwrap();
+        let tar = builder.into_inner().unwrap();
+
+        let mut archive = tar::Archive::new(tar.as_slice());
+        let mut entries = archive.entries().unwrap();
+        let mut entry = entries.next().unwrap().unwrap();
+        assert_eq!(entry.path_bytes().as_ref(), path);
+
+        let mut content = String::new();
+        entry.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "hello");
+        assert!(entries.next().is_none());
+    }
+
+    fn test_repository() -> Repository {
+        Repository::new(".", ConfigFile::default())
+    }
+
+    /// Lists the paths in the payload, opened as `full_type`.
+    fn list_payload(path: &str, full_type: SnapshotFullType) -> Vec<String> {
+        let repo = test_repository();
+        let mut archive = tar::Archive::new(open_payload_file(&repo, path, &full_type).unwrap());
+        archive
+            .entries()
+            .unwrap()
+            .map(|x| String::from_utf8(x.unwrap().path_bytes().into_owned()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    pub fn opens_payloads_of_each_type() {
+        let path = env::temp_dir().join(format!("jbackup-payload-test-{}", process::id()));
+        let path_str = path.to_str().unwrap();
+        let repo = test_repository();
+        let mut tar = create_tar_gz(&repo, path_str).unwrap();
+        let mut header = TarFormat::Gnu.new_header();
+        header.set_size(5);
+        append_tar_entry(&mut tar, &mut header, b"a.txt", "hello".as_bytes()).unwrap();
+        tar.into_inner().unwrap().finish().unwrap();
+
+        assert_eq!(list_payload(path_str, SnapshotFullType::TarGz), ["a.txt"]);
+
+        let mut decompressed = Vec::new();
+        open_payload_file(&repo, path_str, &SnapshotFullType::TarGz)
+            .unwrap()
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        fs::write(&path, decompressed).unwrap();
+        assert_eq!(list_payload(path_str, SnapshotFullType::Tar), ["a.txt"]);
+
+        assert!(list_payload(path_str, SnapshotFullType::Empty).is_empty());
+        assert!(open_payload_file(&repo, path_str, &SnapshotFullType::None).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    pub fn stores_long_paths_losslessly() {
+        let nested_path = "directory/".repeat(20) + "file.txt";
+        let long_name = "é".repeat(100) + "/file.txt";
+        assert!(nested_path.len() > 200);
+
+        for format in [TarFormat::Gnu, TarFormat::Pax] {
+            round_trip(format, b"short.txt");
+            round_trip(format, ("a".repeat(100) + "/" + &"b".repeat(99)).as_bytes());
+            round_trip(format, nested_path.as_bytes());
+            round_trip(format, long_name.as_bytes());
+        }
+    }
+
+    #[test]
+    pub fn stores_non_utf8_paths_losslessly() {
+        let long_path = [b"dir/".repeat(60), b"file\xff".to_vec()].concat();
+
+        for format in [TarFormat::Gnu, TarFormat::Pax] {
+            round_trip(format, b"bad\xff\xfe");
+            round_trip(format, &long_path);
+        }
+    }
+
+    #[test]
+    pub fn stores_long_link_targets_losslessly() {
+        let target = ("directory/".repeat(20) + "file.txt").into_bytes();
+
+        for format in [TarFormat::Gnu, TarFormat::Pax] {
+            let mut builder = tar::Builder::new(Vec::new());
+            append_tar_link(&mut builder, &mut format.new_header(), b"link", &target).unwrap();
+            let tar = builder.into_inner().unwrap();
+
+            let mut archive = tar::Archive::new(tar.as_slice());
+            let entry = archive.entries().unwrap().next().unwrap().unwrap();
+            assert_eq!(entry.path_bytes().as_ref(), b"link");
+            assert_eq!(get_link_target(&entry).unwrap(), Some(target.clone()));
+        }
+    }
+
+    #[test]
+    pub fn reports_truncated_and_corrupt_archives() {
+        let path = env::temp_dir().join(format!("jbackup-verify-test-{}.tar.gz", process::id()));
+        let path_str = path.to_str().unwrap();
+        let repo = test_repository();
+
+        let mut builder = create_tar_gz(&repo, path_str).unwrap();
+        let mut header = TarFormat::Gnu.new_header();
+        header.set_size(5);
+        append_tar_entry(&mut builder, &mut header, b"a.txt", "hello".as_bytes()).unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+        let tar_gz = fs::read(&path).unwrap();
+
+        let mut paths = Vec::new();
+        verify_tar_gz(&repo, path_str, |path| {
+            paths.push(path.to_vec());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(paths, [b"a.txt".to_vec()]);
+        verify_gz(&repo, path_str).unwrap();
+
+        fs::write(&path, &tar_gz[..tar_gz.len() - 4]).unwrap();
+        assert!(
+            verify_tar_gz(&repo, path_str, |_| Ok(()))
+                .unwrap_err()
+                .contains("truncated")
+        );
+        assert!(
+            verify_gz(&repo, path_str)
+                .unwrap_err()
+                .contains("truncated")
+        );
+
+        // the last 8 bytes are the checksum and size of the contents
+        let mut corrupt = tar_gz.clone();
+        let checksum_index = corrupt.len() - 8;
+        corrupt[checksum_index] ^= 0xFF;
+        fs::write(&path, corrupt).unwrap();
+        assert!(
+            verify_tar_gz(&repo, path_str, |_| Ok(()))
+                .unwrap_err()
+                .contains("corrupt")
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    pub fn pax_record_length_includes_itself() {
+        assert_eq!(pax_record("path", b"a"), b"9 path=a\n");
+
+        // lengths where the number of digits changes
+        for value_len in 85..95 {
+            let record = pax_record("path", "a".repeat(value_len).as_bytes());
+            let record = String::from_utf8(record).unwrap();
+            let (len, _) = record.split_once(' ').unwrap();
+            assert_eq!(len.parse::<usize>().unwrap(), record.len());
+        }
+    }
 }
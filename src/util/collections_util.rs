@@ -1,13 +1,14 @@
 /// Creates a HashSet with String::from(...)
 ///
 /// ```
+/// # use jbackup::string_set;
 /// let s = string_set!["a", "b", "c"];
 /// let mut expected = std::collections::HashSet::new();
 /// expected.insert(String::from("a"));
 /// expected.insert(String::from("b"));
 /// expected.insert(String::from("c"));
 /// assert_eq!(s, expected);
-/// ``````
+/// ```
 #[macro_export]
 macro_rules! string_set {
     ($($elm:literal),*) => {{
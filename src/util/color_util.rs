@@ -0,0 +1,94 @@
+use std::{
+    io::{self, IsTerminal},
+    str::FromStr,
+    sync::OnceLock,
+};
+
+/// Whether to colorize output with ANSI escape codes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ColorChoice {
+    /// colorize if stdout is a terminal and `NO_COLOR` isn't set
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for ColorChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(format!(
+                "Invalid color option: '{}' (expected 'auto', 'always' or 'never')",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Color {
+    Red,
+    Green,
+    Yellow,
+}
+
+impl Color {
+    fn ansi_code(self) -> &'static str {
+        match self {
+            Color::Red => "31",
+            Color::Green => "32",
+            Color::Yellow => "33",
+        }
+    }
+}
+
+static COLORS_ENABLED: OnceLock<bool> = OnceLock::new();
+
+/// Sets whether `paint` colorizes text. Only the first call has an effect.
+/// Output isn't colorized if this is never called.
+pub fn set_color_choice(choice: ColorChoice) {
+    COLORS_ENABLED.get_or_init(|| match choice {
+        ColorChoice::Auto => {
+            // see https://no-color.org
+            let no_color = std::env::var_os("NO_COLOR").is_some_and(|x| !x.is_empty());
+            !no_color && io::stdout().is_terminal()
+        }
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+    });
+}
+
+/// Colorizes text printed to stdout, if colors are enabled.
+pub fn paint(text: &str, color: Color) -> String {
+    if COLORS_ENABLED.get().copied().unwrap_or(false) {
+        format_colored(text, color)
+    } else {
+        String::from(text)
+    }
+}
+
+fn format_colored(text: &str, color: Color) -> String {
+    format!("\x1b[{}m{}\x1b[0m", color.ansi_code(), text)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Color, ColorChoice, format_colored};
+
+    #[test]
+    pub fn parses_color_choice() {
+        assert_eq!("auto".parse(), Ok(ColorChoice::Auto));
+        assert_eq!("always".parse(), Ok(ColorChoice::Always));
+        assert_eq!("never".parse(), Ok(ColorChoice::Never));
+        assert!("yes".parse::<ColorChoice>().is_err());
+    }
+
+    #[test]
+    pub fn formats_colored_text() {
+        assert_eq!(format_colored("a", Color::Red), "\x1b[31ma\x1b[0m");
+    }
+}
@@ -0,0 +1,83 @@
+/// Checks if a path matches a glob pattern.
+///
+/// - `*` matches any sequence of characters, except `/`
+/// - `**` matches any sequence of characters, including `/`
+/// - `?` matches any single character, except `/`
+///
+/// A leading `./` in the pattern is ignored, since paths in snapshots are
+/// relative to the working directory.
+pub fn glob_matches(pattern: &str, path: &str) -> bool {
+    let pattern = pattern.strip_prefix("./").unwrap_or(pattern);
+    let pattern: Vec<char> = pattern.chars().collect();
+    let path: Vec<char> = path.chars().collect();
+    matches(&pattern, &path)
+}
+
+fn matches(pattern: &[char], path: &[char]) -> bool {
+    match pattern.first() {
+        None => path.is_empty(),
+        Some('*') => {
+            if pattern.get(1) == Some(&'*') {
+                let rest = &pattern[2..];
+
+                // "**/" can also match no directories
+                if rest.first() == Some(&'/') && matches(&rest[1..], path) {
+                    return true;
+                }
+
+                (0..=path.len()).any(|i| matches(rest, &path[i..]))
+            } else {
+                let rest = &pattern[1..];
+
+                for i in 0..=path.len() {
+                    if matches(rest, &path[i..]) {
+                        return true;
+                    }
+                    if i < path.len() && path[i] == '/' {
+                        break;
+                    }
+                }
+
+                false
+            }
+        }
+        Some('?') => !path.is_empty() && path[0] != '/' && matches(&pattern[1..], &path[1..]),
+        Some(ch) => path.first() == Some(ch) && matches(&pattern[1..], &path[1..]),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::glob_matches;
+
+    #[test]
+    pub fn matches_literal_paths() {
+        assert!(glob_matches("a.txt", "a.txt"));
+        assert!(glob_matches("./a.txt", "a.txt"));
+        assert!(glob_matches("dir/a.txt", "dir/a.txt"));
+        assert!(!glob_matches("a.txt", "b.txt"));
+        assert!(!glob_matches("a.txt", "dir/a.txt"));
+        assert!(!glob_matches("dir", "dir/a.txt"));
+    }
+
+    #[test]
+    pub fn matches_wildcards() {
+        assert!(glob_matches("*.txt", "a.txt"));
+        assert!(glob_matches("*", "a.txt"));
+        assert!(!glob_matches("*.txt", "dir/a.txt"));
+        assert!(glob_matches("dir/*.txt", "dir/a.txt"));
+        assert!(glob_matches("?.txt", "a.txt"));
+        assert!(!glob_matches("?.txt", "ab.txt"));
+        assert!(!glob_matches("dir?a.txt", "dir/a.txt"));
+    }
+
+    #[test]
+    pub fn matches_double_star() {
+        assert!(glob_matches("**", "dir/sub/a.txt"));
+        assert!(glob_matches("dir/**", "dir/sub/a.txt"));
+        assert!(glob_matches("**/a.txt", "a.txt"));
+        assert!(glob_matches("**/a.txt", "dir/sub/a.txt"));
+        assert!(glob_matches("dir/**/*.mca", "dir/region/r.0.0.mca"));
+        assert!(!glob_matches("dir/**", "other/a.txt"));
+    }
+}
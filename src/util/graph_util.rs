@@ -0,0 +1,160 @@
+/// A line of an ASCII rendering of a graph (see `render_graph`).
+#[derive(PartialEq, Eq, Debug)]
+pub struct GraphLine {
+    /// the edges (and node) drawn on the line, like `git log --graph`
+    pub graph: String,
+    /// the node drawn on the line, if any. Other lines only connect edges.
+    pub node: Option<usize>,
+}
+
+/// Renders a directed acyclic graph with ASCII edges, one node per line.
+///
+/// Nodes are identified by their index in `children`, and are drawn in
+/// that order, so every node must come before its children. Each node is
+/// drawn as `*` in a column, with `|`, `/` and `\` edges leading down to its
+/// children. Edges are moved one column per line, so a node with several
+/// children forks into several columns, and they join again at a node with
+/// several parents.
+pub fn render_graph(children: &[Vec<usize>]) -> Vec<GraphLine> {
+    let mut lines = Vec::new();
+    // the node each column is leading to
+    let mut columns: Vec<usize> = Vec::new();
+
+    for (node, node_children) in children.iter().enumerate() {
+        let mut node_columns: Vec<usize> =
+            (0..columns.len()).filter(|&i| columns[i] == node).collect();
+        if node_columns.is_empty() {
+            columns.push(node);
+            node_columns.push(columns.len() - 1);
+        }
+        let node_column = node_columns[0];
+
+        // join every edge leading to the node into its first column
+        let mut edges = Vec::new();
+        let mut num_joined = 0;
+        for i in 0..columns.len() {
+            if i != node_column && node_columns.contains(&i) {
+                edges.push((i, node_column));
+                num_joined += 1;
+            } else {
+                edges.push((i, i - num_joined));
+            }
+        }
+        lines.extend(render_edges(edges));
+        for &i in node_columns[1..].iter().rev() {
+            columns.remove(i);
+        }
+
+        let mut graph: Vec<char> = columns
+            .iter()
+            .enumerate()
+            .flat_map(|(i, _)| [if i == node_column { '*' } else { '|' }, ' '])
+            .collect();
+        graph.pop();
+        lines.push(GraphLine {
+            graph: graph.into_iter().collect(),
+            node: Some(node),
+        });
+
+        // fork the node's column into a column for each child
+        let num_children = node_children.len();
+        let mut edges = Vec::new();
+        for i in 0..columns.len() {
+            if i < node_column {
+                edges.push((i, i));
+            } else if i == node_column {
+                for child in 0..num_children {
+                    edges.push((i, i + child));
+                }
+            } else {
+                edges.push((i, i + num_children - 1));
+            }
+        }
+        lines.extend(render_edges(edges));
+        columns.splice(node_column..=node_column, node_children.iter().copied());
+    }
+
+    lines
+}
+
+/// Draws edges moving from one column (the first of each pair) to another
+/// (the second), one column per line. Draws nothing if no edge moves.
+fn render_edges(mut edges: Vec<(usize, usize)>) -> Vec<GraphLine> {
+    let mut lines = Vec::new();
+
+    while edges.iter().any(|(from, to)| from != to) {
+        let mut graph: Vec<char> = Vec::new();
+        let mut draw = |position: usize, c: char| {
+            if graph.len() <= position {
+                graph.resize(position + 1, ' ');
+            }
+            graph[position] = c;
+        };
+
+        for (from, to) in &mut edges {
+            if *from < *to {
+                draw(*from * 2 + 1, '\\');
+                *from += 1;
+            } else if *from > *to {
+                draw(*from * 2 - 1, '/');
+                *from -= 1;
+            } else {
+                draw(*from * 2, '|');
+            }
+        }
+
+        lines.push(GraphLine {
+            graph: graph.into_iter().collect(),
+            node: None,
+        });
+    }
+
+    lines
+}
+
+#[cfg(test)]
+mod test {
+    use super::render_graph;
+
+    fn render(children: &[Vec<usize>]) -> Vec<String> {
+        render_graph(children)
+            .into_iter()
+            .map(|line| match line.node {
+                Some(node) => format!("{} {}", line.graph, node),
+                None => line.graph,
+            })
+            .collect()
+    }
+
+    #[test]
+    pub fn renders_linear_graph() {
+        assert_eq!(render(&[vec![1], vec![2], vec![]]), ["* 0", "* 1", "* 2"]);
+    }
+
+    #[test]
+    pub fn renders_forks_and_merges() {
+        // 0 forks into 1 and 2, which merge into 3
+        assert_eq!(
+            render(&[vec![1, 2], vec![3], vec![3], vec![]]),
+            ["* 0", "|\\", "* | 1", "| * 2", "|/", "* 3"]
+        );
+    }
+
+    #[test]
+    pub fn renders_separate_roots() {
+        // 0 and 1 are both roots, and 2 merges them
+        assert_eq!(
+            render(&[vec![2], vec![2], vec![]]),
+            ["* 0", "| * 1", "|/", "* 2"]
+        );
+    }
+
+    #[test]
+    pub fn moves_columns_after_a_branch_ends() {
+        // 0 forks into 1 and 2, and 1 has no children
+        assert_eq!(
+            render(&[vec![1, 2], vec![], vec![]]),
+            ["* 0", "|\\", "* | 1", " /", "* 2"]
+        );
+    }
+}
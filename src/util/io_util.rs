@@ -1,4 +1,16 @@
-use std::{ffi::OsStr, fmt::Display, process};
+use std::{
+    ffi::OsStr,
+    fmt::Display,
+    fs,
+    io::{self, Seek, SeekFrom, Write},
+    ops::Deref,
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    path::Path,
+    process, thread,
+    time::{Duration, Instant},
+};
+
+use memmap2::Mmap;
 
 /// Converts the error type in a Result into a string.
 pub fn simplify_result<T>(io_result: Result<T, impl Display>) -> Result<T, String> {
@@ -8,6 +20,250 @@ pub fn simplify_result<T>(io_result: Result<T, impl Display>) -> Result<T, Strin
     }
 }
 
+/// Moves a file, falling back to copying it when moving it to another drive.
+pub fn move_file(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), String> {
+    match fs::rename(&from, &to) {
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            simplify_result(fs::copy(&from, &to))?;
+            simplify_result(fs::remove_file(&from))
+        }
+        result => simplify_result(result),
+    }
+}
+
+/// Appends to a file, creating it if it doesn't exist. Each call is one
+/// write, so lines appended by processes running at the same time aren't
+/// interleaved (as long as they're shorter than the OS's atomic write size).
+pub fn append_to_file(path: impl AsRef<Path>, contents: &[u8]) -> Result<(), String> {
+    let mut file = simplify_result(fs::OpenOptions::new().create(true).append(true).open(path))?;
+    simplify_result(file.write_all(contents))
+}
+
+/// Whether a process with the pid is running, so the scratch files named
+/// with its pid (ex. temporary snapshots) may still be in use. A process that
+/// exists but can't be signalled (ex. it's another user's) is running.
+pub fn is_process_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    if pid <= 0 {
+        return false;
+    }
+    // signal 0 only checks that the process exists
+    let result = unsafe { libc::kill(pid, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// How long `LockFile::acquire` waits for another process to release a lock.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A lock file, which only one process can hold at a time, since it's
+/// created with `create_new`. It has the pid of the process holding it, so a
+/// lock left behind by a process that exited is taken over. The lock is
+/// released when this is dropped.
+pub struct LockFile {
+    path: String,
+}
+
+impl LockFile {
+    /// Creates the lock file at `path`, waiting while another process holds
+    /// it.
+    pub fn acquire(path: String) -> Result<LockFile, String> {
+        let start_time = Instant::now();
+        loop {
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(mut file) => {
+                    let lock = LockFile { path };
+                    simplify_result(file.write_all(process::id().to_string().as_bytes()))?;
+                    return Ok(lock);
+                }
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(err) => return Err(format!("Failed to create '{}': {}", path, err)),
+            }
+
+            // the pid is written after the file is created, so an empty lock
+            // is still being taken
+            let holder = fs::read_to_string(&path)
+                .ok()
+                .and_then(|x| x.parse::<u32>().ok());
+            if holder.is_some_and(|pid| !is_process_running(pid)) {
+                eprintln!(
+                    "Warn: Taking over '{}', which was left by a process that exited.",
+                    path
+                );
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+            if start_time.elapsed() > LOCK_TIMEOUT {
+                return Err(format!(
+                    "Timed out waiting for another process to release '{}'",
+                    path
+                ));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Flushes a file to the disk, then the directory it's in, so the file's
+/// contents and its entry in the directory survive a power loss (see
+/// `Repository::should_fsync`).
+pub fn sync_file(path: impl AsRef<Path>) -> Result<(), String> {
+    let path = path.as_ref();
+    simplify_result(fs::File::open(path).and_then(|file| file.sync_all()))?;
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    simplify_result(fs::File::open(dir).and_then(|dir| dir.sync_all()))
+}
+
+/// Runs of zero bytes at least this long are skipped by `write_sparse`. Holes
+/// are allocated in whole filesystem blocks, which are usually 4 KiB.
+const SPARSE_BLOCK_SIZE: usize = 4096;
+
+/// Writes `contents` to a file like `fs::write`, but seeks past blocks of
+/// zero bytes instead of writing them, so they become holes on filesystems
+/// that support sparse files. Other filesystems fill the holes with zeros, so
+/// the file is written densely.
+pub fn write_sparse(path: impl AsRef<Path>, contents: &[u8]) -> Result<(), String> {
+    let mut file = simplify_result(fs::File::create(path))?;
+
+    for block in contents.chunks(SPARSE_BLOCK_SIZE) {
+        if block.iter().all(|x| *x == 0) {
+            simplify_result(file.seek(SeekFrom::Current(block.len() as i64)))?;
+        } else {
+            simplify_result(file.write_all(block))?;
+        }
+    }
+
+    // seeking doesn't extend the file, so trailing holes are added here
+    simplify_result(file.set_len(contents.len() as u64))
+}
+
+/// The contents of a file, either read into memory, or mapped into memory
+/// (see `map_file`).
+pub enum FileContents {
+    Read(Vec<u8>),
+    Mapped(Mmap),
+}
+
+impl Deref for FileContents {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            FileContents::Read(x) => x,
+            FileContents::Mapped(x) => x,
+        }
+    }
+}
+
+/// Maps a file into memory instead of reading it, so a large file isn't
+/// copied into memory at once.
+///
+/// Truncating a mapped file crashes the process reading the map (SIGBUS), so
+/// the file isn't mapped itself. It's cloned as a reflink into a file without
+/// a name in `tmp_dir`, which nothing else can change, and the clone is
+/// mapped. A reflink shares the file's blocks, so it takes no extra IO or
+/// space.
+///
+/// The file is read instead if it isn't `expected_len` bytes long, since it's
+/// being written to, if it's empty, since empty files can't be mapped, or if
+/// it can't be cloned (ex. on ext4 or tmpfs, or if `tmp_dir` is on another
+/// filesystem).
+pub fn map_file(
+    path: impl AsRef<Path>,
+    expected_len: u64,
+    tmp_dir: impl AsRef<Path>,
+) -> io::Result<FileContents> {
+    let file = fs::File::open(&path)?;
+    if expected_len == 0 || file.metadata()?.len() != expected_len {
+        return fs::read(&path).map(FileContents::Read);
+    }
+
+    let Ok(clone) = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .mode(0o600)
+        .custom_flags(libc::O_TMPFILE)
+        .open(tmp_dir)
+    else {
+        return fs::read(&path).map(FileContents::Read);
+    };
+    // SAFETY: both file descriptors are open for as long as the call
+    if unsafe { libc::ioctl(clone.as_raw_fd(), libc::FICLONE, file.as_raw_fd()) } != 0 {
+        return fs::read(&path).map(FileContents::Read);
+    }
+
+    // SAFETY: the map is only read, and the clone has no name, so no other
+    // process can write to or truncate it while it's mapped
+    let map = unsafe { Mmap::map(&clone)? };
+    if map.len() as u64 != expected_len {
+        return fs::read(&path).map(FileContents::Read);
+    }
+    Ok(FileContents::Mapped(map))
+}
+
+/// How often to retry IO that fails with a transient error (ex. on a flaky
+/// network mount), and how long to wait between the attempts.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RetryPolicy {
+    /// number of times to try, including the first
+    pub attempts: u32,
+    /// wait before the first retry, doubled for each retry after it
+    pub initial_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Runs `io_fn`, running it again if it fails with a transient error, until it
+/// succeeds or `policy.attempts` attempts fail. Other errors (ex. `NotFound`,
+/// `PermissionDenied`) are returned right away.
+pub fn retry_transient<T>(
+    policy: RetryPolicy,
+    mut io_fn: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    let mut backoff = policy.initial_backoff;
+    let mut attempt = 1;
+
+    loop {
+        match io_fn() {
+            Err(err) if attempt < policy.attempts && is_transient(&err) => {
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
 pub fn run_command_handle_failures(
     command: &mut process::Command,
 ) -> Result<process::Output, String> {
@@ -23,17 +279,84 @@ pub fn run_command_handle_failures(
         Ok(x) => x,
     };
 
+    handle_command_failure(command, output)
+}
+
+/// Like `run_command_handle_failures`, but `input` is written to the
+/// command's stdin. It's written from another thread, so a command that
+/// writes its output before it's read all of its input doesn't deadlock.
+pub fn run_command_with_input_handle_failures(
+    command: &mut process::Command,
+    input: &[u8],
+) -> Result<process::Output, String> {
+    let child = command
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn();
+    let mut child = match child {
+        Err(err) => {
+            return Err(format!(
+                "Failed to start command: {}: {}",
+                format_command_debug(command),
+                err
+            ));
+        }
+        Ok(x) => x,
+    };
+
+    let mut stdin = child.stdin.take().expect("Stdin should be piped");
+    let output = thread::scope(|scope| {
+        // the command may exit without reading all of its input, which is
+        // reported by its exit status instead of the broken pipe
+        scope.spawn(move || stdin.write_all(input));
+        child.wait_with_output()
+    });
+
+    match output {
+        Ok(output) => handle_command_failure(command, output),
+        Err(err) => Err(format!(
+            "Failed to run command: {}: {}",
+            format_command_debug(command),
+            err
+        )),
+    }
+}
+
+/// Prints the output of a command that failed, and returns the error.
+fn handle_command_failure(
+    command: &process::Command,
+    output: process::Output,
+) -> Result<process::Output, String> {
     if output.status.success() {
         Ok(output)
     } else {
-        let stdout_str = simplify_result(String::from_utf8(output.stdout))?;
-        let stderr_str = simplify_result(String::from_utf8(output.stderr))?;
+        // the output may not be text (ex. transformed files)
+        let stdout_str = String::from_utf8_lossy(&output.stdout);
+        let stderr_str = String::from_utf8_lossy(&output.stderr);
         eprintln!("Stdout from {:?}:\n{}", command.get_program(), stdout_str);
         eprintln!("Stderr from {:?}:\n{}", command.get_program(), stderr_str);
         Err(format!("Command failed: {}", format_command_debug(command)))
     }
 }
 
+/// Opens a file in an editor, and waits for it to be closed. The editor is run
+/// by the shell, so it can have arguments (ex. `EDITOR="code --wait"`), like
+/// git runs it.
+pub fn run_editor(editor: &str, path: &str) -> Result<(), String> {
+    let status = process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", editor))
+        .arg(editor)
+        .arg(path)
+        .status();
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("The editor '{}' failed ({})", editor, status)),
+        Err(err) => Err(format!("Failed to start the editor '{}': {}", editor, err)),
+    }
+}
+
 pub fn format_command_debug(command: &process::Command) -> String {
     format!(
         "{:?}, arguments: {:?}",
@@ -41,3 +364,158 @@ pub fn format_command_debug(command: &process::Command) -> String {
         command.get_args().collect::<Vec<&OsStr>>()
     )
 }
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, io, process, time::Duration};
+
+    use super::{
+        FileContents, LockFile, RetryPolicy, is_process_running, map_file, retry_transient,
+        run_editor, sync_file, write_sparse,
+    };
+
+    const NO_BACKOFF: RetryPolicy = RetryPolicy {
+        attempts: 3,
+        initial_backoff: Duration::ZERO,
+    };
+
+    /// Runs `retry_transient` on a function that fails with `errors` in order,
+    /// then succeeds. Returns the result and the number of calls.
+    fn run_failing(errors: &[io::ErrorKind]) -> (io::Result<()>, usize) {
+        let mut calls = 0;
+        let result = retry_transient(NO_BACKOFF, || {
+            calls += 1;
+            match errors.get(calls - 1) {
+                Some(kind) => Err(io::Error::from(*kind)),
+                None => Ok(()),
+            }
+        });
+        (result, calls)
+    }
+
+    #[test]
+    pub fn retries_transient_errors() {
+        let (result, calls) = run_failing(&[io::ErrorKind::Interrupted, io::ErrorKind::TimedOut]);
+        assert!(result.is_ok());
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    pub fn gives_up_after_max_attempts() {
+        let (result, calls) = run_failing(&[io::ErrorKind::WouldBlock; 5]);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    pub fn does_not_retry_permanent_errors() {
+        let (result, calls) = run_failing(&[io::ErrorKind::NotFound]);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    pub fn checks_if_processes_are_running() {
+        assert!(is_process_running(process::id()));
+
+        let mut child = process::Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        assert!(!is_process_running(pid));
+        assert!(!is_process_running(0));
+    }
+
+    #[test]
+    pub fn holds_lock_files_one_at_a_time() {
+        let path = env::temp_dir().join(format!("jbackup-lock-test-{}", process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let _ = fs::remove_file(&path);
+
+        let lock = LockFile::acquire(path.clone()).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            process::id().to_string()
+        );
+        let waiter = {
+            let path = path.clone();
+            std::thread::spawn(move || LockFile::acquire(path).map(|_| ()))
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!waiter.is_finished());
+        drop(lock);
+        waiter.join().unwrap().unwrap();
+        assert!(!fs::exists(&path).unwrap());
+
+        // a lock left by a process that exited is taken over
+        let mut child = process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        fs::write(&path, child.id().to_string()).unwrap();
+        drop(LockFile::acquire(path.clone()).unwrap());
+        assert!(!fs::exists(&path).unwrap());
+    }
+
+    #[test]
+    pub fn writes_sparse_files() {
+        let path = env::temp_dir().join(format!("jbackup-sparse-test-{}", process::id()));
+        let mut contents = vec![0u8; 5 * 4096 + 10];
+        contents[4096 + 7] = 1;
+        contents[3 * 4096] = 2;
+
+        let mut written = Vec::new();
+        for contents in [&contents[..], &contents[..4096 + 8], &[], &[0; 100]] {
+            write_sparse(&path, contents).unwrap();
+            written.push(fs::read(&path).unwrap() == contents);
+        }
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(written, [true; 4]);
+    }
+
+    #[test]
+    pub fn syncs_files() {
+        let path = env::temp_dir().join(format!("jbackup-sync-test-{}", process::id()));
+        fs::write(&path, b"synced").unwrap();
+        let synced = sync_file(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(synced, Ok(()));
+        assert!(sync_file(&path).is_err());
+    }
+
+    #[test]
+    pub fn maps_files_of_the_expected_size() {
+        let path = env::temp_dir().join(format!("jbackup-map-test-{}", process::id()));
+        fs::write(&path, b"mapped contents").unwrap();
+
+        // the file is only mapped where the temp directory supports reflinks
+        let mapped = map_file(&path, 15, env::temp_dir()).unwrap();
+        assert_eq!(&*mapped, b"mapped contents");
+
+        // the file changed size since it was listed, so it's read instead
+        let read = map_file(&path, 10, env::temp_dir()).unwrap();
+        assert!(matches!(read, FileContents::Read(_)));
+        assert_eq!(&*read, b"mapped contents");
+
+        // truncating the file doesn't change what was mapped or read, or crash
+        fs::write(&path, b"").unwrap();
+        assert_eq!(&*mapped, b"mapped contents");
+
+        assert!(matches!(
+            map_file(&path, 0, env::temp_dir()).unwrap(),
+            FileContents::Read(_)
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    pub fn runs_editors_with_arguments() {
+        let path = env::temp_dir().join(format!("jbackup-editor-test-{}", process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, b"template").unwrap();
+
+        run_editor("printf 'edited' >", path).unwrap();
+        assert_eq!(fs::read(path).unwrap(), b"edited");
+        assert!(run_editor("false", path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,8 +1,8 @@
 use std::{
     collections::VecDeque,
+    panic::{self, AssertUnwindSafe},
     sync::mpsc,
     thread::{self, JoinHandle},
-    usize,
 };
 
 /// The multithreaded pipeline takes a serial list of inputs, distributes
@@ -13,13 +13,11 @@ pub struct MultithreadPipeline<I: Sync + Send, O: Sync + Send, C> {
     // keeps track to ensure completion of work before terminating
     number_outputs_read: usize,
     output_context: C,
-    output_handler: Box<dyn FnMut(&mut C, O)>,
+    output_handler: OutputHandler<C, O>,
     output: OutputBuffer<O>,
-    // Tuples: Output, input index, thread index
-    output_channel: (
-        mpsc::Sender<(O, usize, usize)>,
-        mpsc::Receiver<(O, usize, usize)>,
-    ),
+    /// First error that happened in the pipeline, returned by `finalize`
+    error: Option<String>,
+    output_channel: (mpsc::Sender<OutputTuple<O>>, mpsc::Receiver<OutputTuple<O>>),
     threads: Vec<ThreadState<I>>,
 }
 
@@ -32,16 +30,30 @@ struct ThreadState<I: Sync + Send> {
 struct OutputBuffer<O> {
     offset: usize,
     /// Buffer with the 0th item being the next item to return in the pipeline.
-    buffer: VecDeque<Option<O>>,
+    buffer: VecDeque<OutputSlot<O>>,
 }
 
+enum OutputSlot<O> {
+    Pending,
+    Done(O),
+    /// The worker panicked, so there's no output to handle
+    Failed,
+}
+
+/// Tuples: Output (`Err` if the worker panicked), input index, thread index
+type OutputTuple<O> = (Result<O, String>, usize, usize);
+
+/// Called on each output, in the same order as the inputs. An error is
+/// returned by `finalize`, like a worker panicking.
+type OutputHandler<C, O> = Box<dyn FnMut(&mut C, O) -> Result<(), String>>;
+
 enum DataOrCommand<I> {
     Data(I),
     Terminate,
 }
 
 impl<I: Sync + Send + 'static, O: Sync + Send + 'static, C> MultithreadPipeline<I, O, C> {
-    pub fn new(output_context: C, output_handler: Box<dyn FnMut(&mut C, O)>) -> Self {
+    pub fn new(output_context: C, output_handler: OutputHandler<C, O>) -> Self {
         Self {
             next_input_index: 0,
             number_outputs_read: 0,
@@ -50,13 +62,16 @@ impl<I: Sync + Send + 'static, O: Sync + Send + 'static, C> MultithreadPipeline<
                 offset: 0,
                 buffer: VecDeque::new(),
             },
+            error: None,
             threads: Vec::new(),
             output_context,
             output_handler,
         }
     }
 
-    /// Writes an input to the pipeline. Will wait until the next input is writeable.
+    /// Writes an input to the pipeline. If all workers are busy, the thread
+    /// blocks on the output channel until a worker finishes, so waiting for a
+    /// free worker doesn't spin.
     /// This method should only be called by one thread.
     pub fn write(&mut self, input: I) {
         let index = self.next_input_index;
@@ -92,8 +107,10 @@ impl<I: Sync + Send + 'static, O: Sync + Send + 'static, C> MultithreadPipeline<
         self.flush_buffer();
     }
 
-    /// Keeps polling until the last output has been handled. Will busy-wait.
-    pub fn finalize(mut self) -> C {
+    /// Blocks until the last output has been handled. Returns an error if a
+    /// worker panicked or an output couldn't be put back in order, in which
+    /// case some outputs were never handled.
+    pub fn finalize(mut self) -> Result<C, String> {
         let number_inputs = self.next_input_index;
 
         for thread in &self.threads {
@@ -111,7 +128,10 @@ impl<I: Sync + Send + 'static, O: Sync + Send + 'static, C> MultithreadPipeline<
             thread.join_handle.join().unwrap();
         }
 
-        return self.output_context;
+        match self.error {
+            Some(err) => Err(err),
+            None => Ok(self.output_context),
+        }
     }
 
     pub fn spawn_workers<Init: Send + Clone + 'static>(
@@ -133,11 +153,16 @@ impl<I: Sync + Send + 'static, O: Sync + Send + 'static, C> MultithreadPipeline<
 
                     match next_input {
                         (DataOrCommand::Data(input_data), input_index) => {
-                            if let Err(err) = output_tx.send((
-                                process_fn(&thread_init, input_data),
-                                input_index,
-                                thread_index,
-                            )) {
+                            // a panicking worker would otherwise never send an
+                            // output, leaving the pipeline waiting forever
+                            let output = panic::catch_unwind(AssertUnwindSafe(|| {
+                                process_fn(&thread_init, input_data)
+                            }))
+                            .map_err(|_| {
+                                format!("Worker panicked while processing input {}", input_index)
+                            });
+
+                            if let Err(err) = output_tx.send((output, input_index, thread_index)) {
                                 panic!("{}", err);
                             }
                         }
@@ -172,35 +197,212 @@ impl<I: Sync + Send + 'static, O: Sync + Send + 'static, C> MultithreadPipeline<
 
     fn flush_buffer(&mut self) {
         while let Some(res) = self.try_read_from_buffer() {
-            (self.output_handler)(&mut self.output_context, res);
+            if let Err(err) = (self.output_handler)(&mut self.output_context, res) {
+                self.set_error(err);
+            }
         }
     }
 
     fn try_read_from_buffer(&mut self) -> Option<O> {
-        if self.output.buffer.is_empty() {
-            return None;
-        }
-        let next_item = self.output.buffer.get(0)?;
-        if next_item.is_none() {
-            return None;
-        }
+        loop {
+            if let OutputSlot::Pending = self.output.buffer.front()? {
+                return None;
+            }
 
-        let next_item = self.output.buffer.pop_front()?;
-        self.output.offset += 1;
-        self.number_outputs_read += 1;
-        return next_item;
+            let next_item = self.output.buffer.pop_front()?;
+            self.output.offset += 1;
+            self.number_outputs_read += 1;
+
+            // failed outputs are skipped, the error is returned by `finalize`
+            if let OutputSlot::Done(output) = next_item {
+                return Some(output);
+            }
+        }
     }
 
-    fn process_output_tuple(
-        &mut self,
-        (output_data, input_index, thread_index): (O, usize, usize),
-    ) {
+    fn process_output_tuple(&mut self, (output_data, input_index, thread_index): OutputTuple<O>) {
         self.threads[thread_index].is_working = false;
 
-        let output_index = input_index - self.output.offset;
+        // An output for an input that was already handled. Shouldn't happen,
+        // but putting it in the buffer would misorder every output after it.
+        let Some(output_index) = input_index.checked_sub(self.output.offset) else {
+            debug_assert!(
+                false,
+                "Output for input {} was already handled",
+                input_index
+            );
+            self.set_error(format!(
+                "Output for input {} was already handled",
+                input_index
+            ));
+            return;
+        };
+
         while self.output.buffer.len() <= output_index {
-            self.output.buffer.push_back(None);
+            self.output.buffer.push_back(OutputSlot::Pending);
+        }
+
+        if !matches!(self.output.buffer[output_index], OutputSlot::Pending) {
+            debug_assert!(
+                false,
+                "Received more than one output for input {}",
+                input_index
+            );
+            self.set_error(format!(
+                "Received more than one output for input {}",
+                input_index
+            ));
+            return;
+        }
+
+        self.output.buffer[output_index] = match output_data {
+            Ok(output) => OutputSlot::Done(output),
+            Err(err) => {
+                self.set_error(err);
+                OutputSlot::Failed
+            }
+        };
+    }
+
+    /// Keeps the first error, since later errors may be caused by it.
+    fn set_error(&mut self, err: String) {
+        if self.error.is_none() {
+            self.error = Some(err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MultithreadPipeline;
+
+    fn run_pipeline(num_workers: usize, input_sizes: &[usize]) -> Vec<usize> {
+        let mut pipeline = MultithreadPipeline::<Vec<u8>, usize, Vec<usize>>::new(
+            Vec::new(),
+            Box::new(|outputs, output| {
+                outputs.push(output);
+                Ok(())
+            }),
+        );
+        pipeline.spawn_workers(num_workers, (), |_, input: Vec<u8>| {
+            // some work proportional to the input size
+            std::hint::black_box(
+                input
+                    .iter()
+                    .fold(0usize, |acc, x| acc.wrapping_mul(31) ^ *x as usize),
+            );
+            input.len()
+        });
+
+        for size in input_sizes {
+            pipeline.write(vec![1; *size]);
+            pipeline.poll();
+        }
+        pipeline.finalize().unwrap()
+    }
+
+    #[test]
+    pub fn outputs_are_in_input_order() {
+        let input_sizes: Vec<usize> = (0..200).map(|i| (i * 7919) % 5000).collect();
+        for num_workers in [1, 3, 8] {
+            assert_eq!(run_pipeline(num_workers, &input_sizes), input_sizes);
+        }
+    }
+
+    #[test]
+    pub fn worker_panic_is_returned_as_error() {
+        let mut pipeline = MultithreadPipeline::<usize, usize, Vec<usize>>::new(
+            Vec::new(),
+            Box::new(|outputs, output| {
+                outputs.push(output);
+                Ok(())
+            }),
+        );
+        pipeline.spawn_workers(4, (), |_, input| {
+            if input == 13 {
+                panic!("misbehaving worker");
+            }
+            input
+        });
+
+        for input in 0..50 {
+            pipeline.write(input);
+            pipeline.poll();
+        }
+
+        let err = pipeline.finalize().err().unwrap();
+        assert_eq!(err, "Worker panicked while processing input 13");
+    }
+
+    #[test]
+    pub fn output_handler_error_is_returned() {
+        let mut pipeline = MultithreadPipeline::<usize, usize, Vec<usize>>::new(
+            Vec::new(),
+            Box::new(|outputs, output| {
+                if output % 10 == 7 {
+                    return Err(format!("Failed to store {}", output));
+                }
+                outputs.push(output);
+                Ok(())
+            }),
+        );
+        pipeline.spawn_workers(4, (), |_, input| input);
+
+        for input in 0..50 {
+            pipeline.write(input);
+            pipeline.poll();
+        }
+
+        let err = pipeline.finalize().err().unwrap();
+        assert_eq!(err, "Failed to store 7");
+    }
+
+    #[test]
+    pub fn outputs_after_a_panic_stay_in_order() {
+        let mut pipeline = MultithreadPipeline::<usize, usize, Vec<usize>>::new(
+            Vec::new(),
+            Box::new(|outputs, output| {
+                outputs.push(output);
+                Ok(())
+            }),
+        );
+        pipeline.spawn_workers(4, (), |_, input| {
+            if input == 13 {
+                panic!("misbehaving worker");
+            }
+            input
+        });
+
+        for input in 0..50 {
+            pipeline.write(input);
+        }
+        while pipeline.number_outputs_read < 50 {
+            pipeline.poll_blocking();
         }
-        self.output.buffer[output_index].replace(output_data);
+
+        let expected: Vec<usize> = (0..50).filter(|x| *x != 13).collect();
+        assert_eq!(pipeline.output_context, expected);
+    }
+
+    #[test]
+    #[cfg_attr(debug_assertions, should_panic(expected = "already handled"))]
+    pub fn rejects_output_for_handled_input() {
+        let mut pipeline = MultithreadPipeline::<usize, usize, Vec<usize>>::new(
+            Vec::new(),
+            Box::new(|outputs, output| {
+                outputs.push(output);
+                Ok(())
+            }),
+        );
+        pipeline.spawn_workers(1, (), |_, input| input);
+        pipeline.write(7);
+        pipeline.poll_blocking();
+        assert_eq!(pipeline.output_context, [7]);
+
+        // a second output for input 0, which would underflow the buffer index
+        pipeline.process_output_tuple((Ok(8), 0, 0));
+
+        let err = pipeline.finalize().err().unwrap();
+        assert_eq!(err, "Output for input 0 was already handled");
     }
 }
@@ -0,0 +1,123 @@
+use std::sync::OnceLock;
+
+use crate::util::json_util::json_string;
+
+/// How progress of long operations (snapshot, restore) is reported on
+/// stderr.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProgressFormat {
+    /// messages for people, which leave out per-file progress
+    Text,
+    /// one JSON object per line, for programs built on top of jbackup
+    Json,
+}
+
+/// An event reported while an operation is in progress.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProgressEvent<'a> {
+    /// a file was added to a snapshot, or restored from one. `total` is
+    /// `None` when the number of files isn't known ahead of time.
+    File {
+        path: &'a str,
+        done: u64,
+        total: Option<u64>,
+    },
+    /// a snapshot was reconstructed from its diff, while restoring a snapshot
+    /// through a chain of diffs
+    Snapshot { id: &'a str, done: u64, total: u64 },
+}
+
+impl ProgressEvent<'_> {
+    fn to_json(self) -> String {
+        let format_total = |total: Option<u64>| match total {
+            Some(x) => x.to_string(),
+            None => String::from("null"),
+        };
+
+        match self {
+            ProgressEvent::File { path, done, total } => format!(
+                "{{\"type\":\"file\",\"path\":{},\"done\":{},\"total\":{}}}",
+                json_string(path),
+                done,
+                format_total(total)
+            ),
+            ProgressEvent::Snapshot { id, done, total } => format!(
+                "{{\"type\":\"snapshot\",\"id\":{},\"done\":{},\"total\":{}}}",
+                json_string(id),
+                done,
+                total
+            ),
+        }
+    }
+
+    fn to_text(self) -> Option<String> {
+        match self {
+            ProgressEvent::File { .. } => None,
+            ProgressEvent::Snapshot { id, done, total } => {
+                Some(format!("Restored snapshot {} ({}/{})", id, done, total))
+            }
+        }
+    }
+}
+
+static PROGRESS_FORMAT: OnceLock<ProgressFormat> = OnceLock::new();
+
+/// Sets how progress is reported. Only the first call has an effect.
+/// Progress is reported as text if this is never called.
+pub fn set_progress_format(format: ProgressFormat) {
+    PROGRESS_FORMAT.get_or_init(|| format);
+}
+
+fn progress_format() -> ProgressFormat {
+    PROGRESS_FORMAT
+        .get()
+        .copied()
+        .unwrap_or(ProgressFormat::Text)
+}
+
+/// Reports progress on stderr, so it doesn't mix with output on stdout.
+pub fn report(event: ProgressEvent) {
+    let line = match progress_format() {
+        ProgressFormat::Text => event.to_text(),
+        ProgressFormat::Json => Some(event.to_json()),
+    };
+    if let Some(line) = line {
+        eprintln!("{}", line);
+    }
+}
+
+/// Reports a progress message that's only meant for people, so it's left out
+/// of JSON progress.
+pub fn report_text(message: &str) {
+    if progress_format() == ProgressFormat::Text {
+        eprintln!("{}", message);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ProgressEvent;
+
+    #[test]
+    pub fn formats_events_as_json() {
+        let event = ProgressEvent::File {
+            path: "a/\"b\".txt",
+            done: 1,
+            total: None,
+        };
+        assert_eq!(
+            event.to_json(),
+            "{\"type\":\"file\",\"path\":\"a/\\\"b\\\".txt\",\"done\":1,\"total\":null}"
+        );
+
+        let event = ProgressEvent::Snapshot {
+            id: "1-abc",
+            done: 2,
+            total: 3,
+        };
+        assert_eq!(
+            event.to_json(),
+            "{\"type\":\"snapshot\",\"id\":\"1-abc\",\"done\":2,\"total\":3}"
+        );
+    }
+}
@@ -0,0 +1,78 @@
+/// Parses a size in bytes, such as `1048576`, `512K`, `100M` or `50G`.
+///
+/// Suffixes are powers of 1024 and are case-insensitive. A trailing `B` is
+/// allowed (ex. `100MB`).
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let trimmed = s.trim();
+    let upper = trimmed.to_ascii_uppercase();
+    let without_b = match upper.strip_suffix('B') {
+        Some(x) if !x.is_empty() => x,
+        _ => &upper,
+    };
+
+    let (digits, multiplier): (&str, u64) = match without_b.chars().last() {
+        Some('K') => (&without_b[..without_b.len() - 1], 1 << 10),
+        Some('M') => (&without_b[..without_b.len() - 1], 1 << 20),
+        Some('G') => (&without_b[..without_b.len() - 1], 1 << 30),
+        Some('T') => (&without_b[..without_b.len() - 1], 1 << 40),
+        _ => (without_b, 1),
+    };
+
+    digits
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|x| x.checked_mul(multiplier))
+        .ok_or_else(|| format!("Invalid size: '{}'", trimmed))
+}
+
+/// Formats a size in bytes for humans, such as `512 B`, `1.5 KiB` or
+/// `20.0 MiB`.
+pub fn format_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if size < 1024 {
+        return format!("{} B", size);
+    }
+
+    let mut value = size as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod test {
+    use super::{format_size, parse_size};
+
+    #[test]
+    pub fn parses_sizes() {
+        assert_eq!(parse_size("1048576"), Ok(1048576));
+        assert_eq!(parse_size("512K"), Ok(512 * 1024));
+        assert_eq!(parse_size("100mb"), Ok(100 * 1024 * 1024));
+        assert_eq!(parse_size("50G"), Ok(50 * 1024 * 1024 * 1024));
+        assert_eq!(parse_size("7B"), Ok(7));
+    }
+
+    #[test]
+    pub fn rejects_invalid_sizes() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("B").is_err());
+        assert!(parse_size("-1").is_err());
+        assert!(parse_size("12Q").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+
+    #[test]
+    pub fn formats_sizes() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(20 * 1024 * 1024), "20.0 MiB");
+        assert_eq!(format_size(3 << 40), "3.0 TiB");
+    }
+}
@@ -0,0 +1,48 @@
+/// Parses a duration in seconds, such as `30s`, `15m`, `12h`, `90d` or `2w`.
+///
+/// A unit is required, since a bare number could mean seconds or days.
+pub fn parse_duration(s: &str) -> Result<i64, String> {
+    let trimmed = s.trim();
+    let lower = trimmed.to_ascii_lowercase();
+
+    let (digits, multiplier): (&str, i64) = match lower.chars().last() {
+        Some('s') => (&lower[..lower.len() - 1], 1),
+        Some('m') => (&lower[..lower.len() - 1], 60),
+        Some('h') => (&lower[..lower.len() - 1], 60 * 60),
+        Some('d') => (&lower[..lower.len() - 1], 24 * 60 * 60),
+        Some('w') => (&lower[..lower.len() - 1], 7 * 24 * 60 * 60),
+        _ => {
+            return Err(format!(
+                "Invalid duration: '{}' (expected a number with a unit: s, m, h, d or w)",
+                trimmed
+            ));
+        }
+    };
+
+    digits
+        .trim()
+        .parse::<u32>()
+        .ok()
+        .and_then(|x| i64::from(x).checked_mul(multiplier))
+        .ok_or_else(|| format!("Invalid duration: '{}'", trimmed))
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_duration;
+
+    #[test]
+    pub fn parses_durations() {
+        assert_eq!(parse_duration("30s"), Ok(30));
+        assert_eq!(parse_duration("15m"), Ok(15 * 60));
+        assert_eq!(parse_duration("12H"), Ok(12 * 60 * 60));
+        assert_eq!(parse_duration("90d"), Ok(90 * 24 * 60 * 60));
+        assert_eq!(parse_duration("2w"), Ok(14 * 24 * 60 * 60));
+
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("90").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("-1d").is_err());
+        assert!(parse_duration("3y").is_err());
+    }
+}
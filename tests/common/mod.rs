@@ -0,0 +1,148 @@
+// each integration test uses a different subset of these helpers
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// A temporary working directory to run `jbackup` in. Removed when dropped.
+pub struct TestDir {
+    pub path: PathBuf,
+}
+
+impl TestDir {
+    pub fn new(name: &str) -> TestDir {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "jbackup-test-{}-{}-{}",
+            name,
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        if path.exists() {
+            fs::remove_dir_all(&path).unwrap();
+        }
+        fs::create_dir_all(&path).unwrap();
+
+        TestDir { path }
+    }
+
+    /// Runs `jbackup` with the arguments in this directory.
+    pub fn run(&self, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_jbackup"))
+            .args(args)
+            .current_dir(&self.path)
+            .output()
+            .unwrap()
+    }
+
+    /// Runs `jbackup` with the arguments and extra environment variables in
+    /// this directory.
+    pub fn run_with_env(&self, envs: &[(&str, &Path)], args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_jbackup"))
+            .args(args)
+            .envs(envs.iter().copied())
+            .current_dir(&self.path)
+            .output()
+            .unwrap()
+    }
+
+    /// Runs `jbackup` with the arguments in this directory, writing `stdin`
+    /// to its stdin.
+    pub fn run_with_stdin(&self, stdin: &[u8], args: &[&str]) -> Output {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_jbackup"))
+            .args(args)
+            .current_dir(&self.path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(stdin).unwrap();
+        child.wait_with_output().unwrap()
+    }
+
+    /// Runs `jbackup` and panics if it fails. Returns stdout.
+    pub fn run_ok(&self, args: &[&str]) -> String {
+        let output = self.run(args);
+        assert!(
+            output.status.success(),
+            "jbackup {:?} failed:\n{}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    /// Like `run_ok`, but returns stdout as bytes, for binary output.
+    pub fn run_ok_bytes(&self, args: &[&str]) -> Vec<u8> {
+        let output = self.run(args);
+        assert!(
+            output.status.success(),
+            "jbackup {:?} failed:\n{}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        output.stdout
+    }
+
+    pub fn write_file(&self, path: &str, contents: &[u8]) {
+        let path = self.path.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    pub fn read_file(&self, path: &str) -> Vec<u8> {
+        fs::read(self.path.join(path)).unwrap()
+    }
+
+    pub fn remove_file(&self, path: &str) {
+        fs::remove_file(self.path.join(path)).unwrap();
+    }
+
+    /// Returns the id of the snapshot `HEAD` points to.
+    pub fn head_id(&self) -> String {
+        self.run_ok(&["rev-parse", "HEAD"]).trim().to_string()
+    }
+
+    /// Lists all files and directories in the working directory, excluding
+    /// `.jbackup`, as sorted paths relative to the working directory.
+    pub fn list_entries(&self) -> Vec<String> {
+        let mut entries = Vec::new();
+        list_entries_in(&self.path, &self.path, &mut entries);
+        entries.sort();
+        entries
+    }
+}
+
+fn list_entries_in(root: &Path, dir: &Path, entries: &mut Vec<String>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        let relative_path = path
+            .strip_prefix(root)
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        if relative_path == ".jbackup" {
+            continue;
+        }
+
+        if path.is_dir() {
+            entries.push(relative_path + "/");
+            list_entries_in(root, &path, entries);
+        } else {
+            entries.push(relative_path);
+        }
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
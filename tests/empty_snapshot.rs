@@ -0,0 +1,64 @@
+mod common;
+
+use common::TestDir;
+
+#[test]
+pub fn snapshots_and_restores_empty_directory() {
+    let dir = TestDir::new("empty");
+    dir.run_ok(&["init"]);
+
+    let output = dir.run_ok(&["snapshot", "-m", "empty"]);
+    assert!(output.contains("Snapshot contains 0 file(s)"));
+
+    let log = dir.run_ok(&["log"]);
+    assert!(log.contains("Message:   empty"));
+
+    let output = dir.run_ok(&["restore", "HEAD"]);
+    assert!(output.contains("Restored 0 file(s)"));
+    assert!(dir.list_entries().is_empty());
+}
+
+#[test]
+pub fn restoring_empty_snapshot_removes_files_and_directories() {
+    let dir = TestDir::new("empty-restore");
+    dir.run_ok(&["init"]);
+    dir.run_ok(&["snapshot", "-m", "empty"]);
+
+    dir.write_file("a.txt", b"a");
+    dir.write_file("nested/directory/b.txt", b"b");
+    dir.run_ok(&["snapshot", "-m", "files"]);
+
+    dir.run_ok(&["restore", "HEAD~1"]);
+    assert!(dir.list_entries().is_empty());
+
+    dir.run_ok(&["restore", "HEAD"]);
+    assert_eq!(
+        dir.list_entries(),
+        [
+            "a.txt",
+            "nested/",
+            "nested/directory/",
+            "nested/directory/b.txt"
+        ]
+    );
+}
+
+#[test]
+pub fn skips_snapshot_without_changes() {
+    let dir = TestDir::new("no-changes");
+    dir.run_ok(&["init"]);
+    dir.write_file("a.txt", b"a");
+    dir.run_ok(&["snapshot", "-m", "first"]);
+    let head = dir.head_id();
+
+    let output = dir.run_ok(&["snapshot", "-m", "unchanged"]);
+    assert_eq!(
+        output.lines().last().unwrap(),
+        format!(
+            "No changes since snapshot {}, so no snapshot was created.",
+            head
+        )
+    );
+    assert_eq!(dir.head_id(), head);
+    assert!(!dir.run_ok(&["log"]).contains("unchanged"));
+}
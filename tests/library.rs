@@ -0,0 +1,97 @@
+mod common;
+
+use common::TestDir;
+use jbackup::api::{self, InitOptions, RestoreOptions, SnapshotOptions, SnapshotOutcome};
+
+#[test]
+pub fn snapshots_and_restores_through_library() {
+    let dir = TestDir::new("library");
+    let other_dir = TestDir::new("library-other");
+    api::init(&dir.path, InitOptions::default()).unwrap();
+
+    dir.write_file("a.txt", b"first");
+    let SnapshotOutcome::Created(first) = api::snapshot(
+        &dir.path,
+        SnapshotOptions {
+            message: Some(String::from("first")),
+            tag: Some(String::from("v1")),
+            ..Default::default()
+        },
+    )
+    .unwrap() else {
+        panic!("snapshot wasn't created");
+    };
+    assert_eq!(first.message.as_deref(), Some("first"));
+    assert!(first.parents.is_empty());
+
+    dir.write_file("a.txt", b"second");
+    dir.write_file("b.txt", b"added");
+    let SnapshotOutcome::Created(second) =
+        api::snapshot(&dir.path, SnapshotOptions::default()).unwrap()
+    else {
+        panic!("snapshot wasn't created");
+    };
+    assert_eq!(second.parents, [first.id.as_str()]);
+    assert_eq!(
+        api::snapshot(&dir.path, SnapshotOptions::default()).unwrap(),
+        SnapshotOutcome::NoChanges(second.clone())
+    );
+
+    // snapshots made within the same second may be listed in any order
+    let log = api::log(&dir.path).unwrap();
+    assert_eq!(log.len(), 2);
+    let first_in_log = log.iter().find(|x| x.id == first.id).unwrap();
+    assert_eq!(first_in_log.children, [second.id.as_str()]);
+
+    api::restore(&dir.path, "v1", RestoreOptions::default()).unwrap();
+    assert_eq!(dir.list_entries(), ["a.txt"]);
+    assert_eq!(dir.read_file("a.txt"), b"first");
+
+    // each function is given the repository it uses, so a process can use
+    // several repositories
+    api::init(&other_dir.path, InitOptions::default()).unwrap();
+    other_dir.write_file("c.txt", b"other");
+    let SnapshotOutcome::Created(other) =
+        api::snapshot(&other_dir.path, SnapshotOptions::default()).unwrap()
+    else {
+        panic!("snapshot wasn't created");
+    };
+    assert_eq!(api::log(&other_dir.path).unwrap(), [other]);
+    assert_eq!(api::log(&dir.path).unwrap().len(), 2);
+
+    other_dir.write_file("c.txt", b"changed");
+    api::restore(&other_dir.path, "HEAD", RestoreOptions::default()).unwrap();
+    assert_eq!(other_dir.read_file("c.txt"), b"other");
+    assert_eq!(dir.list_entries(), ["a.txt"]);
+}
+
+#[test]
+pub fn dates_snapshots_through_library() {
+    let dir = TestDir::new("library-date");
+    api::init(&dir.path, InitOptions::default()).unwrap();
+    let snapshot_at = |date: i64| {
+        let options = SnapshotOptions {
+            date: Some(date),
+            ..Default::default()
+        };
+        match api::snapshot(&dir.path, options).unwrap() {
+            SnapshotOutcome::Created(created) => created,
+            SnapshotOutcome::NoChanges(_) => panic!("snapshot wasn't created"),
+        }
+    };
+
+    dir.write_file("a.txt", b"a");
+    let first = snapshot_at(1700000000);
+    assert!(first.id.starts_with("1700000000-"));
+    assert_eq!(first.date, 1700000000);
+
+    dir.write_file("a.txt", b"b");
+    let second = snapshot_at(1700000001);
+    assert!(second.id.starts_with("1700000001-"));
+    let logged_ids: Vec<String> = api::log(&dir.path)
+        .unwrap()
+        .into_iter()
+        .map(|x| x.id)
+        .collect();
+    assert_eq!(logged_ids, [first.id, second.id]);
+}
@@ -0,0 +1,2591 @@
+mod common;
+
+use common::TestDir;
+use jbackup::api::{self, SnapshotOptions};
+
+/// Options for a snapshot dated `date`, so snapshots sort in a known order
+fn dated(date: i64) -> SnapshotOptions {
+    SnapshotOptions {
+        date: Some(date),
+        ..Default::default()
+    }
+}
+
+/// Files in the working directory for each snapshot in `snapshots_restore_each_state`
+fn states() -> Vec<Vec<(&'static str, Vec<u8>)>> {
+    let large: Vec<u8> = (0..200_000u32)
+        .flat_map(|x| (x % 251).to_be_bytes())
+        .collect();
+    let mut large_modified = large.clone();
+    large_modified[1234] ^= 0xFF;
+    large_modified.extend_from_slice(b"appended");
+
+    vec![
+        vec![
+            ("a.txt", b"first version".to_vec()),
+            ("dir/b.bin", large.clone()),
+            ("dir/nested/c.txt", b"unchanged".to_vec()),
+        ],
+        vec![
+            ("a.txt", b"second version".to_vec()),
+            ("dir/b.bin", large_modified),
+            ("dir/nested/c.txt", b"unchanged".to_vec()),
+            ("new.txt", b"added".to_vec()),
+        ],
+        vec![
+            ("dir/b.bin", large),
+            ("dir/nested/c.txt", b"unchanged".to_vec()),
+            ("new.txt", b"added, then changed".to_vec()),
+        ],
+    ]
+}
+
+fn write_state(dir: &TestDir, state: &[(&str, Vec<u8>)]) {
+    for entry in dir.list_entries() {
+        if !entry.ends_with('/') {
+            dir.remove_file(&entry);
+        }
+    }
+    for (path, contents) in state {
+        dir.write_file(path, contents);
+    }
+}
+
+fn assert_state(dir: &TestDir, state: &[(&str, Vec<u8>)]) {
+    let files: Vec<String> = dir
+        .list_entries()
+        .into_iter()
+        .filter(|x| !x.ends_with('/'))
+        .collect();
+    let mut expected_files: Vec<&str> = state.iter().map(|(path, _)| *path).collect();
+    expected_files.sort();
+    assert_eq!(files, expected_files);
+
+    for (path, contents) in state {
+        assert!(dir.read_file(path) == *contents, "{} differs", path);
+    }
+}
+
+#[test]
+pub fn snapshots_restore_each_state() {
+    let dir = TestDir::new("cycle");
+    dir.run_ok(&["init"]);
+
+    let states = states();
+    let mut ids = Vec::new();
+    for (i, state) in states.iter().enumerate() {
+        write_state(&dir, state);
+        dir.run_ok(&["snapshot", "-m", &format!("state {}", i)]);
+        ids.push(dir.head_id());
+    }
+
+    let log = dir.run_ok(&["log"]);
+    for i in 0..states.len() {
+        assert!(log.contains(&format!("Message:   state {}", i)));
+    }
+
+    // oldest first, so the whole diff chain is applied
+    for (state, id) in states.iter().zip(&ids) {
+        dir.run_ok(&["restore", id]);
+        assert_state(&dir, state);
+    }
+
+    // restoring again after the working directory was restored
+    dir.run_ok(&["restore", "HEAD~2"]);
+    assert_state(&dir, &states[0]);
+    dir.run_ok(&["restore", "HEAD"]);
+    assert_state(&dir, &states[2]);
+}
+
+#[test]
+pub fn restores_only_matching_paths() {
+    let dir = TestDir::new("restore-path");
+    dir.run_ok(&["init"]);
+
+    dir.write_file("a.txt", b"a1");
+    dir.write_file("b.txt", b"b1");
+    dir.run_ok(&["snapshot", "-m", "first"]);
+
+    dir.write_file("a.txt", b"a2");
+    dir.write_file("b.txt", b"b2");
+    dir.run_ok(&["snapshot", "-m", "second"]);
+
+    dir.run_ok(&["restore", "HEAD~1", "--path", "a.txt"]);
+    assert_eq!(dir.read_file("a.txt"), b"a1");
+    assert_eq!(dir.read_file("b.txt"), b"b2");
+}
+
+#[test]
+pub fn fails_on_unknown_reference() {
+    let dir = TestDir::new("unknown-ref");
+    dir.run_ok(&["init"]);
+    dir.write_file("a.txt", b"a");
+    dir.run_ok(&["snapshot"]);
+
+    let output = dir.run(&["restore", "does-not-exist"]);
+    assert!(!output.status.success());
+    assert_eq!(dir.read_file("a.txt"), b"a");
+}
+
+#[test]
+pub fn clean_removes_files_not_in_snapshot() {
+    let dir = TestDir::new("clean");
+    dir.run_ok(&["init"]);
+    dir.write_file("kept.txt", b"kept");
+    dir.write_file("dir/kept.txt", b"kept");
+    dir.run_ok(&["snapshot"]);
+
+    dir.write_file("untracked.txt", b"untracked");
+    dir.write_file("new/untracked.txt", b"untracked");
+    dir.write_file("kept.txt", b"modified");
+
+    // nothing is removed without -f
+    assert!(!dir.run(&["clean"]).status.success());
+
+    let output = dir.run_ok(&["clean", "--dry-run"]);
+    assert_eq!(
+        output,
+        "Would remove: ./new/untracked.txt\nWould remove: ./untracked.txt\n"
+    );
+    assert_eq!(dir.list_entries().len(), 6);
+
+    dir.run_ok(&["clean", "-f"]);
+    assert_eq!(dir.list_entries(), ["dir/", "dir/kept.txt", "kept.txt"]);
+    assert_eq!(dir.read_file("kept.txt"), b"modified");
+    assert!(dir.path.join(".jbackup").exists());
+}
+
+#[test]
+pub fn keeps_files_left_out_of_the_snapshot() {
+    use std::{ffi::OsStr, fs, os::unix::ffi::OsStrExt};
+
+    let dir = TestDir::new("left-out");
+    dir.run_ok(&["init"]);
+    dir.write_file("a.txt", b"a");
+    let non_utf8_path = dir.path.join(OsStr::from_bytes(b"caf\xe9.txt"));
+    fs::write(&non_utf8_path, b"left out").unwrap();
+    dir.run_ok(&["snapshot", "--skip-non-utf8"]);
+
+    dir.write_file("untracked.txt", b"untracked");
+    dir.run_ok(&["restore", "HEAD"]);
+    assert!(non_utf8_path.exists());
+    assert!(!dir.path.join("untracked.txt").exists());
+
+    dir.run_ok(&["clean", "-f"]);
+    assert!(non_utf8_path.exists());
+    assert_eq!(dir.read_file("a.txt"), b"a");
+}
+
+#[test]
+pub fn keeps_files_over_the_max_file_size() {
+    let dir = TestDir::new("max-file-size");
+    dir.run_ok(&["init", "--max-file-size", "1K"]);
+    dir.write_file("a.txt", b"a");
+    dir.write_file("big", &[b'b'; 5000]);
+    dir.run_ok(&["snapshot"]);
+
+    let output = dir.run_ok(&["restore", "HEAD"]);
+    assert!(!output.contains("big"));
+    assert_eq!(dir.list_entries(), ["a.txt", "big"]);
+    dir.run_ok(&["clean", "-f"]);
+    assert_eq!(dir.list_entries(), ["a.txt", "big"]);
+}
+
+#[test]
+pub fn keeps_directories_excluded_from_a_single_snapshot() {
+    let dir = TestDir::new("exclude-once");
+    dir.run_ok(&["init"]);
+    dir.write_file("a.txt", b"a");
+    dir.write_file("c/.nobackup", b"");
+    dir.write_file("c/f", b"f");
+    dir.run_ok(&["snapshot", "--exclude-if-present", ".nobackup"]);
+
+    dir.run_ok(&["restore", "HEAD"]);
+    assert_eq!(dir.list_entries(), ["a.txt", "c/", "c/.nobackup", "c/f"]);
+    dir.run_ok(&["clean", "-f"]);
+    assert_eq!(dir.list_entries(), ["a.txt", "c/", "c/.nobackup", "c/f"]);
+}
+
+#[test]
+pub fn keeps_on_large_without_max_file_size() {
+    let dir = TestDir::new("on-large");
+    dir.run_ok(&["init", "--on-large", "abort"]);
+    dir.write_file("big", &[b'b'; 5000]);
+    let output = dir.run(&["snapshot", "--max-file-size", "1K"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+pub fn stash_pop_restores_stashed_files() {
+    let dir = TestDir::new("stash");
+    dir.run_ok(&["init"]);
+    dir.write_file("a.txt", b"snapshotted");
+    dir.run_ok(&["snapshot"]);
+    let head = dir.head_id();
+
+    dir.write_file("a.txt", b"work in progress");
+    dir.write_file("dir/new.txt", b"new");
+    dir.run_ok(&["stash", "-m", "wip"]);
+    assert!(dir.run_ok(&["stash", "list"]).contains("wip"));
+
+    // stashing doesn't change the working directory or the history
+    assert_eq!(dir.read_file("a.txt"), b"work in progress");
+    assert_eq!(dir.head_id(), head);
+    assert!(!dir.run_ok(&["log"]).contains("wip"));
+
+    dir.run_ok(&["restore", "HEAD"]);
+    assert_eq!(dir.list_entries(), ["a.txt"]);
+
+    dir.run_ok(&["stash", "pop"]);
+    assert_eq!(dir.list_entries(), ["a.txt", "dir/", "dir/new.txt"]);
+    assert_eq!(dir.read_file("a.txt"), b"work in progress");
+    assert_eq!(dir.run_ok(&["stash", "list"]), "");
+    assert!(!dir.run(&["stash", "pop"]).status.success());
+}
+
+/// The pid of a process that has exited.
+fn exited_pid() -> u32 {
+    let mut child = std::process::Command::new("true").spawn().unwrap();
+    child.wait().unwrap();
+    child.id()
+}
+
+/// Leaves the state of a restore of `HEAD~2` by the process `pid` that was
+/// interrupted after reconstructing `HEAD~1`. Returns the path of the
+/// reconstructed tar.
+fn interrupt_restore(dir: &TestDir, pid: u32) -> String {
+    let target = dir.run_ok(&["rev-parse", "HEAD~2"]);
+    let completed = dir.run_ok(&["rev-parse", "HEAD~1"]);
+
+    let tar = dir.run(&["show-tar", "HEAD~1"]).stdout;
+    let tar_path = format!(".jbackup/tmp-restored-{}-{}", pid, completed.trim());
+    dir.write_file(&tar_path, &tar);
+    dir.write_file(
+        ".jbackup/restore_progress",
+        format!("target\t{}completed\t{}pid\t{}\n", target, completed, pid).as_bytes(),
+    );
+    tar_path
+}
+
+#[test]
+pub fn resumes_interrupted_restore() {
+    let dir = TestDir::new("resume");
+    dir.run_ok(&["init"]);
+
+    let states = states();
+    for state in &states {
+        write_state(&dir, state);
+        dir.run_ok(&["snapshot"]);
+    }
+
+    interrupt_restore(&dir, exited_pid());
+    let output = dir.run(&["restore", "HEAD~2"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Resuming an interrupted restore"));
+    assert_state(&dir, &states[0]);
+
+    // nothing is left of the interrupted restore
+    let jbackup_files: Vec<String> = std::fs::read_dir(dir.path.join(".jbackup"))
+        .unwrap()
+        .map(|x| x.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    assert!(
+        jbackup_files
+            .iter()
+            .all(|x| !x.starts_with("tmp-restored-") && x != "restore_progress"),
+        "{:?}",
+        jbackup_files
+    );
+}
+
+#[test]
+pub fn aborts_interrupted_restore() {
+    let dir = TestDir::new("abort");
+    dir.run_ok(&["init"]);
+
+    let states = states();
+    for state in &states {
+        write_state(&dir, state);
+        dir.run_ok(&["snapshot"]);
+    }
+
+    // the files of a restore that's still running are kept
+    let tar_path = interrupt_restore(&dir, std::process::id());
+    let output = dir.run(&["restore", "HEAD~2"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Another restore"));
+    let output = dir.run_ok(&["restore", "--abort"]);
+    assert_eq!(output, "Removed 0 file(s) from an interrupted restore\n");
+    assert!(dir.path.join(&tar_path).exists());
+    assert!(dir.path.join(".jbackup/restore_progress").exists());
+    dir.remove_file(&tar_path);
+
+    interrupt_restore(&dir, exited_pid());
+    let output = dir.run_ok(&["restore", "--abort"]);
+    assert_eq!(output, "Removed 1 file(s) from an interrupted restore\n");
+    assert!(!dir.path.join(".jbackup/restore_progress").exists());
+
+    // restoring then starts from the newest snapshot again
+    let output = dir.run(&["restore", "HEAD~2"]);
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("Resuming"));
+    assert_state(&dir, &states[0]);
+}
+
+#[test]
+pub fn only_removes_tmp_snapshots_of_exited_processes() {
+    let dir = TestDir::new("tmp-snapshots");
+    dir.run_ok(&["init"]);
+    dir.write_file("a.txt", b"a");
+
+    let running_path = format!(".jbackup/tmp_snapshot-{}-1.tar.gz", std::process::id());
+    let exited_path = format!(".jbackup/tmp_snapshot-{}-1.tar.gz", exited_pid());
+    dir.write_file(&running_path, b"running");
+    dir.write_file(&exited_path, b"exited");
+    dir.run_ok(&["snapshot"]);
+    assert!(dir.path.join(&running_path).exists());
+    assert!(!dir.path.join(&exited_path).exists());
+}
+
+#[test]
+pub fn refuses_to_resume_from_truncated_tar() {
+    let dir = TestDir::new("resume-truncated");
+    dir.run_ok(&["init"]);
+
+    let states = states();
+    for state in &states {
+        write_state(&dir, state);
+        dir.run_ok(&["snapshot"]);
+    }
+
+    let tar_path = interrupt_restore(&dir, exited_pid());
+    let tar = dir.read_file(&tar_path);
+    dir.write_file(&tar_path, &tar[..tar.len() / 2]);
+
+    let output = dir.run(&["restore", "HEAD~2"]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("is truncated"), "{}", stdout);
+    assert!(stdout.contains("restore --abort"), "{}", stdout);
+}
+
+#[test]
+pub fn uses_tmp_dir_for_scratch_files() {
+    let dir = TestDir::new("tmp-dir");
+    let tmp_dir = TestDir::new("tmp-dir-scratch");
+    let scratch_path = tmp_dir.path.join("scratch");
+    let env = [("JBACKUP_TMPDIR", scratch_path.as_path())];
+    dir.run_ok(&["init"]);
+
+    let states = states();
+    for state in &states {
+        write_state(&dir, state);
+        assert!(dir.run_with_env(&env, &["snapshot"]).status.success());
+    }
+    assert!(dir.run_with_env(&env, &["stash"]).status.success());
+
+    // an interrupted restore is only found in the temp directory
+    interrupt_restore(&dir, exited_pid());
+    let jbackup_path = dir.path.join(".jbackup");
+    for entry in std::fs::read_dir(&jbackup_path).unwrap() {
+        let path = entry.unwrap().path();
+        if path
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with("tmp-restored-")
+        {
+            std::fs::rename(&path, scratch_path.join(path.file_name().unwrap())).unwrap();
+        }
+    }
+
+    let output = dir.run_with_env(&env, &["restore", "HEAD~2"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Resuming an interrupted restore"));
+    assert_state(&dir, &states[0]);
+
+    assert_eq!(std::fs::read_dir(&scratch_path).unwrap().count(), 0);
+    assert!(
+        std::fs::read_dir(&jbackup_path).unwrap().all(|x| !x
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .starts_with("tmp"))
+    );
+}
+
+#[test]
+pub fn round_trips_empty_files() {
+    let dir = TestDir::new("empty-files");
+    dir.run_ok(&["init", "--transformer", "minecraft_mca"]);
+
+    let states: Vec<Vec<(&str, Vec<u8>)>> = vec![
+        vec![
+            ("becomes_empty.txt", b"contents".to_vec()),
+            ("empty.txt", Vec::new()),
+            ("region/r.0.0.mca", Vec::new()),
+        ],
+        vec![
+            ("becomes_empty.txt", Vec::new()),
+            ("empty.txt", Vec::new()),
+            ("new_empty.txt", Vec::new()),
+            ("region/r.0.0.mca", Vec::new()),
+        ],
+        vec![
+            ("becomes_empty.txt", b"contents again".to_vec()),
+            ("empty.txt", vec![0; 10_000]),
+        ],
+    ];
+
+    let mut ids = Vec::new();
+    for state in &states {
+        write_state(&dir, state);
+        dir.run_ok(&["snapshot"]);
+        ids.push(dir.head_id());
+    }
+
+    for (state, id) in states.iter().zip(&ids) {
+        dir.run_ok(&["restore", id]);
+        assert_state(&dir, state);
+    }
+}
+
+#[test]
+pub fn colors_output_when_asked() {
+    let dir = TestDir::new("color");
+    dir.run_ok(&["init"]);
+    dir.write_file("a.txt", b"a");
+    dir.run_ok(&["snapshot"]);
+    let head = dir.head_id();
+
+    let output = dir.run_ok(&["--color", "always", "log"]);
+    assert!(output.contains(&format!("\x1b[32m{}\x1b[0m", head)));
+
+    // the output isn't a terminal
+    assert!(!dir.run_ok(&["log"]).contains('\x1b'));
+    assert!(!dir.run_ok(&["--color", "never", "log"]).contains('\x1b'));
+    assert!(!dir.run(&["--color", "sometimes", "log"]).status.success());
+}
+
+#[test]
+pub fn stores_symlink_targets_when_following_symlinks() {
+    let dir = TestDir::new("follow-symlinks");
+    let data_dir = TestDir::new("follow-symlinks-data");
+    data_dir.write_file("data.txt", b"data");
+    dir.run_ok(&["init"]);
+    dir.write_file("a.txt", b"a");
+    std::os::unix::fs::symlink(&data_dir.path, dir.path.join("data")).unwrap();
+    std::os::unix::fs::symlink(".", dir.path.join("loop")).unwrap();
+
+    dir.run_ok(&["snapshot"]);
+    let tar = dir.run(&["show-tar", "HEAD"]).stdout;
+    assert!(!String::from_utf8_lossy(&tar).contains("data.txt"));
+
+    dir.run_ok(&["snapshot", "--follow-symlinks"]);
+
+    // restored as regular files
+    std::fs::remove_file(dir.path.join("data")).unwrap();
+    std::fs::remove_file(dir.path.join("loop")).unwrap();
+    dir.run_ok(&["restore", "HEAD"]);
+    assert_eq!(dir.list_entries(), ["a.txt", "data/", "data/data.txt"]);
+    assert_eq!(dir.read_file("data/data.txt"), b"data");
+}
+
+#[test]
+pub fn verifies_snapshots() {
+    let dir = TestDir::new("verify");
+    dir.run_ok(&["init"]);
+    for contents in [b"a1", b"a2", b"a3"] {
+        dir.write_file("a.txt", contents);
+        dir.run_ok(&["snapshot"]);
+    }
+
+    assert!(dir.run_ok(&["verify"]).contains("Verified 3 snapshot(s)"));
+    assert!(
+        dir.run_ok(&["verify", "--deep"])
+            .contains("Verified 3 snapshot(s)")
+    );
+
+    let snapshots_path = dir.path.join(".jbackup/snapshots");
+    let diff_path = std::fs::read_dir(&snapshots_path)
+        .unwrap()
+        .map(|x| x.unwrap().path())
+        .find(|x| x.to_string_lossy().contains("-diff-"))
+        .unwrap();
+    let diff = std::fs::read(&diff_path).unwrap();
+    std::fs::write(&diff_path, &diff[..diff.len() / 2]).unwrap();
+
+    assert!(!dir.run(&["verify"]).status.success());
+    assert!(!dir.run(&["verify", "--deep"]).status.success());
+
+    std::fs::remove_file(&diff_path).unwrap();
+    assert!(!dir.run(&["verify"]).status.success());
+}
+
+#[test]
+pub fn squashes_snapshot_ranges() {
+    let dir = TestDir::new("squash");
+    dir.run_ok(&["init"]);
+
+    let mut states = states();
+    let mut last_state = states[0].clone();
+    last_state.push(("last.txt", b"last".to_vec()));
+    states.push(last_state);
+    let mut ids = Vec::new();
+    for (i, state) in states.iter().enumerate() {
+        write_state(&dir, state);
+        if i == 1 {
+            dir.run_ok(&["snapshot", "--tag", "keep"]);
+        } else {
+            dir.run_ok(&["snapshot"]);
+        }
+        ids.push(dir.head_id());
+    }
+
+    // the tag points to a snapshot that would be removed
+    let range = format!("{}..{}", ids[1], ids[2]);
+    assert!(!dir.run(&["squash", &range, "-f"]).status.success());
+
+    let range = format!("{}..HEAD", ids[2]);
+    assert!(!dir.run(&["squash", &range]).status.success());
+    let dry_run = dir.run_ok(&["squash", &range, "--dry-run"]);
+    assert_eq!(dry_run, format!("Would remove: {}\n", ids[2]));
+
+    dir.run_ok(&["squash", &range, "-f", "-m", "squashed"]);
+    let log = dir.run_ok(&["log"]);
+    assert!(!log.contains(&ids[2]));
+    assert!(log.contains("Message:   squashed"));
+    dir.run_ok(&["verify", "--deep"]);
+    for i in [0, 1, 3] {
+        dir.run_ok(&["restore", &ids[i]]);
+        assert_state(&dir, &states[i]);
+    }
+
+    // squashing the first snapshot
+    dir.run_ok(&["squash", &format!("{}..keep", ids[0]), "-f"]);
+    assert!(!dir.run_ok(&["log"]).contains(&ids[0]));
+    dir.run_ok(&["verify", "--deep"]);
+    for i in [1, 3] {
+        dir.run_ok(&["restore", &ids[i]]);
+        assert_state(&dir, &states[i]);
+    }
+}
+
+#[test]
+pub fn refuses_to_remove_snapshots_in_immutable_repositories() {
+    let dir = TestDir::new("immutable");
+    dir.run_ok(&["init", "--immutable"]);
+    let mut ids = Vec::new();
+    for i in 0..3 {
+        dir.write_file("a.txt", format!("version {}", i).as_bytes());
+        dir.run_ok(&["snapshot"]);
+        ids.push(dir.head_id());
+    }
+
+    let range = format!("{}..HEAD", ids[1]);
+    let output = dir.run(&["squash", &range, "-f"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("immutable"));
+    dir.write_file("a.txt", b"amended");
+    assert!(!dir.run(&["snapshot", "--amend"]).status.success());
+    assert!(
+        !dir.run(&["clean-snapshots", "--keep-last", "1", "-f"])
+            .status
+            .success()
+    );
+    dir.run_ok(&["clean-snapshots", "--keep-last", "1", "--dry-run"]);
+
+    // nothing was changed
+    let log = dir.run_ok(&["log"]);
+    assert!(ids.iter().all(|id| log.contains(id)));
+    // new snapshots didn't remove their parents' full payloads
+    for id in &ids {
+        dir.read_file(&format!(".jbackup/snapshots/{}-full.tar.gz", id));
+    }
+    assert_eq!(dir.head_id(), ids[2]);
+    dir.run_ok(&["verify", "--deep"]);
+
+    dir.run_ok(&["--force-immutable", "squash", &range, "-f"]);
+    assert!(!dir.run_ok(&["log"]).contains(&ids[1]));
+    dir.run_ok(&["verify", "--deep"]);
+}
+
+#[test]
+pub fn cleans_snapshots_by_retention_policy() {
+    let dir = TestDir::new("clean-snapshots");
+    dir.run_ok(&["init"]);
+
+    // four old snapshots, the second one tagged, then two recent ones
+    let mut ids = Vec::new();
+    for i in 0..6 {
+        dir.write_file("a.txt", format!("version {}", i).as_bytes());
+        let options = SnapshotOptions {
+            tag: (i == 1).then(|| String::from("keep")),
+            date: (i < 4).then_some(1_000_000 + i),
+            ..Default::default()
+        };
+        api::snapshot(&dir.path, options).unwrap();
+        ids.push(dir.head_id());
+    }
+
+    assert!(!dir.run(&["clean-snapshots", "-f"]).status.success());
+    assert!(
+        !dir.run(&["clean-snapshots", "--keep-last", "1"])
+            .status
+            .success()
+    );
+    assert!(
+        !dir.run(&["clean-snapshots", "--keep-within", "90", "-f"])
+            .status
+            .success()
+    );
+
+    let args = ["clean-snapshots", "--keep-last", "1", "--keep-within", "1d"];
+    let dry_run = dir.run_ok(&[&args[..], &["--dry-run"]].concat());
+    assert_eq!(
+        dry_run,
+        format!(
+            "Would remove: {}\nWould remove: {}\nWould remove: {}\n",
+            ids[0], ids[2], ids[3]
+        )
+    );
+
+    dir.run_ok(&[&args[..], &["-f"]].concat());
+    let log = dir.run_ok(&["log"]);
+    for i in [0, 2, 3] {
+        assert!(!log.contains(&ids[i]));
+    }
+    dir.run_ok(&["verify", "--deep"]);
+    for i in [1, 4, 5] {
+        dir.run_ok(&["restore", &ids[i]]);
+        assert_eq!(dir.read_file("a.txt"), format!("version {}", i).as_bytes());
+    }
+
+    let again = dir.run_ok(&[&args[..], &["-f"]].concat());
+    assert_eq!(again, "Nothing to clean.\n");
+}
+
+#[test]
+pub fn cleans_snapshots_from_the_same_second_in_order() {
+    let dir = TestDir::new("clean-same-second");
+    dir.run_ok(&["init"]);
+    let mut ids = Vec::new();
+    for i in 0..4 {
+        dir.write_file("a.txt", format!("{}", i).as_bytes());
+        api::snapshot(&dir.path, dated(1_000_000)).unwrap();
+        ids.push(dir.head_id());
+    }
+
+    dir.run_ok(&["clean-snapshots", "--keep-last", "2", "-f"]);
+    let log = dir.run_ok(&["log"]);
+    for i in [0, 1] {
+        assert!(!log.contains(&ids[i]));
+    }
+    dir.run_ok(&["verify", "--deep"]);
+    for i in [2, 3] {
+        dir.run_ok(&["restore", &ids[i]]);
+        assert_eq!(dir.read_file("a.txt"), format!("{}", i).as_bytes());
+    }
+}
+
+#[test]
+pub fn reports_progress_as_json() {
+    let dir = TestDir::new("progress-json");
+    dir.run_ok(&["init"]);
+    dir.write_file("a.txt", b"a1");
+    dir.run_ok(&["snapshot"]);
+    let first_id = dir.head_id();
+    dir.write_file("a.txt", b"a2");
+
+    let output = dir.run(&["--progress-json", "snapshot"]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(
+        stderr,
+        "{\"type\":\"file\",\"path\":\"a.txt\",\"done\":1,\"total\":1}\n"
+    );
+
+    let output = dir.run(&["--progress-json", "restore", "HEAD~1"]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(
+        stderr.lines().collect::<Vec<_>>(),
+        [
+            format!(
+                "{{\"type\":\"snapshot\",\"id\":\"{}\",\"done\":1,\"total\":1}}",
+                first_id
+            )
+            .as_str(),
+            "{\"type\":\"file\",\"path\":\"a.txt\",\"done\":1,\"total\":null}",
+        ]
+    );
+}
+
+#[test]
+pub fn chunks_large_files() {
+    let dir = TestDir::new("chunking");
+    dir.run_ok(&["init", "--chunking", "512K"]);
+
+    let mut state: u64 = 1;
+    let large: Vec<u8> = (0..2_000_000)
+        .map(|_| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 56) as u8
+        })
+        .collect();
+    let mut large_modified = large.clone();
+    large_modified.splice(1_000_000..1_000_000, b"inserted".iter().copied());
+
+    let states = [
+        vec![("large.img", large), ("small.txt", b"small".to_vec())],
+        vec![
+            ("large.img", large_modified),
+            ("small.txt", b"changed".to_vec()),
+        ],
+    ];
+    let count_chunks = || {
+        std::fs::read_dir(dir.path.join(".jbackup/snapshots/chunks"))
+            .unwrap()
+            .count()
+    };
+
+    let mut ids = Vec::new();
+    let mut chunk_counts = Vec::new();
+    for state in &states {
+        write_state(&dir, state);
+        dir.run_ok(&["snapshot"]);
+        ids.push(dir.head_id());
+        chunk_counts.push(count_chunks());
+    }
+    // only the chunks around the insertion are stored again
+    assert!(chunk_counts[1] - chunk_counts[0] <= 2);
+    assert!(dir.run_ok(&["size"]).contains("Chunks:"));
+
+    dir.run_ok(&["verify", "--deep"]);
+    for (state, id) in states.iter().zip(&ids) {
+        dir.run_ok(&["restore", id]);
+        assert_state(&dir, state);
+    }
+    // every chunk is still used
+    assert!(dir.run_ok(&["gc"]).contains("Removed 0 unused chunk(s)"));
+    assert_eq!(count_chunks(), chunk_counts[1]);
+
+    // the chunks only the first snapshot used are removed once it's gone
+    dir.run_ok(&["squash", "-f", &format!("{}..{}", ids[0], ids[1])]);
+    assert!(!dir.run_ok(&["gc", "--dry-run"]).contains("remove 0 "));
+    assert_eq!(count_chunks(), chunk_counts[1]);
+    dir.run_ok(&["gc"]);
+    assert!(count_chunks() < chunk_counts[1]);
+    dir.run_ok(&["restore", "HEAD"]);
+    assert_state(&dir, &states[1]);
+}
+
+#[test]
+pub fn logs_ranges() {
+    let dir = TestDir::new("log-range");
+    dir.run_ok(&["init"]);
+    let mut ids = Vec::new();
+    for (i, contents) in [b"1", b"2", b"3", b"4"].iter().enumerate() {
+        dir.write_file("a.txt", *contents);
+        if i == 1 {
+            dir.run_ok(&["snapshot", "--tag", "v1"]);
+        } else {
+            dir.run_ok(&["snapshot"]);
+        }
+        ids.push(dir.head_id());
+    }
+
+    let logged_ids = |range: &str| -> Vec<bool> {
+        let log = dir.run_ok(&["log", range]);
+        ids.iter().map(|id| log.contains(id.as_str())).collect()
+    };
+    assert_eq!(logged_ids("v1..HEAD~1"), [false, true, true, false]);
+    assert_eq!(logged_ids("v1.."), [false, true, true, true]);
+    assert_eq!(logged_ids("..v1"), [true, true, false, false]);
+    assert!(!dir.run(&["log", "HEAD..v1"]).status.success());
+    assert!(!dir.run(&["log", "v1"]).status.success());
+}
+
+#[test]
+pub fn logs_newest_first_with_a_limit() {
+    let dir = TestDir::new("log-limit");
+    dir.run_ok(&["init"]);
+    let mut ids = Vec::new();
+    for i in 0..4 {
+        dir.write_file("a.txt", format!("{}", i).as_bytes());
+        api::snapshot(&dir.path, dated(1_000_000 + i)).unwrap();
+        ids.push(dir.head_id());
+    }
+
+    // the ids in the order they're logged
+    let logged_ids = |args: &[&str]| -> Vec<usize> {
+        let log = dir.run_ok(&[&["log"], args].concat());
+        let mut logged: Vec<usize> = (0..ids.len())
+            .filter(|&i| log.contains(ids[i].as_str()))
+            .collect();
+        logged.sort_by_key(|&i| log.find(ids[i].as_str()));
+        logged
+    };
+    assert_eq!(logged_ids(&[]), [0, 1, 2, 3]);
+    assert_eq!(logged_ids(&["--reverse"]), [3, 2, 1, 0]);
+    assert_eq!(logged_ids(&["--limit", "2"]), [2, 3]);
+    assert_eq!(logged_ids(&["-n", "3", "--reverse"]), [3, 2, 1]);
+    assert_eq!(logged_ids(&["-n", "10"]), [0, 1, 2, 3]);
+    assert_eq!(logged_ids(&["--graph", "-n", "2"]), [2, 3]);
+
+    assert!(!dir.run(&["log", "-n", "0"]).status.success());
+    assert!(!dir.run(&["log", "--limit", "x"]).status.success());
+    assert!(!dir.run(&["log", "--graph", "--reverse"]).status.success());
+}
+
+#[test]
+pub fn logs_snapshots_from_the_same_second_in_order() {
+    let dir = TestDir::new("log-same-second");
+    dir.run_ok(&["init"]);
+    let mut ids = Vec::new();
+    for i in 0..4 {
+        dir.write_file("a.txt", format!("{}", i).as_bytes());
+        api::snapshot(&dir.path, dated(1_000_000)).unwrap();
+        ids.push(dir.head_id());
+    }
+
+    // the ids in the order they're logged
+    let logged_ids = |args: &[&str]| -> Vec<usize> {
+        let log = dir.run_ok(&[&["log"], args].concat());
+        let mut logged: Vec<usize> = (0..ids.len())
+            .filter(|&i| log.contains(ids[i].as_str()))
+            .collect();
+        logged.sort_by_key(|&i| log.find(ids[i].as_str()));
+        logged
+    };
+    assert_eq!(logged_ids(&[]), [0, 1, 2, 3]);
+    assert_eq!(logged_ids(&["-n", "1"]), [3]);
+    assert_eq!(logged_ids(&["--reverse"]), [3, 2, 1, 0]);
+    assert_eq!(logged_ids(&["-n", "2", "--reverse"]), [3, 2]);
+}
+
+#[test]
+pub fn logs_branches_and_tags() {
+    let dir = TestDir::new("log-branches");
+    dir.run_ok(&["init"]);
+    dir.write_file("a.txt", b"a");
+    dir.run_ok(&["snapshot", "--tag", "first"]);
+    let first_id = dir.head_id();
+    dir.write_file("a.txt", b"b");
+    dir.run_ok(&["snapshot"]);
+    let main_id = dir.head_id();
+
+    // start another branch from the tip of main
+    dir.write_file(
+        ".jbackup/head",
+        format!("snapshotid\t{}\nbranch\texperiment\n", main_id).as_bytes(),
+    );
+    dir.write_file("a.txt", b"c");
+    dir.run_ok(&["snapshot"]);
+    let experiment_id = dir.head_id();
+
+    let log = dir.run_ok(&["log"]);
+    assert!(log.contains("Refs:      tag: first"));
+    assert!(log.contains("Refs:      main"));
+    assert!(log.contains("Refs:      experiment"));
+    let graph = dir.run_ok(&["log", "--graph"]);
+    assert!(graph.contains(&format!("{} (tag: first)", first_id)));
+    assert!(graph.contains(&format!("{} (main)", main_id)));
+
+    let log = dir.run_ok(&["log", "--branch", "experiment"]);
+    assert!(log.contains(&first_id));
+    assert!(log.contains(&main_id));
+    assert!(log.contains(&experiment_id));
+    let log = dir.run_ok(&["log", "--branch", "main", "--graph"]);
+    assert!(log.contains(&first_id));
+    assert!(log.contains(&main_id));
+    assert!(!log.contains(&experiment_id));
+
+    assert!(!dir.run(&["log", "--branch", "missing"]).status.success());
+}
+
+#[test]
+pub fn snapshots_on_another_branch() {
+    let dir = TestDir::new("on-branch");
+    dir.run_ok(&["init"]);
+    dir.write_file("a.txt", b"a");
+    dir.run_ok(&["snapshot"]);
+    let main_id = dir.head_id();
+
+    // the nightly branch is created at the head's snapshot
+    let main_head = dir.read_file(".jbackup/head");
+    dir.write_file("a.txt", b"b");
+    let output = dir.run_ok(&["snapshot", "--on-branch", "nightly", "--print-id"]);
+    let nightly_id = String::from(output.trim());
+    assert_eq!(dir.head_id(), main_id);
+    assert_eq!(dir.run_ok(&["rev-parse", "nightly~1"]).trim(), main_id);
+
+    dir.write_file("a.txt", b"c");
+    let output = dir.run_ok(&["snapshot", "--on-branch", "nightly", "--print-id"]);
+    let new_id = output.trim();
+
+    // the head doesn't move, but the branch does
+    assert_eq!(dir.read_file(".jbackup/head"), main_head);
+    let log = dir.run_ok(&["log", "--branch", "nightly"]);
+    assert!(log.contains(&nightly_id));
+    assert!(log.contains(new_id));
+    assert!(!dir.run_ok(&["log", "--branch", "main"]).contains(new_id));
+
+    dir.write_file("a.txt", b"d");
+    dir.run_ok(&["restore", "nightly~1"]);
+    assert_eq!(dir.read_file("a.txt"), b"b");
+    dir.run_ok(&["restore", "nightly"]);
+    assert_eq!(dir.read_file("a.txt"), b"c");
+
+    // without changes, a new branch is still created at the head's snapshot
+    dir.run_ok(&["restore", "HEAD"]);
+    dir.run_ok(&["snapshot", "--on-branch", "unchanged"]);
+    assert_eq!(dir.run_ok(&["rev-parse", "unchanged"]).trim(), main_id);
+    assert!(
+        !dir.run(&["snapshot", "--on-branch", "a\tb"])
+            .status
+            .success()
+    );
+    assert!(
+        !dir.run(&["snapshot", "--on-branch", "nightly", "--amend"])
+            .status
+            .success()
+    );
+
+    // the head's branch can still be snapshotted, from the snapshot the
+    // nightly branch started at
+    dir.write_file("a.txt", b"e");
+    dir.run_ok(&["snapshot"]);
+    assert_eq!(dir.run_ok(&["rev-parse", "HEAD~1"]).trim(), main_id);
+    dir.run_ok(&["verify", "--deep"]);
+    for (reference, contents) in [("HEAD~1", b"a"), ("nightly~1", b"b"), ("nightly", b"c")] {
+        dir.run_ok(&["restore", reference]);
+        assert_eq!(dir.read_file("a.txt"), contents);
+    }
+    dir.run_ok(&["restore", "HEAD"]);
+    assert_eq!(dir.read_file("a.txt"), b"e");
+
+    // every full payload belongs to a snapshot
+    let log =
+        dir.run_ok(&["log", "--branch", "main"]) + &dir.run_ok(&["log", "--branch", "nightly"]);
+    for entry in std::fs::read_dir(dir.path.join(".jbackup/snapshots")).unwrap() {
+        let name = entry.unwrap().file_name().to_string_lossy().into_owned();
+        if let Some(id) = name.strip_suffix("-full.tar.gz") {
+            assert!(log.contains(id), "{} isn't a snapshot's", name);
+        }
+    }
+}
+
+#[test]
+pub fn logs_operations() {
+    let dir = TestDir::new("operations-log");
+    dir.run_ok(&["init"]);
+    dir.write_file("a.txt", b"a");
+    dir.run_ok(&["snapshot", "-m", "first"]);
+    let first_id = dir.head_id();
+    dir.write_file("a.txt", b"b");
+    dir.run_ok(&["snapshot"]);
+    let second_id = dir.head_id();
+    dir.run_ok(&["log"]);
+    assert!(!dir.run(&["restore", "missing"]).status.success());
+
+    let log = String::from_utf8(dir.read_file(".jbackup/operations.log")).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    // log only reads the repository, so it isn't logged
+    assert_eq!(lines.len(), 4, "{}", log);
+    assert!(lines[0].contains("\"command\":\"init\",\"args\":[\"init\"],\"result\":\"ok\""));
+    assert!(lines[1].contains("\"args\":[\"snapshot\",\"-m\",\"first\"]"));
+    assert!(lines[1].contains(&format!("\"created\":[\"{}\"],\"removed\":[]", first_id)));
+    assert!(lines[2].contains(&format!("\"created\":[\"{}\"]", second_id)));
+    assert!(lines[3].contains("\"command\":\"restore\""));
+    assert!(lines[3].contains("\"result\":\"failed\""));
+
+    // commands outside of a repository aren't logged
+    let outside = TestDir::new("operations-log-outside");
+    assert!(!outside.run(&["snapshot"]).status.success());
+    assert!(!outside.path.join(".jbackup").exists());
+}
+
+#[test]
+pub fn restores_from_uncompressed_payloads() {
+    use std::io::Read;
+
+    let dir = TestDir::new("tar-payload");
+    // ids in the timestamp-hash format have the md5 of the tar.gz
+    dir.run_ok(&["init", "--snapshot-id-format", "ulid"]);
+    write_state(&dir, &states()[0]);
+    dir.run_ok(&["snapshot"]);
+    let first_id = dir.head_id();
+
+    // store the payload as a tar, as if it was created that way
+    let payload = dir.read_file(&format!(".jbackup/snapshots/{}-full.tar.gz", first_id));
+    let mut tar = Vec::new();
+    flate2::read::GzDecoder::new(payload.as_slice())
+        .read_to_end(&mut tar)
+        .unwrap();
+    dir.remove_file(&format!(".jbackup/snapshots/{}-full.tar.gz", first_id));
+    dir.write_file(&format!(".jbackup/snapshots/{}-full.tar", first_id), &tar);
+    let meta_path = format!(".jbackup/snapshots/{}.meta", first_id);
+    let meta = String::from_utf8(dir.read_file(&meta_path)).unwrap();
+    assert!(meta.contains("full\ttar.gz\n"));
+    dir.write_file(
+        &meta_path,
+        meta.replace("full\ttar.gz\n", "full\ttar\n").as_bytes(),
+    );
+
+    let tar_output = dir.run(&["show-tar", &first_id, "--format", "tar"]);
+    assert!(tar_output.status.success());
+    assert_eq!(tar_output.stdout, tar);
+    dir.run_ok(&["verify", "--deep"]);
+
+    // the next snapshot is diffed against the tar
+    write_state(&dir, &states()[1]);
+    dir.run_ok(&["snapshot"]);
+    dir.run_ok(&["restore", &first_id]);
+    assert_state(&dir, &states()[0]);
+    dir.run_ok(&["restore", "HEAD"]);
+    assert_state(&dir, &states()[1]);
+    dir.run_ok(&["verify", "--deep"]);
+}
+
+#[test]
+pub fn diffs_a_snapshot_with_the_working_directory() {
+    let dir = TestDir::new("diff-working");
+    dir.run_ok(&["init"]);
+    write_state(&dir, &states()[0]);
+    dir.run_ok(&["snapshot"]);
+    let first_id = dir.head_id();
+    write_state(&dir, &states()[1]);
+    dir.run_ok(&["snapshot"]);
+
+    assert!(dir.run_ok(&["diff", "HEAD"]).contains("0 file(s) changed."));
+    let expected = dir.run_ok(&["diff", &first_id, "HEAD"]);
+    assert_eq!(dir.run_ok(&["diff", &first_id]), expected);
+
+    dir.write_file("added.txt", b"added");
+    let output = dir.run_ok(&["diff", "HEAD"]);
+    assert!(output.contains("Added:    added.txt"));
+    assert!(output.contains("1 file(s) changed."));
+    // the working directory isn't changed
+    assert_eq!(dir.read_file("added.txt"), b"added");
+}
+
+#[test]
+pub fn stores_forward_diffs() {
+    let dir = TestDir::new("forward-diffs");
+    dir.run_ok(&["init", "--diff-direction", "forward"]);
+
+    let states = states();
+    let mut ids = Vec::new();
+    for state in &states {
+        write_state(&dir, state);
+        dir.run_ok(&["snapshot"]);
+        ids.push(dir.head_id());
+    }
+
+    // only the first and newest snapshots are stored in full
+    let mut full_payloads: Vec<String> = std::fs::read_dir(dir.path.join(".jbackup/snapshots"))
+        .unwrap()
+        .map(|x| x.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|x| x.contains("-full"))
+        .collect();
+    full_payloads.sort();
+    let mut expected = vec![
+        format!("{}-full.tar.gz", ids[0]),
+        format!("{}-full.tar.gz", ids[ids.len() - 1]),
+    ];
+    expected.sort();
+    assert_eq!(full_payloads, expected);
+
+    dir.run_ok(&["verify", "--deep"]);
+    for (state, id) in states.iter().zip(&ids).rev() {
+        dir.run_ok(&["restore", id]);
+        assert_state(&dir, state);
+    }
+    assert!(
+        !dir.run(&["squash", "-f", &format!("{}..", ids[0])])
+            .status
+            .success()
+    );
+}
+
+#[test]
+pub fn stores_the_first_snapshot_as_a_diff_from_empty() {
+    let dir = TestDir::new("from-empty");
+    dir.run_ok(&["init", "--diff-direction", "forward", "--from-empty"]);
+
+    let states = states();
+    let mut ids = Vec::new();
+    for state in &states {
+        write_state(&dir, state);
+        dir.run_ok(&["snapshot"]);
+        ids.push(dir.head_id());
+    }
+
+    // only the newest snapshot is stored in full
+    let snapshot_files: Vec<String> = std::fs::read_dir(dir.path.join(".jbackup/snapshots"))
+        .unwrap()
+        .map(|x| x.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    let full_payloads: Vec<&String> = snapshot_files
+        .iter()
+        .filter(|x| x.contains("-full"))
+        .collect();
+    assert_eq!(
+        full_payloads,
+        [&format!("{}-full.tar.gz", ids[ids.len() - 1])]
+    );
+    assert!(snapshot_files.contains(&format!("{}-diff-empty", ids[0])));
+
+    dir.run_ok(&["verify", "--deep"]);
+    for (state, id) in states.iter().zip(&ids) {
+        dir.run_ok(&["restore", id]);
+        assert_state(&dir, state);
+    }
+    let tar = dir.run(&["show-tar", "--format", "tar", &ids[0]]);
+    assert!(tar.status.success() && !tar.stdout.is_empty());
+
+    let dir = TestDir::new("from-empty-reverse");
+    assert!(!dir.run(&["init", "--from-empty"]).status.success());
+}
+
+/// A region file with one zlib-compressed chunk, like Minecraft writes
+fn region_file(chunk: &[u8]) -> Vec<u8> {
+    use std::io::Write;
+
+    let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(chunk).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    // the chunk is in the sector after the location and timestamp tables
+    let mut region = vec![0; 8192];
+    let sectors = (compressed.len() + 5).div_ceil(4096);
+    region[..4].copy_from_slice(&[0, 0, 2, sectors as u8]);
+    region[4096..4100].copy_from_slice(&1_700_000_000u32.to_be_bytes());
+    region.extend(((compressed.len() + 1) as u32).to_be_bytes());
+    region.push(2);
+    region.extend(compressed);
+    region.resize(8192 + sectors * 4096, 0);
+    region
+}
+
+#[test]
+pub fn shows_stored_payload_or_original_files() {
+    use std::io::Read;
+
+    // the regular files of a tar, by path
+    let read_tar = |tar: &[u8]| -> Vec<(String, Vec<u8>)> {
+        let mut archive = tar::Archive::new(tar);
+        let mut files = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            if entry.header().entry_type() != tar::EntryType::Regular {
+                continue;
+            }
+            let path = entry.path().unwrap().to_string_lossy().into_owned();
+            let path = String::from(path.trim_start_matches("./"));
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents).unwrap();
+            files.push((path, contents));
+        }
+        files.sort();
+        files
+    };
+
+    // region files are stored with their chunks decompressed
+    let dir = TestDir::new("show-tar");
+    dir.run_ok(&["init", "--transformer", "minecraft_mca"]);
+    let nbt_like = b"\x0a\x00\x00\x03\x00\x0bDataVersion\x00\x00\x0f\x3d".repeat(400);
+    dir.write_file("a.txt", b"a");
+    dir.write_file("region/r.0.0.mca", &region_file(&nbt_like));
+    dir.run_ok(&["snapshot"]);
+    dir.write_file("region/r.0.0.mca", &region_file(&nbt_like[..1000]));
+    dir.run_ok(&["snapshot"]);
+
+    // HEAD~1 is reconstructed from a diff
+    dir.run_ok(&["restore", "HEAD~1"]);
+    let expected = vec![
+        (String::from("a.txt"), b"a".to_vec()),
+        (
+            String::from("region/r.0.0.mca"),
+            dir.read_file("region/r.0.0.mca"),
+        ),
+    ];
+    for format in ["tar", "tar.gz"] {
+        let output =
+            dir.run_ok_bytes(&["show-tar", "HEAD~1", "--transform-out", "--format", format]);
+        let tar = if format == "tar" {
+            output
+        } else {
+            let mut tar = Vec::new();
+            flate2::read::GzDecoder::new(output.as_slice())
+                .read_to_end(&mut tar)
+                .unwrap();
+            tar
+        };
+        assert_eq!(read_tar(&tar), expected);
+    }
+
+    // the stored tar is the default
+    let stored = dir.run_ok_bytes(&["show-tar", "HEAD~1", "--format", "tar"]);
+    let payload = dir.run_ok_bytes(&["show-tar", "HEAD~1", "--payload-only", "--format", "tar"]);
+    assert_eq!(stored, payload);
+    let payload = read_tar(&payload);
+    assert_eq!(payload[0], expected[0]);
+    assert_eq!(payload[1].0, expected[1].0);
+    assert_ne!(payload[1].1, expected[1].1);
+
+    assert!(
+        !dir.run(&["show-tar", "HEAD", "--payload-only", "--transform-out"])
+            .status
+            .success()
+    );
+}
+
+#[test]
+pub fn shows_uncompressed_tar() {
+    use std::io::Read;
+
+    let dir = TestDir::new("show-tar-format");
+    dir.run_ok(&["init"]);
+    for state in &states()[..2] {
+        write_state(&dir, state);
+        dir.run_ok(&["snapshot"]);
+    }
+
+    // HEAD has a full payload, and HEAD~1 is reconstructed from a diff
+    for reference in ["HEAD", "HEAD~1"] {
+        let tar_gz = dir.run(&["show-tar", reference]).stdout;
+        let tar = dir.run(&["show-tar", "--format", "tar", reference]).stdout;
+        let mut expected = Vec::new();
+        flate2::read::GzDecoder::new(tar_gz.as_slice())
+            .read_to_end(&mut expected)
+            .unwrap();
+        assert!(!tar.is_empty());
+        assert_eq!(tar, expected);
+    }
+
+    assert!(
+        !dir.run(&["show-tar", "--format", "zip", "HEAD"])
+            .status
+            .success()
+    );
+}
+
+#[test]
+pub fn refuses_working_directory_commands_when_bare() {
+    let dir = TestDir::new("bare");
+    dir.run_ok(&["init", "--bare"]);
+    dir.write_file("a.txt", b"a");
+
+    for args in [
+        &["snapshot"][..],
+        &["restore", "HEAD"],
+        &["clean", "-f"],
+        &["stash"],
+    ] {
+        let output = dir.run(args);
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stdout).contains("bare repository"));
+    }
+    dir.run_ok(&["log"]);
+    dir.run_ok(&["verify"]);
+}
+
+#[test]
+pub fn stores_hard_links_once() {
+    use std::{fs, os::unix::fs::MetadataExt};
+
+    let dir = TestDir::new("hard-links");
+    dir.run_ok(&["init"]);
+    let inode = |path: &str| fs::metadata(dir.path.join(path)).unwrap().ino();
+
+    dir.write_file("a.txt", b"shared");
+    dir.write_file("c.txt", b"c");
+    fs::create_dir(dir.path.join("sub")).unwrap();
+    fs::hard_link(dir.path.join("a.txt"), dir.path.join("sub/b.txt")).unwrap();
+    dir.run_ok(&["snapshot"]);
+    let first_id = dir.head_id();
+
+    let tar = dir.run(&["show-tar", "HEAD"]).stdout;
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(tar.as_slice()));
+    let link_targets: Vec<_> = archive
+        .entries()
+        .unwrap()
+        .map(|x| x.unwrap())
+        .filter(|x| x.header().entry_type() == tar::EntryType::Link)
+        .map(|x| x.link_name_bytes().unwrap().into_owned())
+        .collect();
+    assert_eq!(link_targets, [b"a.txt".to_vec()]);
+
+    dir.remove_file("sub/b.txt");
+    dir.write_file("sub/b.txt", b"own");
+    fs::hard_link(dir.path.join("c.txt"), dir.path.join("d.txt")).unwrap();
+    dir.run_ok(&["snapshot"]);
+    let second_id = dir.head_id();
+
+    dir.run_ok(&["verify", "--deep"]);
+
+    dir.run_ok(&["restore", &first_id]);
+    assert_eq!(dir.read_file("sub/b.txt"), b"shared");
+    assert_eq!(inode("a.txt"), inode("sub/b.txt"));
+    assert!(!dir.path.join("d.txt").exists());
+
+    // sub/b.txt is restored as its own file, without changing a.txt
+    dir.run_ok(&["restore", &second_id]);
+    assert_eq!(dir.read_file("a.txt"), b"shared");
+    assert_eq!(dir.read_file("sub/b.txt"), b"own");
+    assert_ne!(inode("a.txt"), inode("sub/b.txt"));
+    assert_eq!(inode("c.txt"), inode("d.txt"));
+
+    // restoring a link without its target writes the target's contents
+    let output = dir.run_ok(&["restore", "--path", "sub/b.txt", &first_id]);
+    assert!(output.contains("Restored 1 file(s)"));
+    assert_eq!(dir.read_file("sub/b.txt"), b"shared");
+    assert_ne!(inode("a.txt"), inode("sub/b.txt"));
+}
+
+#[test]
+pub fn fails_when_snapshots_dir_config_is_unreadable() {
+    let dir = TestDir::new("unreadable-snapshots-dir");
+    let snapshots_dir = dir.path.join("backups");
+    dir.run_ok(&["init", "--snapshots-dir", snapshots_dir.to_str().unwrap()]);
+    dir.write_file("a.txt", b"contents");
+    dir.run_ok(&["snapshot"]);
+
+    let mut config = dir.read_file(".jbackup/config");
+    config.extend_from_slice(b"tar_format\tinvalid\n");
+    dir.write_file(".jbackup/config", &config);
+
+    // the snapshots aren't looked for in .jbackup/snapshots instead
+    assert!(!dir.run(&["log"]).status.success());
+    assert!(!dir.run(&["snapshot"]).status.success());
+    assert!(!dir.path.join(".jbackup/snapshots").exists());
+}
+
+#[test]
+pub fn skips_snapshots_dir_in_working_directory() {
+    let dir = TestDir::new("nested-snapshots-dir");
+    let snapshots_dir = dir.path.join("backups");
+    dir.run_ok(&["init", "--snapshots-dir", snapshots_dir.to_str().unwrap()]);
+
+    dir.write_file("a.txt", b"first version");
+    dir.run_ok(&["snapshot"]);
+    let first_id = dir.head_id();
+    dir.write_file("a.txt", b"second version");
+    dir.write_file("dir/b.txt", b"added");
+    dir.run_ok(&["snapshot"]);
+
+    // the payloads are in the working directory, but not in the snapshots
+    let tar = dir.run(&["show-tar", "HEAD"]).stdout;
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(tar.as_slice()));
+    let paths: Vec<Vec<u8>> = archive
+        .entries()
+        .unwrap()
+        .map(|x| x.unwrap().path_bytes().into_owned())
+        .collect();
+    assert_eq!(paths, [b"a.txt".to_vec(), b"dir/b.txt".to_vec()]);
+
+    // restoring doesn't remove the snapshots, which aren't in the snapshot
+    dir.run_ok(&["restore", &first_id]);
+    assert_eq!(dir.read_file("a.txt"), b"first version");
+    assert!(!dir.path.join("dir/b.txt").exists());
+    dir.run_ok(&["restore", "HEAD"]);
+    assert_eq!(dir.read_file("dir/b.txt"), b"added");
+    dir.run_ok(&["verify"]);
+}
+
+#[test]
+pub fn snapshots_and_restores_without_transforming() {
+    use std::io::Read;
+
+    let dir = TestDir::new("no-transform");
+    dir.run_ok(&["init", "--chunking", "1M"]);
+
+    dir.write_file("a.txt", b"raw");
+    dir.run_ok(&["snapshot", "--no-transform"]);
+    let raw_id = dir.head_id();
+
+    let tar = dir.run(&["show-tar", "HEAD"]).stdout;
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(tar.as_slice()));
+    let mut stored = Vec::new();
+    let mut entries = archive.entries().unwrap();
+    entries
+        .next()
+        .unwrap()
+        .unwrap()
+        .read_to_end(&mut stored)
+        .unwrap();
+    assert_eq!(stored, b"raw");
+
+    // the chunking transformer stores small files after a tag byte
+    dir.write_file("a.txt", b"transformed");
+    dir.run_ok(&["snapshot"]);
+    dir.run_ok(&["restore", "--no-transform", "HEAD"]);
+    assert_eq!(dir.read_file("a.txt"), b"\0transformed");
+    dir.run_ok(&["restore", "HEAD"]);
+    assert_eq!(dir.read_file("a.txt"), b"transformed");
+
+    dir.run_ok(&["restore", "--no-transform", &raw_id]);
+    assert_eq!(dir.read_file("a.txt"), b"raw");
+}
+
+#[test]
+pub fn restores_with_the_snapshots_transformers() {
+    let dir = TestDir::new("recorded-transformers");
+    dir.run_ok(&["init", "--chunking", "1M"]);
+    dir.write_file("a.txt", b"a");
+    dir.run_ok(&["snapshot"]);
+    dir.write_file("a.txt", b"changed");
+
+    // chunking is removed from the config after the snapshot was made
+    let config = String::from_utf8(dir.read_file(".jbackup/config")).unwrap();
+    let config: String = config
+        .lines()
+        .filter(|x| !x.starts_with("chunking\t"))
+        .map(|x| format!("{}\n", x))
+        .collect();
+    dir.write_file(".jbackup/config", config.as_bytes());
+
+    let output = dir.run(&["restore", "HEAD"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("created with the transformers"));
+    assert_eq!(dir.read_file("a.txt"), b"a");
+}
+
+#[test]
+pub fn records_merge_parents() {
+    let dir = TestDir::new("merge-parents");
+    dir.run_ok(&["init"]);
+    assert!(!dir.run(&["snapshot", "--parent", "HEAD"]).status.success());
+
+    let mut ids = Vec::new();
+    for contents in [b"1", b"2"] {
+        dir.write_file("a.txt", contents);
+        dir.run_ok(&["snapshot"]);
+        ids.push(dir.head_id());
+    }
+
+    // the head is listed as a parent again, which is ignored
+    dir.write_file("b.txt", b"b");
+    dir.run_ok(&["snapshot", "--parent", &ids[0], "--parent", "HEAD"]);
+    let merge_id = dir.head_id();
+
+    let meta = |id: &str| {
+        String::from_utf8(dir.read_file(&format!(".jbackup/snapshots/{}.meta", id))).unwrap()
+    };
+    let values = |id: &str, key: &str| -> Vec<String> {
+        meta(id)
+            .lines()
+            .filter_map(|x| x.strip_prefix(&format!("{}\t", key)).map(String::from))
+            .collect()
+    };
+    // the head is the first parent, and the only one diffed against
+    assert_eq!(
+        values(&merge_id, "parent"),
+        [ids[1].as_str(), ids[0].as_str()]
+    );
+    assert_eq!(
+        values(&ids[0], "child"),
+        [ids[1].as_str(), merge_id.as_str()]
+    );
+    assert_eq!(values(&ids[0], "dchild"), [ids[1].as_str()]);
+    assert_eq!(values(&ids[1], "child"), [merge_id.as_str()]);
+    assert_eq!(values(&ids[1], "dchild"), [merge_id.as_str()]);
+
+    dir.run_ok(&["verify", "--deep"]);
+    dir.run_ok(&["restore", &ids[0]]);
+    dir.run_ok(&["restore", &merge_id]);
+    assert_eq!(dir.read_file("a.txt"), b"2");
+    assert_eq!(dir.read_file("b.txt"), b"b");
+}
+
+#[test]
+pub fn logs_graph() {
+    let dir = TestDir::new("log-graph");
+    dir.run_ok(&["init"]);
+    let mut ids = Vec::new();
+    for contents in [b"1", b"2"] {
+        dir.write_file("a.txt", contents);
+        dir.run_ok(&["snapshot"]);
+        ids.push(dir.head_id());
+    }
+    dir.write_file("a.txt", b"3");
+    dir.run_ok(&["snapshot", "-m", "merged", "--parent", &ids[0]]);
+    ids.push(dir.head_id());
+
+    let log = dir.run_ok(&["log", "--graph"]);
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(lines.len(), 5);
+    assert!(lines[0].starts_with(&format!("* {} ", ids[0])));
+    assert_eq!(lines[1], "|\\");
+    assert!(lines[2].starts_with(&format!("* | {} ", ids[1])));
+    assert_eq!(lines[3], "|/");
+    assert!(lines[4].starts_with(&format!("* {} ", ids[2])));
+    assert!(lines[4].ends_with(" merged"));
+}
+
+#[test]
+pub fn snapshots_files_listed_on_stdin() {
+    let dir = TestDir::new("stdin-file-list");
+    dir.run_ok(&["init"]);
+    dir.write_file("a.txt", b"a");
+    dir.write_file("b/c.txt", b"c");
+    dir.write_file("d.txt", b"d");
+
+    let output = dir.run_with_stdin(
+        b"./b/c.txt\na.txt\n\nb\na.txt\n",
+        &["snapshot", "--stdin-file-list"],
+    );
+    assert!(output.status.success());
+    // restoring keeps the files that weren't listed
+    let output = dir.run_ok(&["restore", "HEAD"]);
+    assert!(output.contains("Kept the files that aren't in the snapshot"));
+    assert_eq!(dir.list_entries(), ["a.txt", "b/", "b/c.txt", "d.txt"]);
+    assert!(!dir.run(&["clean", "-f"]).status.success());
+
+    dir.remove_file("b/c.txt");
+    let absolute_path = dir.path.join("d.txt");
+    let mut stdin = absolute_path.into_os_string().into_encoded_bytes();
+    stdin.extend(b"\0a.txt");
+    let output = dir.run_with_stdin(&stdin, &["snapshot", "--stdin-file-list", "-0"]);
+    assert!(output.status.success());
+    dir.run_ok(&["restore", "HEAD"]);
+    assert_eq!(dir.list_entries(), ["a.txt", "b/", "d.txt"]);
+
+    for invalid_path in ["../a.txt", "/etc/hostname", ".jbackup/head", "missing.txt"] {
+        let output =
+            dir.run_with_stdin(invalid_path.as_bytes(), &["snapshot", "--stdin-file-list"]);
+        assert!(!output.status.success(), "{} was snapshotted", invalid_path);
+    }
+}
+
+#[test]
+pub fn diffs_snapshots() {
+    let dir = TestDir::new("diff");
+    dir.run_ok(&["init"]);
+    dir.write_file("a.txt", b"a1");
+    dir.write_file("deleted.txt", b"d");
+    dir.run_ok(&["snapshot", "--tag", "v1"]);
+    dir.write_file("a.txt", b"a2");
+    dir.write_file("added.txt", b"new");
+    dir.remove_file("deleted.txt");
+    dir.run_ok(&["snapshot"]);
+
+    let diff = dir.run_ok(&["diff", "v1", "HEAD", "--output", "patch.dl"]);
+    let lines: Vec<&str> = diff.lines().collect();
+    assert_eq!(
+        lines[..4],
+        [
+            "Modified: a.txt",
+            "Added:    added.txt",
+            "Deleted:  deleted.txt",
+            "3 file(s) changed."
+        ]
+    );
+    assert!(dir.read_file("patch.dl").starts_with(&[0x1f, 0x8b]));
+
+    assert!(
+        dir.run_ok(&["diff", "HEAD", "HEAD"])
+            .contains("0 file(s) changed.")
+    );
+    dir.run_ok(&["diff", "v1"]);
+    assert!(!dir.run(&["diff"]).status.success());
+
+    // no scratch files are left behind
+    let tmp_files: Vec<_> = std::fs::read_dir(dir.path.join(".jbackup"))
+        .unwrap()
+        .map(|x| x.unwrap().file_name())
+        .filter(|x| x.to_string_lossy().starts_with("tmp"))
+        .collect();
+    assert!(tmp_files.is_empty(), "{:?}", tmp_files);
+}
+
+#[test]
+pub fn renames_branches() {
+    let dir = TestDir::new("rename-branch");
+    dir.run_ok(&["init"]);
+
+    // the current branch has no snapshots yet
+    dir.run_ok(&["rename-branch", "main", "mian"]);
+    dir.write_file("a.txt", b"a");
+    dir.run_ok(&["snapshot"]);
+    let snapshot_id = dir.head_id();
+
+    dir.run_ok(&["rename-branch", "mian", "main"]);
+    let head = String::from_utf8(dir.read_file(".jbackup/head")).unwrap();
+    assert!(head.contains("branch\tmain"));
+    assert_eq!(dir.run_ok(&["rev-parse", "main"]).trim(), snapshot_id);
+    assert!(!dir.run(&["rev-parse", "mian"]).status.success());
+
+    assert!(
+        !dir.run(&["rename-branch", "missing", "other"])
+            .status
+            .success()
+    );
+    assert!(!dir.run(&["rename-branch", "main", "main"]).status.success());
+    assert!(!dir.run(&["rename-branch", "main", ""]).status.success());
+}
+
+#[test]
+pub fn backs_up_overwritten_and_removed_files() {
+    let dir = TestDir::new("restore-backup");
+    dir.run_ok(&["init"]);
+    dir.write_file("changed.txt", b"v1");
+    dir.write_file("same.txt", b"same");
+    dir.run_ok(&["snapshot"]);
+    dir.write_file("changed.txt", b"v2");
+    dir.write_file("added.txt", b"added");
+
+    let output = dir.run_ok(&["restore", "HEAD", "--backup"]);
+    assert!(output.contains("Backed up 2 file(s)"));
+    assert_eq!(
+        dir.list_entries(),
+        [
+            "added.txt.jbackup-orig",
+            "changed.txt",
+            "changed.txt.jbackup-orig",
+            "same.txt"
+        ]
+    );
+    assert_eq!(dir.read_file("changed.txt"), b"v1");
+    assert_eq!(dir.read_file("changed.txt.jbackup-orig"), b"v2");
+    assert_eq!(dir.read_file("added.txt.jbackup-orig"), b"added");
+
+    // older backups are kept
+    dir.write_file("changed.txt", b"v3");
+    dir.run_ok(&["restore", "HEAD", "--backup"]);
+    assert_eq!(dir.read_file("changed.txt.jbackup-orig"), b"v2");
+    assert_eq!(dir.read_file("changed.txt.jbackup-orig.1"), b"v3");
+
+    // without --backup, the backups are removed like any other file
+    dir.run_ok(&["restore", "HEAD"]);
+    assert_eq!(dir.list_entries(), ["changed.txt", "same.txt"]);
+}
+
+#[test]
+pub fn restores_files_that_became_directories() {
+    let dir = TestDir::new("file-to-dir");
+    dir.run_ok(&["init"]);
+    dir.write_file("a", b"file");
+    dir.write_file("b/c", b"c");
+    dir.run_ok(&["snapshot", "-m", "file"]);
+    dir.remove_file("a");
+    dir.remove_file("b/c");
+    std::fs::remove_dir(dir.path.join("b")).unwrap();
+    dir.write_file("a/x", b"x");
+    dir.write_file("b", b"file");
+    dir.run_ok(&["snapshot", "-m", "dir"]);
+
+    dir.run_ok(&["restore", "HEAD~1"]);
+    assert_eq!(dir.list_entries(), ["a", "b/", "b/c"]);
+    assert_eq!(dir.read_file("a"), b"file");
+
+    dir.run_ok(&["restore", "main"]);
+    assert_eq!(dir.list_entries(), ["a/", "a/x", "b"]);
+    assert_eq!(dir.read_file("b"), b"file");
+
+    // the directory and file in the way are backed up
+    dir.run_ok(&["restore", "HEAD~1", "--backup"]);
+    assert_eq!(dir.read_file("a"), b"file");
+    assert_eq!(dir.read_file("a.jbackup-orig/x"), b"x");
+    assert_eq!(dir.read_file("b/c"), b"c");
+    assert_eq!(dir.read_file("b.jbackup-orig"), b"file");
+}
+
+#[test]
+pub fn keeps_going_past_file_errors() {
+    let dir = TestDir::new("keep-going");
+    dir.run_ok(&["init"]);
+    dir.write_file("a.txt", b"a");
+    dir.write_file("b.txt", b"b");
+
+    // a listed file that doesn't exist can't be read
+    let output = dir.run_with_stdin(b"a.txt\nmissing.txt\n", &["snapshot", "--stdin-file-list"]);
+    assert!(!output.status.success());
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("Created snapshot"));
+
+    let output = dir.run_with_stdin(
+        b"a.txt\nb.txt\nmissing.txt\n",
+        &["snapshot", "--stdin-file-list", "--keep-going"],
+    );
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("1 file(s) couldn't be snapshotted"));
+    assert!(stdout.contains("Created snapshot"));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("missing.txt"));
+
+    // a directory with files that aren't snapshotted, where a file should
+    // be, can't be restored
+    dir.remove_file("a.txt");
+    dir.write_file("a.txt/inner.txt", b"inner");
+    std::os::unix::fs::symlink("inner.txt", dir.path.join("a.txt/link")).unwrap();
+    dir.write_file("b.txt", b"changed");
+
+    let output = dir.run(&["restore", "HEAD", "--keep-going"]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("1 file(s) couldn't be restored"));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("'a.txt'"));
+    assert_eq!(dir.read_file("b.txt"), b"b");
+
+    // without the symlink, the directory is removed
+    dir.remove_file("a.txt/link");
+    dir.run_ok(&["restore", "HEAD"]);
+    assert_eq!(dir.read_file("a.txt"), b"a");
+}
+
+#[test]
+pub fn keeps_snapshot_metadata_in_an_index() {
+    let dir = TestDir::new("compact-meta");
+    dir.run_ok(&["init", "--compact-meta"]);
+    let index_path = dir.path.join(".jbackup/snapshots/index");
+
+    let mut ids = Vec::new();
+    for (i, state) in states().iter().enumerate() {
+        write_state(&dir, state);
+        dir.run_ok(&["snapshot", "-m", &format!("state {}", i)]);
+        ids.push(dir.head_id());
+    }
+    assert!(index_path.exists());
+    let log = dir.run_ok(&["log"]);
+    assert!(log.contains("state 0") && log.contains("state 2"));
+    assert_eq!(dir.run_ok(&["rev-parse", "HEAD~2"]).trim(), ids[0]);
+
+    // the index is rebuilt from the meta files when it's missing
+    std::fs::remove_file(&index_path).unwrap();
+    assert_eq!(dir.run_ok(&["log"]), log);
+    assert!(index_path.exists());
+
+    // removed snapshots are removed from the index
+    dir.run_ok(&["squash", &format!("{}..{}", ids[0], ids[1]), "-f"]);
+    let log = dir.run_ok(&["log"]);
+    assert!(!log.contains(&ids[0]));
+    assert_eq!(dir.run_ok(&["rev-parse", "HEAD~1"]).trim(), ids[1]);
+    assert!(!dir.run(&["rev-parse", "HEAD~2"]).status.success());
+
+    dir.run_ok(&["restore", &ids[1]]);
+    assert_state(&dir, &states()[1]);
+    assert!(!dir.run_ok(&["size"]).contains("index"));
+}
+
+#[test]
+pub fn gzips_metadata_files() {
+    let dir = TestDir::new("gzip-meta");
+    dir.run_ok(&["init", "--gzip-meta"]);
+    let is_gzipped = |path: &str| std::fs::read(dir.path.join(path)).unwrap()[..2] == [0x1f, 0x8b];
+
+    let mut ids = Vec::new();
+    for (i, state) in states().iter().enumerate() {
+        write_state(&dir, state);
+        let message = format!("state {}", i);
+        if i == 0 {
+            dir.run_ok(&["snapshot", "-m", &message, "--tag", "first"]);
+        } else {
+            dir.run_ok(&["snapshot", "-m", &message]);
+        }
+        ids.push(dir.head_id());
+    }
+
+    assert!(is_gzipped(".jbackup/head"));
+    assert!(is_gzipped(".jbackup/branches"));
+    assert!(is_gzipped(".jbackup/tags"));
+    for id in &ids {
+        assert!(is_gzipped(&format!(".jbackup/snapshots/{}.meta", id)));
+    }
+    assert!(!is_gzipped(".jbackup/config"));
+
+    let log = dir.run_ok(&["log"]);
+    assert!(log.contains("state 0") && log.contains("state 2"));
+    dir.run_ok(&["verify"]);
+    dir.run_ok(&["restore", "first"]);
+    assert_state(&dir, &states()[0]);
+
+    // once gzip_meta is unset, files are written in plaintext, and the
+    // gzipped files are still read
+    let config_path = dir.path.join(".jbackup/config");
+    let config = std::fs::read_to_string(&config_path).unwrap();
+    std::fs::write(&config_path, config.replace("gzip_meta\ttrue\n", "")).unwrap();
+    // a new file, so the snapshot doesn't have the same id as the first one
+    // if it's made within the same second
+    dir.write_file("plaintext.txt", b"plaintext");
+    dir.run_ok(&["snapshot", "-m", "plaintext"]);
+    assert!(!is_gzipped(".jbackup/head"));
+    assert!(!is_gzipped(&format!(".jbackup/snapshots/{}.meta", ids[2])));
+    assert!(is_gzipped(&format!(".jbackup/snapshots/{}.meta", ids[0])));
+
+    let log = dir.run_ok(&["log"]);
+    assert!(log.contains("state 0") && log.contains("plaintext"));
+    dir.run_ok(&["restore", &ids[1]]);
+    assert_state(&dir, &states()[1]);
+}
+
+#[test]
+pub fn stores_diffs_uncompressed() {
+    let contents = "not compressed ".repeat(100);
+    let mut diff_sizes = Vec::new();
+
+    for compression in ["store", "best"] {
+        let dir = TestDir::new("compression");
+        dir.run_ok(&["init", "--compression", compression]);
+        dir.write_file("a.txt", b"a");
+        dir.write_file("removed.txt", contents.as_bytes());
+        dir.run_ok(&["snapshot"]);
+        dir.remove_file("removed.txt");
+        dir.run_ok(&["snapshot"]);
+
+        let diff_path = std::fs::read_dir(dir.path.join(".jbackup/snapshots"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.to_string_lossy().contains("-diff-"))
+            .unwrap();
+        diff_sizes.push(std::fs::metadata(diff_path).unwrap().len());
+
+        dir.run_ok(&["restore", "HEAD~1"]);
+        assert_eq!(dir.read_file("removed.txt"), contents.as_bytes());
+    }
+
+    // the first snapshot is stored as a diff from the second, which adds the
+    // removed file back
+    assert!(diff_sizes[0] > 1000);
+    assert!(diff_sizes[1] < 100);
+}
+
+#[test]
+pub fn fails_to_restore_corrupt_payloads() {
+    use std::io::{Read, Write};
+
+    let dir = TestDir::new("corrupt-payload");
+    dir.run_ok(&["init"]);
+    dir.write_file("a.txt", b"a");
+    dir.write_file("b.txt", b"b");
+    dir.run_ok(&["snapshot"]);
+    let id = dir.head_id();
+
+    // the header of b.txt, after a.txt's header and contents, gets a bad
+    // checksum
+    let payload_path = format!(".jbackup/snapshots/{}-full.tar.gz", id);
+    let mut tar = Vec::new();
+    flate2::read::GzDecoder::new(dir.read_file(&payload_path).as_slice())
+        .read_to_end(&mut tar)
+        .unwrap();
+    tar[1024 + 148..1024 + 156].copy_from_slice(b"0000000\0");
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+    encoder.write_all(&tar).unwrap();
+    dir.write_file(&payload_path, &encoder.finish().unwrap());
+
+    // b.txt isn't removed as if it wasn't in the snapshot
+    dir.write_file("a.txt", b"changed");
+    let output = dir.run(&["restore", "HEAD"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("corrupt"));
+    assert_eq!(dir.read_file("b.txt"), b"b");
+}
+
+#[test]
+pub fn recovers_corrupt_head() {
+    let dir = TestDir::new("recover-head");
+    dir.run_ok(&["init"]);
+    dir.write_file("a.txt", b"a");
+    dir.run_ok(&["snapshot"]);
+    dir.write_file("a.txt", b"b");
+    dir.run_ok(&["snapshot"]);
+    let id = dir.head_id();
+    dir.run_ok(&["rename-branch", "main", "overworld"]);
+
+    dir.write_file(".jbackup/head", b"branch");
+    assert!(!dir.run(&["log"]).status.success());
+    assert!(dir.run_ok(&["recover-head"]).contains(&id));
+    assert!(!dir.run(&["log"]).status.success());
+
+    dir.run_ok(&["recover-head", "-f"]);
+    assert_eq!(dir.head_id(), id);
+    dir.write_file("a.txt", b"c");
+    dir.run_ok(&["snapshot"]);
+    assert!(dir.run_ok(&["rev-parse", "overworld~1"]).contains(&id));
+    assert!(dir.run_ok(&["recover-head"]).contains("nothing to recover"));
+}
+
+#[test]
+pub fn reads_newer_snapshots_read_only() {
+    let dir = TestDir::new("newer-meta");
+    dir.run_ok(&["init"]);
+    dir.write_file("a.txt", b"a");
+    dir.run_ok(&["snapshot", "-m", "from the future"]);
+    let id = dir.head_id();
+
+    // a newer version may add keys this version doesn't know about
+    let meta_path = dir.path.join(format!(".jbackup/snapshots/{}.meta", id));
+    let meta = std::fs::read_to_string(&meta_path).unwrap();
+    let newer_meta = meta.replace("version\t1\n", "version\t999\nfuture\tvalue\n");
+    assert_ne!(meta, newer_meta);
+    std::fs::write(&meta_path, &newer_meta).unwrap();
+
+    assert!(dir.run_ok(&["log"]).contains("from the future"));
+    dir.run_ok(&["verify", "--deep"]);
+    dir.write_file("a.txt", b"changed");
+    dir.run_ok(&["restore", &id]);
+    assert_eq!(dir.read_file("a.txt"), b"a");
+
+    dir.write_file("a.txt", b"b");
+    let output = dir.run(&["snapshot"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("newer version of jbackup"));
+    assert_eq!(dir.head_id(), id);
+    assert_eq!(std::fs::read_to_string(&meta_path).unwrap(), newer_meta);
+    assert_eq!(dir.run_ok(&["log"]).matches("Message:").count(), 1);
+    let snapshot_files = std::fs::read_dir(dir.path.join(".jbackup/snapshots")).unwrap();
+    assert_eq!(snapshot_files.count(), 2);
+}
+
+#[test]
+pub fn amends_the_head_snapshot() {
+    let dir = TestDir::new("amend");
+    dir.run_ok(&["init"]);
+    let states = states();
+
+    assert!(!dir.run(&["snapshot", "--amend"]).status.success());
+
+    write_state(&dir, &states[0]);
+    dir.run_ok(&["snapshot", "-m", "first"]);
+    let first_id = dir.head_id();
+    write_state(&dir, &states[1]);
+    dir.run_ok(&["snapshot", "-m", "second", "--tag", "second"]);
+    let second_id = dir.head_id();
+
+    write_state(&dir, &states[2]);
+    dir.run_ok(&["snapshot", "--amend"]);
+    let amended_id = dir.head_id();
+    assert_ne!(amended_id, second_id);
+
+    // the amended snapshot takes the old snapshot's place and message
+    let log = dir.run_ok(&["log"]);
+    assert!(!log.contains(&second_id));
+    assert_eq!(log.matches("Message:").count(), 2);
+    assert!(log.contains("Message:   second"));
+    assert_eq!(dir.run_ok(&["rev-parse", "HEAD~1"]).trim(), first_id);
+    assert_eq!(dir.run_ok(&["rev-parse", "second"]).trim(), amended_id);
+    assert_eq!(dir.run_ok(&["rev-parse", "main"]).trim(), amended_id);
+    assert!(dir.run_ok(&["reflog"]).contains("snapshot (amend)"));
+
+    // without changes, only the message is amended
+    dir.run_ok(&["snapshot", "--amend", "-m", "renamed"]);
+    assert_eq!(dir.head_id(), amended_id);
+    assert!(dir.run_ok(&["log"]).contains("Message:   renamed"));
+    assert!(!dir.run_ok(&["log"]).contains("Message:   second"));
+    dir.run_ok(&["verify", "--deep"]);
+    dir.run_ok(&["restore", &first_id]);
+    assert_state(&dir, &states[0]);
+    dir.run_ok(&["restore", &amended_id]);
+    assert_state(&dir, &states[2]);
+
+    // snapshots that other snapshots descend from can't be amended
+    let head_path = dir.path.join(".jbackup/head");
+    let head = std::fs::read_to_string(&head_path).unwrap();
+    std::fs::write(&head_path, head.replace(&amended_id, &first_id)).unwrap();
+    write_state(&dir, &states[1]);
+    assert!(!dir.run(&["snapshot", "--amend"]).status.success());
+    assert_eq!(dir.run_ok(&["log"]).matches("Message:").count(), 2);
+}
+
+#[test]
+pub fn excludes_tagged_cache_directories() {
+    let dir = TestDir::new("exclude-caches");
+    dir.run_ok(&["init", "--exclude-caches"]);
+
+    let tag = b"Signature: 8a477f597d28d172789f06886806bc55\n# This is a cache.\n";
+    dir.write_file("world/level.dat", b"level");
+    dir.write_file("world/cache/CACHEDIR.TAG", tag);
+    dir.write_file("world/cache/chunk.bin", b"regenerated");
+    dir.write_file("notes/CACHEDIR.TAG", b"not a real tag");
+    dir.run_ok(&["snapshot"]);
+    let id = dir.head_id();
+
+    // the tagged directory is left alone when restoring
+    dir.write_file("world/level.dat", b"changed");
+    dir.remove_file("notes/CACHEDIR.TAG");
+    dir.run_ok(&["restore", &id]);
+    assert_eq!(dir.read_file("world/level.dat"), b"level");
+    assert_eq!(dir.read_file("notes/CACHEDIR.TAG"), b"not a real tag");
+    assert_eq!(dir.read_file("world/cache/chunk.bin"), b"regenerated");
+
+    // and isn't in the snapshot
+    std::fs::remove_dir_all(dir.path.join("world/cache")).unwrap();
+    dir.run_ok(&["restore", &id]);
+    assert!(!dir.path.join("world/cache").exists());
+
+    // without the config key, the snapshot records that it left caches out,
+    // so restoring it leaves them alone too
+    let dir = TestDir::new("exclude-caches-flag");
+    dir.run_ok(&["init"]);
+    dir.write_file("a.txt", b"a");
+    dir.write_file("cache/CACHEDIR.TAG", tag);
+    dir.write_file("cache/file", b"cached");
+    dir.run_ok(&["snapshot", "--exclude-caches"]);
+    dir.run_ok(&["restore", &dir.head_id()]);
+    assert_eq!(
+        dir.list_entries(),
+        ["a.txt", "cache/", "cache/CACHEDIR.TAG", "cache/file"]
+    );
+}
+
+#[test]
+pub fn stays_on_one_filesystem() {
+    // the test directory is on one filesystem, so nothing is left out
+    let dir = TestDir::new("one-file-system");
+    dir.run_ok(&["init", "--xdev"]);
+    let config = String::from_utf8(dir.read_file(".jbackup/config")).unwrap();
+    assert!(config.contains("one_file_system\ttrue"));
+
+    dir.write_file("a.txt", b"a");
+    dir.write_file("world/level.dat", b"level");
+    dir.run_ok(&["snapshot", "--one-file-system"]);
+    let id = dir.head_id();
+    dir.remove_file("world/level.dat");
+    dir.run_ok(&["restore", &id]);
+    assert_eq!(dir.read_file("world/level.dat"), b"level");
+}
+
+#[test]
+pub fn snapshots_with_fsync() {
+    let dir = TestDir::new("fsync");
+    dir.run_ok(&["init", "--fsync"]);
+    let config = String::from_utf8(dir.read_file(".jbackup/config")).unwrap();
+    assert!(config.contains("fsync\ttrue"));
+
+    dir.write_file("a.txt", b"a");
+    dir.run_ok(&["snapshot"]);
+    let first_id = dir.head_id();
+    dir.write_file("a.txt", b"b");
+    dir.run_ok(&["snapshot"]);
+    dir.run_ok(&["restore", &first_id]);
+    assert_eq!(dir.read_file("a.txt"), b"a");
+
+    // without the config key, only the snapshot is flushed
+    let dir = TestDir::new("fsync-flag");
+    dir.run_ok(&["init"]);
+    dir.write_file("a.txt", b"a");
+    dir.run_ok(&["snapshot", "--fsync"]);
+    dir.remove_file("a.txt");
+    dir.run_ok(&["restore", &dir.head_id()]);
+    assert_eq!(dir.read_file("a.txt"), b"a");
+}
+
+#[test]
+pub fn excludes_directories_with_marker_files() {
+    let dir = TestDir::new("exclude-if-present");
+    dir.run_ok(&["init", "--exclude-if-present", ".nobackup"]);
+
+    dir.write_file("a.txt", b"a");
+    dir.write_file("downloads/.nobackup", b"");
+    dir.write_file("downloads/big.iso", b"iso");
+    dir.write_file("photos/b.jpg", b"b");
+    dir.write_file("photos/thumbs/.thumbs", b"");
+    dir.write_file("photos/thumbs/b.jpg", b"thumb");
+    dir.run_ok(&["snapshot", "--exclude-if-present", ".thumbs"]);
+    let id = dir.head_id();
+
+    // directories with either marker are left alone when restoring
+    dir.run_ok(&["restore", &id]);
+    assert_eq!(dir.read_file("downloads/big.iso"), b"iso");
+    assert_eq!(dir.read_file("photos/thumbs/b.jpg"), b"thumb");
+
+    // and neither is in the snapshot
+    std::fs::remove_dir_all(dir.path.join("downloads")).unwrap();
+    std::fs::remove_dir_all(dir.path.join("photos/thumbs")).unwrap();
+    dir.run_ok(&["restore", &id]);
+    assert_eq!(dir.list_entries(), ["a.txt", "photos/", "photos/b.jpg"]);
+
+    assert!(
+        !dir.run(&["snapshot", "--exclude-if-present", "a/b"])
+            .status
+            .success()
+    );
+}
+
+#[test]
+pub fn prints_only_the_snapshot_id() {
+    let dir = TestDir::new("print-id");
+    dir.run_ok(&["init"]);
+    dir.write_file("a.txt", b"a");
+
+    let output = dir.run(&["snapshot", "--print-id", "-v", "--tag", "first"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, format!("{}\n", dir.head_id()));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Inserting: ./a.txt") && stderr.contains("Tagged snapshot"));
+
+    // the head's id is printed when no snapshot is created
+    let output = dir.run(&["snapshot", "--print-id"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, format!("{}\n", dir.head_id()));
+    assert!(
+        String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("No changes")
+    );
+}
+
+#[test]
+pub fn self_tests_transformers() {
+    let dir = TestDir::new("self-test");
+    dir.run_ok(&["init", "--transformer", "minecraft_mca"]);
+
+    let output = dir.run_ok(&["self-test"]);
+    assert!(output.contains("minecraft_mca: passed"));
+    assert!(output.contains("All 1 transformer(s) passed."));
+
+    dir.write_file("notes.txt", b"notes");
+    let output = dir.run_ok(&[
+        "self-test",
+        "--transformer",
+        "chunking",
+        "--file",
+        "notes.txt",
+    ]);
+    assert!(output.contains("chunking: passed"));
+    assert!(!output.contains("minecraft_mca"));
+
+    assert!(
+        !dir.run(&["self-test", "--transformer", "unknown"])
+            .status
+            .success()
+    );
+}
+
+#[test]
+pub fn snapshots_mapped_files() {
+    let dir = TestDir::new("mmap");
+    dir.run_ok(&["init", "--mmap", "1K", "--transformer", "minecraft_mca"]);
+
+    let large: Vec<u8> = (0..100_000u32).flat_map(|x| x.to_le_bytes()).collect();
+    dir.write_file("large.bin", &large);
+    dir.write_file("small.txt", b"small");
+    dir.write_file("empty.mca", b"");
+    dir.run_ok(&["snapshot"]);
+    let id = dir.head_id();
+
+    let mut changed = large.clone();
+    changed[1000] = 0xff;
+    dir.write_file("large.bin", &changed);
+    dir.run_ok(&["snapshot"]);
+    assert!(dir.run_ok(&["diff", &id, "HEAD"]).contains("large.bin"));
+
+    dir.run_ok(&["restore", &id]);
+    assert_eq!(dir.read_file("large.bin"), large);
+    assert_eq!(dir.read_file("small.txt"), b"small");
+    dir.run_ok(&["restore", "main"]);
+    assert_eq!(dir.read_file("large.bin"), changed);
+}
+
+#[test]
+pub fn restores_sparse_files() {
+    let dir = TestDir::new("sparse");
+    dir.run_ok(&["init"]);
+
+    let mut disk = vec![0u8; 1 << 20];
+    disk[0] = 1;
+    disk[(1 << 19) + 3] = 2;
+    dir.write_file("disk.img", &disk);
+    dir.write_file("a.txt", b"a");
+    dir.run_ok(&["snapshot"]);
+
+    dir.remove_file("disk.img");
+    dir.write_file("a.txt", b"changed");
+    dir.run_ok(&["restore", "HEAD", "--sparse"]);
+    assert!(dir.read_file("disk.img") == disk);
+    assert_eq!(dir.read_file("a.txt"), b"a");
+}
+
+#[test]
+pub fn reports_chain_depths() {
+    let dir = TestDir::new("chain-depth");
+    dir.run_ok(&["init"]);
+    let mut ids = Vec::new();
+    for (i, state) in states().iter().enumerate() {
+        write_state(&dir, state);
+        // snapshots made within the same second would tie when sorted by date
+        api::snapshot(&dir.path, dated(1_000_000 + i as i64)).unwrap();
+        ids.push(dir.head_id());
+    }
+
+    let report = dir.run_ok(&["chain-depth"]);
+    assert!(report.starts_with("3 snapshot(s), deepest chain: 2, average chain: 1.0\n"));
+    let deepest = report.lines().nth(3).unwrap();
+    assert_eq!(
+        deepest.split_whitespace().collect::<Vec<_>>(),
+        ["2", &ids[0]]
+    );
+
+    let json = dir.run_ok(&["chain-depth", "--sort", "date", "--top", "1", "--json"]);
+    assert!(json.contains("\"max_depth\":2"));
+    assert!(json.contains(&format!("\"snapshots\":[{{\"id\":\"{}\"", ids[2])));
+    assert!(json.contains("\"depth\":0}]"));
+
+    assert!(!dir.run(&["chain-depth", "--sort", "size"]).status.success());
+}
+
+#[test]
+pub fn limits_diff_chains() {
+    for direction in ["reverse", "forward"] {
+        let dir = TestDir::new(&format!("max-diff-chain-{}", direction));
+        dir.run_ok(&[
+            "init",
+            "--diff-direction",
+            direction,
+            "--max-diff-chain",
+            "2",
+        ]);
+        let mut ids = Vec::new();
+        for i in 0..6 {
+            dir.write_file("a.txt", format!("version {}", i).as_bytes());
+            dir.run_ok(&["snapshot"]);
+            ids.push(dir.head_id());
+        }
+
+        let json = dir.run_ok(&["chain-depth", "--json"]);
+        assert!(json.contains("\"max_depth\":2"), "{}", json);
+        dir.run_ok(&["verify", "--deep"]);
+        for (i, id) in ids.iter().enumerate() {
+            dir.run_ok(&["restore", id]);
+            assert_eq!(dir.read_file("a.txt"), format!("version {}", i).as_bytes());
+        }
+    }
+
+    assert!(
+        !TestDir::new("max-diff-chain-invalid")
+            .run(&["init", "--max-diff-chain", "many"])
+            .status
+            .success()
+    );
+}
+
+/// Creates a tar.gz of (path, contents) files, and (path, target) hard links.
+fn create_archive(files: &[(&str, &[u8])], links: &[(&str, &str)]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+        Vec::new(),
+        flate2::Compression::fast(),
+    ));
+    for (path, contents) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, path, *contents).unwrap();
+    }
+    for (path, target) in links {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_size(0);
+        builder.append_link(&mut header, path, target).unwrap();
+    }
+    builder.into_inner().unwrap().finish().unwrap()
+}
+
+#[test]
+pub fn imports_an_archive_on_init() {
+    use std::{fs, os::unix::fs::MetadataExt};
+
+    let data_dir = TestDir::new("import-data");
+    // not in the order snapshots are stored in
+    let archive = create_archive(
+        &[
+            ("./z/one.txt", b"one"),
+            ("./a/two.txt", b"two"),
+            ("b.txt", b"b"),
+        ],
+        &[("a/link.txt", "z/one.txt")],
+    );
+    data_dir.write_file("archive.tar.gz", &archive);
+    let archive_path = data_dir.path.join("archive.tar.gz");
+
+    let dir = TestDir::new("import");
+    let output = dir.run_ok(&[
+        "init",
+        "--import",
+        archive_path.to_str().unwrap(),
+        "-m",
+        "imported",
+    ]);
+    assert!(output.contains("Imported 4 file(s)"));
+    assert!(dir.run_ok(&["log"]).contains("imported"));
+    dir.run_ok(&["verify", "--deep"]);
+
+    // the imported files weren't walked from the working directory, so it
+    // isn't known which files were left out, and none are removed
+    dir.write_file("untracked.txt", b"untracked");
+    let output = dir.run_ok(&["restore", "HEAD"]);
+    assert!(output.contains("Kept the files that aren't in the snapshot"));
+    assert_eq!(
+        dir.list_entries(),
+        [
+            "a/",
+            "a/link.txt",
+            "a/two.txt",
+            "b.txt",
+            "untracked.txt",
+            "z/",
+            "z/one.txt"
+        ]
+    );
+    assert!(!dir.run(&["clean", "-f"]).status.success());
+    dir.remove_file("untracked.txt");
+    assert_eq!(dir.read_file("a/link.txt"), b"one");
+    let inode = |path: &str| fs::metadata(dir.path.join(path)).unwrap().ino();
+    assert_eq!(inode("a/link.txt"), inode("z/one.txt"));
+
+    // later snapshots are diffed against the imported snapshot
+    dir.write_file("b.txt", b"changed");
+    dir.run_ok(&["snapshot"]);
+    dir.run_ok(&["restore", "HEAD~1"]);
+    assert_eq!(dir.read_file("b.txt"), b"b");
+}
+
+#[test]
+pub fn checks_archives_before_importing() {
+    let data_dir = TestDir::new("import-bad-data");
+    let archive = create_archive(&[("a.txt", b"a")], &[("b.txt", "missing.txt")]);
+    data_dir.write_file("missing-link.tar.gz", &archive);
+    let archive = create_archive(&[("a.txt", &[7u8; 100_000])], &[]);
+    data_dir.write_file("truncated.tar.gz", &archive[..archive.len() / 2]);
+
+    let dir = TestDir::new("import-bad");
+    for name in [
+        "missing-link.tar.gz",
+        "truncated.tar.gz",
+        "nonexistent.tar.gz",
+    ] {
+        let archive_path = data_dir.path.join(name);
+        let output = dir.run(&["init", "--import", archive_path.to_str().unwrap()]);
+        assert!(!output.status.success(), "{}", name);
+        assert!(!dir.path.join(".jbackup").exists(), "{}", name);
+    }
+
+    assert!(!dir.run(&["init", "-m", "no archive"]).status.success());
+}
+
+#[test]
+pub fn makes_snapshot_ids_in_other_formats() {
+    let dir = TestDir::new("id-format-seq");
+    dir.run_ok(&["init", "--snapshot-id-format", "seq"]);
+    dir.write_file("a.txt", b"1");
+    dir.run_ok(&["snapshot"]);
+    assert_eq!(dir.head_id(), "0000000001");
+    dir.write_file("a.txt", b"2");
+    dir.run_ok(&["snapshot"]);
+    assert_eq!(dir.head_id(), "0000000002");
+
+    // unchanged files are found without the md5 in the id
+    let output = dir.run_ok(&["snapshot"]);
+    assert!(output.contains("No changes"));
+    assert_eq!(dir.head_id(), "0000000002");
+
+    dir.run_ok(&["verify", "--deep"]);
+    dir.run_ok(&["restore", "HEAD~1"]);
+    assert_eq!(dir.read_file("a.txt"), b"1");
+    assert!(
+        String::from_utf8_lossy(&dir.read_file(".jbackup/config")).contains("snapshot_id_seq\t2")
+    );
+
+    let dir = TestDir::new("id-format-ulid");
+    dir.run_ok(&["init", "--snapshot-id-format", "ulid"]);
+    dir.write_file("a.txt", b"1");
+    dir.run_ok(&["snapshot"]);
+    let first_id = dir.head_id();
+    dir.write_file("a.txt", b"2");
+    dir.run_ok(&["snapshot"]);
+    let second_id = dir.head_id();
+    assert_eq!(first_id.len(), 26);
+    assert!(first_id < second_id);
+    assert!(dir.run_ok(&["snapshot"]).contains("No changes"));
+    dir.run_ok(&["verify", "--deep"]);
+
+    let dir = TestDir::new("id-format-invalid");
+    assert!(
+        !dir.run(&["init", "--snapshot-id-format", "uuid"])
+            .status
+            .success()
+    );
+}
+
+#[test]
+pub fn ignores_case_when_configured() {
+    let dir = TestDir::new("ignore-case");
+    dir.run_ok(&["init", "--ignore-case"]);
+    dir.write_file("README", b"one");
+    dir.write_file("readme", b"two");
+    dir.write_file("b.txt", b"b");
+
+    // on a case-insensitive filesystem, both names are the same file
+    let output = dir.run(&["snapshot"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("differs only by case"));
+
+    dir.remove_file("readme");
+    dir.write_file("C/x", b"x");
+    dir.write_file("c.txt", b"c");
+    dir.run_ok(&["snapshot"]);
+    dir.run_ok(&["verify", "--deep"]);
+    assert!(
+        dir.run_ok(&["diff", "HEAD~1", "HEAD"])
+            .contains("2 file(s) changed")
+    );
+
+    dir.run_ok(&["restore", "HEAD~1"]);
+    assert_eq!(dir.list_entries(), ["README", "b.txt"]);
+    assert_eq!(dir.read_file("README"), b"one");
+
+    // a file renamed to another case is the same file, so it's not removed
+    dir.remove_file("README");
+    dir.write_file("readme", b"one");
+    dir.run_ok(&["restore", "HEAD"]);
+    assert!(dir.path.join("readme").exists());
+}
+
+#[test]
+pub fn snapshots_with_a_small_io_buffer() {
+    // every write is split across many small buffers, which must all be
+    // flushed before the files are read back
+    let dir = TestDir::new("io-buffer-size");
+    dir.run_ok(&["init", "--io-buffer-size", "7"]);
+
+    let states = states();
+    let mut ids = Vec::new();
+    for state in &states {
+        write_state(&dir, state);
+        dir.run_ok(&["snapshot"]);
+        ids.push(dir.head_id());
+    }
+    dir.run_ok(&["verify", "--deep"]);
+    assert!(
+        dir.run_ok(&["diff", &ids[0], &ids[2]])
+            .contains("2 file(s) changed")
+    );
+    for (state, id) in states.iter().zip(&ids) {
+        dir.run_ok(&["restore", id]);
+        assert_state(&dir, state);
+    }
+
+    let dir = TestDir::new("io-buffer-size-zero");
+    assert!(!dir.run(&["init", "--io-buffer-size", "0"]).status.success());
+}
+
+#[test]
+pub fn extracts_a_file_from_a_snapshot() {
+    let dir = TestDir::new("extract");
+    let scratch = TestDir::new("extract-scratch");
+    dir.run_ok(&["init"]);
+    dir.write_file("d/a", b"old");
+    dir.run_ok(&["snapshot"]);
+    dir.write_file("d/a", b"new");
+    std::fs::hard_link(dir.path.join("d/a"), dir.path.join("link")).unwrap();
+    dir.run_ok(&["snapshot"]);
+
+    let output_path = scratch.path.join("a");
+    let output_path = output_path.to_str().unwrap();
+    dir.run_ok(&["extract", "HEAD~1", "./d/a", output_path]);
+    assert_eq!(scratch.read_file("a"), b"old");
+    dir.run_ok(&["extract", "HEAD", "link", output_path]);
+    assert_eq!(scratch.read_file("a"), b"new");
+
+    // the working directory is untouched
+    assert_eq!(dir.read_file("d/a"), b"new");
+    assert_eq!(dir.list_entries(), ["d/", "d/a", "link"]);
+
+    assert!(
+        !dir.run(&["extract", "HEAD~1", "link", output_path])
+            .status
+            .success()
+    );
+    assert!(
+        !dir.run(&["extract", "HEAD", "d/../d/a", output_path])
+            .status
+            .success()
+    );
+    assert!(!dir.run(&["extract", "HEAD", "d/a"]).status.success());
+}
+
+#[test]
+pub fn transforms_with_an_external_program() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = TestDir::new("exec-transformer");
+    let tools = TestDir::new("exec-transformer-tools");
+    // reverses the lines of files in, and back out
+    tools.write_file("reverse", b"#!/bin/sh\nexec tac\n");
+    let program = tools.path.join("reverse");
+    std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let transformer = format!("exec:{}", program.to_str().unwrap());
+
+    dir.run_ok(&["init", "--transformer", &transformer]);
+    dir.write_file("a.txt", b"1\n2\n3\n");
+    dir.run_ok(&["snapshot"]);
+
+    let tar = dir.run_ok_bytes(&["show-tar", "--payload-only", "HEAD"]);
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(tar.as_slice()));
+    let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+    let mut stored = Vec::new();
+    std::io::Read::read_to_end(&mut entry, &mut stored).unwrap();
+    assert_eq!(stored, b"3\n2\n1\n");
+
+    dir.write_file("a.txt", b"changed\n");
+    dir.run_ok(&["restore", "HEAD"]);
+    assert_eq!(dir.read_file("a.txt"), b"1\n2\n3\n");
+
+    // a lossy program needs --allow-lossy
+    let lossy_dir = TestDir::new("exec-lossy-transformer");
+    let lossy_transformer = format!("exec-lossy:{}", program.to_str().unwrap());
+    lossy_dir.run_ok(&["init", "--transformer", &lossy_transformer]);
+    lossy_dir.write_file("a.txt", b"1\n2\n");
+    let output = lossy_dir.run(&["snapshot"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("--allow-lossy"));
+    lossy_dir.run_ok(&["snapshot", "--allow-lossy"]);
+
+    // a program that fails fails the snapshot, without crashing or leaving
+    // its tar behind
+    tools.write_file("reverse", b"#!/bin/sh\nexit 1\n");
+    dir.write_file("a.txt", b"4\n");
+    let output = dir.run(&["snapshot"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Fatal:"));
+    let tmp_files = std::fs::read_dir(dir.path.join(".jbackup"))
+        .unwrap()
+        .filter(|x| {
+            let name = x.as_ref().unwrap().file_name();
+            name.to_string_lossy().starts_with("tmp_snapshot")
+        })
+        .count();
+    assert_eq!(tmp_files, 0);
+}
+
+#[test]
+pub fn prints_transformer_stats() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = TestDir::new("transformer-stats");
+    let tools = TestDir::new("transformer-stats-tools");
+    // doubles every line
+    tools.write_file("double", b"#!/bin/sh\nexec sed p\n");
+    let program = tools.path.join("double");
+    std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let transformer = format!("exec:{}", program.to_str().unwrap());
+
+    dir.run_ok(&["init", "--transformer", &transformer]);
+    dir.write_file("a.txt", b"1\n2\n");
+    dir.write_file("b.txt", b"345\n");
+    let output = dir.run_ok(&["snapshot", "--stats"]);
+    assert!(output.contains(&format!(
+        "Transformer {}: 2 file(s), 8 B in, 16 B out (2.00x)",
+        transformer
+    )));
+
+    dir.write_file("c.txt", b"6\n");
+    assert!(!dir.run_ok(&["snapshot"]).contains("Transformer"));
+    assert!(
+        !dir.run_ok(&["snapshot", "--stats", "--no-transform"])
+            .contains("Transformer")
+    );
+}